@@ -0,0 +1,283 @@
+use axum::{
+    body::{Body, Bytes},
+    extract::{Path, Query, State},
+    http::{HeaderValue, Response, StatusCode},
+    response::IntoResponse,
+    routing::{delete, get, post},
+    Json, Router,
+};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use crate::capture::{CaptureStore, RequestEdits};
+use crate::config::AdminConfig;
+use crate::har::{self, Har};
+use crate::maintenance::Maintenance;
+use crate::proxy::{forward_request, ProxyContext};
+
+/// State shared by the admin API handlers
+#[derive(Clone)]
+pub struct AdminState {
+    pub capture: Option<Arc<CaptureStore>>,
+    /// Listener contexts by port, used to replay through the original listener
+    pub listeners: HashMap<u16, Arc<ProxyContext>>,
+}
+
+/// Build the admin API router
+pub fn router(state: AdminState) -> Router {
+    Router::new()
+        .route("/api/requests", get(list_requests))
+        .route("/api/requests/:id", get(get_request))
+        .route("/api/requests/:id/replay", post(replay_request))
+        .route("/api/har", get(export_har))
+        .route("/api/cache", delete(purge_cache))
+        .route("/api/circuits", get(list_circuits))
+        .route(
+            "/api/maintenance",
+            get(list_maintenance).put(set_maintenance),
+        )
+        .with_state(state)
+}
+
+/// Serve the admin API over plain HTTP
+pub async fn serve(config: AdminConfig, state: AdminState) -> anyhow::Result<()> {
+    let addr = SocketAddr::new(config.bind, config.port);
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    tracing::info!("Admin API on {}", addr);
+    axum::serve(listener, router(state)).await?;
+    Ok(())
+}
+
+#[derive(Debug, Deserialize)]
+struct ListParams {
+    limit: Option<usize>,
+    /// Only include requests received on this listener port
+    port: Option<u16>,
+}
+
+fn capture_store(state: &AdminState) -> Result<&Arc<CaptureStore>, (StatusCode, String)> {
+    state.capture.as_ref().ok_or((
+        StatusCode::NOT_FOUND,
+        "Request capture is disabled".to_string(),
+    ))
+}
+
+fn not_found(id: u64) -> (StatusCode, String) {
+    (
+        StatusCode::NOT_FOUND,
+        format!("No captured request with id {}", id),
+    )
+}
+
+/// GET /api/requests - captured requests, newest first
+async fn list_requests(
+    State(state): State<AdminState>,
+    Query(params): Query<ListParams>,
+) -> impl IntoResponse {
+    let capture = capture_store(&state)?;
+    let entries: Vec<_> = capture
+        .list(usize::MAX)
+        .iter()
+        .filter(|entry| params.port.is_none_or(|port| entry.listener_port == port))
+        .take(params.limit.unwrap_or(100))
+        .map(|entry| entry.summary())
+        .collect();
+    Ok::<_, (StatusCode, String)>(Json(entries))
+}
+
+/// GET /api/requests/:id - a single captured request with headers and body
+async fn get_request(State(state): State<AdminState>, Path(id): Path<u64>) -> impl IntoResponse {
+    let capture = capture_store(&state)?;
+    let entry = capture.get(id).ok_or_else(|| not_found(id))?;
+    Ok::<_, (StatusCode, String)>(Json(entry.detail(capture.max_body_bytes())))
+}
+
+/// GET /api/har - captured requests as a HAR 1.2 download, oldest first
+async fn export_har(
+    State(state): State<AdminState>,
+    Query(params): Query<ListParams>,
+) -> impl IntoResponse {
+    let capture = capture_store(&state)?;
+    let mut entries: Vec<_> = capture
+        .list(usize::MAX)
+        .iter()
+        .filter(|entry| params.port.is_none_or(|port| entry.listener_port == port))
+        .take(params.limit.unwrap_or(usize::MAX))
+        .map(|entry| har::entry(entry, capture.max_body_bytes()))
+        .collect();
+    entries.reverse();
+
+    let filename = match params.port {
+        Some(port) => format!("proxy-{}.har", port),
+        None => "proxy.har".to_string(),
+    };
+    Ok::<_, (StatusCode, String)>((
+        [(
+            "content-disposition",
+            format!("attachment; filename=\"{}\"", filename),
+        )],
+        Json(Har::new(entries)),
+    ))
+}
+
+#[derive(Debug, Deserialize)]
+struct PurgeParams {
+    /// Only purge the cache of this listener port
+    port: Option<u16>,
+    /// Only purge responses whose path starts with this prefix
+    path: Option<String>,
+}
+
+/// DELETE /api/cache - drop cached responses, returning how many were removed
+async fn purge_cache(
+    State(state): State<AdminState>,
+    Query(params): Query<PurgeParams>,
+) -> impl IntoResponse {
+    let purged: usize = state
+        .listeners
+        .iter()
+        .filter(|(port, _)| params.port.is_none_or(|p| p == **port))
+        .flat_map(|(_, ctx)| {
+            let routes = ctx.routes.iter().filter_map(|route| route.cache.as_ref());
+            ctx.cache.iter().chain(routes)
+        })
+        .map(|cache| cache.purge(params.path.as_deref()))
+        .sum();
+    tracing::info!("Purged {} cached responses", purged);
+    Json(serde_json::json!({ "purged": purged }))
+}
+
+/// GET /api/circuits - circuit breaker state of every listener's targets
+async fn list_circuits(State(state): State<AdminState>) -> impl IntoResponse {
+    let mut ports: Vec<_> = state.listeners.keys().copied().collect();
+    ports.sort_unstable();
+    let circuits: Vec<_> = ports
+        .into_iter()
+        .filter_map(|port| {
+            let breaker = state.listeners[&port].circuit_breaker.as_ref()?;
+            Some(serde_json::json!({ "port": port, "circuits": breaker.snapshot() }))
+        })
+        .collect();
+    Json(circuits)
+}
+
+/// GET /api/maintenance - maintenance state of every listener and route
+async fn list_maintenance(State(state): State<AdminState>) -> impl IntoResponse {
+    let mut ports: Vec<_> = state.listeners.keys().copied().collect();
+    ports.sort_unstable();
+    let switches: Vec<_> = ports
+        .into_iter()
+        .flat_map(|port| {
+            let ctx = &state.listeners[&port];
+            let listener = serde_json::json!({
+                "port": port,
+                "path": null,
+                "enabled": ctx.maintenance.is_enabled(),
+            });
+            let routes = ctx.routes.iter().map(move |route| {
+                serde_json::json!({
+                    "port": port,
+                    "path": route.path(),
+                    "enabled": route.maintenance.is_enabled(),
+                })
+            });
+            std::iter::once(listener).chain(routes)
+        })
+        .collect();
+    Json(switches)
+}
+
+#[derive(Debug, Deserialize)]
+struct MaintenanceParams {
+    port: u16,
+    /// The route to switch; the whole listener when omitted
+    path: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct MaintenanceState {
+    enabled: bool,
+}
+
+/// PUT /api/maintenance - switch maintenance mode of a listener or route
+async fn set_maintenance(
+    State(state): State<AdminState>,
+    Query(params): Query<MaintenanceParams>,
+    Json(body): Json<MaintenanceState>,
+) -> impl IntoResponse {
+    let ctx = state.listeners.get(&params.port).ok_or_else(|| {
+        (
+            StatusCode::NOT_FOUND,
+            format!("No listener on port {}", params.port),
+        )
+    })?;
+    let maintenance: &Maintenance = match &params.path {
+        Some(path) => {
+            let route = ctx.routes.get(path).ok_or_else(|| {
+                (
+                    StatusCode::NOT_FOUND,
+                    format!("No route {} on port {}", path, params.port),
+                )
+            })?;
+            &route.maintenance
+        }
+        None => &ctx.maintenance,
+    };
+    maintenance.set_enabled(body.enabled);
+    tracing::info!(
+        "Maintenance mode {} for :{}{}",
+        if body.enabled { "on" } else { "off" },
+        params.port,
+        params.path.as_deref().unwrap_or("")
+    );
+    Ok::<_, (StatusCode, String)>(Json(serde_json::json!({
+        "port": params.port,
+        "path": params.path,
+        "enabled": body.enabled,
+    })))
+}
+
+/// POST /api/requests/:id/replay - send a captured request upstream again
+///
+/// The optional JSON body holds `RequestEdits`. The upstream response is
+/// returned as-is, tagged with `X-Replay-Of`.
+async fn replay_request(
+    State(state): State<AdminState>,
+    Path(id): Path<u64>,
+    body: Bytes,
+) -> Result<Response<Body>, (StatusCode, String)> {
+    let capture = capture_store(&state)?;
+    let entry = capture.get(id).ok_or_else(|| not_found(id))?;
+    let ctx = state.listeners.get(&entry.listener_port).ok_or_else(|| {
+        (
+            StatusCode::NOT_FOUND,
+            format!("Listener :{} is no longer configured", entry.listener_port),
+        )
+    })?;
+    let edits: RequestEdits = if body.is_empty() {
+        RequestEdits::default()
+    } else {
+        serde_json::from_slice(&body).map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?
+    };
+
+    let req = entry
+        .to_request(&edits, capture.max_body_bytes())
+        .map_err(|e| (StatusCode::UNPROCESSABLE_ENTITY, e.to_string()))?;
+    let target = edits.target.as_deref().unwrap_or(&entry.target);
+
+    tracing::info!(
+        "Replaying request {} ({} {}) -> {}",
+        id,
+        entry.method,
+        entry.uri,
+        target
+    );
+
+    let mut response = forward_request(ctx, req, target, entry.client_addr).await;
+    response
+        .headers_mut()
+        .insert("x-replay-of", HeaderValue::from(id));
+    Ok(response)
+}
@@ -0,0 +1,304 @@
+//! HTTP Basic and bearer token authentication for listeners and routes.
+
+use anyhow::Context;
+use axum::{
+    body::Body,
+    http::{
+        header::{AUTHORIZATION, WWW_AUTHENTICATE},
+        HeaderName, HeaderValue, Request, Response, StatusCode,
+    },
+};
+use base64::Engine;
+use ring::digest::{digest, Digest, SHA256};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use crate::config::AuthConfig;
+
+/// Marks a request that passed authentication (credentials, an OIDC
+/// session or the forward auth service). Its response may be meant for that
+/// user alone, so it is kept out of the shared cache.
+#[derive(Debug, Clone, Copy)]
+pub struct Authenticated;
+
+/// Verified Basic credentials remembered, so bcrypt runs once per client
+const MAX_VERIFIED: usize = 1000;
+
+/// Checks requests against a listener's or route's credentials
+#[derive(Debug)]
+pub struct Authenticator {
+    realm: String,
+    /// bcrypt hashes by user name, from the htpasswd file
+    users: HashMap<String, Arc<str>>,
+    /// SHA-256 of each bearer token, with the name it authenticates as
+    tokens: Vec<(String, Digest)>,
+    user_header: HeaderName,
+    /// SHA-256 of `Authorization` values that passed bcrypt, and their user
+    verified: Mutex<HashMap<Vec<u8>, String>>,
+}
+
+impl Authenticator {
+    pub fn new(config: &AuthConfig) -> anyhow::Result<Self> {
+        let users = match &config.htpasswd {
+            Some(path) => {
+                let content = std::fs::read_to_string(path)
+                    .with_context(|| format!("failed to read htpasswd file {}", path))?;
+                parse_htpasswd(&content)
+                    .with_context(|| format!("invalid htpasswd file {}", path))?
+            }
+            None => HashMap::new(),
+        };
+        let tokens = config
+            .bearer_tokens
+            .iter()
+            .map(|(name, token)| (name.clone(), digest(&SHA256, token.as_bytes())))
+            .collect();
+
+        Ok(Self {
+            realm: config.realm.clone(),
+            users,
+            tokens,
+            user_header: HeaderName::from_bytes(config.user_header.as_bytes())
+                .with_context(|| format!("invalid user_header {:?}", config.user_header))?,
+            verified: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Check the request's credentials. On success the credentials are
+    /// removed and the user is set in the user header; otherwise the 401
+    /// response to send is returned.
+    pub async fn authenticate(&self, req: &mut Request<Body>) -> Result<(), Response<Body>> {
+        // Never pass on a user name the client picked itself
+        req.headers_mut().remove(&self.user_header);
+
+        let user = match req.headers().get(AUTHORIZATION) {
+            Some(value) => self.verify(value).await,
+            None => None,
+        };
+        let Some(user) = user else {
+            return Err(self.unauthorized());
+        };
+
+        req.headers_mut().remove(AUTHORIZATION);
+        if let Ok(value) = HeaderValue::from_str(&user) {
+            req.headers_mut().insert(self.user_header.clone(), value);
+        }
+        req.extensions_mut().insert(Authenticated);
+        Ok(())
+    }
+
+    /// The user an `Authorization` value authenticates as
+    async fn verify(&self, value: &HeaderValue) -> Option<String> {
+        let (scheme, credentials) = value.to_str().ok()?.trim().split_once(' ')?;
+        let credentials = credentials.trim();
+
+        if scheme.eq_ignore_ascii_case("bearer") {
+            let presented = digest(&SHA256, credentials.as_bytes());
+            // Check every token so the time taken doesn't reveal which matched
+            return self.tokens.iter().fold(None, |found, (name, token)| {
+                if constant_time_eq(token.as_ref(), presented.as_ref()) {
+                    Some(name.clone())
+                } else {
+                    found
+                }
+            });
+        }
+        if !scheme.eq_ignore_ascii_case("basic") || self.users.is_empty() {
+            return None;
+        }
+
+        let key = digest(&SHA256, value.as_bytes()).as_ref().to_vec();
+        if let Some(user) = self.verified.lock().unwrap().get(&key) {
+            return Some(user.clone());
+        }
+
+        let decoded = base64::engine::general_purpose::STANDARD
+            .decode(credentials)
+            .ok()?;
+        let (user, password) = String::from_utf8(decoded)
+            .ok()?
+            .split_once(':')
+            .map(|(user, password)| (user.to_string(), password.to_string()))?;
+        let hash = self.users.get(&user)?.clone();
+        // bcrypt is deliberately slow; keep it off the async workers
+        let valid = tokio::task::spawn_blocking(move || bcrypt::verify(password, &hash))
+            .await
+            .ok()?
+            .unwrap_or(false);
+        if !valid {
+            return None;
+        }
+
+        let mut verified = self.verified.lock().unwrap();
+        if verified.len() >= MAX_VERIFIED {
+            verified.clear();
+        }
+        verified.insert(key, user.clone());
+        Some(user)
+    }
+
+    /// 401 response challenging for the configured schemes
+    fn unauthorized(&self) -> Response<Body> {
+        let mut response = Response::builder()
+            .status(StatusCode::UNAUTHORIZED)
+            .header("content-type", "text/plain; charset=utf-8")
+            .body(Body::from("401 Unauthorized"))
+            .unwrap();
+        let realm = self.realm.replace(['\\', '"'], "");
+        let mut challenges = Vec::new();
+        if !self.users.is_empty() {
+            challenges.push(format!("Basic realm=\"{}\", charset=\"UTF-8\"", realm));
+        }
+        if !self.tokens.is_empty() {
+            challenges.push(format!("Bearer realm=\"{}\"", realm));
+        }
+        for challenge in challenges {
+            if let Ok(value) = HeaderValue::from_str(&challenge) {
+                response.headers_mut().append(WWW_AUTHENTICATE, value);
+            }
+        }
+        response
+    }
+}
+
+/// Parse `user:hash` lines, accepting only bcrypt hashes (`htpasswd -B`)
+fn parse_htpasswd(content: &str) -> anyhow::Result<HashMap<String, Arc<str>>> {
+    let mut users = HashMap::new();
+    for (number, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let Some((user, hash)) = line.split_once(':') else {
+            anyhow::bail!("line {} is not user:hash", number + 1);
+        };
+        if !["$2a$", "$2b$", "$2y$"]
+            .iter()
+            .any(|prefix| hash.starts_with(prefix))
+        {
+            anyhow::bail!(
+                "user {} on line {} does not have a bcrypt hash (use htpasswd -B)",
+                user,
+                number + 1
+            );
+        }
+        users.insert(user.to_string(), Arc::from(hash));
+    }
+    Ok(users)
+}
+
+/// Compare secrets without leaking how much of them matched through timing
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+    use std::io::Write;
+    use tempfile::NamedTempFile;
+
+    fn authenticator(htpasswd: Option<&NamedTempFile>, tokens: &[(&str, &str)]) -> Authenticator {
+        Authenticator::new(&AuthConfig {
+            realm: "test".to_string(),
+            htpasswd: htpasswd.map(|file| file.path().to_str().unwrap().to_string()),
+            bearer_tokens: tokens
+                .iter()
+                .map(|(name, token)| (name.to_string(), token.to_string()))
+                .collect::<BTreeMap<_, _>>(),
+            user_header: "X-Forwarded-User".to_string(),
+        })
+        .unwrap()
+    }
+
+    fn htpasswd() -> NamedTempFile {
+        let mut file = NamedTempFile::new().unwrap();
+        let hash = bcrypt::hash("s3cret", 4).unwrap();
+        writeln!(file, "# users\nalice:{}\n", hash).unwrap();
+        file
+    }
+
+    fn request(authorization: Option<&str>) -> Request<Body> {
+        let mut builder = Request::builder().header("x-forwarded-user", "mallory");
+        if let Some(authorization) = authorization {
+            builder = builder.header("authorization", authorization);
+        }
+        builder.body(Body::empty()).unwrap()
+    }
+
+    fn basic(credentials: &str) -> String {
+        format!(
+            "Basic {}",
+            base64::engine::general_purpose::STANDARD.encode(credentials)
+        )
+    }
+
+    #[test]
+    fn test_parse_htpasswd() {
+        let users = parse_htpasswd("# comment\n\nbob:$2y$05$abc\n").unwrap();
+        assert_eq!(users.len(), 1);
+        assert!(users.contains_key("bob"));
+
+        assert!(parse_htpasswd("bob:$apr1$salt$hash").is_err());
+        assert!(parse_htpasswd("bob").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_basic_auth() {
+        let file = htpasswd();
+        let auth = authenticator(Some(&file), &[]);
+
+        let mut req = request(Some(&basic("alice:s3cret")));
+        auth.authenticate(&mut req).await.unwrap();
+        assert!(req.headers().get("authorization").is_none());
+        assert_eq!(req.headers().get("x-forwarded-user").unwrap(), "alice");
+
+        // Served from the verified cache the second time
+        let mut req = request(Some(&basic("alice:s3cret")));
+        auth.authenticate(&mut req).await.unwrap();
+
+        for bad in [basic("alice:wrong"), basic("bob:s3cret"), "Basic !!".into()] {
+            let mut req = request(Some(&bad));
+            let response = auth.authenticate(&mut req).await.unwrap_err();
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+            assert!(req.headers().get("x-forwarded-user").is_none());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_bearer_tokens() {
+        let auth = authenticator(None, &[("ci", "token-1"), ("deploy", "token-2")]);
+
+        let mut req = request(Some("Bearer token-2"));
+        auth.authenticate(&mut req).await.unwrap();
+        assert_eq!(req.headers().get("x-forwarded-user").unwrap(), "deploy");
+
+        let mut req = request(Some("bearer token-3"));
+        assert!(auth.authenticate(&mut req).await.is_err());
+        // Basic is not accepted without an htpasswd file
+        let mut req = request(Some(&basic("ci:token-1")));
+        assert!(auth.authenticate(&mut req).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_challenges() {
+        let file = htpasswd();
+        let auth = authenticator(Some(&file), &[("ci", "token")]);
+
+        let response = auth.authenticate(&mut request(None)).await.unwrap_err();
+        let challenges: Vec<_> = response
+            .headers()
+            .get_all("www-authenticate")
+            .iter()
+            .map(|v| v.to_str().unwrap())
+            .collect();
+        assert_eq!(
+            challenges,
+            [
+                "Basic realm=\"test\", charset=\"UTF-8\"",
+                "Bearer realm=\"test\""
+            ]
+        );
+    }
+}
@@ -0,0 +1,370 @@
+//! Substitutions in streamed response bodies, like nginx's `sub_filter`.
+//! Compressed bodies are decoded for the substitutions and encoded again.
+
+use anyhow::Context;
+use async_compression::tokio::bufread::{
+    BrotliDecoder, BrotliEncoder, GzipDecoder, GzipEncoder, ZstdDecoder, ZstdEncoder,
+};
+use axum::body::{Body, Bytes};
+use axum::http::{header, HeaderMap, HeaderValue, Method, Response, StatusCode};
+use futures_util::{Stream, StreamExt, TryStreamExt};
+use regex::bytes::Regex;
+use std::sync::Arc;
+use tokio_util::io::{ReaderStream, StreamReader};
+
+use crate::config::BodyFilterConfig;
+
+/// Content codings the filter can see through
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Coding {
+    Identity,
+    Gzip,
+    Brotli,
+    Zstd,
+}
+
+impl Coding {
+    fn of(headers: &HeaderMap) -> Option<Self> {
+        let Some(value) = headers.get(header::CONTENT_ENCODING) else {
+            return Some(Coding::Identity);
+        };
+        match value.to_str().ok()?.trim().to_ascii_lowercase().as_str() {
+            "" | "identity" => Some(Coding::Identity),
+            "gzip" | "x-gzip" => Some(Coding::Gzip),
+            "br" => Some(Coding::Brotli),
+            "zstd" => Some(Coding::Zstd),
+            _ => None,
+        }
+    }
+}
+
+/// One configured substitution
+#[derive(Debug)]
+struct Rule {
+    pattern: Regex,
+    replace: String,
+    /// Whether `replace` may refer to the pattern's captures
+    expand: bool,
+    /// Longest match looked for across chunk boundaries
+    window: usize,
+}
+
+/// A route's body substitutions
+#[derive(Debug)]
+pub struct BodyFilter {
+    rules: Arc<Vec<Rule>>,
+    content_types: Vec<String>,
+}
+
+impl BodyFilter {
+    pub fn new(config: &BodyFilterConfig) -> anyhow::Result<Self> {
+        let rules = config
+            .substitutions
+            .iter()
+            .map(|substitution| {
+                Ok(match (&substitution.find, &substitution.regex) {
+                    (Some(find), _) => Rule {
+                        pattern: Regex::new(&regex::escape(find))?,
+                        replace: substitution.replace.clone(),
+                        expand: false,
+                        window: find.len(),
+                    },
+                    (None, Some(regex)) => Rule {
+                        pattern: Regex::new(regex)
+                            .with_context(|| format!("invalid body_filter regex {:?}", regex))?,
+                        replace: substitution.replace.clone(),
+                        expand: true,
+                        window: config.max_match_len,
+                    },
+                    (None, None) => anyhow::bail!("body_filter substitution needs find or regex"),
+                })
+            })
+            .collect::<anyhow::Result<_>>()?;
+
+        Ok(Self {
+            rules: Arc::new(rules),
+            content_types: config
+                .content_types
+                .iter()
+                .map(|t| t.trim().to_ascii_lowercase())
+                .collect(),
+        })
+    }
+
+    /// Run the substitutions over `response`'s body as it streams through
+    pub fn apply(&self, method: &Method, response: Response<Body>) -> Response<Body> {
+        if method == Method::HEAD || !self.is_filtered(response.status(), response.headers()) {
+            return response;
+        }
+        let Some(coding) = Coding::of(response.headers()) else {
+            return response;
+        };
+
+        let (mut parts, body) = response.into_parts();
+        // The length changes, and byte ranges of the old body mean nothing
+        parts.headers.remove(header::CONTENT_LENGTH);
+        parts.headers.remove(header::ACCEPT_RANGES);
+        if let Some(etag) = parts
+            .headers
+            .get(header::ETAG)
+            .and_then(|v| v.to_str().ok())
+        {
+            if !etag.starts_with("W/") {
+                if let Ok(weak) = HeaderValue::from_str(&format!("W/{}", etag)) {
+                    parts.headers.insert(header::ETAG, weak);
+                }
+            }
+        }
+
+        let stream = body.into_data_stream().map_err(std::io::Error::other);
+        let body = match coding {
+            Coding::Identity => Body::from_stream(substitute(stream, self.rules.clone())),
+            Coding::Gzip => {
+                let mut decoder = GzipDecoder::new(StreamReader::new(stream));
+                decoder.multiple_members(true);
+                let filtered = substitute(ReaderStream::new(decoder), self.rules.clone());
+                Body::from_stream(ReaderStream::new(GzipEncoder::new(StreamReader::new(
+                    filtered,
+                ))))
+            }
+            Coding::Brotli => {
+                let decoded = ReaderStream::new(BrotliDecoder::new(StreamReader::new(stream)));
+                let filtered = substitute(decoded, self.rules.clone());
+                Body::from_stream(ReaderStream::new(BrotliEncoder::new(StreamReader::new(
+                    filtered,
+                ))))
+            }
+            Coding::Zstd => {
+                let decoded = ReaderStream::new(ZstdDecoder::new(StreamReader::new(stream)));
+                let filtered = substitute(decoded, self.rules.clone());
+                Body::from_stream(ReaderStream::new(ZstdEncoder::new(StreamReader::new(
+                    filtered,
+                ))))
+            }
+        };
+        Response::from_parts(parts, body)
+    }
+
+    fn is_filtered(&self, status: StatusCode, headers: &HeaderMap) -> bool {
+        if status.is_informational()
+            || status == StatusCode::NO_CONTENT
+            || status == StatusCode::NOT_MODIFIED
+            || status == StatusCode::PARTIAL_CONTENT
+            || headers.contains_key(header::CONTENT_RANGE)
+        {
+            return false;
+        }
+        let Some(content_type) = headers
+            .get(header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| {
+                v.split(';')
+                    .next()
+                    .unwrap_or("")
+                    .trim()
+                    .to_ascii_lowercase()
+            })
+        else {
+            return false;
+        };
+        self.content_types
+            .iter()
+            .any(|allowed| match allowed.strip_suffix("/*") {
+                Some(top_level) => content_type
+                    .split_once('/')
+                    .is_some_and(|(t, _)| t == top_level),
+                None => *allowed == content_type,
+            })
+    }
+}
+
+/// Apply `rules` to a byte stream, one after another
+fn substitute<S>(
+    stream: S,
+    rules: Arc<Vec<Rule>>,
+) -> impl Stream<Item = std::io::Result<Bytes>> + Send + 'static
+where
+    S: Stream<Item = std::io::Result<Bytes>> + Send + Unpin + 'static,
+{
+    let pending = vec![Vec::new(); rules.len()];
+    futures_util::stream::unfold(
+        (stream, rules, pending, false),
+        |(mut stream, rules, mut pending, done)| async move {
+            if done {
+                return None;
+            }
+            loop {
+                let (chunk, last) = match stream.next().await {
+                    Some(Ok(chunk)) => (chunk.to_vec(), false),
+                    Some(Err(e)) => return Some((Err(e), (stream, rules, pending, true))),
+                    None => (Vec::new(), true),
+                };
+                let output = rules
+                    .iter()
+                    .zip(pending.iter_mut())
+                    .fold(chunk, |input, (rule, pending)| {
+                        rule.push(pending, &input, last)
+                    });
+                if last || !output.is_empty() {
+                    return Some((Ok(Bytes::from(output)), (stream, rules, pending, last)));
+                }
+            }
+        },
+    )
+}
+
+impl Rule {
+    /// Add `input` to what's pending and return the output that can no
+    /// longer be part of a match; the rest stays pending unless `last`
+    fn push(&self, pending: &mut Vec<u8>, input: &[u8], last: bool) -> Vec<u8> {
+        pending.extend_from_slice(input);
+        // Text this close to the end may start a match completed later
+        let mut limit = if last {
+            pending.len()
+        } else {
+            pending.len().saturating_sub(self.window)
+        };
+
+        let mut output = Vec::with_capacity(pending.len());
+        let mut position = 0;
+        for captures in self.pattern.captures_iter(pending) {
+            let found = captures.get(0).unwrap();
+            if found.start() >= limit {
+                break;
+            }
+            // A match running to the end might continue in the next chunk
+            if !last && found.end() == pending.len() {
+                limit = found.start();
+                break;
+            }
+            output.extend_from_slice(&pending[position..found.start()]);
+            if self.expand {
+                captures.expand(self.replace.as_bytes(), &mut output);
+            } else {
+                output.extend_from_slice(self.replace.as_bytes());
+            }
+            position = found.end();
+        }
+
+        let keep_from = limit.max(position);
+        output.extend_from_slice(&pending[position..keep_from]);
+        pending.drain(..keep_from);
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http_body_util::BodyExt;
+    use tokio::io::AsyncReadExt;
+
+    fn filter(yaml: &str) -> BodyFilter {
+        BodyFilter::new(&serde_yaml::from_str(yaml).unwrap()).unwrap()
+    }
+
+    fn chunked(chunks: &[&'static str], content_type: &str) -> Response<Body> {
+        let stream = futures_util::stream::iter(
+            chunks
+                .iter()
+                .map(|chunk| Ok::<_, std::io::Error>(Bytes::from_static(chunk.as_bytes())))
+                .collect::<Vec<_>>(),
+        );
+        Response::builder()
+            .header("content-type", content_type)
+            .header("content-length", "999")
+            .body(Body::from_stream(stream))
+            .unwrap()
+    }
+
+    async fn text(response: Response<Body>) -> String {
+        let bytes = response.into_body().collect().await.unwrap().to_bytes();
+        String::from_utf8(bytes.to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_literal_across_chunks() {
+        let filter = filter(
+            "substitutions:\n  - find: http://app:3001\n    replace: https://localhost:8443\n",
+        );
+        let response = filter.apply(
+            &Method::GET,
+            chunked(
+                &[
+                    "<a href=\"http://ap",
+                    "p:3001/x\">",
+                    "http://app:3001",
+                    "</a>",
+                ],
+                "text/html; charset=utf-8",
+            ),
+        );
+        assert!(response.headers().get("content-length").is_none());
+        assert_eq!(
+            text(response).await,
+            "<a href=\"https://localhost:8443/x\">https://localhost:8443</a>"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_regex_and_rules_in_order() {
+        let filter = filter(
+            r#"
+content_types: [application/javascript]
+substitutions:
+  - regex: 'http://(\w+):3001'
+    replace: https://$1.localhost
+  - find: https://app.localhost
+    replace: /
+"#,
+        );
+        let response = filter.apply(
+            &Method::GET,
+            chunked(
+                &["fetch('http://api:30", "01/v1'); go('http://app:3001')"],
+                "application/javascript",
+            ),
+        );
+        assert_eq!(
+            text(response).await,
+            "fetch('https://api.localhost/v1'); go('/')"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_skips_other_content_types() {
+        let filter = filter("substitutions:\n  - find: a\n    replace: b\n");
+        let response = filter.apply(&Method::GET, chunked(&["aaa"], "image/png"));
+        assert_eq!(response.headers()["content-length"], "999");
+        let response = filter.apply(&Method::HEAD, chunked(&["aaa"], "text/html"));
+        assert_eq!(response.headers()["content-length"], "999");
+    }
+
+    #[tokio::test]
+    async fn test_recompresses_gzip() {
+        let filter = filter("substitutions:\n  - find: upstream\n    replace: proxy\n");
+        let body = "served by upstream. ".repeat(100);
+        let mut compressed = Vec::new();
+        GzipEncoder::new(body.as_bytes())
+            .read_to_end(&mut compressed)
+            .await
+            .unwrap();
+        let response = Response::builder()
+            .header("content-type", "text/html")
+            .header("content-encoding", "gzip")
+            .header("etag", "\"v1\"")
+            .body(Body::from(compressed))
+            .unwrap();
+
+        let response = filter.apply(&Method::GET, response);
+        assert_eq!(response.headers()["content-encoding"], "gzip");
+        assert_eq!(response.headers()["etag"], "W/\"v1\"");
+        let bytes = response.into_body().collect().await.unwrap().to_bytes();
+        let mut decoded = String::new();
+        GzipDecoder::new(&bytes[..])
+            .read_to_string(&mut decoded)
+            .await
+            .unwrap();
+        assert_eq!(decoded, "served by proxy. ".repeat(100));
+    }
+}
@@ -0,0 +1,775 @@
+//! In-memory cache of upstream responses following `Cache-Control`.
+//!
+//! Only responses with explicit freshness (`s-maxage`, `max-age` or
+//! `Expires`) or with validators to revalidate against are stored. The cache
+//! is shared, so `private` responses, responses setting cookies and requests
+//! carrying `Authorization` or authenticated by the proxy are never cached.
+
+use axum::{
+    body::{Body, Bytes},
+    http::{
+        header::{
+            AGE, AUTHORIZATION, CACHE_CONTROL, CONTENT_LENGTH, DATE, ETAG, EXPIRES, HOST,
+            IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED, PRAGMA, SET_COOKIE, TRANSFER_ENCODING,
+            VARY,
+        },
+        HeaderMap, HeaderName, HeaderValue, Method, Request, Response, StatusCode,
+    },
+};
+use futures_util::{Stream, StreamExt};
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::auth::Authenticated;
+use crate::config::CacheConfig;
+
+/// Response header telling clients whether the cache answered
+pub const X_CACHE: &str = "x-cache";
+
+/// Statuses stored when the response carries explicit freshness
+const CACHEABLE_STATUSES: [u16; 8] = [200, 203, 204, 300, 301, 308, 404, 410];
+
+/// A stored response
+#[derive(Debug)]
+pub struct CachedResponse {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+    /// Request path, used for purging by prefix
+    path: String,
+    /// Request header values selected by the response's `Vary`
+    vary: Vec<(HeaderName, Option<String>)>,
+    stored: Instant,
+    initial_age: Duration,
+    lifetime: Duration,
+}
+
+impl CachedResponse {
+    fn new(
+        status: StatusCode,
+        headers: HeaderMap,
+        body: Bytes,
+        path: String,
+        vary: Vec<(HeaderName, Option<String>)>,
+    ) -> Self {
+        let initial_age = Duration::from_secs(
+            headers
+                .get(AGE)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.trim().parse().ok())
+                .unwrap_or(0),
+        );
+        let lifetime = freshness_lifetime(&headers).unwrap_or_default();
+
+        Self {
+            status,
+            headers,
+            body,
+            path,
+            vary,
+            stored: Instant::now(),
+            initial_age,
+            lifetime,
+        }
+    }
+
+    fn age(&self) -> Duration {
+        self.initial_age + self.stored.elapsed()
+    }
+
+    pub fn is_fresh(&self) -> bool {
+        self.age() < self.lifetime
+    }
+
+    /// Conditional headers to revalidate this response with the upstream
+    pub fn validators(&self) -> Vec<(HeaderName, HeaderValue)> {
+        let mut validators = Vec::new();
+        if let Some(etag) = self.headers.get(ETAG) {
+            validators.push((IF_NONE_MATCH, etag.clone()));
+        }
+        if let Some(last_modified) = self.headers.get(LAST_MODIFIED) {
+            validators.push((IF_MODIFIED_SINCE, last_modified.clone()));
+        }
+        validators
+    }
+
+    fn matches(&self, headers: &HeaderMap) -> bool {
+        self.vary
+            .iter()
+            .all(|(name, value)| header_string(headers, name) == *value)
+    }
+
+    fn size(&self) -> usize {
+        self.body.len()
+            + self.path.len()
+            + self
+                .headers
+                .iter()
+                .map(|(name, value)| name.as_str().len() + value.len())
+                .sum::<usize>()
+    }
+
+    /// Build the response for a client, answering its own conditional
+    /// headers with 304 where they match
+    pub fn respond(&self, method: &Method, request_headers: &HeaderMap) -> Response<Body> {
+        let mut response = Response::builder().status(self.status);
+        let headers = response.headers_mut().unwrap();
+        *headers = self.headers.clone();
+        headers.insert(AGE, HeaderValue::from(self.age().as_secs()));
+        headers.insert(X_CACHE, HeaderValue::from_static("HIT"));
+
+        if self.status == StatusCode::OK && self.not_modified(request_headers) {
+            headers.remove(CONTENT_LENGTH);
+            return response
+                .status(StatusCode::NOT_MODIFIED)
+                .body(Body::empty())
+                .unwrap();
+        }
+
+        let body = if method == Method::HEAD {
+            Body::empty()
+        } else {
+            Body::from(self.body.clone())
+        };
+        response.body(body).unwrap()
+    }
+
+    fn not_modified(&self, request_headers: &HeaderMap) -> bool {
+        if let Some(if_none_match) = request_headers
+            .get(IF_NONE_MATCH)
+            .and_then(|v| v.to_str().ok())
+        {
+            let Some(etag) = self.headers.get(ETAG).and_then(|v| v.to_str().ok()) else {
+                return false;
+            };
+            let strip_weak = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+            return if_none_match
+                .split(',')
+                .any(|tag| tag.trim() == "*" || strip_weak(tag) == strip_weak(etag));
+        }
+
+        let date = |headers: &HeaderMap, name| {
+            headers
+                .get(name)
+                .and_then(|v: &HeaderValue| v.to_str().ok())
+                .and_then(|v| httpdate::parse_http_date(v).ok())
+        };
+        match (
+            date(request_headers, IF_MODIFIED_SINCE),
+            date(&self.headers, LAST_MODIFIED),
+        ) {
+            (Some(since), Some(modified)) => modified <= since,
+            _ => false,
+        }
+    }
+}
+
+/// Cache key for a request to `target`; variants are told apart by `Vary`
+pub fn key<B>(req: &Request<B>, target: &str) -> String {
+    let host = req
+        .headers()
+        .get(HOST)
+        .and_then(|v| v.to_str().ok())
+        .or_else(|| req.uri().authority().map(|a| a.as_str()))
+        .unwrap_or("");
+    let path_and_query = req
+        .uri()
+        .path_and_query()
+        .map(|pq| pq.as_str())
+        .unwrap_or("/");
+    format!("{} {}{}", host, target, path_and_query)
+}
+
+/// Whether the cache may answer (and store the response to) this request
+pub fn is_cacheable_request<B>(req: &Request<B>) -> bool {
+    (req.method() == Method::GET || req.method() == Method::HEAD)
+        && !req.headers().contains_key(AUTHORIZATION)
+        && req.extensions().get::<Authenticated>().is_none()
+        && !directives(req.headers()).contains_key("no-store")
+}
+
+/// Whether the client asked for a cached response to be revalidated first
+pub fn requires_revalidation(headers: &HeaderMap) -> bool {
+    let directives = directives(headers);
+    directives.contains_key("no-cache")
+        || directives.get("max-age").is_some_and(|v| v == "0")
+        || headers
+            .get(PRAGMA)
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|v| v.to_ascii_lowercase().contains("no-cache"))
+}
+
+/// `Cache-Control` directives, lowercased, with their unquoted values
+fn directives(headers: &HeaderMap) -> HashMap<String, String> {
+    headers
+        .get_all(CACHE_CONTROL)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .filter_map(|directive| {
+            let (name, value) = directive.split_once('=').unwrap_or((directive, ""));
+            let name = name.trim().to_ascii_lowercase();
+            (!name.is_empty()).then(|| (name, value.trim().trim_matches('"').to_string()))
+        })
+        .collect()
+}
+
+/// Explicit freshness lifetime of a response, if it has one
+fn freshness_lifetime(headers: &HeaderMap) -> Option<Duration> {
+    let directives = directives(headers);
+    if directives.contains_key("no-cache") {
+        return Some(Duration::ZERO);
+    }
+    if let Some(seconds) = directives
+        .get("s-maxage")
+        .or_else(|| directives.get("max-age"))
+    {
+        return Some(Duration::from_secs(seconds.parse().unwrap_or(0)));
+    }
+
+    let expires = headers.get(EXPIRES)?.to_str().ok();
+    // An invalid Expires means "already expired"
+    let Some(expires) = expires.and_then(|v| httpdate::parse_http_date(v).ok()) else {
+        return Some(Duration::ZERO);
+    };
+    let date = headers
+        .get(DATE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| httpdate::parse_http_date(v).ok())
+        .unwrap_or_else(SystemTime::now);
+    Some(expires.duration_since(date).unwrap_or_default())
+}
+
+fn header_string(headers: &HeaderMap, name: &HeaderName) -> Option<String> {
+    let values: Vec<&str> = headers
+        .get_all(name)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .collect();
+    (!values.is_empty()).then(|| values.join(", "))
+}
+
+/// The request headers named by `Vary`, or `None` for `Vary: *`
+fn vary_values(
+    response_headers: &HeaderMap,
+    request_headers: &HeaderMap,
+) -> Option<Vec<(HeaderName, Option<String>)>> {
+    let mut vary = Vec::new();
+    for name in response_headers
+        .get_all(VARY)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+    {
+        if name == "*" {
+            return None;
+        }
+        let name = HeaderName::from_bytes(name.to_ascii_lowercase().as_bytes()).ok()?;
+        let value = header_string(request_headers, &name);
+        vary.push((name, value));
+    }
+    Some(vary)
+}
+
+struct Slot {
+    response: Arc<CachedResponse>,
+    last_used: u64,
+}
+
+#[derive(Default)]
+struct Entries {
+    /// Variants by cache key
+    slots: HashMap<String, Vec<Slot>>,
+    count: usize,
+    bytes: usize,
+    clock: u64,
+}
+
+impl Entries {
+    fn tick(&mut self) -> u64 {
+        self.clock += 1;
+        self.clock
+    }
+
+    /// Remove variants for which `remove` returns true, returning how many went
+    fn remove_where(&mut self, mut remove: impl FnMut(&str, &Slot) -> bool) -> usize {
+        let mut removed = 0;
+        let mut bytes = 0;
+        self.slots.retain(|key, variants| {
+            variants.retain(|slot| {
+                let gone = remove(key, slot);
+                if gone {
+                    removed += 1;
+                    bytes += slot.response.size();
+                }
+                !gone
+            });
+            !variants.is_empty()
+        });
+        self.count -= removed;
+        self.bytes -= bytes;
+        removed
+    }
+
+    fn evict_least_recently_used(&mut self) {
+        let oldest = self
+            .slots
+            .values()
+            .flatten()
+            .map(|slot| slot.last_used)
+            .min();
+        if let Some(oldest) = oldest {
+            self.remove_where(|_, slot| slot.last_used == oldest);
+        }
+    }
+}
+
+/// Bounded, least-recently-used response cache for one listener or route
+pub struct ResponseCache {
+    entries: Mutex<Entries>,
+    max_entries: usize,
+    max_bytes: usize,
+    max_entry_bytes: usize,
+}
+
+impl std::fmt::Debug for ResponseCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ResponseCache")
+            .field("max_entries", &self.max_entries)
+            .field("max_bytes", &self.max_bytes)
+            .field("max_entry_bytes", &self.max_entry_bytes)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ResponseCache {
+    pub fn new(config: &CacheConfig) -> Self {
+        Self {
+            entries: Mutex::new(Entries::default()),
+            max_entries: config.max_entries,
+            max_bytes: config.max_bytes,
+            max_entry_bytes: config.max_entry_bytes,
+        }
+    }
+
+    /// The stored variant of `key` matching the request's headers
+    pub fn lookup(&self, key: &str, request_headers: &HeaderMap) -> Option<Arc<CachedResponse>> {
+        let mut entries = self.entries.lock().unwrap();
+        let now = entries.tick();
+        let slot = entries
+            .slots
+            .get_mut(key)?
+            .iter_mut()
+            .find(|slot| slot.response.matches(request_headers))?;
+        slot.last_used = now;
+        Some(slot.response.clone())
+    }
+
+    /// Cache the upstream response while it streams to the client. It is
+    /// stored once the whole body has passed through.
+    pub fn store(
+        self: &Arc<Self>,
+        key: String,
+        path: &str,
+        request_headers: &HeaderMap,
+        response: Response<Body>,
+    ) -> Response<Body> {
+        if !self.storable(&response) {
+            return response;
+        }
+        let Some(vary) = vary_values(response.headers(), request_headers) else {
+            return response;
+        };
+
+        let (parts, body) = response.into_parts();
+        let mut headers = parts.headers.clone();
+        // Hit responses are sent with a fixed-length body
+        headers.remove(TRANSFER_ENCODING);
+        let status = parts.status;
+        let path = path.to_string();
+        let cache = self.clone();
+
+        let body = Filling {
+            inner: Box::pin(body.into_data_stream()),
+            buffer: Some(Vec::new()),
+            limit: self.max_entry_bytes,
+            on_complete: Some(Box::new(move |body: Bytes| {
+                headers.insert(CONTENT_LENGTH, HeaderValue::from(body.len()));
+                cache.insert(key, CachedResponse::new(status, headers, body, path, vary));
+            })),
+        };
+
+        Response::from_parts(parts, Body::from_stream(body))
+    }
+
+    fn storable(&self, response: &Response<Body>) -> bool {
+        let headers = response.headers();
+        if !CACHEABLE_STATUSES.contains(&response.status().as_u16())
+            || headers.contains_key(SET_COOKIE)
+        {
+            return false;
+        }
+
+        let directives = directives(headers);
+        if directives.contains_key("no-store") || directives.contains_key("private") {
+            return false;
+        }
+
+        let too_large = headers
+            .get(CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse::<usize>().ok())
+            .is_some_and(|len| len > self.max_entry_bytes);
+        if too_large {
+            return false;
+        }
+
+        // Worth keeping if it is fresh for a while or can be revalidated
+        let has_validators = headers.contains_key(ETAG) || headers.contains_key(LAST_MODIFIED);
+        match freshness_lifetime(headers) {
+            Some(lifetime) => !lifetime.is_zero() || has_validators,
+            None => false,
+        }
+    }
+
+    fn insert(&self, key: String, response: CachedResponse) {
+        let size = response.size();
+        if self.max_entries == 0 || size > self.max_bytes {
+            return;
+        }
+
+        let mut entries = self.entries.lock().unwrap();
+        // Replace the variant this response supersedes
+        entries.remove_where(|k, slot| k == key && slot.response.vary == response.vary);
+        while entries.count > 0
+            && (entries.count >= self.max_entries || entries.bytes + size > self.max_bytes)
+        {
+            entries.evict_least_recently_used();
+        }
+
+        let last_used = entries.tick();
+        entries.count += 1;
+        entries.bytes += size;
+        entries.slots.entry(key).or_default().push(Slot {
+            response: Arc::new(response),
+            last_used,
+        });
+    }
+
+    /// Update a stale response from the upstream's 304, returning the
+    /// refreshed response
+    pub fn refresh(
+        &self,
+        key: &str,
+        stale: &CachedResponse,
+        not_modified: &HeaderMap,
+    ) -> Arc<CachedResponse> {
+        let mut headers = stale.headers.clone();
+        for name in not_modified.keys() {
+            if *name == CONTENT_LENGTH || *name == TRANSFER_ENCODING {
+                continue;
+            }
+            headers.remove(name);
+            for value in not_modified.get_all(name) {
+                headers.append(name.clone(), value.clone());
+            }
+        }
+
+        let refreshed = CachedResponse::new(
+            stale.status,
+            headers,
+            stale.body.clone(),
+            stale.path.clone(),
+            stale.vary.clone(),
+        );
+        let refreshed = Arc::new(refreshed);
+
+        let mut entries = self.entries.lock().unwrap();
+        let now = entries.tick();
+        let replaced = entries
+            .slots
+            .get_mut(key)
+            .and_then(|variants| variants.iter_mut().find(|s| s.response.vary == stale.vary))
+            .map(|slot| {
+                slot.last_used = now;
+                std::mem::replace(&mut slot.response, refreshed.clone())
+            });
+        if let Some(replaced) = replaced {
+            entries.bytes = entries.bytes - replaced.size() + refreshed.size();
+        }
+        refreshed
+    }
+
+    /// Drop every variant of `key`, after an unsafe request changed it
+    pub fn invalidate(&self, key: &str) {
+        self.entries.lock().unwrap().remove_where(|k, _| k == key);
+    }
+
+    /// Remove responses whose path starts with `prefix` (all when `None`),
+    /// returning how many were removed
+    pub fn purge(&self, prefix: Option<&str>) -> usize {
+        self.entries
+            .lock()
+            .unwrap()
+            .remove_where(|_, slot| prefix.is_none_or(|p| slot.response.path.starts_with(p)))
+    }
+
+    /// Number of stored responses
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+type DataStream = Pin<Box<dyn Stream<Item = Result<Bytes, axum::Error>> + Send>>;
+
+/// Body stream that copies data into a buffer and hands it over once the
+/// body has been read to the end without exceeding `limit`
+struct Filling {
+    inner: DataStream,
+    buffer: Option<Vec<u8>>,
+    limit: usize,
+    on_complete: Option<Box<dyn FnOnce(Bytes) + Send>>,
+}
+
+impl Stream for Filling {
+    type Item = Result<Bytes, axum::Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let item = std::task::ready!(self.inner.poll_next_unpin(cx));
+        match &item {
+            Some(Ok(chunk)) => {
+                let limit = self.limit;
+                if let Some(buffer) = &mut self.buffer {
+                    if buffer.len() + chunk.len() > limit {
+                        self.buffer = None;
+                    } else {
+                        buffer.extend_from_slice(chunk);
+                    }
+                }
+            }
+            Some(Err(_)) => self.buffer = None,
+            None => {
+                if let (Some(buffer), Some(on_complete)) =
+                    (self.buffer.take(), self.on_complete.take())
+                {
+                    on_complete(Bytes::from(buffer));
+                }
+            }
+        }
+        Poll::Ready(item)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http_body_util::BodyExt;
+
+    fn cache(max_entries: usize, max_bytes: usize) -> Arc<ResponseCache> {
+        Arc::new(ResponseCache::new(&CacheConfig {
+            max_entries,
+            max_bytes,
+            max_entry_bytes: 1024,
+        }))
+    }
+
+    fn upstream(cache_control: &str, body: &str) -> Response<Body> {
+        Response::builder()
+            .header("cache-control", cache_control)
+            .header("etag", "\"v1\"")
+            .body(Body::from(body.to_string()))
+            .unwrap()
+    }
+
+    async fn store(
+        cache: &Arc<ResponseCache>,
+        key: &str,
+        headers: &HeaderMap,
+        response: Response<Body>,
+    ) {
+        let response = cache.store(key.to_string(), "/", headers, response);
+        response.into_body().collect().await.unwrap();
+    }
+
+    #[test]
+    fn test_freshness_lifetime() {
+        let headers = |pairs: &[(&'static str, &str)]| {
+            let mut map = HeaderMap::new();
+            for (name, value) in pairs {
+                map.append(*name, value.parse().unwrap());
+            }
+            map
+        };
+
+        assert_eq!(
+            freshness_lifetime(&headers(&[("cache-control", "public, max-age=60")])),
+            Some(Duration::from_secs(60))
+        );
+        assert_eq!(
+            freshness_lifetime(&headers(&[("cache-control", "max-age=60, s-maxage=5")])),
+            Some(Duration::from_secs(5))
+        );
+        assert_eq!(
+            freshness_lifetime(&headers(&[("cache-control", "no-cache, max-age=60")])),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(
+            freshness_lifetime(&headers(&[
+                ("date", "Sun, 06 Nov 1994 08:49:37 GMT"),
+                ("expires", "Sun, 06 Nov 1994 08:59:37 GMT"),
+            ])),
+            Some(Duration::from_secs(600))
+        );
+        assert_eq!(
+            freshness_lifetime(&headers(&[("expires", "0")])),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(freshness_lifetime(&HeaderMap::new()), None);
+    }
+
+    #[tokio::test]
+    async fn test_store_and_hit() {
+        let cache = cache(10, 4096);
+        store(
+            &cache,
+            "k",
+            &HeaderMap::new(),
+            upstream("max-age=60", "hello"),
+        )
+        .await;
+
+        let entry = cache.lookup("k", &HeaderMap::new()).unwrap();
+        assert!(entry.is_fresh());
+
+        let response = entry.respond(&Method::GET, &HeaderMap::new());
+        assert_eq!(response.headers().get(X_CACHE).unwrap(), "HIT");
+        assert_eq!(response.headers().get("age").unwrap(), "0");
+        assert_eq!(response.headers().get("content-length").unwrap(), "5");
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(body, "hello");
+
+        let mut conditional = HeaderMap::new();
+        conditional.insert(IF_NONE_MATCH, HeaderValue::from_static("\"v1\""));
+        let response = entry.respond(&Method::GET, &conditional);
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+    }
+
+    #[tokio::test]
+    async fn test_not_stored() {
+        let cache = cache(10, 4096);
+        for response in [
+            upstream("no-store", "a"),
+            upstream("private, max-age=60", "a"),
+            Response::new(Body::from("no freshness")),
+            Response::builder()
+                .header("cache-control", "max-age=60")
+                .header("set-cookie", "session=1")
+                .body(Body::from("a"))
+                .unwrap(),
+            Response::builder()
+                .header("cache-control", "max-age=60")
+                .header("vary", "*")
+                .body(Body::from("a"))
+                .unwrap(),
+            upstream("max-age=60", &"x".repeat(2048)),
+        ] {
+            store(&cache, "k", &HeaderMap::new(), response).await;
+        }
+        assert!(cache.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_vary_selects_variant() {
+        let cache = cache(10, 4096);
+        let accept = |value: &'static str| {
+            let mut headers = HeaderMap::new();
+            headers.insert("accept-language", HeaderValue::from_static(value));
+            headers
+        };
+        let varying = |body: &str| {
+            let mut response = upstream("max-age=60", body);
+            response
+                .headers_mut()
+                .insert("vary", HeaderValue::from_static("Accept-Language"));
+            response
+        };
+
+        store(&cache, "k", &accept("en"), varying("hello")).await;
+        store(&cache, "k", &accept("fr"), varying("bonjour")).await;
+
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.lookup("k", &accept("fr")).unwrap().body, "bonjour");
+        assert!(cache.lookup("k", &accept("de")).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_evicts_least_recently_used() {
+        let cache = cache(2, 4096);
+        store(&cache, "a", &HeaderMap::new(), upstream("max-age=60", "a")).await;
+        store(&cache, "b", &HeaderMap::new(), upstream("max-age=60", "b")).await;
+        cache.lookup("a", &HeaderMap::new()).unwrap();
+        store(&cache, "c", &HeaderMap::new(), upstream("max-age=60", "c")).await;
+
+        assert!(cache.lookup("a", &HeaderMap::new()).is_some());
+        assert!(cache.lookup("b", &HeaderMap::new()).is_none());
+        assert!(cache.lookup("c", &HeaderMap::new()).is_some());
+    }
+
+    #[tokio::test]
+    async fn test_refresh_and_purge() {
+        let cache = cache(10, 4096);
+        store(
+            &cache,
+            "k",
+            &HeaderMap::new(),
+            upstream("no-cache", "hello"),
+        )
+        .await;
+
+        let stale = cache.lookup("k", &HeaderMap::new()).unwrap();
+        assert!(!stale.is_fresh());
+        assert_eq!(stale.validators()[0].0, IF_NONE_MATCH);
+
+        let mut not_modified = HeaderMap::new();
+        not_modified.insert("cache-control", HeaderValue::from_static("max-age=30"));
+        let refreshed = cache.refresh("k", &stale, &not_modified);
+        assert!(refreshed.is_fresh());
+        assert!(cache.lookup("k", &HeaderMap::new()).unwrap().is_fresh());
+
+        assert_eq!(cache.purge(Some("/other")), 0);
+        assert_eq!(cache.purge(None), 1);
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn test_cacheable_request() {
+        let request = |builder: axum::http::request::Builder| builder.body(()).unwrap();
+
+        assert!(is_cacheable_request(&request(Request::builder())));
+        assert!(!is_cacheable_request(&request(
+            Request::builder().method("POST")
+        )));
+        assert!(!is_cacheable_request(&request(
+            Request::builder().header("authorization", "Bearer x")
+        )));
+        assert!(!is_cacheable_request(&request(
+            Request::builder().header("cache-control", "no-store")
+        )));
+        let mut authenticated = request(Request::builder());
+        authenticated.extensions_mut().insert(Authenticated);
+        assert!(!is_cacheable_request(&authenticated));
+
+        let mut headers = HeaderMap::new();
+        headers.insert("cache-control", HeaderValue::from_static("max-age=0"));
+        assert!(requires_revalidation(&headers));
+    }
+}
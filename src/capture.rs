@@ -0,0 +1,557 @@
+use axum::{
+    body::{Body, Bytes, HttpBody},
+    http::{HeaderMap, HeaderValue, Method, Request, Response, StatusCode, Uri, Version},
+};
+use futures_util::StreamExt;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, VecDeque};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use crate::config::CaptureConfig;
+
+/// Ids are unique across listeners, so replay and HAR entries can refer to them
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Body bytes recorded while the body streams through the proxy
+#[derive(Debug, Default)]
+struct BodyBuffer {
+    data: Vec<u8>,
+    size: usize,
+}
+
+impl BodyBuffer {
+    fn push(&mut self, chunk: &[u8], limit: usize) {
+        let room = limit.saturating_sub(self.data.len());
+        self.data.extend_from_slice(&chunk[..chunk.len().min(room)]);
+        self.size += chunk.len();
+    }
+}
+
+/// Shared handle to a body that is being recorded
+#[derive(Debug, Default, Clone)]
+struct RecordedBody(Arc<Mutex<BodyBuffer>>);
+
+impl RecordedBody {
+    /// Copy up to `limit` bytes of `body` as it streams through
+    fn tee(&self, body: Body, limit: usize, on_end: Option<OnEnd>) -> Body {
+        // Leave empty bodies alone so GET requests don't turn into chunked uploads
+        if body.is_end_stream() {
+            drop(on_end);
+            return body;
+        }
+
+        let sink = self.clone();
+        Body::from_stream(body.into_data_stream().inspect(move |chunk| {
+            // The guard lives (and fires on drop) with the stream
+            let _ = &on_end;
+            if let Ok(bytes) = chunk {
+                sink.0.lock().unwrap().push(bytes, limit);
+            }
+        }))
+    }
+
+    /// Recorded bytes (at most `limit`), and whether the body was longer
+    fn get(&self, limit: usize) -> (Bytes, bool) {
+        let buffer = self.0.lock().unwrap();
+        let len = buffer.data.len().min(limit);
+        (
+            Bytes::copy_from_slice(&buffer.data[..len]),
+            buffer.size > len,
+        )
+    }
+
+    /// Total body size seen so far
+    fn size(&self) -> usize {
+        self.0.lock().unwrap().size
+    }
+}
+
+/// Runs a callback when dropped, i.e. when a streamed body finishes or is abandoned
+struct OnEnd(Option<Box<dyn FnOnce() + Send>>);
+
+impl Drop for OnEnd {
+    fn drop(&mut self) {
+        if let Some(callback) = self.0.take() {
+            callback();
+        }
+    }
+}
+
+/// A single request/response exchange recorded by the proxy
+#[derive(Debug)]
+pub struct CapturedRequest {
+    pub id: u64,
+    /// When the request arrived
+    pub started: SystemTime,
+    pub listener_port: u16,
+    pub target: String,
+    pub client_addr: SocketAddr,
+    pub method: Method,
+    pub uri: Uri,
+    pub version: Version,
+    /// Headers as received from the client (before forwarding headers are added)
+    pub headers: HeaderMap,
+    pub status: StatusCode,
+    pub response_version: Version,
+    pub response_headers: HeaderMap,
+    /// Time until the response headers arrived
+    pub wait_ms: u64,
+    /// Time spent streaming the response body, once it has finished
+    receive_ms: OnceLock<u64>,
+    body: RecordedBody,
+    response_body: RecordedBody,
+}
+
+impl CapturedRequest {
+    /// Captured request body (at most `limit` bytes) and whether it was cut off
+    pub fn body(&self, limit: usize) -> (Bytes, bool) {
+        self.body.get(limit)
+    }
+
+    /// Full request body size as seen by the proxy
+    pub fn body_size(&self) -> usize {
+        self.body.size()
+    }
+
+    /// Captured response body (at most `limit` bytes) and whether it was cut off
+    pub fn response_body(&self, limit: usize) -> (Bytes, bool) {
+        self.response_body.get(limit)
+    }
+
+    /// Full response body size as seen by the proxy
+    pub fn response_body_size(&self) -> usize {
+        self.response_body.size()
+    }
+
+    /// Time spent streaming the response body, if it has finished
+    pub fn receive_ms(&self) -> Option<u64> {
+        self.receive_ms.get().copied()
+    }
+
+    /// Milliseconds since the Unix epoch when the request arrived
+    pub fn timestamp_ms(&self) -> u64 {
+        self.started
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or(0)
+    }
+
+    /// Rebuild the captured request, applying the given edits
+    pub fn to_request(&self, edits: &RequestEdits, limit: usize) -> anyhow::Result<Request<Body>> {
+        let mut headers = self.headers.clone();
+        for name in &edits.remove_headers {
+            headers.remove(name.as_str());
+        }
+        for (name, value) in &edits.headers {
+            headers.insert(
+                axum::http::HeaderName::from_bytes(name.as_bytes())?,
+                HeaderValue::from_str(value)?,
+            );
+        }
+
+        let body = match &edits.body {
+            Some(body) => {
+                headers.remove("transfer-encoding");
+                headers.insert("content-length", HeaderValue::from(body.len()));
+                Bytes::from(body.clone())
+            }
+            None => {
+                let (body, truncated) = self.body(limit);
+                if truncated {
+                    anyhow::bail!(
+                        "captured body of request {} was truncated; supply a replacement body",
+                        self.id
+                    );
+                }
+                body
+            }
+        };
+
+        let mut req = Request::builder()
+            .method(self.method.clone())
+            .uri(self.uri.clone())
+            .body(Body::from(body))?;
+        *req.headers_mut() = headers;
+
+        Ok(req)
+    }
+
+    /// Short JSON view used for history listings
+    pub fn summary(&self) -> CaptureSummary {
+        CaptureSummary {
+            id: self.id,
+            timestamp_ms: self.timestamp_ms(),
+            listener_port: self.listener_port,
+            target: self.target.clone(),
+            client_addr: self.client_addr.to_string(),
+            method: self.method.to_string(),
+            uri: self.uri.to_string(),
+            status: self.status.as_u16(),
+            duration_ms: self.wait_ms + self.receive_ms().unwrap_or(0),
+        }
+    }
+
+    /// Full JSON view including headers and bodies
+    pub fn detail(&self, limit: usize) -> CaptureDetail {
+        let (body, body_truncated) = self.body(limit);
+        let (response_body, response_body_truncated) = self.response_body(limit);
+        CaptureDetail {
+            summary: self.summary(),
+            headers: header_pairs(&self.headers),
+            body: String::from_utf8_lossy(&body).into_owned(),
+            body_truncated,
+            response_headers: header_pairs(&self.response_headers),
+            response_body: String::from_utf8_lossy(&response_body).into_owned(),
+            response_body_truncated,
+        }
+    }
+}
+
+fn header_pairs(headers: &HeaderMap) -> Vec<(String, String)> {
+    headers
+        .iter()
+        .map(|(name, value)| {
+            (
+                name.to_string(),
+                String::from_utf8_lossy(value.as_bytes()).into_owned(),
+            )
+        })
+        .collect()
+}
+
+/// Changes applied to a captured request before it is replayed
+#[derive(Debug, Default, Clone, Deserialize, Serialize)]
+pub struct RequestEdits {
+    /// Send the replay to this target instead of the original one
+    #[serde(default)]
+    pub target: Option<String>,
+    /// Headers to set (replacing any captured values)
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    /// Headers to drop from the captured request
+    #[serde(default)]
+    pub remove_headers: Vec<String>,
+    /// Replacement request body
+    #[serde(default)]
+    pub body: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CaptureSummary {
+    pub id: u64,
+    pub timestamp_ms: u64,
+    pub listener_port: u16,
+    pub target: String,
+    pub client_addr: String,
+    pub method: String,
+    pub uri: String,
+    pub status: u16,
+    pub duration_ms: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CaptureDetail {
+    #[serde(flatten)]
+    pub summary: CaptureSummary,
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+    pub body_truncated: bool,
+    pub response_headers: Vec<(String, String)>,
+    pub response_body: String,
+    pub response_body_truncated: bool,
+}
+
+/// A request that is still in flight; turned into a `CapturedRequest` by `finish`
+pub struct PendingCapture {
+    started: SystemTime,
+    clock: Instant,
+    listener_port: u16,
+    target: String,
+    client_addr: SocketAddr,
+    method: Method,
+    uri: Uri,
+    version: Version,
+    headers: HeaderMap,
+    body: RecordedBody,
+    max_body_bytes: usize,
+}
+
+/// Start recording a request. The returned request streams its body to the
+/// upstream as before, copying up to `max_body_bytes` into the capture.
+pub fn record(
+    listener_port: u16,
+    target: &str,
+    client_addr: SocketAddr,
+    req: Request<Body>,
+    max_body_bytes: usize,
+) -> (PendingCapture, Request<Body>) {
+    let (parts, body) = req.into_parts();
+    let recorded = RecordedBody::default();
+    let body = recorded.tee(body, max_body_bytes, None);
+
+    let pending = PendingCapture {
+        started: SystemTime::now(),
+        clock: Instant::now(),
+        listener_port,
+        target: target.to_string(),
+        client_addr,
+        method: parts.method.clone(),
+        uri: parts.uri.clone(),
+        version: parts.version,
+        headers: parts.headers.clone(),
+        body: recorded,
+        max_body_bytes,
+    };
+
+    (pending, Request::from_parts(parts, body))
+}
+
+impl PendingCapture {
+    /// Complete the capture once the response headers are known. The response
+    /// body is recorded as it streams to the client, and `on_complete` runs
+    /// once it has been fully sent (or the client went away).
+    pub fn finish(
+        self,
+        response: Response<Body>,
+        on_complete: impl FnOnce(Arc<CapturedRequest>) + Send + 'static,
+    ) -> (Arc<CapturedRequest>, Response<Body>) {
+        let (parts, body) = response.into_parts();
+        let wait = self.clock.elapsed();
+
+        let entry = Arc::new(CapturedRequest {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            started: self.started,
+            listener_port: self.listener_port,
+            target: self.target,
+            client_addr: self.client_addr,
+            method: self.method,
+            uri: self.uri,
+            version: self.version,
+            headers: self.headers,
+            status: parts.status,
+            response_version: parts.version,
+            response_headers: parts.headers.clone(),
+            wait_ms: wait.as_millis() as u64,
+            receive_ms: OnceLock::new(),
+            body: self.body,
+            response_body: RecordedBody::default(),
+        });
+
+        let clock = self.clock;
+        let on_end = OnEnd(Some(Box::new({
+            let entry = entry.clone();
+            move || {
+                let receive = clock.elapsed().saturating_sub(wait);
+                let _ = entry.receive_ms.set(receive.as_millis() as u64);
+                on_complete(entry);
+            }
+        })));
+        let body = entry
+            .response_body
+            .tee(body, self.max_body_bytes, Some(on_end));
+
+        (entry, Response::from_parts(parts, body))
+    }
+}
+
+/// Bounded in-memory history of proxied requests
+pub struct CaptureStore {
+    entries: Mutex<VecDeque<Arc<CapturedRequest>>>,
+    max_entries: usize,
+    max_body_bytes: usize,
+}
+
+impl CaptureStore {
+    pub fn new(config: &CaptureConfig) -> Self {
+        Self {
+            entries: Mutex::new(VecDeque::with_capacity(config.max_entries)),
+            max_entries: config.max_entries,
+            max_body_bytes: config.max_body_bytes,
+        }
+    }
+
+    /// Body bytes kept per captured request and response
+    pub fn max_body_bytes(&self) -> usize {
+        self.max_body_bytes
+    }
+
+    /// Store a captured request, evicting the oldest entry when full
+    pub fn push(&self, entry: Arc<CapturedRequest>) {
+        if self.max_entries == 0 {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        while entries.len() >= self.max_entries {
+            entries.pop_front();
+        }
+        entries.push_back(entry);
+    }
+
+    /// Look up a captured request by id
+    pub fn get(&self, id: u64) -> Option<Arc<CapturedRequest>> {
+        self.entries
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|entry| entry.id == id)
+            .cloned()
+    }
+
+    /// Most recent captured requests, newest first
+    pub fn list(&self, limit: usize) -> Vec<Arc<CapturedRequest>> {
+        self.entries
+            .lock()
+            .unwrap()
+            .iter()
+            .rev()
+            .take(limit)
+            .cloned()
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http_body_util::BodyExt;
+
+    fn store(max_entries: usize, max_body_bytes: usize) -> CaptureStore {
+        CaptureStore::new(&CaptureConfig {
+            max_entries,
+            max_body_bytes,
+        })
+    }
+
+    fn client() -> SocketAddr {
+        "10.0.0.1:5000".parse().unwrap()
+    }
+
+    async fn capture(store: &CaptureStore, req: Request<Body>) -> u64 {
+        let (pending, req) = record(
+            440,
+            "http://api:3000",
+            client(),
+            req,
+            store.max_body_bytes(),
+        );
+        // Drain the body the way the upstream client would
+        req.into_body().collect().await.unwrap();
+        let (entry, _) = pending.finish(Response::new(Body::from("ok")), |_| {});
+        store.push(entry.clone());
+        entry.id
+    }
+
+    #[tokio::test]
+    async fn test_capture_records_request_and_body() {
+        let store = store(10, 1024);
+        let req = Request::builder()
+            .method("POST")
+            .uri("/api/items?x=1")
+            .header("content-type", "application/json")
+            .body(Body::from(r#"{"a":1}"#))
+            .unwrap();
+
+        let id = capture(&store, req).await;
+        let entry = store.get(id).unwrap();
+
+        assert_eq!(entry.method, Method::POST);
+        assert_eq!(entry.uri, "/api/items?x=1");
+        assert_eq!(
+            entry.headers.get("content-type").unwrap(),
+            "application/json"
+        );
+        assert_eq!(entry.body(1024), (Bytes::from(r#"{"a":1}"#), false));
+    }
+
+    #[tokio::test]
+    async fn test_capture_truncates_large_body() {
+        let store = store(10, 4);
+        let req = Request::builder()
+            .method("POST")
+            .body(Body::from("0123456789"))
+            .unwrap();
+
+        let id = capture(&store, req).await;
+        let entry = store.get(id).unwrap();
+        assert_eq!(entry.body(4), (Bytes::from("0123"), true));
+        assert_eq!(entry.body_size(), 10);
+    }
+
+    #[tokio::test]
+    async fn test_capture_records_response_when_streamed() {
+        let (pending, _req) = record(440, "http://api:3000", client(), Request::default(), 1024);
+        let (tx, rx) = std::sync::mpsc::channel();
+        let (entry, response) = pending.finish(Response::new(Body::from("hello")), move |e| {
+            tx.send(e.id).unwrap();
+        });
+
+        // Nothing is reported until the client has read the body
+        assert!(rx.try_recv().is_err());
+        assert_eq!(entry.receive_ms(), None);
+
+        response.into_body().collect().await.unwrap();
+        assert_eq!(rx.try_recv().unwrap(), entry.id);
+        assert_eq!(entry.response_body(1024), (Bytes::from("hello"), false));
+        assert!(entry.receive_ms().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_capture_evicts_oldest() {
+        let store = store(2, 16);
+        let mut ids = Vec::new();
+        for _ in 0..3 {
+            ids.push(capture(&store, Request::new(Body::empty())).await);
+        }
+
+        assert!(store.get(ids[0]).is_none());
+        let listed: Vec<u64> = store.list(10).iter().map(|e| e.id).collect();
+        assert_eq!(listed, vec![ids[2], ids[1]]);
+    }
+
+    #[tokio::test]
+    async fn test_to_request_applies_edits() {
+        let store = store(10, 1024);
+        let req = Request::builder()
+            .method("PUT")
+            .uri("/thing")
+            .header("cookie", "session=abc")
+            .header("content-length", "3")
+            .body(Body::from("old"))
+            .unwrap();
+        let id = capture(&store, req).await;
+
+        let edits = RequestEdits {
+            headers: BTreeMap::from([("x-debug".into(), "1".into())]),
+            remove_headers: vec!["cookie".into()],
+            body: Some("brand new".into()),
+            ..Default::default()
+        };
+        let replay = store.get(id).unwrap().to_request(&edits, 1024).unwrap();
+
+        assert_eq!(replay.method(), Method::PUT);
+        assert_eq!(replay.headers().get("x-debug").unwrap(), "1");
+        assert!(replay.headers().get("cookie").is_none());
+        assert_eq!(replay.headers().get("content-length").unwrap(), "9");
+        let body = replay.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(body, "brand new");
+    }
+
+    #[tokio::test]
+    async fn test_to_request_rejects_truncated_body() {
+        let store = store(10, 2);
+        let req = Request::builder()
+            .method("POST")
+            .body(Body::from("too long"))
+            .unwrap();
+        let id = capture(&store, req).await;
+
+        let result = store
+            .get(id)
+            .unwrap()
+            .to_request(&RequestEdits::default(), 2);
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,341 @@
+//! Per-target circuit breakers that stop sending requests to failing upstreams.
+
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::config::CircuitBreakerConfig;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CircuitState {
+    /// Requests flow normally while failures are counted
+    Closed,
+    /// Requests are rejected until the open period ends
+    Open,
+    /// A few probe requests decide whether to close or reopen
+    HalfOpen,
+}
+
+#[derive(Debug)]
+struct Circuit {
+    state: CircuitState,
+    opened_at: Instant,
+    window_start: Instant,
+    requests: u32,
+    failures: u32,
+    probes: u32,
+}
+
+impl Circuit {
+    fn new(now: Instant) -> Self {
+        Self {
+            state: CircuitState::Closed,
+            opened_at: now,
+            window_start: now,
+            requests: 0,
+            failures: 0,
+            probes: 0,
+        }
+    }
+
+    fn reset_counts(&mut self, now: Instant) {
+        self.window_start = now;
+        self.requests = 0;
+        self.failures = 0;
+    }
+}
+
+/// Current state of one target's circuit, as shown by the admin API
+#[derive(Debug, Clone, Serialize)]
+pub struct CircuitSnapshot {
+    pub target: String,
+    pub state: CircuitState,
+    /// Requests and failures in the current window
+    pub requests: u32,
+    pub failures: u32,
+}
+
+/// Permission to send one request; report its outcome with `record`
+#[derive(Debug)]
+pub struct Attempt<'a> {
+    breaker: &'a CircuitBreaker,
+    target: String,
+    started: Instant,
+    probe: bool,
+    recorded: bool,
+}
+
+impl Attempt<'_> {
+    /// Record how the upstream answered: `Ok(status)` or a connection error
+    pub fn record(self, status: Result<u16, ()>) {
+        self.record_at(status, Instant::now());
+    }
+
+    fn record_at(mut self, status: Result<u16, ()>, now: Instant) {
+        let slow = self
+            .breaker
+            .slow_call
+            .is_some_and(|slow_call| now.duration_since(self.started) > slow_call);
+        let success = matches!(status, Ok(status) if status < 500) && !slow;
+        self.recorded = true;
+        self.breaker
+            .finish(&self.target, self.probe, Some(success), now);
+    }
+}
+
+impl Drop for Attempt<'_> {
+    fn drop(&mut self) {
+        // Abandoned by the client: free the probe slot without a verdict
+        if !self.recorded {
+            self.breaker
+                .finish(&self.target, self.probe, None, Instant::now());
+        }
+    }
+}
+
+/// Circuit breakers for every target a listener sends to
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    failure_rate: f64,
+    min_requests: u32,
+    window: Duration,
+    slow_call: Option<Duration>,
+    open_duration: Duration,
+    half_open_requests: u32,
+    circuits: Mutex<HashMap<String, Circuit>>,
+}
+
+impl CircuitBreaker {
+    pub fn new(config: &CircuitBreakerConfig) -> Self {
+        Self {
+            failure_rate: config.failure_rate,
+            min_requests: config.min_requests.max(1),
+            window: Duration::from_secs(config.window_secs.max(1)),
+            slow_call: config.slow_call_ms.map(Duration::from_millis),
+            open_duration: Duration::from_secs(config.open_secs),
+            half_open_requests: config.half_open_requests.max(1),
+            circuits: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Ask to send a request to `target`. While the circuit is open this
+    /// fails with the time left before it will be probed again.
+    pub fn attempt(&self, target: &str) -> Result<Attempt<'_>, Duration> {
+        self.attempt_at(target, Instant::now())
+    }
+
+    fn attempt_at(&self, target: &str, now: Instant) -> Result<Attempt<'_>, Duration> {
+        let mut circuits = self.circuits.lock().unwrap();
+        let circuit = circuits
+            .entry(target.to_string())
+            .or_insert_with(|| Circuit::new(now));
+
+        if circuit.state == CircuitState::Open {
+            let open_for = now.duration_since(circuit.opened_at);
+            if open_for < self.open_duration {
+                return Err(self.open_duration - open_for);
+            }
+            tracing::info!("Circuit for {} half-open, probing", target);
+            circuit.state = CircuitState::HalfOpen;
+            circuit.probes = 0;
+        }
+
+        let probe = circuit.state == CircuitState::HalfOpen;
+        if probe {
+            if circuit.probes >= self.half_open_requests {
+                return Err(Duration::from_secs(1));
+            }
+            circuit.probes += 1;
+        }
+
+        Ok(Attempt {
+            breaker: self,
+            target: target.to_string(),
+            started: now,
+            probe,
+            recorded: false,
+        })
+    }
+
+    fn finish(&self, target: &str, probe: bool, success: Option<bool>, now: Instant) {
+        let mut circuits = self.circuits.lock().unwrap();
+        let Some(circuit) = circuits.get_mut(target) else {
+            return;
+        };
+
+        match circuit.state {
+            CircuitState::HalfOpen if probe => {
+                circuit.probes = circuit.probes.saturating_sub(1);
+                match success {
+                    Some(true) => {
+                        tracing::info!("Circuit for {} closed, upstream recovered", target);
+                        circuit.state = CircuitState::Closed;
+                        circuit.reset_counts(now);
+                    }
+                    Some(false) => {
+                        tracing::warn!("Circuit for {} reopened, probe failed", target);
+                        circuit.state = CircuitState::Open;
+                        circuit.opened_at = now;
+                    }
+                    None => {}
+                }
+            }
+            CircuitState::Closed => {
+                let Some(success) = success else {
+                    return;
+                };
+                if now.duration_since(circuit.window_start) >= self.window {
+                    circuit.reset_counts(now);
+                }
+                circuit.requests += 1;
+                if !success {
+                    circuit.failures += 1;
+                }
+
+                let rate = f64::from(circuit.failures) / f64::from(circuit.requests);
+                if circuit.requests >= self.min_requests && rate >= self.failure_rate {
+                    tracing::warn!(
+                        "Circuit for {} opened: {} of {} requests failed",
+                        target,
+                        circuit.failures,
+                        circuit.requests
+                    );
+                    circuit.state = CircuitState::Open;
+                    circuit.opened_at = now;
+                    circuit.reset_counts(now);
+                }
+            }
+            // Late results of requests sent before the circuit changed state
+            _ => {}
+        }
+    }
+
+    /// State of every circuit, for the admin API
+    pub fn snapshot(&self) -> Vec<CircuitSnapshot> {
+        let now = Instant::now();
+        let mut snapshot: Vec<CircuitSnapshot> = self
+            .circuits
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(target, circuit)| CircuitSnapshot {
+                target: target.clone(),
+                // An open circuit past its open period is probed by the next request
+                state: match circuit.state {
+                    CircuitState::Open
+                        if now.duration_since(circuit.opened_at) >= self.open_duration =>
+                    {
+                        CircuitState::HalfOpen
+                    }
+                    state => state,
+                },
+                requests: circuit.requests,
+                failures: circuit.failures,
+            })
+            .collect();
+        snapshot.sort_by(|a, b| a.target.cmp(&b.target));
+        snapshot
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn breaker() -> CircuitBreaker {
+        CircuitBreaker::new(&CircuitBreakerConfig {
+            failure_rate: 0.5,
+            min_requests: 4,
+            window_secs: 60,
+            slow_call_ms: None,
+            open_secs: 30,
+            half_open_requests: 1,
+        })
+    }
+
+    fn send(breaker: &CircuitBreaker, now: Instant, status: Result<u16, ()>) -> bool {
+        match breaker.attempt_at("http://api", now) {
+            Ok(attempt) => {
+                attempt.record_at(status, now);
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    fn state(breaker: &CircuitBreaker) -> CircuitState {
+        breaker.snapshot()[0].state
+    }
+
+    #[test]
+    fn test_opens_on_failure_rate() {
+        let breaker = breaker();
+        let now = Instant::now();
+
+        send(&breaker, now, Ok(200));
+        send(&breaker, now, Err(()));
+        send(&breaker, now, Ok(503));
+        assert_eq!(state(&breaker), CircuitState::Closed);
+
+        // The fourth request reaches min_requests with 3 of 4 failed
+        send(&breaker, now, Ok(500));
+        assert_eq!(state(&breaker), CircuitState::Open);
+
+        let retry_after = breaker
+            .attempt_at("http://api", now + Duration::from_secs(10))
+            .unwrap_err();
+        assert_eq!(retry_after, Duration::from_secs(20));
+    }
+
+    #[test]
+    fn test_half_open_probe() {
+        let breaker = breaker();
+        let now = Instant::now();
+        for _ in 0..4 {
+            send(&breaker, now, Err(()));
+        }
+
+        // After the open period a single probe is let through
+        let later = now + Duration::from_secs(30);
+        let probe = breaker.attempt_at("http://api", later).unwrap();
+        assert!(breaker.attempt_at("http://api", later).is_err());
+
+        // A failed probe reopens the circuit
+        probe.record_at(Err(()), later);
+        assert!(breaker.attempt_at("http://api", later).is_err());
+
+        // A successful one closes it
+        let much_later = later + Duration::from_secs(30);
+        assert!(send(&breaker, much_later, Ok(200)));
+        assert_eq!(state(&breaker), CircuitState::Closed);
+        assert!(send(&breaker, much_later, Ok(200)));
+    }
+
+    #[test]
+    fn test_abandoned_probe_frees_slot() {
+        let breaker = breaker();
+        let now = Instant::now();
+        for _ in 0..4 {
+            send(&breaker, now, Err(()));
+        }
+
+        let later = now + Duration::from_secs(30);
+        drop(breaker.attempt_at("http://api", later).unwrap());
+        assert!(breaker.attempt_at("http://api", later).is_ok());
+    }
+
+    #[test]
+    fn test_slow_calls_count_as_failures() {
+        let breaker = CircuitBreaker::new(&CircuitBreakerConfig {
+            slow_call_ms: Some(0),
+            min_requests: 1,
+            ..CircuitBreakerConfig::default()
+        });
+        let now = Instant::now();
+        let attempt = breaker.attempt_at("http://api", now).unwrap();
+        attempt.record_at(Ok(200), now + Duration::from_millis(2));
+        assert_eq!(state(&breaker), CircuitState::Open);
+    }
+}
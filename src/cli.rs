@@ -0,0 +1,157 @@
+//! Command-line subcommands that talk to a running proxy's admin API.
+
+use axum::body::Bytes;
+use axum::http::{Method, Request};
+use http_body_util::{BodyExt, Full};
+use hyper_util::client::legacy::Client;
+use hyper_util::rt::TokioExecutor;
+use std::io::Write;
+
+use crate::capture::RequestEdits;
+
+const DEFAULT_ADMIN_URL: &str = "http://127.0.0.1:9000";
+
+const REPLAY_USAGE: &str = "\
+Usage: https-proxy replay <id> [options]
+
+Options:
+  --admin <url>          Admin API base URL (default: $ADMIN_URL or http://127.0.0.1:9000)
+  --target <url>         Send the request to a different target
+  -H, --header <k: v>    Set a header (repeatable)
+  --remove-header <k>    Drop a captured header (repeatable)
+  -d, --data <body>      Replace the request body (@file reads it from a file)";
+
+/// Parsed `replay` arguments
+#[derive(Debug)]
+struct ReplayArgs {
+    admin_url: String,
+    id: u64,
+    edits: RequestEdits,
+}
+
+fn parse_replay_args(args: &[String]) -> anyhow::Result<ReplayArgs> {
+    let mut admin_url =
+        std::env::var("ADMIN_URL").unwrap_or_else(|_| DEFAULT_ADMIN_URL.to_string());
+    let mut id = None;
+    let mut edits = RequestEdits::default();
+
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        let mut value = |flag: &str| {
+            iter.next()
+                .cloned()
+                .ok_or_else(|| anyhow::anyhow!("{} requires a value", flag))
+        };
+        match arg.as_str() {
+            "--admin" => admin_url = value(arg)?,
+            "--target" => edits.target = Some(value(arg)?),
+            "-H" | "--header" => {
+                let header = value(arg)?;
+                let (name, val) = header
+                    .split_once(':')
+                    .ok_or_else(|| anyhow::anyhow!("invalid header {:?}", header))?;
+                edits
+                    .headers
+                    .insert(name.trim().to_string(), val.trim().to_string());
+            }
+            "--remove-header" => edits.remove_headers.push(value(arg)?),
+            "-d" | "--data" => {
+                let data = value(arg)?;
+                edits.body = Some(match data.strip_prefix('@') {
+                    Some(path) => std::fs::read_to_string(path)?,
+                    None => data,
+                });
+            }
+            other if id.is_none() && !other.starts_with('-') => id = Some(other.parse()?),
+            other => anyhow::bail!("unexpected argument {:?}\n\n{}", other, REPLAY_USAGE),
+        }
+    }
+
+    let id = id.ok_or_else(|| anyhow::anyhow!("missing request id\n\n{}", REPLAY_USAGE))?;
+
+    Ok(ReplayArgs {
+        admin_url: admin_url.trim_end_matches('/').to_string(),
+        id,
+        edits,
+    })
+}
+
+/// `https-proxy replay` - replay a captured request via the admin API and
+/// print the upstream response
+pub async fn replay(args: &[String]) -> anyhow::Result<()> {
+    let args = parse_replay_args(args)?;
+
+    let req = Request::builder()
+        .method(Method::POST)
+        .uri(format!(
+            "{}/api/requests/{}/replay",
+            args.admin_url, args.id
+        ))
+        .header("content-type", "application/json")
+        .body(Full::<Bytes>::from(serde_json::to_vec(&args.edits)?))?;
+
+    let client = Client::builder(TokioExecutor::new()).build_http();
+    let response = client.request(req).await?;
+    let (parts, body) = response.into_parts();
+    let body = body.collect().await?.to_bytes();
+
+    let mut out = std::io::stdout().lock();
+    writeln!(out, "{:?} {}", parts.version, parts.status)?;
+    for (name, value) in parts.headers.iter() {
+        writeln!(
+            out,
+            "{}: {}",
+            name,
+            String::from_utf8_lossy(value.as_bytes())
+        )?;
+    }
+    writeln!(out)?;
+    out.write_all(&body)?;
+    out.flush()?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(list: &[&str]) -> Vec<String> {
+        list.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse_replay_args_full() {
+        let parsed = parse_replay_args(&args(&[
+            "42",
+            "--admin",
+            "http://proxy:9000/",
+            "--target",
+            "http://other:3000",
+            "-H",
+            "X-Debug: 1",
+            "--remove-header",
+            "cookie",
+            "-d",
+            "{}",
+        ]))
+        .unwrap();
+
+        assert_eq!(parsed.id, 42);
+        assert_eq!(parsed.admin_url, "http://proxy:9000");
+        assert_eq!(parsed.edits.target.as_deref(), Some("http://other:3000"));
+        assert_eq!(parsed.edits.headers.get("X-Debug").unwrap(), "1");
+        assert_eq!(parsed.edits.remove_headers, vec!["cookie"]);
+        assert_eq!(parsed.edits.body.as_deref(), Some("{}"));
+    }
+
+    #[test]
+    fn test_parse_replay_args_missing_id() {
+        assert!(parse_replay_args(&args(&["--target", "http://x"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_replay_args_bad_header() {
+        assert!(parse_replay_args(&args(&["1", "-H", "no-colon"])).is_err());
+    }
+}
@@ -0,0 +1,314 @@
+//! Response compression negotiated from the client's `Accept-Encoding`.
+
+use async_compression::tokio::bufread::{BrotliEncoder, GzipEncoder, ZstdEncoder};
+use async_compression::Level;
+use axum::body::Body;
+use axum::http::{header, HeaderMap, HeaderValue, Method, Response, StatusCode};
+use futures_util::TryStreamExt;
+use tokio_util::io::{ReaderStream, StreamReader};
+
+use crate::config::{CompressionAlgorithm, CompressionConfig};
+
+/// Brotli's default quality (11) is far too slow for on-the-fly compression
+const BROTLI_QUALITY: i32 = 4;
+
+impl CompressionAlgorithm {
+    fn coding(self) -> &'static str {
+        match self {
+            CompressionAlgorithm::Zstd => "zstd",
+            CompressionAlgorithm::Brotli => "br",
+            CompressionAlgorithm::Gzip => "gzip",
+        }
+    }
+}
+
+/// Compiled form of `CompressionConfig`
+#[derive(Debug, Clone)]
+pub struct Compressor {
+    algorithms: Vec<CompressionAlgorithm>,
+    min_size: u64,
+    content_types: Vec<String>,
+}
+
+impl Compressor {
+    pub fn new(config: &CompressionConfig) -> Self {
+        Self {
+            algorithms: config.algorithms.clone(),
+            min_size: config.min_size,
+            content_types: config
+                .content_types
+                .iter()
+                .map(|t| t.trim().to_ascii_lowercase())
+                .collect(),
+        }
+    }
+
+    /// Compress `response` when the client accepts one of our encodings and
+    /// the response is worth compressing
+    pub fn apply(
+        &self,
+        method: &Method,
+        accept_encoding: Option<&HeaderValue>,
+        response: Response<Body>,
+    ) -> Response<Body> {
+        if method == Method::HEAD || !self.is_compressible(response.status(), response.headers()) {
+            return response;
+        }
+        let Some(algorithm) = accept_encoding
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| self.negotiate(v))
+        else {
+            return response;
+        };
+
+        let (mut parts, body) = response.into_parts();
+        parts.headers.remove(header::CONTENT_LENGTH);
+        // Byte ranges of the compressed stream are not offered
+        parts.headers.remove(header::ACCEPT_RANGES);
+        parts.headers.insert(
+            header::CONTENT_ENCODING,
+            HeaderValue::from_static(algorithm.coding()),
+        );
+        parts
+            .headers
+            .append(header::VARY, HeaderValue::from_static("accept-encoding"));
+        // The compressed body is a different representation
+        if let Some(etag) = parts
+            .headers
+            .get(header::ETAG)
+            .and_then(|v| v.to_str().ok())
+        {
+            if !etag.starts_with("W/") {
+                if let Ok(weak) = HeaderValue::from_str(&format!("W/{}", etag)) {
+                    parts.headers.insert(header::ETAG, weak);
+                }
+            }
+        }
+
+        let reader = StreamReader::new(body.into_data_stream().map_err(std::io::Error::other));
+        let body = match algorithm {
+            CompressionAlgorithm::Zstd => {
+                Body::from_stream(ReaderStream::new(ZstdEncoder::new(reader)))
+            }
+            CompressionAlgorithm::Brotli => Body::from_stream(ReaderStream::new(
+                BrotliEncoder::with_quality(reader, Level::Precise(BROTLI_QUALITY)),
+            )),
+            CompressionAlgorithm::Gzip => {
+                Body::from_stream(ReaderStream::new(GzipEncoder::new(reader)))
+            }
+        };
+
+        Response::from_parts(parts, body)
+    }
+
+    fn is_compressible(&self, status: StatusCode, headers: &HeaderMap) -> bool {
+        if status.is_informational()
+            || status == StatusCode::NO_CONTENT
+            || status == StatusCode::NOT_MODIFIED
+            || status == StatusCode::PARTIAL_CONTENT
+        {
+            return false;
+        }
+
+        // Already encoded by the upstream
+        if headers
+            .get(header::CONTENT_ENCODING)
+            .is_some_and(|v| !v.as_bytes().eq_ignore_ascii_case(b"identity"))
+        {
+            return false;
+        }
+
+        let no_transform = headers
+            .get_all(header::CACHE_CONTROL)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .any(|v| v.to_ascii_lowercase().contains("no-transform"));
+        if no_transform || headers.contains_key(header::CONTENT_RANGE) {
+            return false;
+        }
+
+        let too_small = headers
+            .get(header::CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse::<u64>().ok())
+            .is_some_and(|len| len < self.min_size);
+        if too_small {
+            return false;
+        }
+
+        let Some(content_type) = headers
+            .get(header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| {
+                v.split(';')
+                    .next()
+                    .unwrap_or("")
+                    .trim()
+                    .to_ascii_lowercase()
+            })
+        else {
+            return false;
+        };
+
+        // Server-sent events must reach the client as they are produced
+        if content_type == "text/event-stream" {
+            return false;
+        }
+
+        self.content_types
+            .iter()
+            .any(|allowed| match allowed.strip_suffix("/*") {
+                Some(top_level) => content_type
+                    .split_once('/')
+                    .is_some_and(|(t, _)| t == top_level),
+                None => *allowed == content_type,
+            })
+    }
+
+    /// Pick the encoding the client prefers most, breaking ties with our own
+    /// preference order
+    fn negotiate(&self, accept_encoding: &str) -> Option<CompressionAlgorithm> {
+        let accepted: Vec<(String, f32)> = accept_encoding
+            .split(',')
+            .filter_map(|item| {
+                let mut params = item.split(';');
+                let coding = params.next()?.trim().to_ascii_lowercase();
+                if coding.is_empty() {
+                    return None;
+                }
+                let q = params
+                    .filter_map(|p| p.trim().strip_prefix("q="))
+                    .find_map(|q| q.trim().parse::<f32>().ok())
+                    .unwrap_or(1.0);
+                Some((coding, q))
+            })
+            .collect();
+
+        let quality = |coding: &str| {
+            accepted
+                .iter()
+                .find(|(c, _)| c == coding)
+                .or_else(|| accepted.iter().find(|(c, _)| c == "*"))
+                .map_or(0.0, |(_, q)| *q)
+        };
+
+        let mut best: Option<(CompressionAlgorithm, f32)> = None;
+        for algorithm in &self.algorithms {
+            let q = quality(algorithm.coding());
+            if q > 0.0 && best.is_none_or(|(_, best_q)| q > best_q) {
+                best = Some((*algorithm, q));
+            }
+        }
+
+        best.map(|(algorithm, _)| algorithm)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http_body_util::BodyExt;
+    use tokio::io::AsyncReadExt;
+
+    fn compressor() -> Compressor {
+        Compressor::new(&CompressionConfig::default())
+    }
+
+    fn response(content_type: &str, body: &str) -> Response<Body> {
+        Response::builder()
+            .header("content-type", content_type)
+            .header("content-length", body.len())
+            .body(Body::from(body.to_string()))
+            .unwrap()
+    }
+
+    #[test]
+    fn test_negotiate() {
+        let compressor = compressor();
+
+        assert_eq!(
+            compressor.negotiate("gzip, deflate, br, zstd"),
+            Some(CompressionAlgorithm::Zstd)
+        );
+        assert_eq!(
+            compressor.negotiate("gzip;q=1.0, br;q=0.5"),
+            Some(CompressionAlgorithm::Gzip)
+        );
+        assert_eq!(
+            compressor.negotiate("*;q=0.5, zstd;q=0"),
+            Some(CompressionAlgorithm::Brotli)
+        );
+        assert_eq!(compressor.negotiate("deflate, identity"), None);
+        assert_eq!(compressor.negotiate(""), None);
+    }
+
+    #[test]
+    fn test_is_compressible() {
+        let compressor = compressor();
+        let big = &"x".repeat(2048);
+
+        let check = |response: Response<Body>| {
+            compressor.is_compressible(response.status(), response.headers())
+        };
+        assert!(check(response("text/html; charset=utf-8", big)));
+        assert!(check(response("application/json", big)));
+        assert!(!check(response("image/png", big)));
+        assert!(!check(response("text/event-stream", big)));
+        assert!(!check(response("text/html", "tiny")));
+
+        let mut encoded = response("text/html", big);
+        encoded
+            .headers_mut()
+            .insert("content-encoding", HeaderValue::from_static("gzip"));
+        assert!(!check(encoded));
+
+        let mut no_transform = response("text/html", big);
+        no_transform.headers_mut().insert(
+            "cache-control",
+            HeaderValue::from_static("public, no-transform"),
+        );
+        assert!(!check(no_transform));
+    }
+
+    #[tokio::test]
+    async fn test_apply_gzip() {
+        let body = "hello world ".repeat(200);
+        let mut upstream = response("text/plain", &body);
+        upstream
+            .headers_mut()
+            .insert("etag", HeaderValue::from_static("\"v1\""));
+
+        let accept = HeaderValue::from_static("gzip");
+        let compressed = compressor().apply(&Method::GET, Some(&accept), upstream);
+
+        assert_eq!(
+            compressed.headers().get("content-encoding").unwrap(),
+            "gzip"
+        );
+        assert_eq!(compressed.headers().get("vary").unwrap(), "accept-encoding");
+        assert_eq!(compressed.headers().get("etag").unwrap(), "W/\"v1\"");
+        assert!(compressed.headers().get("content-length").is_none());
+
+        let bytes = compressed.into_body().collect().await.unwrap().to_bytes();
+        assert!(bytes.len() < body.len());
+        let mut decoded = String::new();
+        async_compression::tokio::bufread::GzipDecoder::new(&bytes[..])
+            .read_to_string(&mut decoded)
+            .await
+            .unwrap();
+        assert_eq!(decoded, body);
+    }
+
+    #[tokio::test]
+    async fn test_apply_skips_head_and_unaccepted() {
+        let body = &"x".repeat(2048);
+
+        let accept = HeaderValue::from_static("br");
+        let head = compressor().apply(&Method::HEAD, Some(&accept), response("text/html", body));
+        assert!(head.headers().get("content-encoding").is_none());
+
+        let plain = compressor().apply(&Method::GET, None, response("text/html", body));
+        assert!(plain.headers().get("content-encoding").is_none());
+        assert_eq!(plain.headers().get("content-length").unwrap(), "2048");
+    }
+}
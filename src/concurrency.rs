@@ -0,0 +1,179 @@
+//! Limits on concurrent upstream requests, with a bounded wait queue.
+
+use axum::body::{Body, HttpBody};
+use futures_util::StreamExt;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::config::ConcurrencyConfig;
+
+/// Why a request could not be sent upstream
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Rejected {
+    QueueFull,
+    Timeout,
+}
+
+impl std::fmt::Display for Rejected {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Rejected::QueueFull => write!(f, "upstream queue is full"),
+            Rejected::Timeout => write!(f, "timed out waiting in the upstream queue"),
+        }
+    }
+}
+
+/// Slot held by a request while it is in flight
+#[derive(Debug)]
+pub struct InFlight {
+    _permit: OwnedSemaphorePermit,
+}
+
+impl InFlight {
+    /// Keep the slot until `body` has been streamed to the client
+    pub fn hold_until_sent(self, body: Body) -> Body {
+        if body.is_end_stream() {
+            return body;
+        }
+        Body::from_stream(body.into_data_stream().map(move |chunk| {
+            let _ = &self;
+            chunk
+        }))
+    }
+}
+
+/// In-flight limit and queue for a single upstream
+#[derive(Debug)]
+struct UpstreamSlots {
+    semaphore: Arc<Semaphore>,
+    waiting: AtomicUsize,
+}
+
+/// A place in an upstream's wait queue
+struct Queued<'a>(&'a AtomicUsize);
+
+impl<'a> Queued<'a> {
+    fn join(waiting: &'a AtomicUsize, max_queue: usize) -> Option<Self> {
+        if waiting.fetch_add(1, Ordering::SeqCst) >= max_queue {
+            waiting.fetch_sub(1, Ordering::SeqCst);
+            return None;
+        }
+        Some(Self(waiting))
+    }
+}
+
+impl Drop for Queued<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Per-target concurrency limits of a listener
+#[derive(Debug)]
+pub struct ConcurrencyLimiter {
+    max_requests: usize,
+    max_queue: usize,
+    queue_timeout: Duration,
+    upstreams: Mutex<HashMap<String, Arc<UpstreamSlots>>>,
+}
+
+impl ConcurrencyLimiter {
+    pub fn new(config: &ConcurrencyConfig) -> Self {
+        Self {
+            max_requests: config.max_requests,
+            max_queue: config.max_queue,
+            queue_timeout: Duration::from_millis(config.queue_timeout_ms),
+            upstreams: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Wait for a free slot towards `target`, queueing behind other requests
+    pub async fn acquire(&self, target: &str) -> Result<InFlight, Rejected> {
+        let slots = self
+            .upstreams
+            .lock()
+            .unwrap()
+            .entry(target.to_string())
+            .or_insert_with(|| {
+                Arc::new(UpstreamSlots {
+                    semaphore: Arc::new(Semaphore::new(self.max_requests)),
+                    waiting: AtomicUsize::new(0),
+                })
+            })
+            .clone();
+
+        if let Ok(permit) = slots.semaphore.clone().try_acquire_owned() {
+            return Ok(InFlight { _permit: permit });
+        }
+
+        // Leaves the queue on every path, including the client going away
+        let _queued = Queued::join(&slots.waiting, self.max_queue).ok_or(Rejected::QueueFull)?;
+        tracing::debug!("Queueing request for {}", target);
+        let permit =
+            tokio::time::timeout(self.queue_timeout, slots.semaphore.clone().acquire_owned()).await;
+
+        match permit {
+            Ok(Ok(permit)) => Ok(InFlight { _permit: permit }),
+            // The semaphore is never closed
+            Ok(Err(_)) | Err(_) => Err(Rejected::Timeout),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http_body_util::BodyExt;
+
+    fn limiter(max_requests: usize, max_queue: usize) -> Arc<ConcurrencyLimiter> {
+        Arc::new(ConcurrencyLimiter::new(&ConcurrencyConfig {
+            max_requests,
+            max_queue,
+            queue_timeout_ms: 50,
+        }))
+    }
+
+    #[tokio::test]
+    async fn test_queue_full() {
+        let limiter = limiter(1, 0);
+        let _held = limiter.acquire("http://a").await.unwrap();
+
+        assert_eq!(
+            limiter.acquire("http://a").await.unwrap_err(),
+            Rejected::QueueFull
+        );
+        // Each target has its own slots
+        assert!(limiter.acquire("http://b").await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_queue_timeout() {
+        let limiter = limiter(1, 1);
+        let _held = limiter.acquire("http://a").await.unwrap();
+
+        assert_eq!(
+            limiter.acquire("http://a").await.unwrap_err(),
+            Rejected::Timeout
+        );
+    }
+
+    #[tokio::test]
+    async fn test_queued_request_runs_when_slot_frees() {
+        let limiter = limiter(1, 1);
+        let held = limiter.acquire("http://a").await.unwrap();
+
+        let waiter = tokio::spawn({
+            let limiter = limiter.clone();
+            async move { limiter.acquire("http://a").await.is_ok() }
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        // The slot is held until the response body has been read
+        let body = held.hold_until_sent(Body::from("response"));
+        body.collect().await.unwrap();
+        assert!(waiter.await.unwrap());
+    }
+}
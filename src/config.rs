@@ -1,17 +1,915 @@
-use serde::Deserialize;
+use ipnet::IpNet;
+use serde::{Deserialize, Deserializer};
+use std::collections::BTreeMap;
+use std::net::{IpAddr, Ipv4Addr};
 
 /// Single listener entry - each port maps to one target
 #[derive(Debug, Clone, Deserialize)]
 pub struct Listener {
     /// Port to listen on
     pub port: u16,
-    /// Target upstream URL (e.g., "http://app1:8080")
+    /// Target upstream URL (e.g., "http://app1:8080"). May be omitted when
+    /// `routes` cover every path.
+    #[serde(default)]
     pub target: String,
+    /// How a base path in `target` (or a route's target) is joined with
+    /// the request path
+    #[serde(default)]
+    pub path_join: PathJoin,
+    /// Path-based routes, checked before falling back to `target`
+    #[serde(default)]
+    pub routes: Vec<Route>,
+    /// Continuous HAR export of this listener's traffic
+    #[serde(default)]
+    pub har: Option<HarConfig>,
+    /// Rules applied to request headers before forwarding
+    #[serde(default)]
+    pub request_headers: HeaderRules,
+    /// Rules applied to response headers before returning them to the client
+    #[serde(default)]
+    pub response_headers: HeaderRules,
+    /// `Host` header (and TLS SNI) sent upstream. When unset the client's
+    /// `Host` is forwarded and SNI uses the target host.
+    #[serde(default)]
+    pub host_header: Option<HostHeader>,
+    /// Peers (IPs or CIDRs) whose incoming X-Forwarded-* and `Forwarded`
+    /// headers are kept; they are overwritten for everyone else
+    #[serde(default, deserialize_with = "deserialize_ip_nets")]
+    pub trusted_proxies: Vec<IpNet>,
+    /// Also send the RFC 7239 `Forwarded` header upstream
+    #[serde(default)]
+    pub forwarded_header: bool,
+    /// HAProxy PROXY protocol on the listener and/or towards the target
+    #[serde(default)]
+    pub proxy_protocol: ProxyProtocolConfig,
+    /// Compress responses for clients that accept it (disabled when absent)
+    #[serde(default)]
+    pub compression: Option<CompressionConfig>,
+    /// In-memory response cache, when enabled
+    #[serde(default)]
+    pub cache: Option<CacheConfig>,
+    /// Request rate limit for the whole listener
+    #[serde(default)]
+    pub rate_limit: Option<RateLimitConfig>,
+    /// Limit on concurrent requests to each upstream target
+    #[serde(default)]
+    pub concurrency: Option<ConcurrencyConfig>,
+    /// Stop sending to a target that keeps failing
+    #[serde(default)]
+    pub circuit_breaker: Option<CircuitBreakerConfig>,
+    /// Require credentials for every request on the listener
+    #[serde(default)]
+    pub auth: Option<AuthConfig>,
+    /// Require an OpenID Connect login for every request on the listener
+    #[serde(default)]
+    pub oidc: Option<OidcConfig>,
+    /// Ask an external auth service about every request on the listener
+    #[serde(default)]
+    pub forward_auth: Option<ForwardAuthConfig>,
+    /// Client IPs allowed on or denied from the listener
+    #[serde(default)]
+    pub ip_access: Option<IpAccessConfig>,
+    /// CORS policy for the listener, answering preflights at the proxy
+    #[serde(default)]
+    pub cors: Option<CorsConfig>,
+    /// Security headers added to every response
+    #[serde(default)]
+    pub security_headers: Option<SecurityHeadersConfig>,
+    /// Rewrite upstream URLs in redirects and cookies to the public origin
+    #[serde(default)]
+    pub response_rewrite: Option<ResponseRewriteConfig>,
+    /// Header and body size limits for the listener
+    #[serde(default)]
+    pub limits: Option<LimitsConfig>,
+    /// Give up on an upstream that hasn't answered within this long, with 504
+    #[serde(default)]
+    pub upstream_timeout_ms: Option<u64>,
+    /// Error pages and problem documents replacing the proxy's plain errors
+    #[serde(default)]
+    pub error_pages: Option<ErrorPagesConfig>,
+    /// Maintenance mode for the whole listener
+    #[serde(default)]
+    pub maintenance: Option<MaintenanceConfig>,
+}
+
+/// Maintenance mode, answering 503 to everyone but the allowlist
+#[derive(Debug, Clone, Deserialize)]
+pub struct MaintenanceConfig {
+    /// Start in maintenance; the admin API can switch it either way
+    #[serde(default)]
+    pub enabled: bool,
+    /// IPs or CIDRs that still reach the upstream
+    #[serde(default, deserialize_with = "deserialize_ip_nets")]
+    pub allow: Vec<IpNet>,
+    /// Header values that still reach the upstream (header name -> value)
+    #[serde(default)]
+    pub allow_headers: BTreeMap<String, String>,
+    /// `Retry-After` sent with the 503
+    #[serde(default = "default_maintenance_retry_after_secs")]
+    pub retry_after_secs: u64,
+    /// HTML page served with the 503
+    #[serde(default)]
+    pub page: Option<String>,
+}
+
+impl Default for MaintenanceConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            allow: Vec::new(),
+            allow_headers: BTreeMap::new(),
+            retry_after_secs: default_maintenance_retry_after_secs(),
+            page: None,
+        }
+    }
+}
+
+fn default_maintenance_retry_after_secs() -> u64 {
+    300
+}
+
+/// Error responses. Clients preferring JSON get `application/problem+json`;
+/// others get the HTML page for the status, if there is one.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ErrorPagesConfig {
+    /// HTML templates by status code (`502`) or class (`5xx`)
+    #[serde(default, deserialize_with = "deserialize_status_keys")]
+    pub pages: BTreeMap<String, String>,
+    /// Leave internal error details out of error responses
+    #[serde(default)]
+    pub hide_details: bool,
+    /// Also replace upstream 4xx and 5xx responses with the pages
+    #[serde(default)]
+    pub intercept_upstream: bool,
+}
+
+/// Status keys are written bare in YAML, so accept numbers as well as strings
+fn deserialize_status_keys<'de, D>(deserializer: D) -> Result<BTreeMap<String, String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+    #[serde(untagged)]
+    enum StatusKey {
+        Code(u16),
+        Name(String),
+    }
+
+    let pages = BTreeMap::<StatusKey, String>::deserialize(deserializer)?;
+    Ok(pages
+        .into_iter()
+        .map(|(key, path)| match key {
+            StatusKey::Code(code) => (code.to_string(), path),
+            StatusKey::Name(name) => (name, path),
+        })
+        .collect())
+}
+
+/// Size limits; each is unlimited when omitted
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct LimitsConfig {
+    /// Largest request body accepted, answered with 413 beyond it
+    #[serde(default)]
+    pub max_request_body_bytes: Option<u64>,
+    /// Largest response body passed on to the client
+    #[serde(default)]
+    pub max_response_body_bytes: Option<u64>,
+    /// Most request headers accepted, answered with 431 beyond it
+    #[serde(default)]
+    pub max_header_count: Option<usize>,
+    /// Largest total size of request header names and values
+    #[serde(default)]
+    pub max_header_bytes: Option<usize>,
+}
+
+/// Rewriting of upstream URLs in response headers
+#[derive(Debug, Clone, Deserialize)]
+pub struct ResponseRewriteConfig {
+    /// Rewrite `Location`, `Content-Location` and `Refresh`
+    #[serde(default = "default_true")]
+    pub locations: bool,
+    /// Rewrite `Set-Cookie` `Domain` and `Path` attributes
+    #[serde(default = "default_true")]
+    pub cookies: bool,
+    /// Mark every cookie `Secure`, as the proxy serves HTTPS
+    #[serde(default = "default_true")]
+    pub secure_cookies: bool,
+    /// Further URL prefixes to rewrite (upstream prefix -> public prefix)
+    #[serde(default)]
+    pub location_map: BTreeMap<String, String>,
+    /// Cookie domains to rewrite (upstream domain -> public domain)
+    #[serde(default)]
+    pub cookie_domains: BTreeMap<String, String>,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+/// Security headers policy. Each value is sent as-is; set one to `null` to
+/// leave that header out.
+#[derive(Debug, Clone, Deserialize)]
+pub struct SecurityHeadersConfig {
+    #[serde(default)]
+    pub strict_transport_security: Option<String>,
+    #[serde(default)]
+    pub content_security_policy: Option<String>,
+    #[serde(default)]
+    pub content_security_policy_report_only: Option<String>,
+    #[serde(default = "default_x_content_type_options")]
+    pub x_content_type_options: Option<String>,
+    #[serde(default = "default_referrer_policy")]
+    pub referrer_policy: Option<String>,
+    #[serde(default)]
+    pub permissions_policy: Option<String>,
+    #[serde(default = "default_x_frame_options")]
+    pub x_frame_options: Option<String>,
+    /// Keep headers the upstream already set instead of replacing them
+    #[serde(default)]
+    pub prefer_upstream: bool,
+}
+
+fn default_x_content_type_options() -> Option<String> {
+    Some("nosniff".to_string())
+}
+
+fn default_referrer_policy() -> Option<String> {
+    Some("strict-origin-when-cross-origin".to_string())
+}
+
+fn default_x_frame_options() -> Option<String> {
+    Some("SAMEORIGIN".to_string())
+}
+
+/// CORS policy settings
+#[derive(Debug, Clone, Deserialize)]
+pub struct CorsConfig {
+    /// Allowed origins: exact (`http://localhost:5173`), `*`, with
+    /// wildcards (`https://*.example.com`) or a regex prefixed with `~`
+    #[serde(default)]
+    pub allow_origins: Vec<String>,
+    #[serde(default = "default_cors_allow_methods")]
+    pub allow_methods: Vec<String>,
+    /// Request headers allowed; a preflight's are all allowed when empty
+    #[serde(default)]
+    pub allow_headers: Vec<String>,
+    /// Response headers scripts may read
+    #[serde(default)]
+    pub expose_headers: Vec<String>,
+    /// Allow cookies and other credentials
+    #[serde(default)]
+    pub allow_credentials: bool,
+    /// How long browsers may cache a preflight, in seconds
+    #[serde(default)]
+    pub max_age_secs: Option<u64>,
+}
+
+fn default_cors_allow_methods() -> Vec<String> {
+    ["GET", "HEAD", "POST", "PUT", "PATCH", "DELETE"]
+        .map(String::from)
+        .to_vec()
+}
+
+/// Client IP allow and deny lists, checked against the real client IP
+/// (see `trusted_proxies`)
+#[derive(Debug, Clone, Deserialize)]
+pub struct IpAccessConfig {
+    /// When non-empty, only these IPs or CIDRs are let in
+    #[serde(default, deserialize_with = "deserialize_ip_nets")]
+    pub allow: Vec<IpNet>,
+    /// IPs or CIDRs always refused, even when also allowed
+    #[serde(default, deserialize_with = "deserialize_ip_nets")]
+    pub deny: Vec<IpNet>,
+    /// Body of the 403 response sent to refused clients
+    #[serde(default = "default_ip_access_deny_body")]
+    pub deny_body: String,
+}
+
+fn default_ip_access_deny_body() -> String {
+    "403 Forbidden".to_string()
+}
+
+/// External auth service settings
+#[derive(Debug, Clone, Deserialize)]
+pub struct ForwardAuthConfig {
+    /// URL the auth subrequest is sent to
+    pub address: String,
+    /// Headers of a successful auth response copied onto the upstream request
+    #[serde(default)]
+    pub auth_response_headers: Vec<String>,
+    /// Request headers sent to the auth service (all when empty)
+    #[serde(default)]
+    pub auth_request_headers: Vec<String>,
+    /// How long to wait for the auth service, in milliseconds
+    #[serde(default = "default_forward_auth_timeout_ms")]
+    pub timeout_ms: u64,
+}
+
+fn default_forward_auth_timeout_ms() -> u64 {
+    5000
+}
+
+/// OpenID Connect login settings
+#[derive(Debug, Clone, Deserialize)]
+pub struct OidcConfig {
+    /// Issuer URL; endpoints are found via its discovery document
+    pub issuer: String,
+    pub client_id: String,
+    #[serde(default)]
+    pub client_secret: String,
+    /// Secret the session cookie is encrypted with
+    pub cookie_secret: String,
+    #[serde(default = "default_oidc_scopes")]
+    pub scopes: Vec<String>,
+    /// Path the provider redirects back to after login
+    #[serde(default = "default_oidc_callback_path")]
+    pub callback_path: String,
+    /// Path that clears the session
+    #[serde(default = "default_oidc_sign_out_path")]
+    pub sign_out_path: String,
+    #[serde(default = "default_oidc_cookie_name")]
+    pub cookie_name: String,
+    /// How long a login lasts, in seconds
+    #[serde(default = "default_oidc_session_ttl_secs")]
+    pub session_ttl_secs: u64,
+    /// ID token claims forwarded upstream (claim -> header)
+    #[serde(default = "default_oidc_claim_headers")]
+    pub claim_headers: BTreeMap<String, String>,
+}
+
+fn default_oidc_scopes() -> Vec<String> {
+    vec!["openid".into(), "email".into(), "profile".into()]
+}
+
+fn default_oidc_callback_path() -> String {
+    "/oauth2/callback".to_string()
+}
+
+fn default_oidc_sign_out_path() -> String {
+    "/oauth2/sign_out".to_string()
+}
+
+fn default_oidc_cookie_name() -> String {
+    "_oauth2_proxy".to_string()
+}
+
+fn default_oidc_session_ttl_secs() -> u64 {
+    8 * 60 * 60
+}
+
+fn default_oidc_claim_headers() -> BTreeMap<String, String> {
+    BTreeMap::from([
+        ("sub".to_string(), "X-Auth-Request-User".to_string()),
+        ("email".to_string(), "X-Auth-Request-Email".to_string()),
+        (
+            "preferred_username".to_string(),
+            "X-Auth-Request-Preferred-Username".to_string(),
+        ),
+    ])
+}
+
+/// Credentials a listener or route requires
+#[derive(Debug, Clone, Deserialize)]
+pub struct AuthConfig {
+    /// Realm shown in the client's login prompt
+    #[serde(default = "default_auth_realm")]
+    pub realm: String,
+    /// htpasswd file of Basic auth users (bcrypt hashes only)
+    #[serde(default)]
+    pub htpasswd: Option<String>,
+    /// Accepted bearer tokens, by the name they authenticate as
+    #[serde(default)]
+    pub bearer_tokens: BTreeMap<String, String>,
+    /// Header the authenticated user is forwarded in
+    #[serde(default = "default_auth_user_header")]
+    pub user_header: String,
+}
+
+fn default_auth_realm() -> String {
+    "https-proxy".to_string()
+}
+
+fn default_auth_user_header() -> String {
+    "X-Forwarded-User".to_string()
+}
+
+/// Circuit breaker settings, applied to each target separately
+#[derive(Debug, Clone, Deserialize)]
+pub struct CircuitBreakerConfig {
+    /// Fraction of failed requests (0.0-1.0) that opens the circuit
+    #[serde(default = "default_circuit_failure_rate")]
+    pub failure_rate: f64,
+    /// Requests needed in a window before the failure rate is trusted
+    #[serde(default = "default_circuit_min_requests")]
+    pub min_requests: u32,
+    /// Length of the window failures are counted over, in seconds
+    #[serde(default = "default_circuit_window_secs")]
+    pub window_secs: u64,
+    /// Responses slower than this count as failures
+    #[serde(default)]
+    pub slow_call_ms: Option<u64>,
+    /// How long the circuit stays open before probing, in seconds
+    #[serde(default = "default_circuit_open_secs")]
+    pub open_secs: u64,
+    /// Probe requests let through while half-open
+    #[serde(default = "default_circuit_half_open_requests")]
+    pub half_open_requests: u32,
+}
+
+impl Default for CircuitBreakerConfig {
+    fn default() -> Self {
+        Self {
+            failure_rate: default_circuit_failure_rate(),
+            min_requests: default_circuit_min_requests(),
+            window_secs: default_circuit_window_secs(),
+            slow_call_ms: None,
+            open_secs: default_circuit_open_secs(),
+            half_open_requests: default_circuit_half_open_requests(),
+        }
+    }
+}
+
+fn default_circuit_failure_rate() -> f64 {
+    0.5
+}
+
+fn default_circuit_min_requests() -> u32 {
+    10
+}
+
+fn default_circuit_window_secs() -> u64 {
+    30
+}
+
+fn default_circuit_open_secs() -> u64 {
+    30
+}
+
+fn default_circuit_half_open_requests() -> u32 {
+    1
+}
+
+/// Concurrent upstream request settings
+#[derive(Debug, Clone, Deserialize)]
+pub struct ConcurrencyConfig {
+    /// Requests in flight to one target at a time
+    pub max_requests: usize,
+    /// Requests waiting for a slot; more are rejected with 503
+    #[serde(default = "default_concurrency_max_queue")]
+    pub max_queue: usize,
+    /// How long a request may wait for a slot before getting a 503
+    #[serde(default = "default_concurrency_queue_timeout_ms")]
+    pub queue_timeout_ms: u64,
+}
+
+fn default_concurrency_max_queue() -> usize {
+    100
+}
+
+fn default_concurrency_queue_timeout_ms() -> u64 {
+    10_000
+}
+
+/// Response cache settings
+#[derive(Debug, Clone, Deserialize)]
+pub struct CacheConfig {
+    /// Number of responses kept
+    #[serde(default = "default_cache_max_entries")]
+    pub max_entries: usize,
+    /// Total body and header bytes kept
+    #[serde(default = "default_cache_max_bytes")]
+    pub max_bytes: usize,
+    /// Larger responses are passed through without being stored
+    #[serde(default = "default_cache_max_entry_bytes")]
+    pub max_entry_bytes: usize,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            max_entries: default_cache_max_entries(),
+            max_bytes: default_cache_max_bytes(),
+            max_entry_bytes: default_cache_max_entry_bytes(),
+        }
+    }
+}
+
+fn default_cache_max_entries() -> usize {
+    1000
+}
+
+fn default_cache_max_bytes() -> usize {
+    64 * 1024 * 1024
+}
+
+fn default_cache_max_entry_bytes() -> usize {
+    1024 * 1024
+}
+
+/// Response compression settings
+#[derive(Debug, Clone, Deserialize)]
+pub struct CompressionConfig {
+    /// Encodings offered, most preferred first
+    #[serde(default = "default_compression_algorithms")]
+    pub algorithms: Vec<CompressionAlgorithm>,
+    /// Responses with a smaller `Content-Length` are sent as-is
+    #[serde(default = "default_compression_min_size")]
+    pub min_size: u64,
+    /// Content types to compress; `type/*` matches a whole type
+    #[serde(default = "default_compression_content_types")]
+    pub content_types: Vec<String>,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        Self {
+            algorithms: default_compression_algorithms(),
+            min_size: default_compression_min_size(),
+            content_types: default_compression_content_types(),
+        }
+    }
+}
+
+/// Content codings the proxy can produce
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+pub enum CompressionAlgorithm {
+    #[serde(rename = "zstd")]
+    Zstd,
+    #[serde(rename = "br")]
+    Brotli,
+    #[serde(rename = "gzip")]
+    Gzip,
+}
+
+fn default_compression_algorithms() -> Vec<CompressionAlgorithm> {
+    vec![
+        CompressionAlgorithm::Zstd,
+        CompressionAlgorithm::Brotli,
+        CompressionAlgorithm::Gzip,
+    ]
+}
+
+fn default_compression_min_size() -> u64 {
+    1024
+}
+
+fn default_compression_content_types() -> Vec<String> {
+    [
+        "text/*",
+        "application/json",
+        "application/javascript",
+        "application/xml",
+        "application/xhtml+xml",
+        "application/rss+xml",
+        "application/atom+xml",
+        "application/manifest+json",
+        "application/wasm",
+        "image/svg+xml",
+    ]
+    .into_iter()
+    .map(String::from)
+    .collect()
+}
+
+/// PROXY protocol settings
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ProxyProtocolConfig {
+    /// Require a PROXY v1/v2 header on every incoming connection
+    #[serde(default)]
+    pub accept: bool,
+    /// Send a PROXY header of this version on upstream connections
+    #[serde(default)]
+    pub send: Option<ProxyProtocolVersion>,
+}
+
+/// How a target's base path and the request path are joined
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PathJoin {
+    /// One slash between them: `/base/` and `/users` give `/base/users`,
+    /// and `/` gives `/base/`
+    #[default]
+    Merge,
+    /// Like `merge`, but `/` gives the base path itself, `/base`
+    NoTrailingSlash,
+    /// Plain concatenation: `/base/` and `/users` give `/base//users`
+    Concat,
+}
+
+/// PROXY protocol header format
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ProxyProtocolVersion {
+    V1,
+    V2,
+}
+
+/// Parse a list of CIDRs, accepting bare addresses as single-host networks
+fn deserialize_ip_nets<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<IpNet>, D::Error> {
+    Vec::<String>::deserialize(deserializer)?
+        .iter()
+        .map(|value| {
+            value
+                .parse::<IpNet>()
+                .or_else(|_| value.parse::<IpAddr>().map(IpNet::from))
+                .map_err(|_| serde::de::Error::custom(format!("invalid CIDR {:?}", value)))
+        })
+        .collect()
+}
+
+/// How the upstream `Host` header is chosen
+#[derive(Debug, Clone, PartialEq)]
+pub enum HostHeader {
+    /// Forward the client's `Host` header
+    Preserve,
+    /// Use the target's host (and port)
+    Upstream,
+    /// Send a fixed value
+    Custom(String),
+}
+
+impl<'de> Deserialize<'de> for HostHeader {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = String::deserialize(deserializer)?;
+        Ok(match value.as_str() {
+            "preserve" => HostHeader::Preserve,
+            "upstream" => HostHeader::Upstream,
+            _ => HostHeader::Custom(value),
+        })
+    }
+}
+
+/// Request rate limit settings
+#[derive(Debug, Clone, Deserialize)]
+pub struct RateLimitConfig {
+    #[serde(default)]
+    pub algorithm: RateLimitAlgorithm,
+    /// Requests allowed per window
+    pub limit: u32,
+    /// Window length in seconds
+    #[serde(default = "default_rate_limit_window_secs")]
+    pub window_secs: u64,
+    /// Token bucket capacity, i.e. the largest burst (defaults to `limit`)
+    #[serde(default)]
+    pub burst: Option<u32>,
+    /// What requests are counted by
+    #[serde(default)]
+    pub key: RateLimitKey,
+}
+
+fn default_rate_limit_window_secs() -> u64 {
+    1
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RateLimitAlgorithm {
+    /// Refills `limit` tokens per window, allowing bursts up to `burst`
+    #[default]
+    TokenBucket,
+    /// At most `limit` requests in any window, weighted across the boundary
+    SlidingWindow,
+}
+
+/// What a rate limit is counted by
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum RateLimitKey {
+    /// The client IP
+    #[default]
+    Ip,
+    /// The value of a request header (`header:<name>`)
+    Header(String),
+    /// The `X-Api-Key` header or `Authorization` bearer token
+    ApiKey,
+}
+
+impl<'de> Deserialize<'de> for RateLimitKey {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = String::deserialize(deserializer)?;
+        match value.as_str() {
+            "ip" => Ok(RateLimitKey::Ip),
+            "api_key" => Ok(RateLimitKey::ApiKey),
+            _ => match value.strip_prefix("header:") {
+                Some(name) if !name.trim().is_empty() => {
+                    Ok(RateLimitKey::Header(name.trim().to_ascii_lowercase()))
+                }
+                _ => Err(serde::de::Error::custom(format!(
+                    "invalid rate limit key {:?}, expected ip, api_key or header:<name>",
+                    value
+                ))),
+            },
+        }
+    }
+}
+
+/// A path prefix on a listener, either proxied or served from disk
+#[derive(Debug, Clone, Deserialize)]
+pub struct Route {
+    /// Path prefix, matched on segment boundaries (`/app` matches `/app/x`
+    /// but not `/apple`)
+    pub path: String,
+    /// Upstream URL for this prefix
+    #[serde(default)]
+    pub target: Option<String>,
+    /// Directory served for this prefix, with the prefix stripped
+    #[serde(default, rename = "static")]
+    pub static_dir: Option<String>,
+    /// File served for directory requests
+    #[serde(default = "default_index")]
+    pub index: String,
+    /// Serve the index for paths that match no file (single-page apps)
+    #[serde(default)]
+    pub spa: bool,
+    /// Request rate limit for this route, checked before the listener's
+    #[serde(default)]
+    pub rate_limit: Option<RateLimitConfig>,
+    /// Credentials for this route, replacing the listener's
+    #[serde(default)]
+    pub auth: Option<AuthConfig>,
+    /// OpenID Connect login for this route, replacing the listener's
+    /// authentication
+    #[serde(default)]
+    pub oidc: Option<OidcConfig>,
+    /// External auth service for this route, replacing the listener's
+    /// authentication
+    #[serde(default)]
+    pub forward_auth: Option<ForwardAuthConfig>,
+    /// Client IPs allowed on or denied from this route, checked as well as
+    /// the listener's
+    #[serde(default)]
+    pub ip_access: Option<IpAccessConfig>,
+    /// CORS policy for this route, replacing the listener's
+    #[serde(default)]
+    pub cors: Option<CorsConfig>,
+    /// URL rewrite rules, evaluated in order before forwarding
+    #[serde(default)]
+    pub rewrite: Vec<RewriteRule>,
+    /// Substitutions in response bodies from this route's target
+    #[serde(default)]
+    pub body_filter: Option<BodyFilterConfig>,
+    /// Rules applied to request headers after the listener's
+    #[serde(default)]
+    pub request_headers: HeaderRules,
+    /// Rules applied to response headers after the listener's
+    #[serde(default)]
+    pub response_headers: HeaderRules,
+    /// Header and body size limits for this route, replacing the listener's
+    #[serde(default)]
+    pub limits: Option<LimitsConfig>,
+    /// Maintenance mode for this route
+    #[serde(default)]
+    pub maintenance: Option<MaintenanceConfig>,
+    /// `Host` header (and TLS SNI) sent to this route's target, replacing
+    /// the listener's
+    #[serde(default)]
+    pub host_header: Option<HostHeader>,
+    /// Response compression for this route, replacing the listener's
+    #[serde(default)]
+    pub compression: Option<CompressionConfig>,
+    /// Response cache for this route, used instead of the listener's
+    #[serde(default)]
+    pub cache: Option<CacheConfig>,
+}
+
+/// Substitutions made in streamed response bodies
+#[derive(Debug, Clone, Deserialize)]
+pub struct BodyFilterConfig {
+    /// Substitutions, applied in order
+    pub substitutions: Vec<Substitution>,
+    /// Content types filtered (`type/*` matches a whole top-level type)
+    #[serde(default = "default_body_filter_content_types")]
+    pub content_types: Vec<String>,
+    /// Longest text a regex is expected to match; matches split across more
+    /// than this many bytes of streamed chunks may be missed
+    #[serde(default = "default_max_match_len")]
+    pub max_match_len: usize,
+}
+
+/// One body substitution: a literal `find` or a `regex`, whose captures
+/// `replace` may use as `$1` or `${name}`
+#[derive(Debug, Clone, Deserialize)]
+pub struct Substitution {
+    #[serde(default)]
+    pub find: Option<String>,
+    #[serde(default)]
+    pub regex: Option<String>,
+    pub replace: String,
+}
+
+fn default_body_filter_content_types() -> Vec<String> {
+    vec!["text/html".to_string()]
+}
+
+fn default_max_match_len() -> usize {
+    1024
+}
+
+fn default_index() -> String {
+    "index.html".to_string()
+}
+
+/// A URL rewrite rule. `replace`, `redirect` and `add_query` values may use
+/// the regex's captures as `$1` or `${name}`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct RewriteRule {
+    /// Regex matched against the path; the rule applies to every path when
+    /// omitted
+    #[serde(default, rename = "match")]
+    pub pattern: Option<String>,
+    /// New path for the request
+    #[serde(default)]
+    pub replace: Option<String>,
+    /// Path or URL to redirect the client to instead of forwarding
+    #[serde(default)]
+    pub redirect: Option<String>,
+    /// Redirect status: 301, 302, 303, 307 or 308
+    #[serde(default = "default_redirect_status")]
+    pub status: u16,
+    /// Query parameters to set, replacing existing ones with the same name
+    #[serde(default)]
+    pub add_query: BTreeMap<String, String>,
+    /// Query parameters to drop
+    #[serde(default)]
+    pub remove_query: Vec<String>,
+    /// Stop evaluating rules when this one matches
+    #[serde(default)]
+    pub last: bool,
+}
+
+fn default_redirect_status() -> u16 {
+    302
+}
+
+/// Header manipulation rules. Values may use `${client_ip}`, `${host}`,
+/// `${request_id}`, `${method}`, `${path}` and `${port}`.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct HeaderRules {
+    /// Headers to set, replacing existing values
+    #[serde(default)]
+    pub set: BTreeMap<String, String>,
+    /// Headers to add alongside existing values
+    #[serde(default)]
+    pub append: BTreeMap<String, String>,
+    /// Headers to drop
+    #[serde(default)]
+    pub remove: Vec<String>,
+    /// Headers to rename (old name -> new name)
+    #[serde(default)]
+    pub rename: BTreeMap<String, String>,
+}
+
+/// HAR file export settings
+#[derive(Debug, Clone, Deserialize)]
+pub struct HarConfig {
+    /// File the HAR document is written to (truncated on startup)
+    pub path: String,
+    /// Request/response body bytes kept per entry
+    #[serde(default = "default_capture_max_body_bytes")]
+    pub max_body_bytes: usize,
+}
+
+/// Admin API settings
+#[derive(Debug, Clone, Deserialize)]
+pub struct AdminConfig {
+    /// Port for the plain-HTTP admin API
+    pub port: u16,
+    /// Address to bind the admin API to (defaults to loopback only)
+    #[serde(default = "default_admin_bind")]
+    pub bind: IpAddr,
+}
+
+fn default_admin_bind() -> IpAddr {
+    IpAddr::V4(Ipv4Addr::LOCALHOST)
+}
+
+/// Request history settings
+#[derive(Debug, Clone, Deserialize)]
+pub struct CaptureConfig {
+    /// Number of requests kept in memory
+    #[serde(default = "default_capture_max_entries")]
+    pub max_entries: usize,
+    /// Request body bytes kept per request
+    #[serde(default = "default_capture_max_body_bytes")]
+    pub max_body_bytes: usize,
+}
+
+fn default_capture_max_entries() -> usize {
+    500
+}
+
+fn default_capture_max_body_bytes() -> usize {
+    64 * 1024
 }
 
 /// Listeners configuration
 #[derive(Debug, Clone, Deserialize)]
 pub struct Config {
+    /// Admin API (disabled when absent)
+    #[serde(default)]
+    pub admin: Option<AdminConfig>,
+    /// Request history capture (disabled when absent)
+    #[serde(default)]
+    pub capture: Option<CaptureConfig>,
     pub listeners: Vec<Listener>,
 }
 
@@ -20,10 +918,163 @@ impl Config {
     pub fn load(path: &str) -> anyhow::Result<Self> {
         let content = std::fs::read_to_string(path)?;
         let config: Config = serde_yaml::from_str(&content)?;
+        for listener in &config.listeners {
+            listener.validate()?;
+        }
         Ok(config)
     }
 }
 
+impl Listener {
+    /// Check that every request has somewhere to go
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.target.is_empty() && self.routes.is_empty() {
+            anyhow::bail!("listener :{} needs a target or routes", self.port);
+        }
+        let rate_limits = self.rate_limit.iter().chain(
+            self.routes
+                .iter()
+                .filter_map(|route| route.rate_limit.as_ref()),
+        );
+        for rate_limit in rate_limits {
+            if rate_limit.limit == 0 || rate_limit.window_secs == 0 || rate_limit.burst == Some(0) {
+                anyhow::bail!(
+                    "listener :{} rate limits need a non-zero limit, window_secs and burst",
+                    self.port
+                );
+            }
+        }
+        if self
+            .concurrency
+            .as_ref()
+            .is_some_and(|concurrency| concurrency.max_requests == 0)
+        {
+            anyhow::bail!(
+                "listener :{} concurrency.max_requests must be at least 1",
+                self.port
+            );
+        }
+        if let Some(circuit_breaker) = &self.circuit_breaker {
+            if !(circuit_breaker.failure_rate > 0.0 && circuit_breaker.failure_rate <= 1.0) {
+                anyhow::bail!(
+                    "listener :{} circuit_breaker.failure_rate must be in (0, 1]",
+                    self.port
+                );
+            }
+        }
+        let auths = self
+            .auth
+            .iter()
+            .chain(self.routes.iter().filter_map(|route| route.auth.as_ref()));
+        for auth in auths {
+            if auth.htpasswd.is_none() && auth.bearer_tokens.is_empty() {
+                anyhow::bail!(
+                    "listener :{} auth needs an htpasswd file or bearer_tokens",
+                    self.port
+                );
+            }
+        }
+        let ip_access = self.ip_access.iter().chain(
+            self.routes
+                .iter()
+                .filter_map(|route| route.ip_access.as_ref()),
+        );
+        for ip_access in ip_access {
+            if ip_access.allow.is_empty() && ip_access.deny.is_empty() {
+                anyhow::bail!("listener :{} ip_access needs allow or deny", self.port);
+            }
+        }
+        let cors = self
+            .cors
+            .iter()
+            .chain(self.routes.iter().filter_map(|route| route.cors.as_ref()));
+        for cors in cors {
+            if cors.allow_origins.is_empty() {
+                anyhow::bail!("listener :{} cors needs allow_origins", self.port);
+            }
+        }
+        let scopes = std::iter::once((&self.auth, &self.oidc, &self.forward_auth)).chain(
+            self.routes
+                .iter()
+                .map(|route| (&route.auth, &route.oidc, &route.forward_auth)),
+        );
+        for (auth, oidc, forward_auth) in scopes {
+            let methods = [auth.is_some(), oidc.is_some(), forward_auth.is_some()];
+            if methods.into_iter().filter(|set| *set).count() > 1 {
+                anyhow::bail!(
+                    "listener :{} can use only one of auth, oidc and forward_auth on the listener or a route",
+                    self.port
+                );
+            }
+            let Some(oidc) = oidc else { continue };
+            if oidc.cookie_secret.len() < 16 {
+                anyhow::bail!(
+                    "listener :{} oidc.cookie_secret must be at least 16 characters",
+                    self.port
+                );
+            }
+            if !oidc.callback_path.starts_with('/') || !oidc.sign_out_path.starts_with('/') {
+                anyhow::bail!(
+                    "listener :{} oidc callback and sign-out paths must start with '/'",
+                    self.port
+                );
+            }
+        }
+        for route in &self.routes {
+            if !route.path.starts_with('/') {
+                anyhow::bail!(
+                    "listener :{} route {:?} must start with '/'",
+                    self.port,
+                    route.path
+                );
+            }
+            if route.target.is_some() == route.static_dir.is_some() {
+                anyhow::bail!(
+                    "listener :{} route {} needs exactly one of target or static",
+                    self.port,
+                    route.path
+                );
+            }
+            let substitutions = route
+                .body_filter
+                .iter()
+                .flat_map(|filter| &filter.substitutions);
+            for substitution in substitutions {
+                let valid = match (&substitution.find, &substitution.regex) {
+                    (Some(find), None) => !find.is_empty(),
+                    (None, Some(regex)) => !regex.is_empty(),
+                    _ => false,
+                };
+                if !valid {
+                    anyhow::bail!(
+                        "listener :{} route {} body_filter substitutions need one of find or regex",
+                        self.port,
+                        route.path
+                    );
+                }
+            }
+            for rule in &route.rewrite {
+                if rule.replace.is_some() && rule.redirect.is_some() {
+                    anyhow::bail!(
+                        "listener :{} route {} rewrite can't both replace and redirect",
+                        self.port,
+                        route.path
+                    );
+                }
+                if ![301, 302, 303, 307, 308].contains(&rule.status) {
+                    anyhow::bail!(
+                        "listener :{} route {} redirect status {} is not a redirect",
+                        self.port,
+                        route.path,
+                        rule.status
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -47,16 +1098,815 @@ listeners:
         assert_eq!(config.listeners[0].port, 440);
         assert_eq!(config.listeners[0].target, "http://api:3000");
         assert_eq!(config.listeners[1].port, 441);
+        assert!(config.listeners[0].har.is_none());
+        assert!(config.listeners[0].host_header.is_none());
+        assert!(config.listeners[0].trusted_proxies.is_empty());
+        assert!(!config.listeners[0].forwarded_header);
+        assert_eq!(config.listeners[0].path_join, PathJoin::Merge);
     }
 
     #[test]
-    fn test_load_empty_listeners() {
-        let yaml = "listeners: []";
+    fn test_load_path_join() {
+        let yaml = r#"
+listeners:
+  - port: 440
+    target: http://api:3000/service-a
+    path_join: no_trailing_slash
+"#;
         let mut file = NamedTempFile::new().unwrap();
         file.write_all(yaml.as_bytes()).unwrap();
 
         let config = Config::load(file.path().to_str().unwrap()).unwrap();
-        assert!(config.listeners.is_empty());
+        assert_eq!(config.listeners[0].path_join, PathJoin::NoTrailingSlash);
+
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(yaml.replace("no_trailing_slash", "squash").as_bytes())
+            .unwrap();
+        assert!(Config::load(file.path().to_str().unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_load_trusted_proxies() {
+        let yaml = r#"
+listeners:
+  - port: 440
+    target: http://api:3000
+    trusted_proxies: [10.0.0.0/8, "192.168.1.10", "fd00::/8"]
+    forwarded_header: true
+"#;
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(yaml.as_bytes()).unwrap();
+
+        let config = Config::load(file.path().to_str().unwrap()).unwrap();
+        let listener = &config.listeners[0];
+        let nets: Vec<String> = listener
+            .trusted_proxies
+            .iter()
+            .map(|net| net.to_string())
+            .collect();
+        assert_eq!(nets, vec!["10.0.0.0/8", "192.168.1.10/32", "fd00::/8"]);
+        assert!(listener.forwarded_header);
+    }
+
+    #[test]
+    fn test_load_proxy_protocol() {
+        let yaml = r#"
+listeners:
+  - port: 440
+    target: http://api:3000
+    proxy_protocol:
+      accept: true
+      send: v2
+  - port: 441
+    target: http://app:3001
+"#;
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(yaml.as_bytes()).unwrap();
+
+        let config = Config::load(file.path().to_str().unwrap()).unwrap();
+        let proxy_protocol = &config.listeners[0].proxy_protocol;
+        assert!(proxy_protocol.accept);
+        assert_eq!(proxy_protocol.send, Some(ProxyProtocolVersion::V2));
+        assert!(!config.listeners[1].proxy_protocol.accept);
+        assert!(config.listeners[1].proxy_protocol.send.is_none());
+    }
+
+    #[test]
+    fn test_load_compression() {
+        let yaml = r#"
+listeners:
+  - port: 440
+    target: http://api:3000
+    compression:
+      algorithms: [br, gzip]
+      min_size: 256
+  - port: 441
+    target: http://app:3001
+    compression: {}
+    routes:
+      - path: /assets
+        static: ./dist
+        compression:
+          algorithms: [gzip]
+"#;
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(yaml.as_bytes()).unwrap();
+
+        let config = Config::load(file.path().to_str().unwrap()).unwrap();
+        let compression = config.listeners[0].compression.as_ref().unwrap();
+        assert_eq!(
+            compression.algorithms,
+            vec![CompressionAlgorithm::Brotli, CompressionAlgorithm::Gzip]
+        );
+        assert_eq!(compression.min_size, 256);
+        assert!(compression.content_types.contains(&"text/*".to_string()));
+
+        let defaults = config.listeners[1].compression.as_ref().unwrap();
+        assert_eq!(defaults.algorithms.len(), 3);
+        assert_eq!(defaults.min_size, 1024);
+
+        let route = config.listeners[1].routes[0].compression.as_ref().unwrap();
+        assert_eq!(route.algorithms, vec![CompressionAlgorithm::Gzip]);
+    }
+
+    #[test]
+    fn test_load_cache() {
+        let yaml = r#"
+listeners:
+  - port: 442
+    target: https://httpbin.org
+    cache:
+      max_entries: 50
+  - port: 443
+    target: http://app:3001
+    routes:
+      - path: /assets
+        target: http://cdn:8080
+        cache:
+          max_entries: 500
+      - path: /api
+        target: http://api:3000
+"#;
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(yaml.as_bytes()).unwrap();
+
+        let config = Config::load(file.path().to_str().unwrap()).unwrap();
+        let cache = config.listeners[0].cache.as_ref().unwrap();
+        assert_eq!(cache.max_entries, 50);
+        assert_eq!(cache.max_entry_bytes, 1024 * 1024);
+        assert!(config.listeners[1].cache.is_none());
+
+        let routes = &config.listeners[1].routes;
+        assert_eq!(routes[0].cache.as_ref().unwrap().max_entries, 500);
+        assert!(routes[1].cache.is_none());
+    }
+
+    #[test]
+    fn test_load_rate_limit() {
+        let yaml = r#"
+listeners:
+  - port: 440
+    target: http://api:3000
+    rate_limit:
+      limit: 100
+      window_secs: 60
+      key: header:X-Client-Id
+    routes:
+      - path: /login
+        target: http://api:3000
+        rate_limit:
+          algorithm: sliding_window
+          limit: 5
+          key: api_key
+"#;
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(yaml.as_bytes()).unwrap();
+
+        let config = Config::load(file.path().to_str().unwrap()).unwrap();
+        let listener = &config.listeners[0];
+        let rate_limit = listener.rate_limit.as_ref().unwrap();
+        assert_eq!(rate_limit.algorithm, RateLimitAlgorithm::TokenBucket);
+        assert_eq!(rate_limit.limit, 100);
+        assert_eq!(rate_limit.window_secs, 60);
+        assert_eq!(rate_limit.key, RateLimitKey::Header("x-client-id".into()));
+
+        let route_limit = listener.routes[0].rate_limit.as_ref().unwrap();
+        assert_eq!(route_limit.algorithm, RateLimitAlgorithm::SlidingWindow);
+        assert_eq!(route_limit.window_secs, 1);
+        assert_eq!(route_limit.key, RateLimitKey::ApiKey);
+
+        let invalid = "listeners:\n  - port: 440\n    target: http://a\n    rate_limit:\n      limit: 1\n      key: cookie\n";
+        assert!(serde_yaml::from_str::<Config>(invalid).is_err());
+    }
+
+    #[test]
+    fn test_load_concurrency() {
+        let yaml = r#"
+listeners:
+  - port: 440
+    target: http://api:3000
+    concurrency:
+      max_requests: 4
+      queue_timeout_ms: 500
+"#;
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(yaml.as_bytes()).unwrap();
+
+        let config = Config::load(file.path().to_str().unwrap()).unwrap();
+        let concurrency = config.listeners[0].concurrency.as_ref().unwrap();
+        assert_eq!(concurrency.max_requests, 4);
+        assert_eq!(concurrency.max_queue, 100);
+        assert_eq!(concurrency.queue_timeout_ms, 500);
+
+        let zero = "listeners:\n  - port: 440\n    target: http://a\n    concurrency:\n      max_requests: 0\n";
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(zero.as_bytes()).unwrap();
+        assert!(Config::load(file.path().to_str().unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_load_circuit_breaker() {
+        let yaml = r#"
+listeners:
+  - port: 442
+    target: https://httpbin.org
+    circuit_breaker:
+      failure_rate: 0.25
+      slow_call_ms: 2000
+"#;
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(yaml.as_bytes()).unwrap();
+
+        let config = Config::load(file.path().to_str().unwrap()).unwrap();
+        let breaker = config.listeners[0].circuit_breaker.as_ref().unwrap();
+        assert_eq!(breaker.failure_rate, 0.25);
+        assert_eq!(breaker.slow_call_ms, Some(2000));
+        assert_eq!(breaker.min_requests, 10);
+        assert_eq!(breaker.open_secs, 30);
+
+        let invalid = yaml.replace("0.25", "1.5");
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(invalid.as_bytes()).unwrap();
+        assert!(Config::load(file.path().to_str().unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_load_auth() {
+        let yaml = r#"
+listeners:
+  - port: 441
+    target: http://app:3001
+    auth:
+      htpasswd: /etc/proxy/htpasswd
+    routes:
+      - path: /hooks
+        target: http://app:3001
+        auth:
+          realm: hooks
+          bearer_tokens:
+            github: secret
+          user_header: X-Hook-Source
+"#;
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(yaml.as_bytes()).unwrap();
+
+        let config = Config::load(file.path().to_str().unwrap()).unwrap();
+        let listener = &config.listeners[0];
+        let auth = listener.auth.as_ref().unwrap();
+        assert_eq!(auth.htpasswd.as_deref(), Some("/etc/proxy/htpasswd"));
+        assert_eq!(auth.realm, "https-proxy");
+        assert_eq!(auth.user_header, "X-Forwarded-User");
+
+        let route_auth = listener.routes[0].auth.as_ref().unwrap();
+        assert_eq!(route_auth.bearer_tokens["github"], "secret");
+        assert_eq!(route_auth.user_header, "X-Hook-Source");
+
+        let empty = "listeners:\n  - port: 441\n    target: http://a\n    auth: {}\n";
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(empty.as_bytes()).unwrap();
+        assert!(Config::load(file.path().to_str().unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_load_oidc() {
+        let yaml = r#"
+listeners:
+  - port: 441
+    target: http://app:3001
+    routes:
+      - path: /admin
+        target: http://app:3001
+        oidc:
+          issuer: http://keycloak:8080/realms/dev
+          client_id: proxy
+          client_secret: secret
+          cookie_secret: 0123456789abcdef
+          claim_headers:
+            email: X-Email
+"#;
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(yaml.as_bytes()).unwrap();
+
+        let config = Config::load(file.path().to_str().unwrap()).unwrap();
+        let oidc = config.listeners[0].routes[0].oidc.as_ref().unwrap();
+        assert_eq!(oidc.scopes, ["openid", "email", "profile"]);
+        assert_eq!(oidc.callback_path, "/oauth2/callback");
+        assert_eq!(oidc.cookie_name, "_oauth2_proxy");
+        assert_eq!(oidc.session_ttl_secs, 8 * 60 * 60);
+        assert_eq!(oidc.claim_headers.len(), 1);
+
+        let weak = yaml.replace("0123456789abcdef", "short");
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(weak.as_bytes()).unwrap();
+        assert!(Config::load(file.path().to_str().unwrap()).is_err());
+
+        let both = yaml.replace(
+            "        oidc:",
+            "        auth:\n          bearer_tokens:\n            ci: token\n        oidc:",
+        );
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(both.as_bytes()).unwrap();
+        assert!(Config::load(file.path().to_str().unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_load_forward_auth() {
+        let yaml = r#"
+listeners:
+  - port: 441
+    target: http://app:3001
+    forward_auth:
+      address: http://authelia:9091/api/verify
+      auth_response_headers: [Remote-User, Remote-Groups]
+"#;
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(yaml.as_bytes()).unwrap();
+
+        let config = Config::load(file.path().to_str().unwrap()).unwrap();
+        let forward_auth = config.listeners[0].forward_auth.as_ref().unwrap();
+        assert_eq!(forward_auth.address, "http://authelia:9091/api/verify");
+        assert_eq!(
+            forward_auth.auth_response_headers,
+            ["Remote-User", "Remote-Groups"]
+        );
+        assert!(forward_auth.auth_request_headers.is_empty());
+        assert_eq!(forward_auth.timeout_ms, 5000);
+
+        let both = format!(
+            "{}    auth:\n      bearer_tokens:\n        ci: token\n",
+            yaml
+        );
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(both.as_bytes()).unwrap();
+        assert!(Config::load(file.path().to_str().unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_load_ip_access() {
+        let yaml = r#"
+listeners:
+  - port: 440
+    target: http://api:3000
+    ip_access:
+      deny: [203.0.113.7]
+    routes:
+      - path: /admin
+        target: http://api:3000
+        ip_access:
+          allow: [10.0.0.0/8, "fd00::/8"]
+          deny_body: Admin is internal only
+"#;
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(yaml.as_bytes()).unwrap();
+
+        let config = Config::load(file.path().to_str().unwrap()).unwrap();
+        let listener = &config.listeners[0];
+        let ip_access = listener.ip_access.as_ref().unwrap();
+        assert_eq!(ip_access.deny, ["203.0.113.7/32".parse::<IpNet>().unwrap()]);
+        assert_eq!(ip_access.deny_body, "403 Forbidden");
+        let route_access = listener.routes[0].ip_access.as_ref().unwrap();
+        assert_eq!(route_access.allow.len(), 2);
+        assert_eq!(route_access.deny_body, "Admin is internal only");
+
+        let empty = "listeners:\n  - port: 440\n    target: http://a\n    ip_access: {}\n";
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(empty.as_bytes()).unwrap();
+        assert!(Config::load(file.path().to_str().unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_load_cors() {
+        let yaml = r#"
+listeners:
+  - port: 440
+    target: http://api:3000
+    cors:
+      allow_origins: ["http://localhost:5173", "https://*.example.com"]
+      allow_credentials: true
+      max_age_secs: 600
+"#;
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(yaml.as_bytes()).unwrap();
+
+        let config = Config::load(file.path().to_str().unwrap()).unwrap();
+        let cors = config.listeners[0].cors.as_ref().unwrap();
+        assert_eq!(cors.allow_origins.len(), 2);
+        assert_eq!(
+            cors.allow_methods,
+            ["GET", "HEAD", "POST", "PUT", "PATCH", "DELETE"]
+        );
+        assert!(cors.allow_headers.is_empty());
+        assert!(cors.allow_credentials);
+        assert_eq!(cors.max_age_secs, Some(600));
+
+        let empty = "listeners:\n  - port: 440\n    target: http://a\n    cors: {}\n";
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(empty.as_bytes()).unwrap();
+        assert!(Config::load(file.path().to_str().unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_load_security_headers() {
+        let yaml = r#"
+listeners:
+  - port: 441
+    target: http://app:3001
+    security_headers:
+      strict_transport_security: max-age=63072000; includeSubDomains; preload
+      content_security_policy: "default-src 'self'"
+      x_frame_options: ~
+"#;
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(yaml.as_bytes()).unwrap();
+
+        let config = Config::load(file.path().to_str().unwrap()).unwrap();
+        let headers = config.listeners[0].security_headers.as_ref().unwrap();
+        assert_eq!(
+            headers.strict_transport_security.as_deref(),
+            Some("max-age=63072000; includeSubDomains; preload")
+        );
+        assert_eq!(
+            headers.content_security_policy.as_deref(),
+            Some("default-src 'self'")
+        );
+        assert_eq!(headers.x_content_type_options.as_deref(), Some("nosniff"));
+        assert!(headers.x_frame_options.is_none());
+        assert!(!headers.prefer_upstream);
+    }
+
+    #[test]
+    fn test_load_response_rewrite() {
+        let yaml = r#"
+listeners:
+  - port: 441
+    target: http://app:3001
+    response_rewrite:
+      secure_cookies: false
+      cookie_domains:
+        app.internal: localhost
+"#;
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(yaml.as_bytes()).unwrap();
+
+        let config = Config::load(file.path().to_str().unwrap()).unwrap();
+        let rewrite = config.listeners[0].response_rewrite.as_ref().unwrap();
+        assert!(rewrite.locations);
+        assert!(rewrite.cookies);
+        assert!(!rewrite.secure_cookies);
+        assert!(rewrite.location_map.is_empty());
+        assert_eq!(rewrite.cookie_domains["app.internal"], "localhost");
+    }
+
+    #[test]
+    fn test_load_limits() {
+        let yaml = r#"
+listeners:
+  - port: 441
+    target: http://app:3001
+    limits:
+      max_request_body_bytes: 1048576
+      max_header_count: 100
+    routes:
+      - path: /upload
+        target: http://app:3001
+        limits:
+          max_request_body_bytes: 104857600
+"#;
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(yaml.as_bytes()).unwrap();
+
+        let config = Config::load(file.path().to_str().unwrap()).unwrap();
+        let limits = config.listeners[0].limits.as_ref().unwrap();
+        assert_eq!(limits.max_request_body_bytes, Some(1048576));
+        assert_eq!(limits.max_header_count, Some(100));
+        assert!(limits.max_response_body_bytes.is_none());
+        assert!(limits.max_header_bytes.is_none());
+        let route_limits = config.listeners[0].routes[0].limits.as_ref().unwrap();
+        assert_eq!(route_limits.max_request_body_bytes, Some(104857600));
+    }
+
+    #[test]
+    fn test_load_error_pages() {
+        let yaml = r#"
+listeners:
+  - port: 441
+    target: http://app:3001
+    upstream_timeout_ms: 30000
+    error_pages:
+      hide_details: true
+      pages:
+        502: ./errors/502.html
+        5xx: ./errors/5xx.html
+"#;
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(yaml.as_bytes()).unwrap();
+
+        let config = Config::load(file.path().to_str().unwrap()).unwrap();
+        assert_eq!(config.listeners[0].upstream_timeout_ms, Some(30000));
+        let error_pages = config.listeners[0].error_pages.as_ref().unwrap();
+        assert!(error_pages.hide_details);
+        assert!(!error_pages.intercept_upstream);
+        assert_eq!(error_pages.pages["502"], "./errors/502.html");
+        assert_eq!(error_pages.pages["5xx"], "./errors/5xx.html");
+    }
+
+    #[test]
+    fn test_load_maintenance() {
+        let yaml = r#"
+listeners:
+  - port: 441
+    target: http://app:3001
+    routes:
+      - path: /api
+        target: http://api:3000
+        maintenance:
+          enabled: true
+          allow: [10.0.0.0/8]
+          allow_headers:
+            X-Maintenance-Bypass: s3cret
+"#;
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(yaml.as_bytes()).unwrap();
+
+        let config = Config::load(file.path().to_str().unwrap()).unwrap();
+        assert!(config.listeners[0].maintenance.is_none());
+        let maintenance = config.listeners[0].routes[0].maintenance.as_ref().unwrap();
+        assert!(maintenance.enabled);
+        assert_eq!(
+            maintenance.allow,
+            vec!["10.0.0.0/8".parse::<IpNet>().unwrap()]
+        );
+        assert_eq!(maintenance.allow_headers["X-Maintenance-Bypass"], "s3cret");
+        assert_eq!(maintenance.retry_after_secs, 300);
+        assert!(maintenance.page.is_none());
+    }
+
+    #[test]
+    fn test_load_routes() {
+        let yaml = r#"
+listeners:
+  - port: 441
+    routes:
+      - path: /api
+        target: http://api:3000
+      - path: /
+        static: ./dist
+        spa: true
+"#;
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(yaml.as_bytes()).unwrap();
+
+        let config = Config::load(file.path().to_str().unwrap()).unwrap();
+        let listener = &config.listeners[0];
+        assert!(listener.target.is_empty());
+        assert_eq!(listener.routes.len(), 2);
+        assert_eq!(
+            listener.routes[0].target.as_deref(),
+            Some("http://api:3000")
+        );
+        assert_eq!(listener.routes[1].static_dir.as_deref(), Some("./dist"));
+        assert_eq!(listener.routes[1].index, "index.html");
+        assert!(listener.routes[1].spa);
+    }
+
+    #[test]
+    fn test_load_rewrite_rules() {
+        let yaml = r#"
+listeners:
+  - port: 441
+    routes:
+      - path: /
+        target: http://api:3000
+        rewrite:
+          - match: ^/v1/(.*)$
+            replace: /api/v1/$1
+            remove_query: [debug]
+          - match: ^/old/(.*)$
+            redirect: /new/$1
+            status: 308
+"#;
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(yaml.as_bytes()).unwrap();
+
+        let config = Config::load(file.path().to_str().unwrap()).unwrap();
+        let rules = &config.listeners[0].routes[0].rewrite;
+        assert_eq!(rules.len(), 2);
+        assert_eq!(rules[0].pattern.as_deref(), Some("^/v1/(.*)$"));
+        assert_eq!(rules[0].replace.as_deref(), Some("/api/v1/$1"));
+        assert_eq!(rules[0].remove_query, ["debug"]);
+        assert_eq!(rules[0].status, 302);
+        assert!(!rules[0].last);
+        assert_eq!(rules[1].redirect.as_deref(), Some("/new/$1"));
+        assert_eq!(rules[1].status, 308);
+
+        for rule in [
+            "replace: /a\n            redirect: /b",
+            "redirect: /b\n            status: 200",
+        ] {
+            let yaml = format!(
+                "listeners:\n  - port: 441\n    routes:\n      - path: /\n        target: http://api:3000\n        rewrite:\n          - {}\n",
+                rule
+            );
+            let mut file = NamedTempFile::new().unwrap();
+            file.write_all(yaml.as_bytes()).unwrap();
+            assert!(
+                Config::load(file.path().to_str().unwrap()).is_err(),
+                "{}",
+                yaml
+            );
+        }
+    }
+
+    #[test]
+    fn test_load_body_filter() {
+        let yaml = r#"
+listeners:
+  - port: 441
+    routes:
+      - path: /
+        target: http://app:3001
+        body_filter:
+          substitutions:
+            - find: http://app:3001
+              replace: https://localhost:8443
+            - regex: 'http://(\w+):3001'
+              replace: https://$1.localhost
+"#;
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(yaml.as_bytes()).unwrap();
+
+        let config = Config::load(file.path().to_str().unwrap()).unwrap();
+        let filter = config.listeners[0].routes[0].body_filter.as_ref().unwrap();
+        assert_eq!(filter.content_types, ["text/html"]);
+        assert_eq!(filter.max_match_len, 1024);
+        assert_eq!(filter.substitutions.len(), 2);
+        assert_eq!(
+            filter.substitutions[0].find.as_deref(),
+            Some("http://app:3001")
+        );
+        assert_eq!(
+            filter.substitutions[1].regex.as_deref(),
+            Some("http://(\\w+):3001")
+        );
+
+        let yaml = yaml.replace("- find: http://app:3001", "- find: ''");
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(yaml.as_bytes()).unwrap();
+        assert!(Config::load(file.path().to_str().unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_load_invalid_routes() {
+        for yaml in [
+            "listeners:\n  - port: 441\n",
+            "listeners:\n  - port: 441\n    routes:\n      - path: /x\n",
+            "listeners:\n  - port: 441\n    routes:\n      - path: x\n        static: ./dist\n",
+            "listeners:\n  - port: 441\n    routes:\n      - path: /\n        static: ./dist\n        target: http://a\n",
+        ] {
+            let mut file = NamedTempFile::new().unwrap();
+            file.write_all(yaml.as_bytes()).unwrap();
+            assert!(Config::load(file.path().to_str().unwrap()).is_err(), "{}", yaml);
+        }
+    }
+
+    #[test]
+    fn test_load_invalid_trusted_proxy() {
+        let yaml = r#"
+listeners:
+  - port: 440
+    target: http://api:3000
+    trusted_proxies: [not-an-ip]
+"#;
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(yaml.as_bytes()).unwrap();
+
+        assert!(Config::load(file.path().to_str().unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_load_host_header() {
+        let yaml = r#"
+listeners:
+  - port: 440
+    target: http://api:3000
+    host_header: preserve
+  - port: 441
+    target: https://httpbin.org
+    host_header: upstream
+  - port: 442
+    target: https://10.0.0.5
+    host_header: bucket.s3.example.com
+    routes:
+      - path: /api
+        target: https://httpbin.org
+        host_header: upstream
+"#;
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(yaml.as_bytes()).unwrap();
+
+        let config = Config::load(file.path().to_str().unwrap()).unwrap();
+        assert_eq!(config.listeners[0].host_header, Some(HostHeader::Preserve));
+        assert_eq!(config.listeners[1].host_header, Some(HostHeader::Upstream));
+        assert_eq!(
+            config.listeners[2].host_header,
+            Some(HostHeader::Custom("bucket.s3.example.com".into()))
+        );
+        assert_eq!(
+            config.listeners[2].routes[0].host_header,
+            Some(HostHeader::Upstream)
+        );
+    }
+
+    #[test]
+    fn test_load_har_export() {
+        let yaml = r#"
+listeners:
+  - port: 440
+    target: http://api:3000
+    har:
+      path: /var/log/proxy/440.har
+"#;
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(yaml.as_bytes()).unwrap();
+
+        let config = Config::load(file.path().to_str().unwrap()).unwrap();
+        let har = config.listeners[0].har.as_ref().unwrap();
+        assert_eq!(har.path, "/var/log/proxy/440.har");
+        assert_eq!(har.max_body_bytes, 64 * 1024);
+    }
+
+    #[test]
+    fn test_load_empty_listeners() {
+        let yaml = "listeners: []";
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(yaml.as_bytes()).unwrap();
+
+        let config = Config::load(file.path().to_str().unwrap()).unwrap();
+        assert!(config.listeners.is_empty());
+    }
+
+    #[test]
+    fn test_load_header_rules() {
+        let yaml = r#"
+listeners:
+  - port: 440
+    target: http://api:3000
+    request_headers:
+      set:
+        X-Client-IP: "${client_ip}"
+      remove: [X-Debug]
+    response_headers:
+      rename:
+        Server: X-Upstream-Server
+    routes:
+      - path: /admin
+        target: http://admin:3000
+        request_headers:
+          set:
+            X-Area: admin
+        response_headers:
+          remove: [X-Debug]
+"#;
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(yaml.as_bytes()).unwrap();
+
+        let config = Config::load(file.path().to_str().unwrap()).unwrap();
+        let listener = &config.listeners[0];
+        assert_eq!(
+            listener.request_headers.set.get("X-Client-IP").unwrap(),
+            "${client_ip}"
+        );
+        assert_eq!(listener.request_headers.remove, vec!["X-Debug"]);
+        assert!(listener.request_headers.append.is_empty());
+        assert_eq!(
+            listener.response_headers.rename.get("Server").unwrap(),
+            "X-Upstream-Server"
+        );
+        let route = &listener.routes[0];
+        assert_eq!(route.request_headers.set.get("X-Area").unwrap(), "admin");
+        assert_eq!(route.response_headers.remove, vec!["X-Debug"]);
+        assert!(route.response_headers.set.is_empty());
+    }
+
+    #[test]
+    fn test_load_admin_and_capture() {
+        let yaml = r#"
+admin:
+  port: 9000
+capture:
+  max_entries: 50
+listeners: []
+"#;
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(yaml.as_bytes()).unwrap();
+
+        let config = Config::load(file.path().to_str().unwrap()).unwrap();
+        let admin = config.admin.unwrap();
+        assert_eq!(admin.port, 9000);
+        assert_eq!(admin.bind, IpAddr::V4(Ipv4Addr::LOCALHOST));
+        let capture = config.capture.unwrap();
+        assert_eq!(capture.max_entries, 50);
+        assert_eq!(capture.max_body_bytes, 64 * 1024);
     }
 
     #[test]
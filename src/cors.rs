@@ -0,0 +1,338 @@
+//! CORS handling at the proxy: preflights are answered here and
+//! `Access-Control-*` headers are set on every response.
+
+use anyhow::Context;
+use axum::{
+    body::Body,
+    http::{
+        header::{
+            ACCESS_CONTROL_ALLOW_CREDENTIALS, ACCESS_CONTROL_ALLOW_HEADERS,
+            ACCESS_CONTROL_ALLOW_METHODS, ACCESS_CONTROL_ALLOW_ORIGIN,
+            ACCESS_CONTROL_EXPOSE_HEADERS, ACCESS_CONTROL_MAX_AGE, ACCESS_CONTROL_REQUEST_HEADERS,
+            ACCESS_CONTROL_REQUEST_METHOD, ORIGIN, VARY,
+        },
+        HeaderMap, HeaderName, HeaderValue, Method, Request, Response, StatusCode,
+    },
+};
+use regex::Regex;
+
+use crate::config::CorsConfig;
+
+/// One entry of `allow_origins`
+#[derive(Debug)]
+enum OriginPattern {
+    Any,
+    Exact(String),
+    /// `~regex`, or an origin with `*` wildcards
+    Pattern(Regex),
+}
+
+impl OriginPattern {
+    fn parse(pattern: &str) -> anyhow::Result<Self> {
+        if pattern == "*" {
+            return Ok(OriginPattern::Any);
+        }
+        if let Some(regex) = pattern.strip_prefix('~') {
+            let regex =
+                Regex::new(regex).with_context(|| format!("invalid origin regex {:?}", regex))?;
+            return Ok(OriginPattern::Pattern(regex));
+        }
+        if pattern.contains('*') {
+            let regex = pattern
+                .split('*')
+                .map(regex::escape)
+                .collect::<Vec<_>>()
+                .join("[^/]*");
+            return Ok(OriginPattern::Pattern(Regex::new(&format!(
+                "(?i)^{}$",
+                regex
+            ))?));
+        }
+        Ok(OriginPattern::Exact(
+            pattern.trim_end_matches('/').to_string(),
+        ))
+    }
+
+    fn matches(&self, origin: &str) -> bool {
+        match self {
+            OriginPattern::Any => true,
+            OriginPattern::Exact(exact) => exact.eq_ignore_ascii_case(origin),
+            OriginPattern::Pattern(regex) => regex.is_match(origin),
+        }
+    }
+}
+
+/// A listener's or route's CORS policy
+#[derive(Debug)]
+pub struct CorsPolicy {
+    origins: Vec<OriginPattern>,
+    methods: Vec<Method>,
+    /// Request headers allowed; the preflight's are echoed when empty
+    headers: Vec<HeaderName>,
+    expose_headers: Vec<HeaderName>,
+    credentials: bool,
+    max_age_secs: Option<u64>,
+}
+
+impl CorsPolicy {
+    pub fn new(config: &CorsConfig) -> anyhow::Result<Self> {
+        let header_names = |names: &[String]| {
+            names
+                .iter()
+                .map(|name| {
+                    HeaderName::from_bytes(name.as_bytes())
+                        .with_context(|| format!("invalid header name {:?}", name))
+                })
+                .collect::<anyhow::Result<Vec<_>>>()
+        };
+        Ok(Self {
+            origins: config
+                .allow_origins
+                .iter()
+                .map(|origin| OriginPattern::parse(origin))
+                .collect::<anyhow::Result<_>>()?,
+            methods: config
+                .allow_methods
+                .iter()
+                .map(|method| {
+                    Method::from_bytes(method.to_ascii_uppercase().as_bytes())
+                        .with_context(|| format!("invalid method {:?}", method))
+                })
+                .collect::<anyhow::Result<_>>()?,
+            headers: header_names(&config.allow_headers)?,
+            expose_headers: header_names(&config.expose_headers)?,
+            credentials: config.allow_credentials,
+            max_age_secs: config.max_age_secs,
+        })
+    }
+
+    /// Whether the request is a CORS preflight the proxy should answer
+    pub fn is_preflight<B>(req: &Request<B>) -> bool {
+        req.method() == Method::OPTIONS
+            && req.headers().contains_key(ORIGIN)
+            && req.headers().contains_key(ACCESS_CONTROL_REQUEST_METHOD)
+    }
+
+    fn allows_origin(&self, origin: &HeaderValue) -> bool {
+        origin
+            .to_str()
+            .is_ok_and(|origin| self.origins.iter().any(|pattern| pattern.matches(origin)))
+    }
+
+    /// Answer a preflight: 204 with the policy when the origin, method and
+    /// headers are allowed, 403 otherwise
+    pub fn preflight<B>(&self, req: &Request<B>) -> Response<Body> {
+        let headers = req.headers();
+        let origin = headers.get(ORIGIN);
+        let method = headers
+            .get(ACCESS_CONTROL_REQUEST_METHOD)
+            .and_then(|v| Method::from_bytes(v.as_bytes()).ok());
+        let requested: Vec<HeaderName> = headers
+            .get_all(ACCESS_CONTROL_REQUEST_HEADERS)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .filter_map(|name| HeaderName::from_bytes(name.as_bytes()).ok())
+            .collect();
+
+        let allowed = origin.is_some_and(|origin| self.allows_origin(origin))
+            && method
+                .as_ref()
+                .is_some_and(|method| self.methods.contains(method))
+            && (self.headers.is_empty()
+                || requested.iter().all(|name| self.headers.contains(name)));
+        if !allowed {
+            tracing::debug!("Rejected CORS preflight from {:?} for {:?}", origin, method);
+            return Response::builder()
+                .status(StatusCode::FORBIDDEN)
+                .header("content-type", "text/plain; charset=utf-8")
+                .header(VARY, "Origin")
+                .body(Body::from("403 CORS preflight rejected"))
+                .unwrap();
+        }
+
+        let mut response = Response::builder()
+            .status(StatusCode::NO_CONTENT)
+            .body(Body::empty())
+            .unwrap();
+        let out = response.headers_mut();
+        if let Some(origin) = origin {
+            self.set_origin(out, origin);
+        }
+        out.insert(ACCESS_CONTROL_ALLOW_METHODS, join(&self.methods));
+        let allow_headers = if self.headers.is_empty() {
+            join(&requested)
+        } else {
+            join(&self.headers)
+        };
+        if !allow_headers.is_empty() {
+            out.insert(ACCESS_CONTROL_ALLOW_HEADERS, allow_headers);
+        }
+        if let Some(max_age) = self.max_age_secs {
+            out.insert(ACCESS_CONTROL_MAX_AGE, HeaderValue::from(max_age));
+        }
+        out.append(
+            VARY,
+            HeaderValue::from_static(
+                "Access-Control-Request-Method, Access-Control-Request-Headers",
+            ),
+        );
+        response
+    }
+
+    /// Replace whatever CORS headers the upstream sent with the policy's
+    pub fn apply(&self, origin: &HeaderValue, headers: &mut HeaderMap) {
+        let upstream: Vec<HeaderName> = headers
+            .keys()
+            .filter(|name| name.as_str().starts_with("access-control-"))
+            .cloned()
+            .collect();
+        for name in upstream {
+            headers.remove(name);
+        }
+
+        if !self.allows_origin(origin) {
+            headers.append(VARY, HeaderValue::from_static("Origin"));
+            return;
+        }
+        self.set_origin(headers, origin);
+        if !self.expose_headers.is_empty() {
+            headers.insert(ACCESS_CONTROL_EXPOSE_HEADERS, join(&self.expose_headers));
+        }
+    }
+
+    fn set_origin(&self, headers: &mut HeaderMap, origin: &HeaderValue) {
+        let any = self
+            .origins
+            .iter()
+            .any(|pattern| matches!(pattern, OriginPattern::Any));
+        // Credentialed requests need the origin itself, never `*`
+        if any && !self.credentials {
+            headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, HeaderValue::from_static("*"));
+        } else {
+            headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, origin.clone());
+            headers.append(VARY, HeaderValue::from_static("Origin"));
+        }
+        if self.credentials {
+            headers.insert(
+                ACCESS_CONTROL_ALLOW_CREDENTIALS,
+                HeaderValue::from_static("true"),
+            );
+        }
+    }
+}
+
+fn join<T: AsRef<str>>(values: &[T]) -> HeaderValue {
+    let joined = values
+        .iter()
+        .map(AsRef::as_ref)
+        .collect::<Vec<_>>()
+        .join(", ");
+    HeaderValue::from_str(&joined).unwrap_or_else(|_| HeaderValue::from_static(""))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(origins: &[&str], credentials: bool) -> CorsPolicy {
+        let mut config: CorsConfig = serde_yaml::from_str("{}").unwrap();
+        config.allow_origins = origins.iter().map(|o| o.to_string()).collect();
+        config.allow_credentials = credentials;
+        config.max_age_secs = Some(600);
+        CorsPolicy::new(&config).unwrap()
+    }
+
+    fn preflight(origin: &str, method: &str, headers: &str) -> Request<()> {
+        Request::builder()
+            .method("OPTIONS")
+            .header("origin", origin)
+            .header("access-control-request-method", method)
+            .header("access-control-request-headers", headers)
+            .body(())
+            .unwrap()
+    }
+
+    #[test]
+    fn test_origin_patterns() {
+        let policy = policy(
+            &[
+                "http://localhost:5173",
+                "https://*.example.com",
+                r"~^https://pr-\d+\.preview\.dev$",
+            ],
+            false,
+        );
+        let allows = |origin: &str| policy.allows_origin(&HeaderValue::from_str(origin).unwrap());
+        assert!(allows("http://localhost:5173"));
+        assert!(!allows("http://localhost:3000"));
+        assert!(allows("https://app.example.com"));
+        assert!(allows("https://App.Example.com"));
+        assert!(!allows("https://example.com.evil.io"));
+        assert!(!allows("http://app.example.com"));
+        assert!(allows("https://pr-42.preview.dev"));
+        assert!(!allows("https://pr-x.preview.dev"));
+    }
+
+    #[test]
+    fn test_preflight() {
+        let policy = policy(&["http://localhost:5173"], true);
+
+        let response = policy.preflight(&preflight(
+            "http://localhost:5173",
+            "PUT",
+            "Content-Type, X-Token",
+        ));
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        let headers = response.headers();
+        assert_eq!(
+            headers["access-control-allow-origin"],
+            "http://localhost:5173"
+        );
+        assert_eq!(headers["access-control-allow-credentials"], "true");
+        assert_eq!(
+            headers["access-control-allow-methods"],
+            "GET, HEAD, POST, PUT, PATCH, DELETE"
+        );
+        // Requested headers are echoed when none are configured
+        assert_eq!(
+            headers["access-control-allow-headers"],
+            "content-type, x-token"
+        );
+        assert_eq!(headers["access-control-max-age"], "600");
+
+        let response = policy.preflight(&preflight("http://evil.io", "PUT", ""));
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let response = policy.preflight(&preflight("http://localhost:5173", "TRACE", ""));
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[test]
+    fn test_apply() {
+        let origin = HeaderValue::from_static("http://localhost:5173");
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "access-control-allow-origin",
+            HeaderValue::from_static("https://upstream.example"),
+        );
+        headers.insert("access-control-max-age", HeaderValue::from_static("5"));
+        policy(&["*"], false).apply(&origin, &mut headers);
+        assert_eq!(headers["access-control-allow-origin"], "*");
+        assert!(headers.get("access-control-max-age").is_none());
+
+        // With credentials the origin is echoed
+        policy(&["*"], true).apply(&origin, &mut headers);
+        assert_eq!(
+            headers["access-control-allow-origin"],
+            "http://localhost:5173"
+        );
+        assert_eq!(headers["vary"], "Origin");
+
+        let mut headers = HeaderMap::new();
+        policy(&["http://localhost:5173"], false)
+            .apply(&HeaderValue::from_static("http://evil.io"), &mut headers);
+        assert!(headers.get("access-control-allow-origin").is_none());
+    }
+}
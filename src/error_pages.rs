@@ -0,0 +1,383 @@
+//! Error responses: the proxy's own plain-text errors, and the configured
+//! HTML pages and `application/problem+json` bodies that replace them.
+
+use anyhow::Context;
+use axum::{
+    body::Body,
+    http::{
+        header::{CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE, ETAG},
+        HeaderValue, Response, StatusCode,
+    },
+};
+use std::collections::HashMap;
+
+use crate::config::ErrorPagesConfig;
+use crate::headers::TemplateVars;
+
+/// Marks a response as an error produced by the proxy, with what went wrong
+#[derive(Debug, Clone)]
+pub struct ProxyError {
+    pub detail: String,
+}
+
+/// A plain-text error response from the proxy, marked so error pages can
+/// replace it
+pub fn error_response(status: StatusCode, detail: &str) -> Response<Body> {
+    let reason = status.canonical_reason().unwrap_or("Error");
+    let body = if detail.is_empty() {
+        format!("{} {}", status.as_u16(), reason)
+    } else {
+        format!("{} {} - {}", status.as_u16(), reason, detail)
+    };
+    let mut response = Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "text/plain; charset=utf-8")
+        .body(Body::from(body))
+        .unwrap();
+    response.extensions_mut().insert(ProxyError {
+        detail: detail.to_string(),
+    });
+    response
+}
+
+/// Variables an error page may use as `${name}`
+const VARIABLES: &[&str] = &[
+    "status",
+    "reason",
+    "detail",
+    "method",
+    "path",
+    "host",
+    "client_ip",
+];
+
+/// A listener's error pages
+#[derive(Debug)]
+pub struct ErrorPages {
+    /// Templates by status code
+    codes: HashMap<u16, String>,
+    /// Templates by status class (4 for `4xx`)
+    classes: HashMap<u16, String>,
+    hide_details: bool,
+    intercept_upstream: bool,
+}
+
+impl ErrorPages {
+    pub fn new(config: &ErrorPagesConfig) -> anyhow::Result<Self> {
+        let mut codes = HashMap::new();
+        let mut classes = HashMap::new();
+        for (key, path) in &config.pages {
+            let template = std::fs::read_to_string(path)
+                .with_context(|| format!("failed to read error page {}", path))?;
+            check_variables(&template).with_context(|| format!("invalid error page {}", path))?;
+            let key = key.trim().to_ascii_lowercase();
+            match key.strip_suffix("xx") {
+                Some(class) => {
+                    let class = class
+                        .parse()
+                        .ok()
+                        .filter(|class| (4..=5).contains(class))
+                        .with_context(|| format!("invalid error page status {:?}", key))?;
+                    classes.insert(class, template);
+                }
+                None => {
+                    let code = key
+                        .parse()
+                        .ok()
+                        .filter(|code| (400..600).contains(code))
+                        .with_context(|| format!("invalid error page status {:?}", key))?;
+                    codes.insert(code, template);
+                }
+            }
+        }
+
+        Ok(Self {
+            codes,
+            classes,
+            hide_details: config.hide_details,
+            intercept_upstream: config.intercept_upstream,
+        })
+    }
+
+    fn template(&self, status: StatusCode) -> Option<&String> {
+        self.codes
+            .get(&status.as_u16())
+            .or_else(|| self.classes.get(&(status.as_u16() / 100)))
+    }
+
+    /// Replace an error response with the page or problem document for it.
+    /// Headers such as `Retry-After` and CORS headers are kept.
+    pub fn render(
+        &self,
+        response: Response<Body>,
+        accept: Option<&HeaderValue>,
+        vars: &TemplateVars,
+    ) -> Response<Body> {
+        let status = response.status();
+        let json = wants_json(accept);
+        let detail = match response.extensions().get::<ProxyError>() {
+            Some(error) => Some(error.detail.clone()),
+            // Upstream errors are only replaced with a page, never with
+            // JSON: an API's own error bodies are more useful than ours
+            None if self.intercept_upstream
+                && (status.is_client_error() || status.is_server_error())
+                && !json
+                && self.template(status).is_some() =>
+            {
+                None
+            }
+            None => return response,
+        };
+        let detail = detail.filter(|detail| !self.hide_details && !detail.is_empty());
+        let reason = status.canonical_reason().unwrap_or("Error");
+
+        let (content_type, body) = if json {
+            let mut problem = serde_json::json!({
+                "type": "about:blank",
+                "title": reason,
+                "status": status.as_u16(),
+            });
+            if let Some(detail) = &detail {
+                problem["detail"] = detail.clone().into();
+            }
+            ("application/problem+json", problem.to_string())
+        } else if let Some(template) = self.template(status) {
+            let body = render_template(template, |name| match name {
+                "status" => status.as_u16().to_string(),
+                "reason" => reason.to_string(),
+                "detail" => detail.clone().unwrap_or_default(),
+                "method" => vars.method.clone(),
+                "path" => vars.path.clone(),
+                "host" => vars.host.clone(),
+                "client_ip" => vars.client_ip.clone(),
+                _ => String::new(),
+            });
+            ("text/html; charset=utf-8", body)
+        } else if self.hide_details {
+            (
+                "text/plain; charset=utf-8",
+                format!("{} {}", status.as_u16(), reason),
+            )
+        } else {
+            return response;
+        };
+
+        let (mut parts, _) = response.into_parts();
+        for name in [CONTENT_LENGTH, CONTENT_ENCODING, ETAG] {
+            parts.headers.remove(name);
+        }
+        parts
+            .headers
+            .insert(CONTENT_TYPE, HeaderValue::from_static(content_type));
+        Response::from_parts(parts, Body::from(body))
+    }
+}
+
+/// Whether the client would rather have JSON than HTML
+fn wants_json(accept: Option<&HeaderValue>) -> bool {
+    let Some(accept) = accept.and_then(|v| v.to_str().ok()) else {
+        return false;
+    };
+    let mut json = 0.0f32;
+    let mut html = 0.0f32;
+    for item in accept.split(',') {
+        let mut params = item.split(';');
+        let media_type = params.next().unwrap_or("").trim().to_ascii_lowercase();
+        let q = params
+            .filter_map(|p| p.trim().strip_prefix("q="))
+            .find_map(|q| q.trim().parse::<f32>().ok())
+            .unwrap_or(1.0);
+        if media_type == "application/json" || media_type.ends_with("+json") {
+            json = json.max(q);
+        } else if media_type == "text/html" {
+            html = html.max(q);
+        }
+    }
+    json > 0.0 && json > html
+}
+
+/// Make sure a template only uses known variables
+fn check_variables(template: &str) -> anyhow::Result<()> {
+    let mut rest = template;
+    while let Some(start) = rest.find("${") {
+        let end = rest[start..].find('}').context("unterminated variable")?;
+        let name = &rest[start + 2..start + end];
+        if !VARIABLES.contains(&name) {
+            anyhow::bail!("unknown variable ${{{}}}", name);
+        }
+        rest = &rest[start + end + 1..];
+    }
+    Ok(())
+}
+
+/// Fill in `${name}` variables, HTML-escaped
+fn render_template(template: &str, value: impl Fn(&str) -> String) -> String {
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("${") {
+        let Some(end) = rest[start..].find('}') else {
+            break;
+        };
+        out.push_str(&rest[..start]);
+        out.push_str(&escape_html(&value(&rest[start + 2..start + end])));
+        rest = &rest[start + end + 1..];
+    }
+    out.push_str(rest);
+    out
+}
+
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http_body_util::BodyExt;
+    use std::io::Write;
+    use tempfile::NamedTempFile;
+
+    fn page(content: &str) -> NamedTempFile {
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(content.as_bytes()).unwrap();
+        file
+    }
+
+    fn error_pages(pages: &[(&str, &NamedTempFile)], options: &str) -> ErrorPages {
+        let mut config: ErrorPagesConfig = serde_yaml::from_str(options).unwrap();
+        config.pages = pages
+            .iter()
+            .map(|(key, file)| (key.to_string(), file.path().to_str().unwrap().to_string()))
+            .collect();
+        ErrorPages::new(&config).unwrap()
+    }
+
+    fn vars() -> TemplateVars {
+        TemplateVars {
+            client_ip: "10.0.0.1".to_string(),
+            host: "app.localhost".to_string(),
+            request_id: String::new(),
+            method: "GET".to_string(),
+            path: "/<script>".to_string(),
+            port: 443,
+        }
+    }
+
+    async fn text(response: Response<Body>) -> String {
+        let bytes = response.into_body().collect().await.unwrap().to_bytes();
+        String::from_utf8(bytes.to_vec()).unwrap()
+    }
+
+    #[test]
+    fn test_wants_json() {
+        let accepts = |accept: &'static str| wants_json(Some(&HeaderValue::from_static(accept)));
+        assert!(accepts("application/json"));
+        assert!(accepts("application/problem+json, */*;q=0.1"));
+        assert!(!accepts("text/html,application/xhtml+xml,*/*;q=0.8"));
+        assert!(!accepts("text/html, application/json;q=0.9"));
+        assert!(!accepts("*/*"));
+        assert!(!wants_json(None));
+    }
+
+    #[tokio::test]
+    async fn test_html_page() {
+        let bad_gateway = page("<h1>${status} ${reason}</h1><p>${detail}</p><p>${path}</p>");
+        let server_error = page("<h1>${status}</h1>");
+        let pages = error_pages(&[("502", &bad_gateway), ("5xx", &server_error)], "{}");
+
+        let mut response = error_response(StatusCode::BAD_GATEWAY, "connection refused");
+        response
+            .headers_mut()
+            .insert("retry-after", HeaderValue::from_static("1"));
+        let response = pages.render(response, None, &vars());
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+        assert_eq!(
+            response.headers()["content-type"],
+            "text/html; charset=utf-8"
+        );
+        assert_eq!(response.headers()["retry-after"], "1");
+        assert_eq!(
+            text(response).await,
+            "<h1>502 Bad Gateway</h1><p>connection refused</p><p>/&lt;script&gt;</p>"
+        );
+
+        let response = error_response(StatusCode::GATEWAY_TIMEOUT, "timed out");
+        let response = pages.render(response, None, &vars());
+        assert_eq!(text(response).await, "<h1>504</h1>");
+    }
+
+    #[tokio::test]
+    async fn test_problem_json_and_hidden_details() {
+        let pages = error_pages(&[], "hide_details: true\n");
+        let accept = HeaderValue::from_static("application/json");
+
+        let response = error_response(StatusCode::SERVICE_UNAVAILABLE, "circuit open");
+        let response = pages.render(response, Some(&accept), &vars());
+        assert_eq!(
+            response.headers()["content-type"],
+            "application/problem+json"
+        );
+        let problem: serde_json::Value = serde_json::from_str(&text(response).await).unwrap();
+        assert_eq!(problem["status"], 503);
+        assert_eq!(problem["title"], "Service Unavailable");
+        assert!(problem.get("detail").is_none());
+
+        let response = error_response(StatusCode::BAD_GATEWAY, "dns error: api");
+        let response = pages.render(response, None, &vars());
+        assert_eq!(text(response).await, "502 Bad Gateway");
+    }
+
+    #[tokio::test]
+    async fn test_upstream_errors() {
+        let not_found = page("<h1>Not here</h1>");
+        let upstream = || {
+            Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .header("content-type", "application/json")
+                .body(Body::from("{\"error\":\"no such user\"}"))
+                .unwrap()
+        };
+
+        // Left alone unless intercepted
+        let pages = error_pages(&[("404", &not_found)], "{}");
+        let response = pages.render(upstream(), None, &vars());
+        assert_eq!(text(response).await, "{\"error\":\"no such user\"}");
+
+        let pages = error_pages(&[("404", &not_found)], "intercept_upstream: true\n");
+        let response = pages.render(upstream(), None, &vars());
+        assert_eq!(text(response).await, "<h1>Not here</h1>");
+        // API clients keep the upstream's own error
+        let accept = HeaderValue::from_static("application/json");
+        let response = pages.render(upstream(), Some(&accept), &vars());
+        assert_eq!(text(response).await, "{\"error\":\"no such user\"}");
+    }
+
+    #[test]
+    fn test_invalid_pages() {
+        let unknown = page("${oops}");
+        let mut config: ErrorPagesConfig = serde_yaml::from_str("{}").unwrap();
+        config.pages = [(
+            "502".to_string(),
+            unknown.path().to_str().unwrap().to_string(),
+        )]
+        .into();
+        assert!(ErrorPages::new(&config).is_err());
+
+        let fine = page("ok");
+        for key in ["200", "6xx", "abc"] {
+            config.pages = [(key.to_string(), fine.path().to_str().unwrap().to_string())].into();
+            assert!(ErrorPages::new(&config).is_err(), "{}", key);
+        }
+    }
+}
@@ -0,0 +1,202 @@
+//! Delegated authentication: ask an external service about each request,
+//! like Traefik's ForwardAuth or nginx's `auth_request`.
+
+use anyhow::Context;
+use axum::{
+    body::Body,
+    http::{
+        header::{CONTENT_LENGTH, HOST},
+        HeaderMap, HeaderName, HeaderValue, Request, Response, Uri,
+    },
+};
+use std::net::IpAddr;
+use std::time::Duration;
+
+use crate::auth::Authenticated;
+use crate::config::ForwardAuthConfig;
+use crate::proxy::{
+    bad_gateway_response, gateway_timeout_response, remove_hop_by_hop_headers, HttpClient,
+};
+
+/// An auth service requests are checked with before being forwarded
+#[derive(Debug)]
+pub struct ForwardAuth {
+    address: Uri,
+    /// Auth response headers copied onto the upstream request
+    response_headers: Vec<HeaderName>,
+    /// Client headers sent to the auth service; all of them when empty
+    request_headers: Vec<HeaderName>,
+    timeout: Duration,
+}
+
+impl ForwardAuth {
+    pub fn new(config: &ForwardAuthConfig) -> anyhow::Result<Self> {
+        let names = |names: &[String]| {
+            names
+                .iter()
+                .map(|name| {
+                    HeaderName::from_bytes(name.as_bytes())
+                        .with_context(|| format!("invalid header name {:?}", name))
+                })
+                .collect::<anyhow::Result<Vec<_>>>()
+        };
+        Ok(Self {
+            address: config
+                .address
+                .parse()
+                .with_context(|| format!("invalid forward_auth address {:?}", config.address))?,
+            response_headers: names(&config.auth_response_headers)?,
+            request_headers: names(&config.auth_request_headers)?,
+            timeout: Duration::from_millis(config.timeout_ms),
+        })
+    }
+
+    /// Ask the auth service about `req`. A 2xx answer lets it through with
+    /// the configured response headers added; any other answer is what the
+    /// client gets back.
+    pub async fn check(
+        &self,
+        client: &HttpClient,
+        req: &mut Request<Body>,
+        client_ip: IpAddr,
+    ) -> Result<(), Response<Body>> {
+        // Only the auth service may set these
+        for name in &self.response_headers {
+            req.headers_mut().remove(name);
+        }
+
+        let subrequest = self
+            .subrequest(req, client_ip)
+            .map_err(|e| bad_gateway_response(&format!("Failed to build auth request: {}", e)))?;
+        let response = match tokio::time::timeout(self.timeout, client.request(subrequest)).await {
+            Ok(Ok(response)) => response,
+            Ok(Err(e)) => {
+                tracing::error!("Auth service {} failed: {}", self.address, e);
+                return Err(bad_gateway_response("auth service unavailable"));
+            }
+            Err(_) => {
+                tracing::error!("Auth service {} timed out", self.address);
+                return Err(gateway_timeout_response("auth service timed out"));
+            }
+        };
+
+        if !response.status().is_success() {
+            tracing::debug!(
+                "Auth service denied {} {} with {}",
+                req.method(),
+                req.uri(),
+                response.status()
+            );
+            let (mut parts, body) = response.into_parts();
+            remove_hop_by_hop_headers(&mut parts.headers);
+            return Err(Response::from_parts(parts, Body::new(body)));
+        }
+
+        for name in &self.response_headers {
+            for value in response.headers().get_all(name) {
+                req.headers_mut().append(name.clone(), value.clone());
+            }
+        }
+        req.extensions_mut().insert(Authenticated);
+        Ok(())
+    }
+
+    /// The original method and headers, without the body, sent to the
+    /// auth service with the original request described in X-Forwarded-*
+    fn subrequest(&self, req: &Request<Body>, client_ip: IpAddr) -> anyhow::Result<Request<Body>> {
+        let mut headers = if self.request_headers.is_empty() {
+            req.headers().clone()
+        } else {
+            let mut headers = HeaderMap::new();
+            for name in &self.request_headers {
+                for value in req.headers().get_all(name) {
+                    headers.append(name.clone(), value.clone());
+                }
+            }
+            headers
+        };
+        remove_hop_by_hop_headers(&mut headers);
+        headers.remove(CONTENT_LENGTH);
+        headers.remove(HOST);
+
+        let host = req.headers().get(HOST).cloned().or_else(|| {
+            req.uri()
+                .authority()
+                .and_then(|authority| HeaderValue::from_str(authority.as_str()).ok())
+        });
+        let uri = req.uri().path_and_query().map_or("/", |pq| pq.as_str());
+        headers.insert(
+            "x-forwarded-method",
+            HeaderValue::from_str(req.method().as_str())?,
+        );
+        headers.insert("x-forwarded-proto", HeaderValue::from_static("https"));
+        headers.insert("x-forwarded-uri", HeaderValue::from_str(uri)?);
+        headers.insert(
+            "x-forwarded-for",
+            HeaderValue::from_str(&client_ip.to_string())?,
+        );
+        if let Some(host) = host {
+            headers.insert("x-forwarded-host", host);
+        }
+
+        let mut subrequest = Request::builder()
+            .method(req.method())
+            .uri(self.address.clone())
+            .body(Body::empty())?;
+        *subrequest.headers_mut() = headers;
+        Ok(subrequest)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn forward_auth(request_headers: &[&str]) -> ForwardAuth {
+        ForwardAuth::new(&ForwardAuthConfig {
+            address: "http://auth:9091/verify".to_string(),
+            auth_response_headers: vec!["X-User".to_string()],
+            auth_request_headers: request_headers.iter().map(|h| h.to_string()).collect(),
+            timeout_ms: 1000,
+        })
+        .unwrap()
+    }
+
+    fn request() -> Request<Body> {
+        Request::post("/orders?id=7")
+            .header("host", "shop.local")
+            .header("cookie", "session=abc")
+            .header("content-length", "5")
+            .header("connection", "keep-alive")
+            .body(Body::from("hello"))
+            .unwrap()
+    }
+
+    #[test]
+    fn test_subrequest() {
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let subrequest = forward_auth(&[]).subrequest(&request(), ip).unwrap();
+
+        assert_eq!(subrequest.method(), "POST");
+        assert_eq!(subrequest.uri(), "http://auth:9091/verify");
+        let headers = subrequest.headers();
+        assert_eq!(headers["cookie"], "session=abc");
+        assert_eq!(headers["x-forwarded-method"], "POST");
+        assert_eq!(headers["x-forwarded-uri"], "/orders?id=7");
+        assert_eq!(headers["x-forwarded-host"], "shop.local");
+        assert_eq!(headers["x-forwarded-for"], "10.0.0.1");
+        assert!(headers.get("host").is_none());
+        assert!(headers.get("content-length").is_none());
+        assert!(headers.get("connection").is_none());
+    }
+
+    #[test]
+    fn test_subrequest_selected_headers() {
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let subrequest = forward_auth(&["Authorization"])
+            .subrequest(&request(), ip)
+            .unwrap();
+        assert!(subrequest.headers().get("cookie").is_none());
+        assert_eq!(subrequest.headers()["x-forwarded-uri"], "/orders?id=7");
+    }
+}
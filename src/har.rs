@@ -0,0 +1,558 @@
+//! HTTP Archive (HAR 1.2) export of proxied traffic.
+//!
+//! Entries are built from `CapturedRequest`s, either written continuously to a
+//! file per listener or rendered on demand from the capture history.
+
+use axum::http::HeaderMap;
+use base64::Engine;
+use serde::Serialize;
+use std::fs::File;
+use std::io::{Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::mpsc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::capture::CapturedRequest;
+use crate::config::HarConfig;
+
+/// Closing brackets of an (entries-last) HAR document; new entries go in front of it
+const TRAILER: &[u8] = b"]}}";
+
+#[derive(Debug, Serialize)]
+pub struct Har {
+    pub log: HarLog,
+}
+
+#[derive(Debug, Serialize)]
+pub struct HarLog {
+    pub version: &'static str,
+    pub creator: HarCreator,
+    // Must stay the last field, see `TRAILER`
+    pub entries: Vec<HarEntry>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct HarCreator {
+    pub name: &'static str,
+    pub version: &'static str,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HarEntry {
+    pub started_date_time: String,
+    pub time: u64,
+    pub request: HarRequest,
+    pub response: HarResponse,
+    pub cache: serde_json::Value,
+    pub timings: HarTimings,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HarRequest {
+    pub method: String,
+    pub url: String,
+    pub http_version: String,
+    pub cookies: Vec<HarCookie>,
+    pub headers: Vec<HarNameValue>,
+    pub query_string: Vec<HarNameValue>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub post_data: Option<HarPostData>,
+    pub headers_size: i64,
+    pub body_size: i64,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HarResponse {
+    pub status: u16,
+    pub status_text: String,
+    pub http_version: String,
+    pub cookies: Vec<HarCookie>,
+    pub headers: Vec<HarNameValue>,
+    pub content: HarContent,
+    #[serde(rename = "redirectURL")]
+    pub redirect_url: String,
+    pub headers_size: i64,
+    pub body_size: i64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct HarNameValue {
+    pub name: String,
+    pub value: String,
+}
+
+#[derive(Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HarCookie {
+    pub name: String,
+    pub value: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub domain: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expires: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub http_only: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub secure: Option<bool>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HarPostData {
+    pub mime_type: String,
+    pub text: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub comment: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HarContent {
+    pub size: i64,
+    pub mime_type: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub text: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub encoding: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub comment: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct HarTimings {
+    pub send: i64,
+    pub wait: i64,
+    pub receive: i64,
+}
+
+impl Har {
+    pub fn new(entries: Vec<HarEntry>) -> Self {
+        Self {
+            log: HarLog {
+                version: "1.2",
+                creator: HarCreator {
+                    name: env!("CARGO_PKG_NAME"),
+                    version: env!("CARGO_PKG_VERSION"),
+                },
+                entries,
+            },
+        }
+    }
+}
+
+/// Build a HAR entry, keeping at most `max_body_bytes` of each body
+pub fn entry(captured: &CapturedRequest, max_body_bytes: usize) -> HarEntry {
+    let receive = captured.receive_ms().unwrap_or(0);
+
+    HarEntry {
+        started_date_time: format_iso8601(captured.started),
+        time: captured.wait_ms + receive,
+        request: request(captured, max_body_bytes),
+        response: response(captured, max_body_bytes),
+        cache: serde_json::json!({}),
+        timings: HarTimings {
+            send: 0,
+            wait: captured.wait_ms as i64,
+            receive: receive as i64,
+        },
+    }
+}
+
+fn request(captured: &CapturedRequest, max_body_bytes: usize) -> HarRequest {
+    let host = header_str(&captured.headers, "host")
+        .map(str::to_string)
+        .or_else(|| captured.uri.authority().map(|a| a.to_string()))
+        .unwrap_or_else(|| format!("localhost:{}", captured.listener_port));
+    let path_and_query = captured
+        .uri
+        .path_and_query()
+        .map(|pq| pq.as_str())
+        .unwrap_or("/");
+
+    let post_data = (captured.body_size() > 0).then(|| {
+        let (body, truncated) = captured.body(max_body_bytes);
+        HarPostData {
+            mime_type: header_str(&captured.headers, "content-type")
+                .unwrap_or("")
+                .to_string(),
+            text: String::from_utf8_lossy(&body).into_owned(),
+            comment: truncated.then(|| truncated_comment(max_body_bytes)),
+        }
+    });
+
+    HarRequest {
+        method: captured.method.to_string(),
+        url: format!("https://{}{}", host, path_and_query),
+        http_version: format!("{:?}", captured.version),
+        cookies: request_cookies(&captured.headers),
+        headers: name_values(&captured.headers),
+        query_string: query_string(captured.uri.query().unwrap_or("")),
+        post_data,
+        headers_size: -1,
+        body_size: captured.body_size() as i64,
+    }
+}
+
+fn response(captured: &CapturedRequest, max_body_bytes: usize) -> HarResponse {
+    let headers = &captured.response_headers;
+    let (body, truncated) = captured.response_body(max_body_bytes);
+
+    // HAR text must be a string; binary bodies are base64 encoded
+    let (text, encoding) = match std::str::from_utf8(&body) {
+        Ok(text) => (text.to_string(), None),
+        Err(_) => (
+            base64::engine::general_purpose::STANDARD.encode(&body),
+            Some("base64"),
+        ),
+    };
+
+    HarResponse {
+        status: captured.status.as_u16(),
+        status_text: captured.status.canonical_reason().unwrap_or("").to_string(),
+        http_version: format!("{:?}", captured.response_version),
+        cookies: response_cookies(headers),
+        headers: name_values(headers),
+        content: HarContent {
+            size: captured.response_body_size() as i64,
+            mime_type: header_str(headers, "content-type")
+                .unwrap_or("")
+                .to_string(),
+            text: (!body.is_empty()).then_some(text),
+            encoding,
+            comment: truncated.then(|| truncated_comment(max_body_bytes)),
+        },
+        redirect_url: header_str(headers, "location").unwrap_or("").to_string(),
+        headers_size: -1,
+        body_size: captured.response_body_size() as i64,
+    }
+}
+
+fn truncated_comment(max_body_bytes: usize) -> String {
+    format!("body truncated to {} bytes", max_body_bytes)
+}
+
+fn header_str<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers.get(name).and_then(|v| v.to_str().ok())
+}
+
+fn name_values(headers: &HeaderMap) -> Vec<HarNameValue> {
+    headers
+        .iter()
+        .map(|(name, value)| HarNameValue {
+            name: name.to_string(),
+            value: String::from_utf8_lossy(value.as_bytes()).into_owned(),
+        })
+        .collect()
+}
+
+fn query_string(query: &str) -> Vec<HarNameValue> {
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            HarNameValue {
+                name: name.to_string(),
+                value: value.to_string(),
+            }
+        })
+        .collect()
+}
+
+/// Cookies from the request `Cookie` header(s)
+fn request_cookies(headers: &HeaderMap) -> Vec<HarCookie> {
+    headers
+        .get_all("cookie")
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(';'))
+        .filter_map(|pair| {
+            let (name, value) = pair.trim().split_once('=')?;
+            Some(HarCookie {
+                name: name.to_string(),
+                value: value.to_string(),
+                ..Default::default()
+            })
+        })
+        .collect()
+}
+
+/// Cookies from the response `Set-Cookie` headers, with their attributes
+fn response_cookies(headers: &HeaderMap) -> Vec<HarCookie> {
+    headers
+        .get_all("set-cookie")
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .filter_map(|v| {
+            let mut parts = v.split(';');
+            let (name, value) = parts.next()?.trim().split_once('=')?;
+            let mut cookie = HarCookie {
+                name: name.to_string(),
+                value: value.to_string(),
+                ..Default::default()
+            };
+            for attribute in parts {
+                let (key, val) = attribute
+                    .trim()
+                    .split_once('=')
+                    .unwrap_or((attribute.trim(), ""));
+                match key.to_ascii_lowercase().as_str() {
+                    "path" => cookie.path = Some(val.to_string()),
+                    "domain" => cookie.domain = Some(val.to_string()),
+                    "expires" => cookie.expires = Some(val.to_string()),
+                    "httponly" => cookie.http_only = Some(true),
+                    "secure" => cookie.secure = Some(true),
+                    _ => {}
+                }
+            }
+            Some(cookie)
+        })
+        .collect()
+}
+
+/// Format a timestamp as ISO 8601 in UTC with millisecond precision
+fn format_iso8601(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (days, rem) = (secs / 86_400, secs % 86_400);
+
+    // Civil-from-days conversion (Howard Hinnant's algorithm)
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        rem / 3_600,
+        rem % 3_600 / 60,
+        rem % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+/// Open HAR file that finished exchanges are appended to
+struct HarFile {
+    file: File,
+    empty: bool,
+}
+
+/// Work for a HAR writer thread
+enum HarMessage {
+    Entry(Box<HarEntry>),
+    /// Answered once every entry sent before it is on disk
+    Flush(mpsc::Sender<()>),
+}
+
+/// Continuously writes a listener's traffic to a HAR file.
+///
+/// The file is a valid HAR document after every entry, so it can be opened
+/// in browser devtools while the proxy is still running. Entries are written
+/// by a thread of their own, keeping file I/O off the async workers.
+pub struct HarWriter {
+    sender: mpsc::Sender<HarMessage>,
+    max_body_bytes: usize,
+}
+
+impl HarWriter {
+    /// Create (or truncate) the HAR file and start its writer thread
+    pub fn open(config: &HarConfig) -> anyhow::Result<Self> {
+        if let Some(parent) = Path::new(&config.path).parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let mut file = File::create(&config.path)?;
+        serde_json::to_writer(&mut file, &Har::new(Vec::new()))?;
+
+        let (sender, receiver) = mpsc::channel();
+        let mut har = HarFile { file, empty: true };
+        // Ends once the writer is dropped and the queue has drained
+        std::thread::Builder::new()
+            .name("har-writer".to_string())
+            .spawn(move || {
+                for message in receiver {
+                    match message {
+                        HarMessage::Entry(entry) => {
+                            if let Err(e) = har.append(&entry) {
+                                tracing::error!("Failed to write HAR entry: {}", e);
+                            }
+                        }
+                        HarMessage::Flush(done) => {
+                            let _ = done.send(());
+                        }
+                    }
+                }
+            })?;
+
+        Ok(Self {
+            sender,
+            max_body_bytes: config.max_body_bytes,
+        })
+    }
+
+    /// Body bytes kept per request and response
+    pub fn max_body_bytes(&self) -> usize {
+        self.max_body_bytes
+    }
+
+    /// Queue a finished exchange for writing
+    pub fn append(&self, captured: &CapturedRequest) {
+        let entry = entry(captured, self.max_body_bytes);
+        if self
+            .sender
+            .send(HarMessage::Entry(Box::new(entry)))
+            .is_err()
+        {
+            tracing::error!("HAR writer has stopped, dropping entry");
+        }
+    }
+
+    /// Wait until the entries queued so far have been written
+    pub fn flush(&self) {
+        let (done, wait) = mpsc::channel();
+        if self.sender.send(HarMessage::Flush(done)).is_ok() {
+            let _ = wait.recv();
+        }
+    }
+}
+
+impl HarFile {
+    fn append(&mut self, entry: &HarEntry) -> anyhow::Result<()> {
+        self.file.seek(SeekFrom::End(-(TRAILER.len() as i64)))?;
+        if !self.empty {
+            self.file.write_all(b",")?;
+        }
+        self.file.write_all(b"\n")?;
+        serde_json::to_writer(&mut self.file, entry)?;
+        self.file.write_all(TRAILER)?;
+        self.file.flush()?;
+        self.empty = false;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::capture;
+    use axum::body::Body;
+    use axum::http::{Request, Response};
+    use http_body_util::BodyExt;
+    use std::sync::Arc;
+    use tempfile::TempDir;
+
+    async fn exchange() -> Arc<CapturedRequest> {
+        let req = Request::builder()
+            .method("POST")
+            .uri("/login?next=%2Fhome&x")
+            .header("host", "localhost:440")
+            .header("cookie", "a=1; b=2")
+            .header("content-type", "application/json")
+            .body(Body::from(r#"{"user":"bob"}"#))
+            .unwrap();
+        let (pending, req) = capture::record(
+            440,
+            "http://api:3000",
+            "10.0.0.1:5000".parse().unwrap(),
+            req,
+            1024,
+        );
+        req.into_body().collect().await.unwrap();
+
+        let response = Response::builder()
+            .status(302)
+            .header("location", "/home")
+            .header("set-cookie", "session=xyz; Path=/; HttpOnly; Secure")
+            .body(Body::from("redirecting"))
+            .unwrap();
+        let (entry, response) = pending.finish(response, |_| {});
+        response.into_body().collect().await.unwrap();
+        entry
+    }
+
+    #[tokio::test]
+    async fn test_entry_fields() {
+        let captured = exchange().await;
+        let entry = entry(&captured, 1024);
+
+        assert_eq!(
+            entry.request.url,
+            "https://localhost:440/login?next=%2Fhome&x"
+        );
+        assert_eq!(entry.request.http_version, "HTTP/1.1");
+        assert_eq!(entry.request.cookies.len(), 2);
+        assert_eq!(entry.request.query_string[0].name, "next");
+        assert_eq!(entry.request.query_string[1].value, "");
+        assert_eq!(entry.request.post_data.unwrap().text, r#"{"user":"bob"}"#);
+
+        assert_eq!(entry.response.status, 302);
+        assert_eq!(entry.response.status_text, "Found");
+        assert_eq!(entry.response.redirect_url, "/home");
+        assert_eq!(entry.response.content.text.as_deref(), Some("redirecting"));
+        let cookie = &entry.response.cookies[0];
+        assert_eq!(
+            (cookie.name.as_str(), cookie.value.as_str()),
+            ("session", "xyz")
+        );
+        assert_eq!(cookie.path.as_deref(), Some("/"));
+        assert_eq!(cookie.http_only, Some(true));
+        assert_eq!(cookie.secure, Some(true));
+    }
+
+    #[tokio::test]
+    async fn test_entry_truncates_bodies() {
+        let captured = exchange().await;
+        let entry = entry(&captured, 4);
+
+        assert_eq!(entry.request.post_data.unwrap().text, r#"{"us"#);
+        assert_eq!(entry.response.content.text.as_deref(), Some("redi"));
+        assert_eq!(entry.response.content.size, 11);
+        assert!(entry.response.content.comment.is_some());
+    }
+
+    #[test]
+    fn test_format_iso8601() {
+        let time = UNIX_EPOCH + std::time::Duration::from_millis(1_709_210_096_789);
+        assert_eq!(format_iso8601(time), "2024-02-29T12:34:56.789Z");
+        assert_eq!(format_iso8601(UNIX_EPOCH), "1970-01-01T00:00:00.000Z");
+    }
+
+    #[tokio::test]
+    async fn test_writer_keeps_file_valid() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("har/440.har");
+        let writer = HarWriter::open(&HarConfig {
+            path: path.to_str().unwrap().to_string(),
+            max_body_bytes: 1024,
+        })
+        .unwrap();
+
+        let read = || -> serde_json::Value {
+            serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap()
+        };
+        assert_eq!(read()["log"]["entries"].as_array().unwrap().len(), 0);
+
+        for _ in 0..2 {
+            let captured = exchange().await;
+            writer.append(&captured);
+        }
+        writer.flush();
+
+        let har = read();
+        assert_eq!(har["log"]["version"], "1.2");
+        assert_eq!(har["log"]["entries"].as_array().unwrap().len(), 2);
+        assert_eq!(har["log"]["entries"][1]["response"]["status"], 302);
+    }
+}
@@ -0,0 +1,300 @@
+//! Declarative request/response header rules from `routes.yaml`.
+//!
+//! Values may reference per-request variables such as `${client_ip}`.
+
+use axum::http::{HeaderMap, HeaderName, HeaderValue, Request};
+use rand::Rng;
+use std::net::IpAddr;
+
+use crate::config::HeaderRules;
+
+/// Per-request values available to header templates
+#[derive(Debug, Clone)]
+pub struct TemplateVars {
+    pub client_ip: String,
+    pub host: String,
+    pub request_id: String,
+    pub method: String,
+    pub path: String,
+    pub port: u16,
+}
+
+impl TemplateVars {
+    /// Collect template variables for a request before it is rewritten
+    pub fn from_request<B>(req: &Request<B>, client_ip: IpAddr, port: u16) -> Self {
+        let header = |name: &str| {
+            req.headers()
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(str::to_string)
+        };
+        Self {
+            client_ip: client_ip.to_string(),
+            host: header("host")
+                .or_else(|| req.uri().authority().map(|a| a.to_string()))
+                .unwrap_or_default(),
+            // Keep an id the client (or an outer proxy) already assigned
+            request_id: header("x-request-id").unwrap_or_else(generate_request_id),
+            method: req.method().to_string(),
+            path: req.uri().path().to_string(),
+            port,
+        }
+    }
+}
+
+/// Random UUID v4 used as `${request_id}`
+fn generate_request_id() -> String {
+    let mut bytes: [u8; 16] = rand::thread_rng().gen();
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[0..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..32]
+    )
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Var {
+    ClientIp,
+    Host,
+    RequestId,
+    Method,
+    Path,
+    Port,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    Literal(String),
+    Var(Var),
+}
+
+/// Header value with `${var}` placeholders, parsed once at startup
+#[derive(Debug, Clone, PartialEq)]
+struct Template(Vec<Segment>);
+
+impl Template {
+    fn parse(input: &str) -> anyhow::Result<Self> {
+        let mut segments = Vec::new();
+        let mut rest = input;
+
+        while let Some(start) = rest.find("${") {
+            if start > 0 {
+                segments.push(Segment::Literal(rest[..start].to_string()));
+            }
+            let end = rest[start..]
+                .find('}')
+                .ok_or_else(|| anyhow::anyhow!("unterminated variable in {:?}", input))?;
+            let var = match &rest[start + 2..start + end] {
+                "client_ip" => Var::ClientIp,
+                "host" => Var::Host,
+                "request_id" => Var::RequestId,
+                "method" => Var::Method,
+                "path" => Var::Path,
+                "port" => Var::Port,
+                other => anyhow::bail!("unknown variable ${{{}}} in {:?}", other, input),
+            };
+            segments.push(Segment::Var(var));
+            rest = &rest[start + end + 1..];
+        }
+        if !rest.is_empty() {
+            segments.push(Segment::Literal(rest.to_string()));
+        }
+
+        Ok(Self(segments))
+    }
+
+    fn render(&self, vars: &TemplateVars) -> anyhow::Result<HeaderValue> {
+        let mut out = String::new();
+        for segment in &self.0 {
+            match segment {
+                Segment::Literal(text) => out.push_str(text),
+                Segment::Var(Var::ClientIp) => out.push_str(&vars.client_ip),
+                Segment::Var(Var::Host) => out.push_str(&vars.host),
+                Segment::Var(Var::RequestId) => out.push_str(&vars.request_id),
+                Segment::Var(Var::Method) => out.push_str(&vars.method),
+                Segment::Var(Var::Path) => out.push_str(&vars.path),
+                Segment::Var(Var::Port) => out.push_str(&vars.port.to_string()),
+            }
+        }
+        Ok(HeaderValue::from_str(&out)?)
+    }
+}
+
+/// Compiled form of `HeaderRules`.
+///
+/// Rules run in a fixed order: remove, rename, set, append.
+#[derive(Debug, Clone, Default)]
+pub struct HeaderRewriter {
+    remove: Vec<HeaderName>,
+    rename: Vec<(HeaderName, HeaderName)>,
+    set: Vec<(HeaderName, Template)>,
+    append: Vec<(HeaderName, Template)>,
+}
+
+impl HeaderRewriter {
+    pub fn new(rules: &HeaderRules) -> anyhow::Result<Self> {
+        let templates = |map: &std::collections::BTreeMap<String, String>| {
+            map.iter()
+                .map(|(name, value)| Ok((parse_name(name)?, Template::parse(value)?)))
+                .collect::<anyhow::Result<Vec<_>>>()
+        };
+
+        Ok(Self {
+            remove: rules
+                .remove
+                .iter()
+                .map(|name| parse_name(name))
+                .collect::<anyhow::Result<_>>()?,
+            rename: rules
+                .rename
+                .iter()
+                .map(|(from, to)| Ok((parse_name(from)?, parse_name(to)?)))
+                .collect::<anyhow::Result<_>>()?,
+            set: templates(&rules.set)?,
+            append: templates(&rules.append)?,
+        })
+    }
+
+    /// Apply the rules to `headers`
+    pub fn apply(&self, headers: &mut HeaderMap, vars: &TemplateVars) -> anyhow::Result<()> {
+        for name in &self.remove {
+            headers.remove(name);
+        }
+
+        for (from, to) in &self.rename {
+            let values: Vec<HeaderValue> = headers.get_all(from).iter().cloned().collect();
+            if values.is_empty() {
+                continue;
+            }
+            headers.remove(from);
+            headers.remove(to);
+            for value in values {
+                headers.append(to.clone(), value);
+            }
+        }
+
+        for (name, template) in &self.set {
+            headers.insert(name.clone(), template.render(vars)?);
+        }
+
+        for (name, template) in &self.append {
+            headers.append(name.clone(), template.render(vars)?);
+        }
+
+        Ok(())
+    }
+}
+
+fn parse_name(name: &str) -> anyhow::Result<HeaderName> {
+    HeaderName::from_bytes(name.as_bytes())
+        .map_err(|_| anyhow::anyhow!("invalid header name {:?}", name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    fn vars() -> TemplateVars {
+        TemplateVars {
+            client_ip: "10.0.0.1".into(),
+            host: "localhost:440".into(),
+            request_id: "req-1".into(),
+            method: "GET".into(),
+            path: "/api".into(),
+            port: 440,
+        }
+    }
+
+    fn map(pairs: &[(&str, &str)]) -> BTreeMap<String, String> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_template_render() {
+        let template = Template::parse("ip=${client_ip} host=${host}:${port}").unwrap();
+        assert_eq!(
+            template.render(&vars()).unwrap(),
+            "ip=10.0.0.1 host=localhost:440:440"
+        );
+    }
+
+    #[test]
+    fn test_template_rejects_unknown_variable() {
+        assert!(Template::parse("${nope}").is_err());
+        assert!(Template::parse("${client_ip").is_err());
+    }
+
+    #[test]
+    fn test_apply_rules_in_order() {
+        let rules = HeaderRules {
+            set: map(&[("x-client", "${client_ip}"), ("x-env", "dev")]),
+            append: map(&[("x-trace", "${request_id}")]),
+            remove: vec!["x-debug".into()],
+            rename: map(&[("x-old", "x-new")]),
+        };
+        let rewriter = HeaderRewriter::new(&rules).unwrap();
+
+        let mut headers = HeaderMap::new();
+        headers.insert("x-debug", HeaderValue::from_static("1"));
+        headers.insert("x-old", HeaderValue::from_static("legacy"));
+        headers.insert("x-env", HeaderValue::from_static("prod"));
+        headers.insert("x-trace", HeaderValue::from_static("upstream"));
+        rewriter.apply(&mut headers, &vars()).unwrap();
+
+        assert!(headers.get("x-debug").is_none());
+        assert!(headers.get("x-old").is_none());
+        assert_eq!(headers.get("x-new").unwrap(), "legacy");
+        assert_eq!(headers.get("x-env").unwrap(), "dev");
+        assert_eq!(headers.get("x-client").unwrap(), "10.0.0.1");
+        let traces: Vec<_> = headers.get_all("x-trace").iter().collect();
+        assert_eq!(traces, vec!["upstream", "req-1"]);
+    }
+
+    #[test]
+    fn test_invalid_header_name() {
+        let rules = HeaderRules {
+            remove: vec!["bad header".into()],
+            ..Default::default()
+        };
+        assert!(HeaderRewriter::new(&rules).is_err());
+    }
+
+    #[test]
+    fn test_vars_from_request() {
+        let req = Request::builder()
+            .method("POST")
+            .uri("/api/items?x=1")
+            .header("host", "localhost:443")
+            .header("x-request-id", "abc")
+            .body(())
+            .unwrap();
+        let vars = TemplateVars::from_request(&req, "::1".parse().unwrap(), 443);
+
+        assert_eq!(vars.client_ip, "::1");
+        assert_eq!(vars.host, "localhost:443");
+        assert_eq!(vars.request_id, "abc");
+        assert_eq!(vars.method, "POST");
+        assert_eq!(vars.path, "/api/items");
+    }
+
+    #[test]
+    fn test_vars_generate_request_id() {
+        let req = Request::new(());
+        let first = TemplateVars::from_request(&req, "::1".parse().unwrap(), 443);
+        let second = TemplateVars::from_request(&req, "::1".parse().unwrap(), 443);
+
+        assert_eq!(first.request_id.len(), 36);
+        assert_eq!(&first.request_id[14..15], "4");
+        assert_ne!(first.request_id, second.request_id);
+    }
+}
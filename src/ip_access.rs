@@ -0,0 +1,86 @@
+//! CIDR allow and deny lists for client IPs.
+
+use axum::{
+    body::Body,
+    http::{Response, StatusCode},
+};
+use ipnet::IpNet;
+use std::net::IpAddr;
+
+use crate::config::IpAccessConfig;
+
+/// Which clients may use a listener or route
+#[derive(Debug)]
+pub struct IpAccess {
+    allow: Vec<IpNet>,
+    deny: Vec<IpNet>,
+    deny_body: String,
+}
+
+impl IpAccess {
+    pub fn new(config: &IpAccessConfig) -> Self {
+        Self {
+            allow: config.allow.clone(),
+            deny: config.deny.clone(),
+            deny_body: config.deny_body.clone(),
+        }
+    }
+
+    /// Deny entries win; with an allow list, only the clients on it get in
+    pub fn is_allowed(&self, ip: IpAddr) -> bool {
+        // IPv4 clients on a dual-stack socket show up as ::ffff:a.b.c.d
+        let ip = ip.to_canonical();
+        if self.deny.iter().any(|net| net.contains(&ip)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|net| net.contains(&ip))
+    }
+
+    /// 403 response for a denied client
+    pub fn forbidden(&self) -> Response<Body> {
+        Response::builder()
+            .status(StatusCode::FORBIDDEN)
+            .header("content-type", "text/plain; charset=utf-8")
+            .body(Body::from(self.deny_body.clone()))
+            .unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn access(allow: &[&str], deny: &[&str]) -> IpAccess {
+        let nets = |nets: &[&str]| nets.iter().map(|net| net.parse().unwrap()).collect();
+        IpAccess::new(&IpAccessConfig {
+            allow: nets(allow),
+            deny: nets(deny),
+            deny_body: "no".to_string(),
+        })
+    }
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn test_allow_list() {
+        let access = access(&["10.0.0.0/8", "fd00::/8"], &["10.0.0.13/32"]);
+        assert!(access.is_allowed(ip("10.1.2.3")));
+        assert!(access.is_allowed(ip("fd00::1")));
+        assert!(!access.is_allowed(ip("10.0.0.13")));
+        assert!(!access.is_allowed(ip("192.168.1.1")));
+        assert!(!access.is_allowed(ip("2001:db8::1")));
+        // IPv4-mapped IPv6 is matched as IPv4
+        assert!(access.is_allowed(ip("::ffff:10.1.2.3")));
+    }
+
+    #[test]
+    fn test_deny_list() {
+        let access = access(&[], &["203.0.113.0/24", "2001:db8::/32"]);
+        assert!(access.is_allowed(ip("198.51.100.1")));
+        assert!(!access.is_allowed(ip("203.0.113.9")));
+        assert!(!access.is_allowed(ip("2001:db8::1")));
+        assert_eq!(access.forbidden().status(), StatusCode::FORBIDDEN);
+    }
+}
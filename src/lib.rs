@@ -2,8 +2,33 @@
 //!
 //! This library provides the core proxy functionality.
 
+pub mod admin;
+pub mod auth;
+pub mod body_filter;
+pub mod cache;
+pub mod capture;
+pub mod circuit_breaker;
+pub mod cli;
+pub mod compression;
+pub mod concurrency;
 pub mod config;
+pub mod cors;
+pub mod error_pages;
+pub mod forward_auth;
+pub mod har;
+pub mod headers;
+pub mod ip_access;
+pub mod limits;
+pub mod maintenance;
+pub mod oidc;
 pub mod proxy;
+pub mod proxy_protocol;
+pub mod rate_limit;
+pub mod response_rewrite;
+pub mod rewrite;
+pub mod routes;
+pub mod security_headers;
+pub mod static_files;
 pub mod tls;
 
-pub use proxy::proxy_handler;
+pub use proxy::{handle_request, proxy_handler, ProxyContext};
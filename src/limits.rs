@@ -0,0 +1,250 @@
+//! Request header limits and request and response body size limits.
+
+use axum::{
+    body::{Body, Bytes, HttpBody},
+    http::{
+        header::{CONNECTION, CONTENT_LENGTH},
+        HeaderMap, HeaderValue, Request, Response, StatusCode,
+    },
+};
+use futures_util::StreamExt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use crate::config::LimitsConfig;
+use crate::error_pages::error_response;
+use crate::proxy::bad_gateway_response;
+
+/// A listener's or route's size limits
+#[derive(Debug, Clone)]
+pub struct Limits {
+    max_request_body_bytes: Option<u64>,
+    max_response_body_bytes: Option<u64>,
+    max_header_count: Option<usize>,
+    max_header_bytes: Option<usize>,
+}
+
+/// Set once a streamed request body has gone over its limit
+#[derive(Debug, Clone, Default)]
+pub struct BodyLimitGuard(Arc<AtomicBool>);
+
+impl BodyLimitGuard {
+    /// Whether the request body was cut off at the limit
+    pub fn exceeded(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+impl Limits {
+    pub fn new(config: &LimitsConfig) -> Self {
+        Self {
+            max_request_body_bytes: config.max_request_body_bytes,
+            max_response_body_bytes: config.max_response_body_bytes,
+            max_header_count: config.max_header_count,
+            max_header_bytes: config.max_header_bytes,
+        }
+    }
+
+    /// The response refusing a request whose headers or declared body
+    /// length are over the limits
+    pub fn reject(&self, headers: &HeaderMap) -> Option<Response<Body>> {
+        if self.max_header_count.is_some_and(|max| headers.len() > max) {
+            return Some(headers_too_large());
+        }
+        if let Some(max) = self.max_header_bytes {
+            let size: usize = headers
+                .iter()
+                .map(|(name, value)| name.as_str().len() + value.len())
+                .sum();
+            if size > max {
+                return Some(headers_too_large());
+            }
+        }
+        let too_long = self
+            .max_request_body_bytes
+            .zip(content_length(headers))
+            .is_some_and(|(max, length)| length > max);
+        too_long.then(payload_too_large)
+    }
+
+    /// Cut the request body off at the limit. The guard tells whether that
+    /// happened, so the failed upload can be answered with 413.
+    pub fn limit_request_body(&self, req: &mut Request<Body>) -> BodyLimitGuard {
+        let guard = BodyLimitGuard::default();
+        let Some(max) = self.max_request_body_bytes else {
+            return guard;
+        };
+        // Leave empty bodies alone so they don't turn into chunked uploads,
+        // and bodies of a declared length within the limit, which hyper
+        // already holds to that length
+        if req.body().is_end_stream()
+            || content_length(req.headers()).is_some_and(|length| length <= max)
+        {
+            return guard;
+        }
+        let exceeded = guard.0.clone();
+        let body = std::mem::take(req.body_mut());
+        *req.body_mut() = limit_body(body, max, move || {
+            exceeded.store(true, Ordering::Relaxed);
+            std::io::Error::other("request body too large")
+        });
+        guard
+    }
+
+    /// Refuse responses declared larger than the limit, and cut off
+    /// streamed ones that grow past it
+    pub fn check_response(&self, response: Response<Body>) -> Response<Body> {
+        let Some(max) = self.max_response_body_bytes else {
+            return response;
+        };
+        if content_length(response.headers()).is_some_and(|length| length > max) {
+            return bad_gateway_response("upstream response too large");
+        }
+        response.map(|body| {
+            limit_body(body, max, move || {
+                tracing::warn!("Response body cut off at {} bytes", max);
+                std::io::Error::other("response body too large")
+            })
+        })
+    }
+}
+
+/// `body`, failing with `on_exceeded`'s error once more than `max` bytes
+/// have passed
+fn limit_body<F>(body: Body, max: u64, on_exceeded: F) -> Body
+where
+    F: Fn() -> std::io::Error + Send + 'static,
+{
+    let mut seen: u64 = 0;
+    let stream = body.into_data_stream().map(move |chunk| {
+        let chunk: Bytes = chunk.map_err(std::io::Error::other)?;
+        seen += chunk.len() as u64;
+        if seen > max {
+            return Err(on_exceeded());
+        }
+        Ok(chunk)
+    });
+    Body::from_stream(stream)
+}
+
+fn content_length(headers: &HeaderMap) -> Option<u64> {
+    headers
+        .get(CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().parse().ok())
+}
+
+/// 413 for request bodies over the limit
+pub fn payload_too_large() -> Response<Body> {
+    let mut response = error_response(StatusCode::PAYLOAD_TOO_LARGE, "");
+    response
+        .headers_mut()
+        .insert(CONNECTION, HeaderValue::from_static("close"));
+    response
+}
+
+fn headers_too_large() -> Response<Body> {
+    error_response(StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE, "")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http_body_util::BodyExt;
+
+    fn limits(yaml: &str) -> Limits {
+        Limits::new(&serde_yaml::from_str(yaml).unwrap())
+    }
+
+    fn chunked(chunks: &[&'static str]) -> Body {
+        Body::from_stream(futures_util::stream::iter(
+            chunks
+                .iter()
+                .map(|chunk| Ok::<_, std::io::Error>(Bytes::from_static(chunk.as_bytes())))
+                .collect::<Vec<_>>(),
+        ))
+    }
+
+    #[test]
+    fn test_header_limits() {
+        let limits = limits("max_header_count: 2\nmax_header_bytes: 40\n");
+        let request = |headers: &[(&str, &str)]| {
+            let mut builder = Request::builder();
+            for (name, value) in headers {
+                builder = builder.header(*name, *value);
+            }
+            builder.body(Body::empty()).unwrap()
+        };
+
+        assert!(limits
+            .reject(request(&[("host", "a"), ("accept", "*/*")]).headers())
+            .is_none());
+        let response = limits
+            .reject(request(&[("a", "1"), ("b", "2"), ("c", "3")]).headers())
+            .unwrap();
+        assert_eq!(
+            response.status(),
+            StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE
+        );
+        let long = "x".repeat(40);
+        assert!(limits
+            .reject(request(&[("cookie", &long)]).headers())
+            .is_some());
+    }
+
+    #[tokio::test]
+    async fn test_request_body_limit() {
+        let limits = limits("max_request_body_bytes: 8\n");
+
+        let req = Request::post("/")
+            .header("content-length", "9")
+            .body(Body::from("123456789"))
+            .unwrap();
+        let response = limits.reject(req.headers()).unwrap();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        // Chunked uploads are cut off as they go over
+        let mut req = Request::post("/")
+            .body(chunked(&["1234", "5678", "9"]))
+            .unwrap();
+        let guard = limits.limit_request_body(&mut req);
+        assert!(req.into_body().collect().await.is_err());
+        assert!(guard.exceeded());
+
+        // Bodies that are empty or declare their length are left as they are
+        let mut req = Request::delete("/").body(Body::empty()).unwrap();
+        limits.limit_request_body(&mut req);
+        assert!(req.body().is_end_stream());
+        let mut req = Request::post("/")
+            .header("content-length", "4")
+            .body(Body::from("1234"))
+            .unwrap();
+        limits.limit_request_body(&mut req);
+        assert_eq!(req.body().size_hint().exact(), Some(4));
+
+        let mut req = Request::post("/").body(chunked(&["1234", "5678"])).unwrap();
+        let guard = limits.limit_request_body(&mut req);
+        let body = req.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(&body[..], b"12345678");
+        assert!(!guard.exceeded());
+    }
+
+    #[tokio::test]
+    async fn test_response_body_limit() {
+        let limits = limits("max_response_body_bytes: 4\n");
+
+        let response = Response::builder()
+            .header("content-length", "5")
+            .body(Body::from("12345"))
+            .unwrap();
+        assert_eq!(
+            limits.check_response(response).status(),
+            StatusCode::BAD_GATEWAY
+        );
+
+        let response = limits.check_response(Response::new(chunked(&["12", "345"])));
+        assert!(response.into_body().collect().await.is_err());
+        let response = limits.check_response(Response::new(chunked(&["12", "34"])));
+        assert!(response.into_body().collect().await.is_ok());
+    }
+}
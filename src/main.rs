@@ -1,11 +1,14 @@
+use https_proxy::admin::{self, AdminState};
+use https_proxy::capture::CaptureStore;
 use https_proxy::config::Config;
-use https_proxy::proxy_handler;
+use https_proxy::proxy_protocol::{ConnectionAddrs, ProxyProtocolAcceptor};
+use https_proxy::{handle_request, ProxyContext};
 
 use std::net::SocketAddr;
 use std::sync::Arc;
 
-use axum::{routing::any, Router};
-use axum_server::tls_rustls::RustlsConfig;
+use axum::{body::Body, extract::ConnectInfo, http::Request, routing::any, Router};
+use axum_server::tls_rustls::{RustlsAcceptor, RustlsConfig};
 use hyper_util::client::legacy::Client;
 use hyper_util::rt::TokioExecutor;
 
@@ -17,6 +20,12 @@ const DEFAULT_CONFIG_PATH: &str = "/etc/proxy/routes.yaml";
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
+    // Subcommands talk to an already running proxy
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.first().map(String::as_str) == Some("replay") {
+        return https_proxy::cli::replay(&args[1..]).await;
+    }
+
     // Install default crypto provider (required for rustls 0.23+)
     // Ignore error if already installed
     let _ = rustls::crypto::ring::default_provider().install_default();
@@ -38,6 +47,13 @@ async fn main() -> anyhow::Result<()> {
     tracing::info!("Loaded {} listeners", config.listeners.len());
     for listener in &config.listeners {
         tracing::info!("  :{} -> {}", listener.port, listener.target);
+        for route in &listener.routes {
+            match (&route.target, &route.static_dir) {
+                (Some(target), _) => tracing::info!("    {} -> {}", route.path, target),
+                (None, Some(dir)) => tracing::info!("    {} -> static {}", route.path, dir),
+                (None, None) => {}
+            }
+        }
     }
 
     // Create insecure TLS config for upstream connections
@@ -49,6 +65,7 @@ async fn main() -> anyhow::Result<()> {
     let https = hyper_rustls::HttpsConnectorBuilder::new()
         .with_tls_config(https_client_config)
         .https_or_http()
+        .with_server_name_resolver(https_proxy::tls::UpstreamServerNameResolver)
         .enable_http1()
         .build();
     let http_client = Arc::new(Client::builder(TokioExecutor::new()).build(https));
@@ -62,38 +79,74 @@ async fn main() -> anyhow::Result<()> {
 
     let rustls_config = RustlsConfig::from_pem_file(&cert_path, &key_path).await?;
 
+    // Request history shared by all listeners and the admin API
+    let capture = config
+        .capture
+        .as_ref()
+        .map(|capture_config| Arc::new(CaptureStore::new(capture_config)));
+
+    // Build per-listener contexts up front so the admin API can replay through them
+    let mut contexts = Vec::new();
+    for listener_config in &config.listeners {
+        if let Some(har_config) = &listener_config.har {
+            tracing::info!(
+                "  :{} writing HAR to {}",
+                listener_config.port,
+                har_config.path
+            );
+        }
+        contexts.push(Arc::new(ProxyContext::new(
+            listener_config,
+            http_client.clone(),
+            ws_client_config.clone(),
+            capture.clone(),
+        )?));
+    }
+
     // Spawn a task for each listener
     let mut handles = Vec::new();
 
-    for listener_config in config.listeners {
+    if let Some(admin_config) = config.admin.clone() {
+        let state = AdminState {
+            capture: capture.clone(),
+            listeners: contexts.iter().map(|ctx| (ctx.port, ctx.clone())).collect(),
+        };
+        handles.push(tokio::spawn(async move {
+            if let Err(e) = admin::serve(admin_config, state).await {
+                tracing::error!("Admin API failed: {}", e);
+            }
+        }));
+    }
+
+    for ctx in contexts {
         let rustls_config = rustls_config.clone();
-        let http_client = http_client.clone();
-        let client_tls_config = ws_client_config.clone();
-        let target = listener_config.target.clone();
-        let port = listener_config.port;
+        let port = ctx.port;
+        let target = ctx.target.clone();
+        let accept_proxy = ctx.proxy_protocol.accept;
 
         let handle = tokio::spawn(async move {
             let addr = SocketAddr::from(([0, 0, 0, 0], port));
 
-            // Create router with the target baked in
-            let app = Router::new().fallback(any({
-                let target = target.clone();
-                let http_client = http_client.clone();
-                let client_tls_config = client_tls_config.clone();
-                move |connect_info, req| {
-                    let target = target.clone();
-                    let http_client = http_client.clone();
-                    let client_tls_config = client_tls_config.clone();
-                    async move {
-                        proxy_handler(connect_info, req, target, http_client, client_tls_config)
-                            .await
-                    }
-                }
-            }));
+            // Create router with the listener context baked in
+            let app = Router::new().fallback(any(
+                move |ConnectInfo(peer_addr): ConnectInfo<SocketAddr>, req: Request<Body>| {
+                    let ctx = ctx.clone();
+                    // Prefer the client address reported via PROXY protocol
+                    let client_addr = req
+                        .extensions()
+                        .get::<ConnectionAddrs>()
+                        .map_or(peer_addr, |addrs| addrs.client);
+                    async move { handle_request(&ctx, client_addr, req).await }
+                },
+            ));
 
             tracing::info!("HTTPS listener on :{} -> {}", port, target);
 
-            if let Err(e) = axum_server::bind_rustls(addr, rustls_config)
+            // Read the PROXY header (if enabled) before the TLS handshake
+            let acceptor = RustlsAcceptor::new(rustls_config)
+                .acceptor(ProxyProtocolAcceptor::new(accept_proxy));
+            if let Err(e) = axum_server::bind(addr)
+                .acceptor(acceptor)
                 .serve(app.into_make_service_with_connect_info::<SocketAddr>())
                 .await
             {
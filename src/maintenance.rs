@@ -0,0 +1,171 @@
+//! Maintenance mode: a listener or route answering 503 to everyone but an
+//! allowlist, switched on in the config or through the admin API.
+
+use anyhow::Context;
+use axum::{
+    body::Body,
+    http::{header::CONTENT_TYPE, HeaderMap, HeaderName, HeaderValue, Response, StatusCode},
+};
+use ipnet::IpNet;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::auth::constant_time_eq;
+use crate::config::MaintenanceConfig;
+use crate::error_pages::error_response;
+
+/// A listener's or route's maintenance switch
+#[derive(Debug)]
+pub struct Maintenance {
+    enabled: AtomicBool,
+    allow: Vec<IpNet>,
+    allow_headers: Vec<(HeaderName, String)>,
+    retry_after_secs: u64,
+    /// HTML page served instead of the plain 503
+    page: Option<String>,
+}
+
+impl Default for Maintenance {
+    fn default() -> Self {
+        Self::new(&MaintenanceConfig::default()).unwrap()
+    }
+}
+
+impl Maintenance {
+    pub fn new(config: &MaintenanceConfig) -> anyhow::Result<Self> {
+        let allow_headers = config
+            .allow_headers
+            .iter()
+            .map(|(name, value)| {
+                let name = HeaderName::from_bytes(name.as_bytes()).with_context(|| {
+                    format!("invalid maintenance allow_headers name {:?}", name)
+                })?;
+                Ok((name, value.clone()))
+            })
+            .collect::<anyhow::Result<_>>()?;
+        let page = config
+            .page
+            .as_ref()
+            .map(|path| {
+                std::fs::read_to_string(path)
+                    .with_context(|| format!("failed to read maintenance page {}", path))
+            })
+            .transpose()?;
+
+        Ok(Self {
+            enabled: AtomicBool::new(config.enabled),
+            allow: config.allow.clone(),
+            allow_headers,
+            retry_after_secs: config.retry_after_secs,
+            page,
+        })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    pub fn set_enabled(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::Relaxed);
+    }
+
+    /// Whether a request gets through to the upstream during maintenance:
+    /// from an allowed IP, or carrying one of the allowed header values
+    pub fn lets_through(&self, headers: &HeaderMap, client_ip: IpAddr) -> bool {
+        let ip = client_ip.to_canonical();
+        self.allow.iter().any(|net| net.contains(&ip))
+            || self.allow_headers.iter().any(|(name, value)| {
+                headers
+                    .get_all(name)
+                    .iter()
+                    .any(|v| constant_time_eq(v.as_bytes(), value.as_bytes()))
+            })
+    }
+
+    /// Remove the bypass headers, so their secrets don't reach the upstream
+    /// or the request history
+    pub fn strip_bypass_headers(&self, headers: &mut HeaderMap) {
+        for (name, _) in &self.allow_headers {
+            headers.remove(name);
+        }
+    }
+
+    /// 503 response for requests turned away
+    pub fn response(&self) -> Response<Body> {
+        let mut response = match &self.page {
+            Some(page) => Response::builder()
+                .status(StatusCode::SERVICE_UNAVAILABLE)
+                .header(CONTENT_TYPE, "text/html; charset=utf-8")
+                .body(Body::from(page.clone()))
+                .unwrap(),
+            None => error_response(StatusCode::SERVICE_UNAVAILABLE, "down for maintenance"),
+        };
+        let headers = response.headers_mut();
+        headers.insert("retry-after", HeaderValue::from(self.retry_after_secs));
+        headers.insert("cache-control", HeaderValue::from_static("no-store"));
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http_body_util::BodyExt;
+    use std::io::Write;
+    use tempfile::NamedTempFile;
+
+    fn parse(yaml: &str) -> Maintenance {
+        Maintenance::new(&serde_yaml::from_str(yaml).unwrap()).unwrap()
+    }
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn test_allowlist() {
+        let maintenance = parse(
+            "enabled: true\nallow: [10.0.0.0/8]\nallow_headers:\n  X-Maintenance-Bypass: s3cret\n",
+        );
+        assert!(maintenance.is_enabled());
+
+        let mut headers = HeaderMap::new();
+        assert!(maintenance.lets_through(&headers, ip("10.1.2.3")));
+        assert!(maintenance.lets_through(&headers, ip("::ffff:10.1.2.3")));
+        assert!(!maintenance.lets_through(&headers, ip("192.168.1.1")));
+
+        headers.insert("x-maintenance-bypass", HeaderValue::from_static("wrong"));
+        assert!(!maintenance.lets_through(&headers, ip("192.168.1.1")));
+        headers.insert("x-maintenance-bypass", HeaderValue::from_static("s3cret"));
+        assert!(maintenance.lets_through(&headers, ip("192.168.1.1")));
+
+        headers.insert("x-other", HeaderValue::from_static("kept"));
+        maintenance.strip_bypass_headers(&mut headers);
+        assert!(headers.get("x-maintenance-bypass").is_none());
+        assert_eq!(headers["x-other"], "kept");
+    }
+
+    #[tokio::test]
+    async fn test_response() {
+        let maintenance = parse("retry_after_secs: 120\n");
+        assert!(!maintenance.is_enabled());
+        maintenance.set_enabled(true);
+        assert!(maintenance.is_enabled());
+
+        let response = maintenance.response();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers()["retry-after"], "120");
+
+        let mut page = NamedTempFile::new().unwrap();
+        page.write_all(b"<h1>Back soon</h1>").unwrap();
+        let maintenance = parse(&format!("page: {}\n", page.path().display()));
+        let response = maintenance.response();
+        assert_eq!(
+            response.headers()["content-type"],
+            "text/html; charset=utf-8"
+        );
+        assert_eq!(response.headers()["retry-after"], "300");
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(&body[..], b"<h1>Back soon</h1>");
+    }
+}
@@ -0,0 +1,848 @@
+//! OpenID Connect login in front of listeners and routes, in the style of
+//! oauth2-proxy: browsers without a session are sent to the provider, the
+//! callback is handled here and identity claims are forwarded as headers.
+
+use anyhow::Context;
+use axum::{
+    body::Body,
+    extract::Query,
+    http::{
+        header::{ACCEPT, CONTENT_TYPE, COOKIE, HOST, LOCATION, SET_COOKIE},
+        request::Parts,
+        HeaderMap, HeaderName, HeaderValue, Method, Request, Response, StatusCode,
+    },
+};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use http_body_util::BodyExt;
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use rand::Rng;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use ring::digest::{digest, SHA256};
+use ring::signature::{
+    RsaPublicKeyComponents, UnparsedPublicKey, ECDSA_P256_SHA256_FIXED, RSA_PKCS1_2048_8192_SHA256,
+};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::RwLock;
+
+use crate::auth::Authenticated;
+use crate::config::OidcConfig;
+use crate::proxy::HttpClient;
+
+/// Everything but RFC 3986 unreserved characters
+const FORM_VALUE: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+/// How long a login may take between the redirect and the callback
+const LOGIN_TTL_SECS: u64 = 600;
+
+/// Endpoints and signing keys from the provider's discovery document
+#[derive(Debug)]
+struct Provider {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    keys: Vec<Jwk>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Discovery {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Jwks {
+    keys: Vec<Jwk>,
+}
+
+/// A public key from the provider's JWKS
+#[derive(Debug, Clone, Deserialize)]
+struct Jwk {
+    kty: String,
+    #[serde(default)]
+    kid: Option<String>,
+    #[serde(default)]
+    n: Option<String>,
+    #[serde(default)]
+    e: Option<String>,
+    #[serde(default)]
+    crv: Option<String>,
+    #[serde(default)]
+    x: Option<String>,
+    #[serde(default)]
+    y: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct JwtHeader {
+    alg: String,
+    #[serde(default)]
+    kid: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+/// An in-progress login, kept in a cookie until the callback
+#[derive(Debug, Serialize, Deserialize)]
+struct LoginState {
+    state: String,
+    nonce: String,
+    verifier: String,
+    redirect_uri: String,
+    /// Path and query to return to after logging in
+    url: String,
+    exp: u64,
+}
+
+/// A logged-in browser's session cookie
+#[derive(Debug, Serialize, Deserialize)]
+struct Session {
+    claims: BTreeMap<String, String>,
+    exp: u64,
+}
+
+/// OIDC login for a listener or route
+pub struct OidcAuth {
+    issuer: String,
+    client_id: String,
+    client_secret: String,
+    scopes: String,
+    callback_path: String,
+    sign_out_path: String,
+    cookie_name: String,
+    state_cookie_name: String,
+    session_ttl_secs: u64,
+    /// Claims forwarded upstream and the headers they go in
+    claim_headers: Vec<(String, HeaderName)>,
+    cookie_key: LessSafeKey,
+    provider: RwLock<Option<Arc<Provider>>>,
+}
+
+impl std::fmt::Debug for OidcAuth {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("OidcAuth")
+            .field("issuer", &self.issuer)
+            .field("client_id", &self.client_id)
+            .field("callback_path", &self.callback_path)
+            .finish_non_exhaustive()
+    }
+}
+
+impl OidcAuth {
+    pub fn new(config: &OidcConfig) -> anyhow::Result<Self> {
+        let claim_headers = config
+            .claim_headers
+            .iter()
+            .map(|(claim, header)| {
+                let name = HeaderName::from_bytes(header.as_bytes())
+                    .with_context(|| format!("invalid claim header {:?}", header))?;
+                Ok((claim.clone(), name))
+            })
+            .collect::<anyhow::Result<_>>()?;
+        if !is_cookie_name(&config.cookie_name) {
+            anyhow::bail!("invalid OIDC cookie_name {:?}", config.cookie_name);
+        }
+        // Any secret length works; the AES-256 key is its SHA-256
+        let key = digest(&SHA256, config.cookie_secret.as_bytes());
+        let cookie_key = LessSafeKey::new(
+            UnboundKey::new(&AES_256_GCM, key.as_ref())
+                .map_err(|_| anyhow::anyhow!("invalid cookie key"))?,
+        );
+
+        Ok(Self {
+            issuer: config.issuer.trim_end_matches('/').to_string(),
+            client_id: config.client_id.clone(),
+            client_secret: config.client_secret.clone(),
+            scopes: config.scopes.join(" "),
+            callback_path: config.callback_path.clone(),
+            sign_out_path: config.sign_out_path.clone(),
+            cookie_name: config.cookie_name.clone(),
+            state_cookie_name: format!("{}_state", config.cookie_name),
+            session_ttl_secs: config.session_ttl_secs,
+            claim_headers,
+            cookie_key,
+            provider: RwLock::new(None),
+        })
+    }
+
+    /// Whether `path` is this login's callback or sign-out path
+    pub fn is_endpoint(&self, path: &str) -> bool {
+        path == self.callback_path || path == self.sign_out_path
+    }
+
+    /// Handle a request to the callback or sign-out path
+    pub async fn endpoint(&self, client: &HttpClient, req: Request<Body>) -> Response<Body> {
+        let (req, _) = req.into_parts();
+        if req.uri.path() == self.sign_out_path {
+            let mut response = redirect("/");
+            response
+                .headers_mut()
+                .append(SET_COOKIE, clear_cookie(&self.cookie_name));
+            return response;
+        }
+
+        match self.callback(client, &req).await {
+            Ok(response) => response,
+            Err(e) => {
+                tracing::warn!("OIDC login via {} failed: {:#}", self.issuer, e);
+                text_response(StatusCode::FORBIDDEN, "403 Login failed")
+            }
+        }
+    }
+
+    /// Check the request's session cookie. Logged-in requests get the
+    /// claim headers; browsers without a session are redirected to log in
+    /// and other clients get a 401.
+    pub async fn authenticate(
+        &self,
+        client: &HttpClient,
+        req: &mut Request<Body>,
+    ) -> Result<(), Response<Body>> {
+        // Never pass on identity headers the client set itself
+        for (_, header) in &self.claim_headers {
+            req.headers_mut().remove(header);
+        }
+
+        let session: Option<Session> = cookie(req.headers(), &self.cookie_name)
+            .and_then(|value| self.open(&self.cookie_name, value))
+            .filter(|session: &Session| session.exp > now());
+        if let Some(session) = session {
+            strip_cookies(
+                req.headers_mut(),
+                &[&self.cookie_name, &self.state_cookie_name],
+            );
+            for (claim, header) in &self.claim_headers {
+                if let Some(value) = session
+                    .claims
+                    .get(claim)
+                    .and_then(|value| HeaderValue::from_str(value).ok())
+                {
+                    req.headers_mut().insert(header.clone(), value);
+                }
+            }
+            req.extensions_mut().insert(Authenticated);
+            return Ok(());
+        }
+
+        if !is_browser_navigation(req) {
+            return Err(text_response(StatusCode::UNAUTHORIZED, "401 Unauthorized"));
+        }
+        // Only the head is needed, and unlike the body it can be shared
+        let (parts, body) = std::mem::take(req).into_parts();
+        let login = self.login(client, &parts).await;
+        *req = Request::from_parts(parts, body);
+        match login {
+            Ok(response) => Err(response),
+            Err(e) => {
+                tracing::error!("OIDC provider {} unavailable: {:#}", self.issuer, e);
+                Err(text_response(
+                    StatusCode::BAD_GATEWAY,
+                    "502 Login provider unavailable",
+                ))
+            }
+        }
+    }
+
+    /// Redirect to the provider's login page, remembering where to return
+    async fn login(&self, client: &HttpClient, req: &Parts) -> anyhow::Result<Response<Body>> {
+        let host = req
+            .headers
+            .get(HOST)
+            .and_then(|v| v.to_str().ok())
+            .or_else(|| req.uri.authority().map(|a| a.as_str()))
+            .context("request has no host")?;
+        let provider = self.provider(client, false).await?;
+
+        let verifier = random_token(32);
+        let login = LoginState {
+            state: random_token(16),
+            nonce: random_token(16),
+            redirect_uri: format!("https://{}{}", host, self.callback_path),
+            url: return_url(req.uri.path_and_query().map_or("/", |pq| pq.as_str())).to_string(),
+            exp: now() + LOGIN_TTL_SECS,
+            verifier,
+        };
+        let challenge = URL_SAFE_NO_PAD.encode(digest(&SHA256, login.verifier.as_bytes()));
+        let separator = if provider.authorization_endpoint.contains('?') {
+            '&'
+        } else {
+            '?'
+        };
+        let location = format!(
+            "{}{}{}",
+            provider.authorization_endpoint,
+            separator,
+            form(&[
+                ("response_type", "code"),
+                ("client_id", &self.client_id),
+                ("redirect_uri", &login.redirect_uri),
+                ("scope", &self.scopes),
+                ("state", &login.state),
+                ("nonce", &login.nonce),
+                ("code_challenge", &challenge),
+                ("code_challenge_method", "S256"),
+            ])
+        );
+
+        tracing::info!("Redirecting {} to log in at {}", login.url, self.issuer);
+        let mut response = redirect(&location);
+        response.headers_mut().append(
+            SET_COOKIE,
+            set_cookie(
+                &self.state_cookie_name,
+                &self.seal(&self.state_cookie_name, &login),
+                LOGIN_TTL_SECS,
+            ),
+        );
+        Ok(response)
+    }
+
+    /// Exchange the authorization code and start a session
+    async fn callback(&self, client: &HttpClient, req: &Parts) -> anyhow::Result<Response<Body>> {
+        let Query(params) = Query::<HashMap<String, String>>::try_from_uri(&req.uri)?;
+        if let Some(error) = params.get("error") {
+            anyhow::bail!("provider returned {}", error);
+        }
+        let login: LoginState = cookie(&req.headers, &self.state_cookie_name)
+            .and_then(|value| self.open(&self.state_cookie_name, value))
+            .filter(|login: &LoginState| login.exp > now())
+            .context("missing or expired login state cookie")?;
+        if params.get("state") != Some(&login.state) {
+            anyhow::bail!("state does not match");
+        }
+        let code = params.get("code").context("callback has no code")?;
+
+        let provider = self.provider(client, false).await?;
+        let mut params = vec![
+            ("grant_type", "authorization_code"),
+            ("code", code.as_str()),
+            ("redirect_uri", login.redirect_uri.as_str()),
+            ("client_id", self.client_id.as_str()),
+            ("code_verifier", login.verifier.as_str()),
+        ];
+        if !self.client_secret.is_empty() {
+            params.push(("client_secret", &self.client_secret));
+        }
+        let token_request = Request::post(&provider.token_endpoint)
+            .header(CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .header(ACCEPT, "application/json")
+            .body(Body::from(form(&params)))?;
+        let tokens: TokenResponse = fetch_json(client, token_request).await?;
+
+        let claims = match verify_id_token(
+            &tokens.id_token,
+            &provider,
+            &self.client_id,
+            &login.nonce,
+            now(),
+        ) {
+            // The provider may have rotated its keys since we fetched them
+            Err(e) if e.is::<UnknownKey>() => {
+                let provider = self.provider(client, true).await?;
+                verify_id_token(
+                    &tokens.id_token,
+                    &provider,
+                    &self.client_id,
+                    &login.nonce,
+                    now(),
+                )?
+            }
+            result => result?,
+        };
+
+        let session = Session {
+            claims: self
+                .claim_headers
+                .iter()
+                .filter_map(|(claim, _)| Some((claim.clone(), claim_value(claims.get(claim)?))))
+                .collect(),
+            exp: now() + self.session_ttl_secs,
+        };
+        tracing::info!(
+            "Logged in {} via {}",
+            claims.get("sub").map_or_else(String::new, claim_value),
+            self.issuer
+        );
+
+        let mut response = redirect(return_url(&login.url));
+        let headers = response.headers_mut();
+        headers.append(
+            SET_COOKIE,
+            set_cookie(
+                &self.cookie_name,
+                &self.seal(&self.cookie_name, &session),
+                self.session_ttl_secs,
+            ),
+        );
+        headers.append(SET_COOKIE, clear_cookie(&self.state_cookie_name));
+        Ok(response)
+    }
+
+    /// The provider's endpoints and keys, fetched on first use
+    async fn provider(&self, client: &HttpClient, refresh: bool) -> anyhow::Result<Arc<Provider>> {
+        if !refresh {
+            if let Some(provider) = self.provider.read().await.as_ref() {
+                return Ok(provider.clone());
+            }
+        }
+
+        let url = format!("{}/.well-known/openid-configuration", self.issuer);
+        let discovery: Discovery = fetch_json(client, Request::get(&url).body(Body::empty())?)
+            .await
+            .context("OIDC discovery failed")?;
+        let jwks: Jwks = fetch_json(
+            client,
+            Request::get(&discovery.jwks_uri).body(Body::empty())?,
+        )
+        .await
+        .context("fetching signing keys failed")?;
+        let provider = Arc::new(Provider {
+            issuer: discovery.issuer,
+            authorization_endpoint: discovery.authorization_endpoint,
+            token_endpoint: discovery.token_endpoint,
+            keys: jwks.keys,
+        });
+        *self.provider.write().await = Some(provider.clone());
+        Ok(provider)
+    }
+
+    /// Encrypt a cookie value, binding it to the cookie's name
+    fn seal<T: Serialize>(&self, name: &str, value: &T) -> String {
+        let nonce: [u8; NONCE_LEN] = rand::thread_rng().gen();
+        let mut data = serde_json::to_vec(value).expect("cookie values serialize");
+        self.cookie_key
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from(name.as_bytes()),
+                &mut data,
+            )
+            .expect("cookie values fit AES-GCM");
+        let mut sealed = nonce.to_vec();
+        sealed.extend_from_slice(&data);
+        URL_SAFE_NO_PAD.encode(sealed)
+    }
+
+    /// Decrypt a cookie written by `seal`, rejecting anything tampered with
+    fn open<T: DeserializeOwned>(&self, name: &str, value: &str) -> Option<T> {
+        let sealed = URL_SAFE_NO_PAD.decode(value).ok()?;
+        if sealed.len() < NONCE_LEN {
+            return None;
+        }
+        let (nonce, data) = sealed.split_at(NONCE_LEN);
+        let mut data = data.to_vec();
+        let plain = self
+            .cookie_key
+            .open_in_place(
+                Nonce::try_assume_unique_for_key(nonce).ok()?,
+                Aad::from(name.as_bytes()),
+                &mut data,
+            )
+            .ok()?;
+        serde_json::from_slice(plain).ok()
+    }
+}
+
+/// The ID token was signed with a key we don't have
+#[derive(Debug)]
+struct UnknownKey;
+
+impl std::fmt::Display for UnknownKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "ID token signed with an unknown key")
+    }
+}
+
+impl std::error::Error for UnknownKey {}
+
+/// Check an ID token's signature and claims, returning its claims
+fn verify_id_token(
+    token: &str,
+    provider: &Provider,
+    client_id: &str,
+    nonce: &str,
+    now: u64,
+) -> anyhow::Result<Map<String, Value>> {
+    let mut parts = token.split('.');
+    let (Some(header), Some(payload), Some(signature), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        anyhow::bail!("ID token is not a JWT");
+    };
+    let header: JwtHeader = serde_json::from_slice(&URL_SAFE_NO_PAD.decode(header)?)?;
+    let signature = URL_SAFE_NO_PAD.decode(signature)?;
+    let signed = &token.as_bytes()[..token.rfind('.').unwrap_or(0)];
+
+    let kty = match header.alg.as_str() {
+        "RS256" => "RSA",
+        "ES256" => "EC",
+        alg => anyhow::bail!("unsupported ID token algorithm {}", alg),
+    };
+    let key = provider
+        .keys
+        .iter()
+        .filter(|key| key.kty == kty)
+        .find(|key| header.kid.is_none() || key.kid == header.kid)
+        .ok_or(UnknownKey)?;
+    let field = |value: &Option<String>| -> anyhow::Result<Vec<u8>> {
+        Ok(URL_SAFE_NO_PAD.decode(value.as_deref().context("incomplete signing key")?)?)
+    };
+    let verified = match kty {
+        "RSA" => RsaPublicKeyComponents {
+            n: field(&key.n)?,
+            e: field(&key.e)?,
+        }
+        .verify(&RSA_PKCS1_2048_8192_SHA256, signed, &signature),
+        _ => {
+            if key.crv.as_deref() != Some("P-256") {
+                anyhow::bail!("unsupported EC curve {:?}", key.crv);
+            }
+            let mut point = vec![0x04];
+            point.extend(field(&key.x)?);
+            point.extend(field(&key.y)?);
+            UnparsedPublicKey::new(&ECDSA_P256_SHA256_FIXED, point).verify(signed, &signature)
+        }
+    };
+    verified.map_err(|_| anyhow::anyhow!("ID token signature is invalid"))?;
+
+    let claims: Map<String, Value> = serde_json::from_slice(&URL_SAFE_NO_PAD.decode(payload)?)?;
+    if claims.get("iss").and_then(Value::as_str) != Some(provider.issuer.as_str()) {
+        anyhow::bail!("ID token issuer does not match");
+    }
+    let audience_ok = match claims.get("aud") {
+        Some(Value::String(aud)) => aud == client_id,
+        Some(Value::Array(auds)) => auds.iter().any(|aud| aud.as_str() == Some(client_id)),
+        _ => false,
+    };
+    if !audience_ok {
+        anyhow::bail!("ID token is for another client");
+    }
+    if claims
+        .get("exp")
+        .and_then(Value::as_u64)
+        .is_none_or(|exp| exp <= now)
+    {
+        anyhow::bail!("ID token has expired");
+    }
+    if claims.get("nonce").and_then(Value::as_str) != Some(nonce) {
+        anyhow::bail!("ID token nonce does not match");
+    }
+    Ok(claims)
+}
+
+async fn fetch_json<T: DeserializeOwned>(
+    client: &HttpClient,
+    req: Request<Body>,
+) -> anyhow::Result<T> {
+    let uri = req.uri().clone();
+    let response = client
+        .request(req)
+        .await
+        .with_context(|| format!("request to {} failed", uri))?;
+    let status = response.status();
+    let body = response.into_body().collect().await?.to_bytes();
+    if !status.is_success() {
+        anyhow::bail!(
+            "{} returned {}: {}",
+            uri,
+            status,
+            String::from_utf8_lossy(&body)
+        );
+    }
+    serde_json::from_slice(&body).with_context(|| format!("invalid JSON from {}", uri))
+}
+
+/// Header value for a claim; lists are comma-separated
+fn claim_value(value: &Value) -> String {
+    match value {
+        Value::String(value) => value.clone(),
+        Value::Array(values) => values.iter().map(claim_value).collect::<Vec<_>>().join(","),
+        value => value.to_string(),
+    }
+}
+
+/// Top-level page loads, which can follow a redirect to the login page
+fn is_browser_navigation(req: &Request<Body>) -> bool {
+    (req.method() == Method::GET || req.method() == Method::HEAD)
+        && req
+            .headers()
+            .get(ACCEPT)
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|accept| accept.contains("text/html"))
+}
+
+fn cookie<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers
+        .get_all(COOKIE)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(cookie, _)| *cookie == name)
+        .map(|(_, value)| value)
+}
+
+/// Remove the named cookies from the `Cookie` header
+fn strip_cookies(headers: &mut HeaderMap, names: &[&str]) {
+    let kept: Vec<String> = headers
+        .get_all(COOKIE)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(';'))
+        .map(str::trim)
+        .filter(|pair| {
+            let name = pair.split_once('=').map_or(*pair, |(name, _)| name);
+            !pair.is_empty() && !names.contains(&name)
+        })
+        .map(str::to_string)
+        .collect();
+    headers.remove(COOKIE);
+    if let Ok(value) = HeaderValue::from_str(&kept.join("; ")) {
+        if !kept.is_empty() {
+            headers.insert(COOKIE, value);
+        }
+    }
+}
+
+/// Whether `name` is an RFC 6265 cookie name: a token of visible ASCII
+/// without separators
+fn is_cookie_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_graphic() && !b"()<>@,;:\\\"/[]?={}".contains(&b))
+}
+
+fn set_cookie(name: &str, value: &str, max_age: u64) -> HeaderValue {
+    HeaderValue::from_str(&format!(
+        "{}={}; Path=/; Max-Age={}; HttpOnly; Secure; SameSite=Lax",
+        name, value, max_age
+    ))
+    .expect("cookie names are validated and values are base64")
+}
+
+fn clear_cookie(name: &str) -> HeaderValue {
+    set_cookie(name, "", 0)
+}
+
+/// Where to go back to after logging in: a path on this host, never a
+/// protocol-relative `//host` or `/\host` that browsers would follow off-site
+fn return_url(path_and_query: &str) -> &str {
+    match path_and_query.as_bytes() {
+        [b'/', b'/' | b'\\', ..] => "/",
+        [b'/', ..] => path_and_query,
+        _ => "/",
+    }
+}
+
+fn redirect(location: &str) -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::FOUND)
+        .header(LOCATION, location)
+        .body(Body::empty())
+        .unwrap_or_else(|_| text_response(StatusCode::BAD_REQUEST, "400 Bad Request"))
+}
+
+fn text_response(status: StatusCode, body: &'static str) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "text/plain; charset=utf-8")
+        .body(Body::from(body))
+        .unwrap()
+}
+
+fn form(params: &[(&str, &str)]) -> String {
+    params
+        .iter()
+        .map(|(name, value)| {
+            format!(
+                "{}={}",
+                utf8_percent_encode(name, FORM_VALUE),
+                utf8_percent_encode(value, FORM_VALUE)
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+fn random_token(bytes: usize) -> String {
+    let mut rng = rand::thread_rng();
+    let token: Vec<u8> = (0..bytes).map(|_| rng.gen()).collect();
+    URL_SAFE_NO_PAD.encode(token)
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ring::rand::SystemRandom;
+    use ring::signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING};
+    use serde_json::json;
+
+    fn config() -> OidcConfig {
+        serde_yaml::from_str(
+            "issuer: https://idp.example\nclient_id: proxy\ncookie_secret: 0123456789abcdef\n",
+        )
+        .unwrap()
+    }
+
+    /// A P-256 signing key and the provider publishing it
+    fn provider() -> (EcdsaKeyPair, Provider) {
+        let rng = SystemRandom::new();
+        let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng).unwrap();
+        let key = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, pkcs8.as_ref(), &rng)
+            .unwrap();
+        let point = key.public_key().as_ref();
+        let jwk = Jwk {
+            kty: "EC".to_string(),
+            kid: Some("k1".to_string()),
+            n: None,
+            e: None,
+            crv: Some("P-256".to_string()),
+            x: Some(URL_SAFE_NO_PAD.encode(&point[1..33])),
+            y: Some(URL_SAFE_NO_PAD.encode(&point[33..])),
+        };
+        let provider = Provider {
+            issuer: "https://idp.example".to_string(),
+            authorization_endpoint: "https://idp.example/auth".to_string(),
+            token_endpoint: "https://idp.example/token".to_string(),
+            keys: vec![jwk],
+        };
+        (key, provider)
+    }
+
+    fn sign(key: &EcdsaKeyPair, kid: &str, claims: &Value) -> String {
+        let header = URL_SAFE_NO_PAD.encode(json!({"alg": "ES256", "kid": kid}).to_string());
+        let payload = URL_SAFE_NO_PAD.encode(claims.to_string());
+        let signed = format!("{}.{}", header, payload);
+        let signature = key.sign(&SystemRandom::new(), signed.as_bytes()).unwrap();
+        format!("{}.{}", signed, URL_SAFE_NO_PAD.encode(signature.as_ref()))
+    }
+
+    fn claims() -> Value {
+        json!({
+            "iss": "https://idp.example",
+            "aud": ["proxy", "other"],
+            "sub": "u-1",
+            "email": "alice@example.com",
+            "exp": 2000,
+            "nonce": "n-1",
+        })
+    }
+
+    #[test]
+    fn test_verify_id_token() {
+        let (key, provider) = provider();
+        let token = sign(&key, "k1", &claims());
+        let verified = verify_id_token(&token, &provider, "proxy", "n-1", 1000).unwrap();
+        assert_eq!(verified["email"], "alice@example.com");
+
+        // Expired, replayed or meant for someone else
+        assert!(verify_id_token(&token, &provider, "proxy", "n-1", 2000).is_err());
+        assert!(verify_id_token(&token, &provider, "proxy", "n-2", 1000).is_err());
+        assert!(verify_id_token(&token, &provider, "other-app", "n-1", 1000).is_err());
+
+        // Tampered with
+        let mut forged = verified.clone();
+        forged.insert("email".into(), json!("mallory@example.com"));
+        let (header, rest) = token.split_once('.').unwrap();
+        let signature = rest.split_once('.').unwrap().1;
+        let forged = format!(
+            "{}.{}.{}",
+            header,
+            URL_SAFE_NO_PAD.encode(Value::Object(forged).to_string()),
+            signature
+        );
+        assert!(verify_id_token(&forged, &provider, "proxy", "n-1", 1000).is_err());
+
+        // Signed by a key the provider doesn't publish
+        let token = sign(&key, "k2", &claims());
+        let err = verify_id_token(&token, &provider, "proxy", "n-1", 1000).unwrap_err();
+        assert!(err.is::<UnknownKey>());
+    }
+
+    #[test]
+    fn test_session_cookie() {
+        let auth = OidcAuth::new(&config()).unwrap();
+        let session = Session {
+            claims: BTreeMap::from([("email".to_string(), "alice@example.com".to_string())]),
+            exp: 10,
+        };
+        let sealed = auth.seal("_oauth2_proxy", &session);
+        let opened: Session = auth.open("_oauth2_proxy", &sealed).unwrap();
+        assert_eq!(opened.claims, session.claims);
+
+        // Bound to its cookie name and key
+        assert!(auth.open::<Session>("_other", &sealed).is_none());
+        let mut other = config();
+        other.cookie_secret = "another secret value".to_string();
+        let other = OidcAuth::new(&other).unwrap();
+        assert!(other.open::<Session>("_oauth2_proxy", &sealed).is_none());
+
+        let mut tampered = URL_SAFE_NO_PAD.decode(&sealed).unwrap();
+        tampered[NONCE_LEN] ^= 1;
+        assert!(auth
+            .open::<Session>("_oauth2_proxy", &URL_SAFE_NO_PAD.encode(tampered))
+            .is_none());
+    }
+
+    #[test]
+    fn test_cookies() {
+        let mut headers = HeaderMap::new();
+        headers.insert(COOKIE, HeaderValue::from_static("a=1; _oauth2_proxy=xyz"));
+        headers.append(COOKIE, HeaderValue::from_static("b=2"));
+        assert_eq!(cookie(&headers, "_oauth2_proxy"), Some("xyz"));
+        assert_eq!(cookie(&headers, "b"), Some("2"));
+
+        strip_cookies(&mut headers, &["_oauth2_proxy"]);
+        assert_eq!(headers.get(COOKIE).unwrap(), "a=1; b=2");
+        strip_cookies(&mut headers, &["a", "b"]);
+        assert!(headers.get(COOKIE).is_none());
+    }
+
+    #[test]
+    fn test_cookie_name() {
+        assert!(is_cookie_name("_oauth2_proxy"));
+        assert!(is_cookie_name("__Host-session"));
+        for name in ["", "a;b", "a=b", "a b", "sessión", "a\"b"] {
+            assert!(!is_cookie_name(name), "{:?}", name);
+            let mut config = config();
+            config.cookie_name = name.to_string();
+            assert!(OidcAuth::new(&config).is_err(), "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn test_return_url() {
+        assert_eq!(return_url("/reports?month=5"), "/reports?month=5");
+        assert_eq!(return_url("/"), "/");
+        assert_eq!(return_url("//evil.example/"), "/");
+        assert_eq!(return_url("/\\evil.example/"), "/");
+        assert_eq!(return_url("https://evil.example/"), "/");
+    }
+
+    #[test]
+    fn test_claim_value() {
+        assert_eq!(claim_value(&json!("alice")), "alice");
+        assert_eq!(claim_value(&json!(["admin", "dev"])), "admin,dev");
+        assert_eq!(claim_value(&json!(true)), "true");
+    }
+}
@@ -1,16 +1,296 @@
 use axum::{
     body::Body,
     extract::ConnectInfo,
-    http::{HeaderMap, HeaderValue, Request, Response, StatusCode, Uri, Version},
+    http::{
+        header::{ACCEPT, ACCEPT_ENCODING, HOST, IF_MODIFIED_SINCE, IF_NONE_MATCH, ORIGIN},
+        uri::Authority,
+        HeaderMap, HeaderValue, Method, Request, Response, StatusCode, Uri, Version,
+    },
 };
 use hyper_rustls::HttpsConnector;
 use hyper_util::client::legacy::{connect::HttpConnector, Client};
+use ipnet::IpNet;
+use rustls::pki_types::ServerName;
 use rustls::ClientConfig;
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
-use tokio_tungstenite::Connector;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::handshake::client::Request as WsRequest;
+use tokio_tungstenite::{Connector, MaybeTlsStream, WebSocketStream};
+
+use crate::auth::Authenticator;
+use crate::cache::{self, ResponseCache};
+use crate::capture::{self, CaptureStore};
+use crate::circuit_breaker::CircuitBreaker;
+use crate::compression::Compressor;
+use crate::concurrency::ConcurrencyLimiter;
+use crate::config::{HostHeader, Listener, PathJoin, ProxyProtocolConfig};
+use crate::cors::CorsPolicy;
+use crate::error_pages::{self, ErrorPages};
+use crate::forward_auth::ForwardAuth;
+use crate::har::HarWriter;
+use crate::headers::{HeaderRewriter, TemplateVars};
+use crate::ip_access::IpAccess;
+use crate::limits::{self, Limits};
+use crate::maintenance::Maintenance;
+use crate::oidc::OidcAuth;
+use crate::proxy_protocol::{self, ConnectionAddrs};
+use crate::rate_limit::{RateLimitStatus, RateLimiter};
+use crate::response_rewrite::{ResponseRewriter, UpstreamUrls};
+use crate::rewrite::Rewritten;
+use crate::routes::{CompiledRoute, RouteAction, RouteTable};
+use crate::security_headers::SecurityHeaders;
+use crate::static_files;
+
+pub type HttpClient = Arc<Client<HttpsConnector<HttpConnector>, Body>>;
+
+/// Per-listener state shared by every request on that listener
+#[derive(Clone)]
+pub struct ProxyContext {
+    /// Port the listener is bound to
+    pub port: u16,
+    /// Target upstream URL for requests no route matches (may be empty)
+    pub target: String,
+    /// How base paths in targets are joined with the request path
+    pub path_join: PathJoin,
+    /// Path-based routes, checked before `target`
+    pub routes: RouteTable,
+    pub http_client: HttpClient,
+    pub tls_config: Arc<ClientConfig>,
+    /// Request history, when capture is enabled
+    pub capture: Option<Arc<CaptureStore>>,
+    /// HAR file this listener's traffic is written to
+    pub har: Option<Arc<HarWriter>>,
+    /// Rules applied to request headers before forwarding
+    pub request_headers: HeaderRewriter,
+    /// Rules applied to upstream response headers
+    pub response_headers: HeaderRewriter,
+    /// `Host` header (and SNI) sent upstream, `None` forwards the client's
+    pub host_header: Option<HostHeader>,
+    /// Handling of X-Forwarded-* and `Forwarded` headers
+    pub forwarding: Forwarding,
+    /// PROXY protocol on the listener and towards the target
+    pub proxy_protocol: ProxyProtocolConfig,
+    /// Response compression, when enabled
+    pub compression: Option<Compressor>,
+    /// Response cache, when enabled
+    pub cache: Option<Arc<ResponseCache>>,
+    /// Listener-wide request rate limit
+    pub rate_limit: Option<Arc<RateLimiter>>,
+    /// Concurrent request limits per upstream target
+    pub concurrency: Option<Arc<ConcurrencyLimiter>>,
+    /// Circuit breakers per upstream target
+    pub circuit_breaker: Option<Arc<CircuitBreaker>>,
+    /// Credentials required on the listener, unless a route has its own
+    pub auth: Option<Arc<Authenticator>>,
+    /// OpenID Connect login required on the listener
+    pub oidc: Option<Arc<OidcAuth>>,
+    /// External auth service asked about every request on the listener
+    pub forward_auth: Option<Arc<ForwardAuth>>,
+    /// Client IPs allowed on or denied from the listener
+    pub ip_access: Option<Arc<IpAccess>>,
+    /// CORS policy, unless a route has its own
+    pub cors: Option<Arc<CorsPolicy>>,
+    /// Security headers added to every response
+    pub security_headers: Option<Arc<SecurityHeaders>>,
+    /// Rewriting of upstream URLs in response headers
+    pub response_rewrite: Option<Arc<ResponseRewriter>>,
+    /// Header and body size limits
+    pub limits: Option<Arc<Limits>>,
+    /// How long to wait for the upstream's response headers
+    pub upstream_timeout: Option<Duration>,
+    /// Error pages and problem documents for error responses
+    pub error_pages: Option<Arc<ErrorPages>>,
+    /// The listener's maintenance switch
+    pub maintenance: Arc<Maintenance>,
+}
+
+/// How incoming forwarding headers are treated on a listener
+#[derive(Debug, Clone, Default)]
+pub struct Forwarding {
+    /// Peers whose incoming forwarding headers are honoured
+    pub trusted_proxies: Vec<IpNet>,
+    /// Also emit the RFC 7239 `Forwarded` header
+    pub forwarded_header: bool,
+}
+
+impl Forwarding {
+    fn is_trusted(&self, ip: IpAddr) -> bool {
+        self.trusted_proxies.iter().any(|net| net.contains(&ip))
+    }
+
+    /// The original client: the right-most X-Forwarded-For entry that is not
+    /// a trusted proxy, or the peer itself when it isn't trusted
+    fn client_ip(&self, headers: &HeaderMap, peer: IpAddr) -> IpAddr {
+        if !self.is_trusted(peer) {
+            return peer;
+        }
+
+        let chain: Vec<IpAddr> = headers
+            .get_all("x-forwarded-for")
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .filter_map(|entry| entry.trim().parse().ok())
+            .collect();
+
+        chain
+            .iter()
+            .rev()
+            .find(|ip| !self.is_trusted(**ip))
+            .or(chain.first())
+            .copied()
+            .unwrap_or(peer)
+    }
+}
 
-type HttpClient = Arc<Client<HttpsConnector<HttpConnector>, Body>>;
+impl ProxyContext {
+    /// Build the context for a configured listener
+    pub fn new(
+        listener: &Listener,
+        http_client: HttpClient,
+        tls_config: Arc<ClientConfig>,
+        capture: Option<Arc<CaptureStore>>,
+    ) -> anyhow::Result<Self> {
+        let har = match &listener.har {
+            Some(har_config) => Some(Arc::new(HarWriter::open(har_config)?)),
+            None => None,
+        };
+
+        Ok(Self {
+            port: listener.port,
+            target: listener.target.clone(),
+            path_join: listener.path_join,
+            routes: RouteTable::new(&listener.routes)?,
+            http_client,
+            tls_config,
+            capture,
+            har,
+            request_headers: HeaderRewriter::new(&listener.request_headers)?,
+            response_headers: HeaderRewriter::new(&listener.response_headers)?,
+            host_header: listener.host_header.clone(),
+            forwarding: Forwarding {
+                trusted_proxies: listener.trusted_proxies.clone(),
+                forwarded_header: listener.forwarded_header,
+            },
+            proxy_protocol: listener.proxy_protocol.clone(),
+            compression: listener.compression.as_ref().map(Compressor::new),
+            cache: listener
+                .cache
+                .as_ref()
+                .map(|cache_config| Arc::new(ResponseCache::new(cache_config))),
+            rate_limit: listener
+                .rate_limit
+                .as_ref()
+                .map(|rate_limit| Arc::new(RateLimiter::new(rate_limit))),
+            concurrency: listener
+                .concurrency
+                .as_ref()
+                .map(|concurrency| Arc::new(ConcurrencyLimiter::new(concurrency))),
+            circuit_breaker: listener
+                .circuit_breaker
+                .as_ref()
+                .map(|circuit_breaker| Arc::new(CircuitBreaker::new(circuit_breaker))),
+            auth: match &listener.auth {
+                Some(auth) => Some(Arc::new(Authenticator::new(auth)?)),
+                None => None,
+            },
+            oidc: match &listener.oidc {
+                Some(oidc) => Some(Arc::new(OidcAuth::new(oidc)?)),
+                None => None,
+            },
+            forward_auth: match &listener.forward_auth {
+                Some(forward_auth) => Some(Arc::new(ForwardAuth::new(forward_auth)?)),
+                None => None,
+            },
+            ip_access: listener
+                .ip_access
+                .as_ref()
+                .map(|ip_access| Arc::new(IpAccess::new(ip_access))),
+            cors: match &listener.cors {
+                Some(cors) => Some(Arc::new(CorsPolicy::new(cors)?)),
+                None => None,
+            },
+            security_headers: listener
+                .security_headers
+                .as_ref()
+                .map(|config| SecurityHeaders::new(config).map(Arc::new))
+                .transpose()?,
+            response_rewrite: listener
+                .response_rewrite
+                .as_ref()
+                .map(|config| Arc::new(ResponseRewriter::new(config))),
+            limits: listener
+                .limits
+                .as_ref()
+                .map(|config| Arc::new(Limits::new(config))),
+            upstream_timeout: listener.upstream_timeout_ms.map(Duration::from_millis),
+            error_pages: listener
+                .error_pages
+                .as_ref()
+                .map(|config| ErrorPages::new(config).map(Arc::new))
+                .transpose()?,
+            maintenance: Arc::new(
+                listener
+                    .maintenance
+                    .as_ref()
+                    .map(Maintenance::new)
+                    .transpose()?
+                    .unwrap_or_default(),
+            ),
+        })
+    }
+
+    /// Template variables for a request, with the client resolved through
+    /// trusted proxies
+    fn template_vars<B>(&self, req: &Request<B>, client_addr: SocketAddr) -> TemplateVars {
+        let client_ip = self.forwarding.client_ip(req.headers(), client_addr.ip());
+        TemplateVars::from_request(req, client_ip, self.port)
+    }
+
+    /// The route's `Host` header option, or the listener's
+    fn host_header<'a>(&'a self, route: Option<&'a CompiledRoute>) -> Option<&'a HostHeader> {
+        route
+            .and_then(|route| route.host_header.as_ref())
+            .or(self.host_header.as_ref())
+    }
+
+    /// Apply the listener's response header rules, then the route's
+    fn apply_response_headers(
+        &self,
+        route: Option<&CompiledRoute>,
+        response: &mut Response<Body>,
+        vars: &TemplateVars,
+    ) {
+        let rewriters = std::iter::once(&self.response_headers)
+            .chain(route.map(|route| &route.response_headers));
+        for rewriter in rewriters {
+            if let Err(e) = rewriter.apply(response.headers_mut(), vars) {
+                tracing::warn!("Failed to apply response header rules: {}", e);
+            }
+        }
+    }
+
+    /// Apply the route's compression, or the listener's, if any
+    fn compress(
+        &self,
+        route: Option<&CompiledRoute>,
+        method: &Method,
+        accept_encoding: Option<&HeaderValue>,
+        response: Response<Body>,
+    ) -> Response<Body> {
+        let compression = route
+            .and_then(|route| route.compression.as_ref())
+            .or(self.compression.as_ref());
+        match compression {
+            Some(compressor) => compressor.apply(method, accept_encoding, response),
+            None => response,
+        }
+    }
+}
 
 /// Main proxy handler - forwards requests to the configured target
 pub async fn proxy_handler(
@@ -20,17 +300,393 @@ pub async fn proxy_handler(
     http_client: HttpClient,
     tls_config: Arc<ClientConfig>,
 ) -> Response<Body> {
+    let ctx = ProxyContext {
+        port: 443,
+        target,
+        path_join: PathJoin::default(),
+        routes: RouteTable::default(),
+        http_client,
+        tls_config,
+        capture: None,
+        har: None,
+        request_headers: HeaderRewriter::default(),
+        response_headers: HeaderRewriter::default(),
+        host_header: None,
+        forwarding: Forwarding::default(),
+        proxy_protocol: ProxyProtocolConfig::default(),
+        compression: None,
+        cache: None,
+        rate_limit: None,
+        concurrency: None,
+        circuit_breaker: None,
+        auth: None,
+        oidc: None,
+        forward_auth: None,
+        ip_access: None,
+        cors: None,
+        security_headers: None,
+        response_rewrite: None,
+        limits: None,
+        upstream_timeout: None,
+        error_pages: None,
+        maintenance: Arc::default(),
+    };
+    handle_request(&ctx, addr, req).await
+}
+
+/// Proxy a request using the listener's context
+pub async fn handle_request(
+    ctx: &ProxyContext,
+    addr: SocketAddr,
+    req: Request<Body>,
+) -> Response<Body> {
+    // The request is gone by the time the error page is rendered
+    let error_page_request = ctx.error_pages.as_ref().map(|_| {
+        let accept = req.headers().get(ACCEPT).cloned();
+        (accept, ctx.template_vars(&req, addr))
+    });
+    let mut response = handle(ctx, addr, req).await;
+    if let (Some(error_pages), Some((accept, vars))) = (&ctx.error_pages, &error_page_request) {
+        response = error_pages.render(response, accept.as_ref(), vars);
+    }
+    if let Some(security_headers) = &ctx.security_headers {
+        security_headers.apply(response.headers_mut());
+    }
+    response
+}
+
+/// Check the client's IP, maintenance mode, size limits and CORS, then serve
+/// the request
+async fn handle(ctx: &ProxyContext, addr: SocketAddr, mut req: Request<Body>) -> Response<Body> {
+    let route = ctx.routes.find(req.uri().path());
+
+    let client_ip = ctx.forwarding.client_ip(req.headers(), addr.ip());
+
+    // Both the listener's and the route's IP lists must let the client in
+    let ip_lists = ctx
+        .ip_access
+        .as_deref()
+        .into_iter()
+        .chain(route.and_then(|route| route.ip_access.as_ref()));
+    for ip_access in ip_lists {
+        if !ip_access.is_allowed(client_ip) {
+            tracing::warn!("Denied {} {} from {}", req.method(), req.uri(), client_ip);
+            return ip_access.forbidden();
+        }
+    }
+
+    // Maintenance on the listener or the route turns away all but its allowlist
+    let maintenance_modes =
+        std::iter::once(ctx.maintenance.as_ref()).chain(route.map(|route| &route.maintenance));
+    for maintenance in maintenance_modes {
+        if maintenance.is_enabled() && !maintenance.lets_through(req.headers(), client_ip) {
+            return maintenance.response();
+        }
+        maintenance.strip_bypass_headers(req.headers_mut());
+    }
+
+    let limits = route
+        .and_then(|route| route.limits.as_ref())
+        .or(ctx.limits.as_deref());
+    if let Some(response) = limits.and_then(|limits| limits.reject(req.headers())) {
+        tracing::warn!(
+            "Rejected {} {} from {}: {}",
+            req.method(),
+            req.uri(),
+            client_ip,
+            response.status()
+        );
+        return response;
+    }
+    let body_limit = limits.map(|limits| limits.limit_request_body(&mut req));
+
+    // Preflights are answered here, before any credentials are asked for
+    let cors = route
+        .and_then(|route| route.cors.as_ref())
+        .or(ctx.cors.as_deref());
+    let origin = req.headers().get(ORIGIN).cloned();
+    if let Some(cors) = cors.filter(|_| CorsPolicy::is_preflight(&req)) {
+        return cors.preflight(&req);
+    }
+
+    let mut response = serve(ctx, addr, req, route, client_ip).await;
+    // The upload was cut off at the limit, whatever the upstream made of it
+    if body_limit.is_some_and(|guard| guard.exceeded()) {
+        tracing::warn!("Request body from {} over the limit", client_ip);
+        response = limits::payload_too_large();
+    }
+    if let Some(limits) = limits {
+        response = limits.check_response(response);
+    }
+    if let (Some(cors), Some(origin)) = (cors, origin) {
+        cors.apply(&origin, response.headers_mut());
+    }
+    response
+}
+
+/// Rate limit and authenticate the request, then dispatch it
+async fn serve(
+    ctx: &ProxyContext,
+    addr: SocketAddr,
+    mut req: Request<Body>,
+    route: Option<&CompiledRoute>,
+    client_ip: IpAddr,
+) -> Response<Body> {
+    // The route's rate limit first, then the listener's; the tightest is reported
+    let mut rate_limit: Option<RateLimitStatus> = None;
+    let limiters = route
+        .and_then(|route| route.rate_limit.as_ref())
+        .into_iter()
+        .chain(ctx.rate_limit.as_deref());
+    for limiter in limiters {
+        let status = limiter.check(&req, client_ip);
+        if !status.allowed {
+            tracing::warn!(
+                "Rate limited {} {} from {}",
+                req.method(),
+                req.uri(),
+                client_ip
+            );
+            return status.too_many_requests();
+        }
+        if rate_limit.is_none_or(|tightest| status.remaining < tightest.remaining) {
+            rate_limit = Some(status);
+        }
+    }
+
+    // Login callbacks may be outside the routes they protect
+    let oidc_endpoint = ctx
+        .oidc
+        .as_deref()
+        .into_iter()
+        .chain(ctx.routes.iter().filter_map(|route| route.oidc.as_ref()))
+        .find(|oidc| oidc.is_endpoint(req.uri().path()));
+    if let Some(oidc) = oidc_endpoint {
+        return oidc.endpoint(&ctx.http_client, req).await;
+    }
+
+    if let Err(response) = authenticate(ctx, route, &mut req, client_ip).await {
+        if response.status() == StatusCode::UNAUTHORIZED {
+            tracing::warn!(
+                "Unauthorized {} {} from {}",
+                req.method(),
+                req.uri(),
+                client_ip
+            );
+        }
+        return response;
+    }
+
+    let mut response = dispatch(ctx, addr, req, route).await;
+    if let Some(status) = rate_limit {
+        status.apply_headers(response.headers_mut());
+    }
+    response
+}
+
+/// Check the request with the route's authentication, or the listener's
+/// when the route has none
+async fn authenticate(
+    ctx: &ProxyContext,
+    route: Option<&CompiledRoute>,
+    req: &mut Request<Body>,
+    client_ip: IpAddr,
+) -> Result<(), Response<Body>> {
+    let route = route.filter(|route| {
+        route.auth.is_some() || route.oidc.is_some() || route.forward_auth.is_some()
+    });
+    let (auth, oidc, forward_auth) = match route {
+        Some(route) => (
+            route.auth.as_ref(),
+            route.oidc.as_ref(),
+            route.forward_auth.as_ref(),
+        ),
+        None => (
+            ctx.auth.as_deref(),
+            ctx.oidc.as_deref(),
+            ctx.forward_auth.as_deref(),
+        ),
+    };
+
+    if let Some(auth) = auth {
+        return auth.authenticate(req).await;
+    }
+    if let Some(oidc) = oidc {
+        return oidc.authenticate(&ctx.http_client, req).await;
+    }
+    if let Some(forward_auth) = forward_auth {
+        return forward_auth.check(&ctx.http_client, req, client_ip).await;
+    }
+    Ok(())
+}
+
+/// Serve the request from its route, or the listener's target
+async fn dispatch(
+    ctx: &ProxyContext,
+    addr: SocketAddr,
+    mut req: Request<Body>,
+    route: Option<&CompiledRoute>,
+) -> Response<Body> {
+    if let Some(rewriter) = route.and_then(|route| route.rewrite.as_ref()) {
+        match rewriter.apply(req.uri()) {
+            Ok(Rewritten::Uri(uri)) => *req.uri_mut() = uri,
+            Ok(Rewritten::Redirect(response)) => return response,
+            Err(e) => return bad_gateway_response(&format!("Failed to rewrite URL: {:#}", e)),
+        }
+    }
+
     let method = req.method().clone();
+    let accept_encoding = req.headers().get(ACCEPT_ENCODING).cloned();
+
+    let target = match route.map(|route| (route, &route.action)) {
+        Some((route, RouteAction::Static(dir))) => {
+            tracing::info!(
+                "Serving {} {} from {}",
+                method,
+                req.uri(),
+                dir.root().display()
+            );
+            let path = route.strip(req.uri().path()).unwrap_or("/").to_string();
+            let (parts, _) = req.into_parts();
+            let response = dir.serve(&parts, &path).await;
+            return ctx.compress(Some(route), &method, accept_encoding.as_ref(), response);
+        }
+        Some((_, RouteAction::Proxy(target))) => target.as_str(),
+        None if ctx.target.is_empty() => return static_files::not_found(),
+        None => ctx.target.as_str(),
+    };
 
     tracing::info!("Proxying {} {} -> {}", method, req.uri(), target);
 
     // Check for WebSocket upgrade
     if is_websocket_upgrade(&req) {
-        return handle_websocket_upgrade(req, &target, addr, &http_client, &tls_config).await;
+        return handle_websocket_upgrade(ctx, req, target, route, addr).await;
+    }
+
+    let vars = ctx.template_vars(&req, addr);
+    let cache = route
+        .and_then(|route| route.cache.as_ref())
+        .or(ctx.cache.as_ref());
+    let mut response = match cache {
+        Some(cache) => cached_forward(ctx, cache, addr, req, target, route, &vars).await,
+        None => record_and_forward(ctx, addr, req, target, route, &vars).await,
+    };
+    // After the cache, so templated values aren't stored and replayed
+    ctx.apply_response_headers(route, &mut response, &vars);
+    if let Some(filter) = route.and_then(|route| route.body_filter.as_ref()) {
+        response = filter.apply(&method, response);
+    }
+
+    // Compress last so the history and HAR keep the upstream's body
+    ctx.compress(route, &method, accept_encoding.as_ref(), response)
+}
+
+/// Answer from the response cache where possible, forwarding (and
+/// revalidating) otherwise
+async fn cached_forward(
+    ctx: &ProxyContext,
+    cache: &Arc<ResponseCache>,
+    addr: SocketAddr,
+    mut req: Request<Body>,
+    target: &str,
+    route: Option<&CompiledRoute>,
+    vars: &TemplateVars,
+) -> Response<Body> {
+    let key = cache::key(&req, target);
+    let method = req.method().clone();
+    let request_headers = req.headers().clone();
+    let path = req.uri().path().to_string();
+
+    if !cache::is_cacheable_request(&req) {
+        let response = record_and_forward(ctx, addr, req, target, route, vars).await;
+        // Unsafe methods invalidate what we hold for the URL
+        let changed = !method.is_safe()
+            && (response.status().is_success() || response.status().is_redirection());
+        if changed {
+            cache.invalidate(&key);
+        }
+        return cache_miss(response);
+    }
+
+    let stale = match cache.lookup(&key, &request_headers) {
+        Some(cached) if cached.is_fresh() && !cache::requires_revalidation(&request_headers) => {
+            tracing::debug!("Cache hit for {}", key);
+            return cached.respond(&method, &request_headers);
+        }
+        Some(cached) => Some(cached),
+        None => None,
+    };
+
+    // Revalidate with our own validators unless the client sent its own
+    let client_conditional = request_headers.contains_key(IF_NONE_MATCH)
+        || request_headers.contains_key(IF_MODIFIED_SINCE);
+    let revalidating =
+        stale.filter(|cached| !client_conditional && !cached.validators().is_empty());
+    if let Some(cached) = &revalidating {
+        for (name, value) in cached.validators() {
+            req.headers_mut().insert(name, value);
+        }
+    }
+
+    let response = record_and_forward(ctx, addr, req, target, route, vars).await;
+
+    if let Some(cached) = revalidating {
+        if response.status() == StatusCode::NOT_MODIFIED {
+            tracing::debug!("Cache revalidated {}", key);
+            let refreshed = cache.refresh(&key, &cached, response.headers());
+            return refreshed.respond(&method, &request_headers);
+        }
+    }
+
+    // A HEAD response has no body to store
+    let response = if method == Method::GET {
+        cache.store(key, &path, &request_headers, response)
+    } else {
+        response
+    };
+    cache_miss(response)
+}
+
+fn cache_miss(mut response: Response<Body>) -> Response<Body> {
+    response
+        .headers_mut()
+        .insert(cache::X_CACHE, HeaderValue::from_static("MISS"));
+    response
+}
+
+/// Forward regular HTTP request, recording it for the history and HAR export
+async fn record_and_forward(
+    ctx: &ProxyContext,
+    addr: SocketAddr,
+    req: Request<Body>,
+    target: &str,
+    route: Option<&CompiledRoute>,
+    vars: &TemplateVars,
+) -> Response<Body> {
+    let max_body_bytes = ctx
+        .capture
+        .iter()
+        .map(|capture| capture.max_body_bytes())
+        .chain(ctx.har.iter().map(|har| har.max_body_bytes()))
+        .max();
+    let Some(max_body_bytes) = max_body_bytes else {
+        return forward_upstream(ctx, req, target, route, addr, vars).await;
+    };
+
+    let (pending, req) = capture::record(ctx.port, target, addr, req, max_body_bytes);
+    let response = forward_upstream(ctx, req, target, route, addr, vars).await;
+
+    let har = ctx.har.clone();
+    let (entry, response) = pending.finish(response, move |entry| {
+        if let Some(har) = har {
+            har.append(&entry);
+        }
+    });
+    if let Some(capture) = &ctx.capture {
+        capture.push(entry);
     }
 
-    // Forward regular HTTP request
-    forward_request(req, &target, addr, &http_client).await
+    response
 }
 
 /// Helper function to check if a header contains a specific value (case-insensitive)
@@ -73,29 +729,46 @@ fn normalize_cookie_headers(headers: &mut HeaderMap) -> anyhow::Result<()> {
     Ok(())
 }
 
-/// Add X-Forwarded-* and X-Real-IP headers to the request
+/// Add X-Forwarded-*, X-Real-IP and (optionally) Forwarded headers to the request.
+///
+/// Headers already present are only honoured when the peer is a trusted proxy;
+/// otherwise they could be spoofed by the client and are replaced.
 fn add_forwarding_headers(
     headers: &mut HeaderMap,
     client_addr: SocketAddr,
     original_host: Option<HeaderValue>,
+    port: u16,
+    forwarding: &Forwarding,
 ) -> anyhow::Result<()> {
+    let peer = client_addr.ip();
+    if !forwarding.is_trusted(peer) {
+        for name in [
+            "x-forwarded-for",
+            "x-forwarded-proto",
+            "x-forwarded-host",
+            "x-forwarded-port",
+            "x-real-ip",
+            "forwarded",
+        ] {
+            headers.remove(name);
+        }
+    }
+
     // X-Real-IP - the actual client IP
-    headers.insert(
-        "x-real-ip",
-        HeaderValue::from_str(&client_addr.ip().to_string())?,
-    );
+    let client_ip = forwarding.client_ip(headers, peer);
+    headers.insert("x-real-ip", HeaderValue::from_str(&client_ip.to_string())?);
 
     // X-Forwarded-For - append to existing or create new
     let xff = match headers.get("x-forwarded-for") {
         Some(existing) => {
             let existing_str = existing.to_str().unwrap_or("");
             if existing_str.trim().is_empty() {
-                client_addr.ip().to_string()
+                peer.to_string()
             } else {
-                format!("{}, {}", existing_str, client_addr.ip())
+                format!("{}, {}", existing_str, peer)
             }
         }
-        None => client_addr.ip().to_string(),
+        None => peer.to_string(),
     };
     headers.insert("x-forwarded-for", HeaderValue::from_str(&xff)?);
 
@@ -106,22 +779,57 @@ fn add_forwarding_headers(
     }
 
     // X-Forwarded-Host (from original Host header)
-    if let Some(host) = original_host {
+    if let Some(host) = &original_host {
         if !headers.contains_key("x-forwarded-host") {
-            headers.insert("x-forwarded-host", host);
+            headers.insert("x-forwarded-host", host.clone());
         }
     }
 
-    // X-Forwarded-Port
+    // X-Forwarded-Port - the listener this request arrived on
     if !headers.contains_key("x-forwarded-port") {
-        headers.insert("x-forwarded-port", HeaderValue::from_static("443"));
+        headers.insert("x-forwarded-port", HeaderValue::from(port));
+    }
+
+    // Forwarded (RFC 7239) - append our hop to any trusted existing value
+    if forwarding.forwarded_header {
+        let element = forwarded_element(peer, original_host.as_ref());
+        let forwarded = match headers.get("forwarded").and_then(|v| v.to_str().ok()) {
+            Some(existing) if !existing.trim().is_empty() => {
+                format!("{}, {}", existing, element)
+            }
+            _ => element,
+        };
+        headers.insert("forwarded", HeaderValue::from_str(&forwarded)?);
     }
 
     Ok(())
 }
 
+/// One `Forwarded` element for this hop, e.g. `for="[::1]";host=example.com;proto=https`
+fn forwarded_element(peer: IpAddr, host: Option<&HeaderValue>) -> String {
+    let mut element = match peer {
+        IpAddr::V4(ip) => format!("for={}", ip),
+        IpAddr::V6(ip) => format!("for=\"[{}]\"", ip),
+    };
+
+    if let Some(host) = host.and_then(|h| h.to_str().ok()) {
+        // Values with characters outside the RFC 7230 `token` set must be quoted
+        let is_token = host
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c));
+        if is_token {
+            element.push_str(&format!(";host={}", host));
+        } else {
+            element.push_str(&format!(";host=\"{}\"", host.replace('"', "")));
+        }
+    }
+
+    element.push_str(";proto=https");
+    element
+}
+
 /// Remove hop-by-hop headers that shouldn't be forwarded
-fn remove_hop_by_hop_headers(headers: &mut HeaderMap) {
+pub(crate) fn remove_hop_by_hop_headers(headers: &mut HeaderMap) {
     headers.remove("connection");
     headers.remove("keep-alive");
     headers.remove("proxy-authenticate");
@@ -132,15 +840,49 @@ fn remove_hop_by_hop_headers(headers: &mut HeaderMap) {
     headers.remove("upgrade");
 }
 
-/// Forward HTTP request to upstream
-async fn forward_request(
+/// Addresses for an outgoing PROXY header: the client, and the address it
+/// connected to when known (the listener's port otherwise)
+fn connection_addrs<B>(req: &Request<B>, client_addr: SocketAddr, port: u16) -> ConnectionAddrs {
+    let local = req
+        .extensions()
+        .get::<ConnectionAddrs>()
+        .map(|addrs| addrs.local)
+        .unwrap_or_else(|| SocketAddr::from(([0, 0, 0, 0], port)));
+    ConnectionAddrs {
+        client: client_addr,
+        local,
+    }
+}
+
+/// Forward HTTP request to upstream, applying the header rules of the
+/// listener and of the route matching its path
+pub(crate) async fn forward_request(
+    ctx: &ProxyContext,
     req: Request<Body>,
     target: &str,
     client_addr: SocketAddr,
-    http_client: &HttpClient,
 ) -> Response<Body> {
+    let route = ctx.routes.find(req.uri().path());
+    let vars = ctx.template_vars(&req, client_addr);
+    let mut response = forward_upstream(ctx, req, target, route, client_addr, &vars).await;
+    ctx.apply_response_headers(route, &mut response, &vars);
+    response
+}
+
+/// Send a request to the upstream. The response header rules are left to
+/// the caller, so they can run after the cache.
+async fn forward_upstream(
+    ctx: &ProxyContext,
+    req: Request<Body>,
+    target: &str,
+    route: Option<&CompiledRoute>,
+    client_addr: SocketAddr,
+    vars: &TemplateVars,
+) -> Response<Body> {
+    let addrs = connection_addrs(&req, client_addr, ctx.port);
+
     // Build upstream URI - preserve full path and query string
-    let upstream_uri = match build_upstream_uri(req.uri(), target) {
+    let upstream_uri = match build_upstream_uri(req.uri(), target, ctx.path_join) {
         Ok(uri) => uri,
         Err(e) => {
             tracing::error!("Failed to build upstream URI: {}", e);
@@ -149,7 +891,14 @@ async fn forward_request(
     };
 
     // Build new request with forwarding headers
-    let upstream_req = match build_upstream_request(req, upstream_uri, client_addr) {
+    let mut upstream_req = match build_upstream_request(
+        req,
+        upstream_uri,
+        client_addr,
+        ctx.port,
+        ctx.host_header(route),
+        &ctx.forwarding,
+    ) {
         Ok(r) => r,
         Err(e) => {
             tracing::error!("Failed to build upstream request: {}", e);
@@ -157,6 +906,29 @@ async fn forward_request(
         }
     };
 
+    // Apply configured header rules last so they can override the defaults,
+    // the route's after the listener's
+    let rewriters =
+        std::iter::once(&ctx.request_headers).chain(route.map(|route| &route.request_headers));
+    for rewriter in rewriters {
+        if let Err(e) = rewriter.apply(upstream_req.headers_mut(), vars) {
+            tracing::error!("Failed to apply request header rules: {}", e);
+            return bad_gateway_response(&format!("Failed to build request: {}", e));
+        }
+    }
+
+    // Send the chosen Host as SNI too (after the rules, which may override it)
+    if ctx.host_header(route).is_some() {
+        let uri = upstream_req.uri().clone();
+        match with_tls_server_name(uri, upstream_req.headers()) {
+            Ok(uri) => *upstream_req.uri_mut() = uri,
+            Err(e) => {
+                tracing::error!("Failed to set upstream server name: {}", e);
+                return bad_gateway_response(&format!("Failed to build request: {}", e));
+            }
+        }
+    }
+
     // Log upstream headers if debug is enabled
     if tracing::enabled!(tracing::Level::DEBUG) {
         tracing::debug!("Upstream request headers:");
@@ -168,26 +940,110 @@ async fn forward_request(
         tracing::debug!("cookie header count = {}", cookie_count);
     }
 
+    // Where the upstream's own URLs point, for rewriting them in the response
+    let upstream_urls = match (&ctx.response_rewrite, target.parse::<Uri>()) {
+        (Some(_), Ok(target_uri)) if !vars.host.is_empty() => {
+            let upstream_host = upstream_req
+                .headers()
+                .get(HOST)
+                .and_then(|v| v.to_str().ok());
+            Some(UpstreamUrls::new(&target_uri, upstream_host, &vars.host))
+        }
+        _ => None,
+    };
+
+    // Wait for a free slot towards the target
+    let in_flight = match &ctx.concurrency {
+        Some(concurrency) => match concurrency.acquire(target).await {
+            Ok(in_flight) => Some(in_flight),
+            Err(rejected) => {
+                tracing::warn!("Rejecting request to {}: {}", target, rejected);
+                return service_unavailable_response(&rejected.to_string());
+            }
+        },
+        None => None,
+    };
+
+    // Fail fast while the target's circuit is open. The attempt starts once
+    // a slot is free, so time spent queued doesn't count as a slow call.
+    let attempt = match &ctx.circuit_breaker {
+        Some(breaker) => match breaker.attempt(target) {
+            Ok(attempt) => Some(attempt),
+            Err(retry_after) => {
+                tracing::debug!("Circuit open for {}, rejecting request", target);
+                let mut response = service_unavailable_response("circuit open");
+                // Round up so clients don't come back just before it half-opens
+                let secs = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+                response
+                    .headers_mut()
+                    .insert("retry-after", HeaderValue::from(secs.max(1)));
+                return response;
+            }
+        },
+        None => None,
+    };
+
     // Send request to upstream
-    match http_client.request(upstream_req).await {
+    let send = async {
+        match ctx.proxy_protocol.send {
+            Some(version) => {
+                let tls_config = ctx.tls_config.clone();
+                proxy_protocol::send_request(version, addrs, upstream_req, tls_config).await
+            }
+            None => ctx
+                .http_client
+                .request(upstream_req)
+                .await
+                .map_err(anyhow::Error::from),
+        }
+    };
+    let (sent, timed_out) = match ctx.upstream_timeout {
+        Some(timeout) => match tokio::time::timeout(timeout, send).await {
+            Ok(sent) => (sent, false),
+            Err(_) => (
+                Err(anyhow::anyhow!("no response within {:?}", timeout)),
+                true,
+            ),
+        },
+        None => (send.await, false),
+    };
+    if let Some(attempt) = attempt {
+        attempt.record(
+            sent.as_ref()
+                .map(|resp| resp.status().as_u16())
+                .map_err(|_| ()),
+        );
+    }
+
+    let mut response = match sent {
         Ok(resp) => {
             let (parts, body) = resp.into_parts();
-            let body = Body::new(body);
+            let mut body = Body::new(body);
+            if let Some(in_flight) = in_flight {
+                body = in_flight.hold_until_sent(body);
+            }
             Response::from_parts(parts, body)
         }
+        Err(e) if timed_out => gateway_timeout_response(&format!("Upstream timed out: {}", e)),
         Err(e) => {
             tracing::error!("Upstream request failed: {}", e);
             bad_gateway_response(&format!("Upstream connection failed: {}", e))
         }
+    };
+
+    if let (Some(rewriter), Some(urls)) = (&ctx.response_rewrite, &upstream_urls) {
+        rewriter.apply(response.headers_mut(), urls);
     }
+
+    response
 }
 
-/// Build the upstream URI - preserving full path and query
-fn build_upstream_uri(original: &Uri, target: &str) -> anyhow::Result<Uri> {
+/// Build the upstream URI: the target's scheme and authority, and its base
+/// path joined with the request's path and query
+fn build_upstream_uri(original: &Uri, target: &str, join: PathJoin) -> anyhow::Result<Uri> {
     // Parse target URL
     let target_uri: Uri = target.parse()?;
 
-    // Build path + query (NOT stripping/rewriting anything)
     let path_and_query = original
         .path_and_query()
         .map(|pq| pq.as_str())
@@ -198,17 +1054,89 @@ fn build_upstream_uri(original: &Uri, target: &str) -> anyhow::Result<Uri> {
         "{}://{}{}",
         target_uri.scheme_str().unwrap_or("http"),
         target_uri.authority().map(|a| a.as_str()).unwrap_or(""),
-        path_and_query,
+        join_base_path(target_uri.path(), path_and_query, join),
     );
 
     Ok(uri_str.parse()?)
 }
 
+/// Prefix `path_and_query` with a target's base path
+fn join_base_path(base: &str, path_and_query: &str, join: PathJoin) -> String {
+    if base.is_empty() || base == "/" {
+        return path_and_query.to_string();
+    }
+    let rest = path_and_query.trim_start_matches('/');
+    match join {
+        PathJoin::Concat => format!("{}{}", base, path_and_query),
+        PathJoin::NoTrailingSlash if rest.is_empty() || rest.starts_with('?') => {
+            format!("{}{}", base.trim_end_matches('/'), rest)
+        }
+        PathJoin::Merge | PathJoin::NoTrailingSlash => {
+            format!("{}/{}", base.trim_end_matches('/'), rest)
+        }
+    }
+}
+
+/// Choose the `Host` header sent upstream
+fn upstream_host(
+    host_header: &HostHeader,
+    original_host: Option<HeaderValue>,
+    target_uri: &Uri,
+) -> anyhow::Result<Option<HeaderValue>> {
+    Ok(match host_header {
+        HostHeader::Preserve => original_host,
+        HostHeader::Upstream => target_uri
+            .authority()
+            .map(|a| HeaderValue::from_str(a.as_str()))
+            .transpose()?,
+        HostHeader::Custom(host) => Some(HeaderValue::from_str(host)?),
+    })
+}
+
+/// Host name of a `Host` header value, or `None` if it can't be sent as SNI
+fn server_name_of(host: &str) -> Option<String> {
+    let authority: Authority = host.parse().ok()?;
+    let name = authority.host();
+
+    // IP addresses are never sent as SNI
+    if name.starts_with('[') || name.parse::<IpAddr>().is_ok() {
+        return None;
+    }
+
+    Some(name.to_string())
+}
+
+/// Tag an https upstream URI with the `Host` header's name so the connector
+/// uses it as SNI (see `tls::UpstreamServerNameResolver`)
+fn with_tls_server_name(uri: Uri, headers: &HeaderMap) -> anyhow::Result<Uri> {
+    if uri.scheme_str() != Some("https") {
+        return Ok(uri);
+    }
+    let server_name = headers
+        .get(HOST)
+        .and_then(|host| host.to_str().ok())
+        .and_then(server_name_of);
+    let (Some(server_name), Some(authority)) = (server_name, uri.authority()) else {
+        return Ok(uri);
+    };
+    if server_name.eq_ignore_ascii_case(authority.host()) {
+        return Ok(uri);
+    }
+
+    let authority = format!("{}@{}", server_name, authority).parse()?;
+    let mut parts = uri.into_parts();
+    parts.authority = Some(authority);
+    Ok(Uri::from_parts(parts)?)
+}
+
 /// Build the upstream request with X-Forwarded-* headers
 fn build_upstream_request(
     req: Request<Body>,
     upstream_uri: Uri,
     client_addr: SocketAddr,
+    port: u16,
+    host_header: Option<&HostHeader>,
+    forwarding: &Forwarding,
 ) -> anyhow::Result<Request<Body>> {
     let (mut parts, body) = req.into_parts();
 
@@ -220,6 +1148,14 @@ fn build_upstream_request(
             .and_then(|auth| HeaderValue::from_str(auth.as_str()).ok())
     });
 
+    // Rewrite Host when configured; otherwise the client's Host is kept
+    if let Some(host_header) = host_header {
+        parts.headers.remove(HOST);
+        if let Some(host) = upstream_host(host_header, original_host.clone(), &upstream_uri)? {
+            parts.headers.insert(HOST, host);
+        }
+    }
+
     // Update URI
     parts.uri = upstream_uri;
 
@@ -227,7 +1163,13 @@ fn build_upstream_request(
     parts.version = Version::HTTP_11;
 
     // Add forwarding headers
-    add_forwarding_headers(&mut parts.headers, client_addr, original_host)?;
+    add_forwarding_headers(
+        &mut parts.headers,
+        client_addr,
+        original_host,
+        port,
+        forwarding,
+    )?;
 
     // Remove hop-by-hop headers
     remove_hop_by_hop_headers(&mut parts.headers);
@@ -240,13 +1182,16 @@ fn build_upstream_request(
 
 /// Handle WebSocket upgrade request
 async fn handle_websocket_upgrade(
+    ctx: &ProxyContext,
     mut req: Request<Body>,
     target: &str,
+    route: Option<&CompiledRoute>,
     client_addr: SocketAddr,
-    _http_client: &HttpClient,
-    tls_config: &Arc<ClientConfig>,
 ) -> Response<Body> {
     tracing::info!("WebSocket upgrade request from {}", client_addr);
+    let proxy_header = ctx.proxy_protocol.send.map(|version| {
+        proxy_protocol::encode(version, connection_addrs(&req, client_addr, ctx.port))
+    });
 
     // 1. Build upstream WebSocket URL (ws:// or wss://)
     let target_uri: Uri = match target.parse() {
@@ -266,7 +1211,38 @@ async fn handle_websocket_upgrade(
         .unwrap_or("/");
     let authority = target_uri.authority().map(|a| a.as_str()).unwrap_or("");
 
-    let upstream_url = format!("{}://{}{}", scheme, authority, path_and_query);
+    let upstream_url = format!(
+        "{}://{}{}",
+        scheme,
+        authority,
+        join_base_path(target_uri.path(), path_and_query, ctx.path_join)
+    );
+    let mut ws_request = match upstream_url.into_client_request() {
+        Ok(r) => r,
+        Err(e) => return bad_gateway_response(&format!("Invalid upstream URI: {}", e)),
+    };
+
+    // Apply the Host header option, and its SNI on wss:// targets
+    let mut server_name = None;
+    if let Some(host_header) = ctx.host_header(route) {
+        let original_host = req.headers().get(HOST).cloned().or_else(|| {
+            req.uri()
+                .authority()
+                .and_then(|auth| HeaderValue::from_str(auth.as_str()).ok())
+        });
+        match upstream_host(host_header, original_host, &target_uri) {
+            Ok(Some(host)) => {
+                server_name = host
+                    .to_str()
+                    .ok()
+                    .and_then(server_name_of)
+                    .filter(|name| scheme == "wss" && Some(name.as_str()) != target_uri.host());
+                ws_request.headers_mut().insert(HOST, host);
+            }
+            Ok(None) => {}
+            Err(e) => return bad_gateway_response(&format!("Invalid Host header: {}", e)),
+        }
+    }
 
     // 2. Prepare upgrade response for the client
     let upgrade_header = match req.headers().get("sec-websocket-key") {
@@ -287,7 +1263,7 @@ async fn handle_websocket_upgrade(
         .unwrap();
 
     // 3. Spawn task to handle the tunnel
-    let tls_config = tls_config.clone();
+    let tls_config = ctx.tls_config.clone();
     tokio::spawn(async move {
         // Wait for the client connection to be upgraded
         match hyper::upgrade::on(&mut req).await {
@@ -296,16 +1272,22 @@ async fn handle_websocket_upgrade(
                 let upgraded = hyper_util::rt::TokioIo::new(upgraded);
 
                 // Connect to upstream using the insecure TLS config
-                let connector = Connector::Rustls(tls_config.clone());
-                match tokio_tungstenite::connect_async_tls_with_config(
-                    upstream_url,
-                    None,
-                    false,
-                    Some(connector),
-                )
-                .await
-                {
-                    Ok((ws_stream, _)) => {
+                let connected = if server_name.is_some() || proxy_header.is_some() {
+                    connect_websocket(ws_request, server_name, proxy_header, tls_config).await
+                } else {
+                    let connector = Connector::Rustls(tls_config.clone());
+                    tokio_tungstenite::connect_async_tls_with_config(
+                        ws_request,
+                        None,
+                        false,
+                        Some(connector),
+                    )
+                    .await
+                    .map(|(ws_stream, _)| ws_stream)
+                    .map_err(Into::into)
+                };
+                match connected {
+                    Ok(ws_stream) => {
                         // Create client WebSocket stream from the upgraded connection
                         // from_raw_socket is async in tokio-tungstenite and returns WebSocketStream
                         let client_ws_stream = tokio_tungstenite::WebSocketStream::from_raw_socket(
@@ -373,14 +1355,65 @@ async fn handle_websocket_upgrade(
     response
 }
 
+/// Open the upstream WebSocket connection by hand, for when it needs a PROXY
+/// header or sends an SNI other than the target host
+async fn connect_websocket(
+    request: WsRequest,
+    server_name: Option<String>,
+    proxy_header: Option<Vec<u8>>,
+    tls_config: Arc<ClientConfig>,
+) -> anyhow::Result<WebSocketStream<MaybeTlsStream<TcpStream>>> {
+    let secure = request.uri().scheme_str() == Some("wss");
+    let host = request
+        .uri()
+        .host()
+        .unwrap_or_default()
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .to_string();
+    let port = request
+        .uri()
+        .port_u16()
+        .unwrap_or(if secure { 443 } else { 80 });
+
+    let mut tcp = TcpStream::connect((host.as_str(), port)).await?;
+    if let Some(header) = proxy_header {
+        tcp.write_all(&header).await?;
+    }
+
+    let stream = if secure {
+        let server_name = ServerName::try_from(server_name.unwrap_or(host))?;
+        let tls = tokio_rustls::TlsConnector::from(tls_config)
+            .connect(server_name, tcp)
+            .await?;
+        MaybeTlsStream::Rustls(tls)
+    } else {
+        MaybeTlsStream::Plain(tcp)
+    };
+    let (ws_stream, _) = tokio_tungstenite::client_async(request, stream).await?;
+
+    Ok(ws_stream)
+}
+
 /// 502 Bad Gateway response with detailed message
-fn bad_gateway_response(message: &str) -> Response<Body> {
+pub(crate) fn bad_gateway_response(message: &str) -> Response<Body> {
     tracing::warn!("Returning 502: {}", message);
-    Response::builder()
-        .status(StatusCode::BAD_GATEWAY)
-        .header("content-type", "text/plain; charset=utf-8")
-        .body(Body::from(format!("502 Bad Gateway - {}", message)))
-        .unwrap()
+    error_pages::error_response(StatusCode::BAD_GATEWAY, message)
+}
+
+/// 503 Service Unavailable for requests the upstream can't take right now
+fn service_unavailable_response(message: &str) -> Response<Body> {
+    let mut response = error_pages::error_response(StatusCode::SERVICE_UNAVAILABLE, message);
+    response
+        .headers_mut()
+        .insert("retry-after", HeaderValue::from_static("1"));
+    response
+}
+
+/// 504 Gateway Timeout for upstreams that didn't answer in time
+pub(crate) fn gateway_timeout_response(message: &str) -> Response<Body> {
+    tracing::warn!("Returning 504: {}", message);
+    error_pages::error_response(StatusCode::GATEWAY_TIMEOUT, message)
 }
 
 #[cfg(test)]
@@ -388,6 +1421,13 @@ mod tests {
     use super::*;
     use axum::http::Request;
 
+    fn trusting(cidr: &str) -> Forwarding {
+        Forwarding {
+            trusted_proxies: vec![cidr.parse().unwrap()],
+            ..Default::default()
+        }
+    }
+
     #[test]
     fn test_header_contains_single_value() {
         let req = Request::builder()
@@ -483,7 +1523,14 @@ mod tests {
         let mut headers = HeaderMap::new();
         let addr: SocketAddr = "192.168.1.100:54321".parse().unwrap();
         let original_host = Some(HeaderValue::from_static("example.com"));
-        add_forwarding_headers(&mut headers, addr, original_host).unwrap();
+        add_forwarding_headers(
+            &mut headers,
+            addr,
+            original_host,
+            443,
+            &Forwarding::default(),
+        )
+        .unwrap();
 
         assert_eq!(headers.get("x-real-ip").unwrap(), "192.168.1.100");
         assert_eq!(headers.get("x-forwarded-for").unwrap(), "192.168.1.100");
@@ -500,12 +1547,13 @@ mod tests {
             HeaderValue::from_static("10.0.0.1, 10.0.0.2"),
         );
         let addr: SocketAddr = "192.168.1.100:54321".parse().unwrap();
-        add_forwarding_headers(&mut headers, addr, None).unwrap();
+        add_forwarding_headers(&mut headers, addr, None, 443, &trusting("192.168.0.0/16")).unwrap();
 
         assert_eq!(
             headers.get("x-forwarded-for").unwrap(),
             "10.0.0.1, 10.0.0.2, 192.168.1.100"
         );
+        assert_eq!(headers.get("x-real-ip").unwrap(), "10.0.0.2");
     }
 
     #[test]
@@ -513,11 +1561,76 @@ mod tests {
         let mut headers = HeaderMap::new();
         headers.insert("x-forwarded-proto", HeaderValue::from_static("http"));
         let addr: SocketAddr = "192.168.1.100:54321".parse().unwrap();
-        add_forwarding_headers(&mut headers, addr, None).unwrap();
+        add_forwarding_headers(&mut headers, addr, None, 443, &trusting("192.168.0.0/16")).unwrap();
 
         assert_eq!(headers.get("x-forwarded-proto").unwrap(), "http");
     }
 
+    #[test]
+    fn test_add_forwarding_headers_overwrites_untrusted() {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", HeaderValue::from_static("1.2.3.4"));
+        headers.insert("x-forwarded-proto", HeaderValue::from_static("http"));
+        headers.insert("x-forwarded-port", HeaderValue::from_static("80"));
+        headers.insert("x-real-ip", HeaderValue::from_static("1.2.3.4"));
+        headers.insert("forwarded", HeaderValue::from_static("for=1.2.3.4"));
+        let addr: SocketAddr = "192.168.1.100:54321".parse().unwrap();
+        add_forwarding_headers(&mut headers, addr, None, 441, &trusting("10.0.0.0/8")).unwrap();
+
+        assert_eq!(headers.get("x-forwarded-for").unwrap(), "192.168.1.100");
+        assert_eq!(headers.get("x-real-ip").unwrap(), "192.168.1.100");
+        assert_eq!(headers.get("x-forwarded-proto").unwrap(), "https");
+        assert_eq!(headers.get("x-forwarded-port").unwrap(), "441");
+        assert!(headers.get("forwarded").is_none());
+    }
+
+    #[test]
+    fn test_add_forwarding_headers_forwarded() {
+        let forwarding = Forwarding {
+            forwarded_header: true,
+            ..trusting("10.0.0.0/8")
+        };
+
+        // Trusted peer: our element is appended to the existing value
+        let mut headers = HeaderMap::new();
+        headers.insert("forwarded", HeaderValue::from_static("for=203.0.113.7"));
+        let addr: SocketAddr = "10.0.0.2:54321".parse().unwrap();
+        let host = Some(HeaderValue::from_static("example.com"));
+        add_forwarding_headers(&mut headers, addr, host, 443, &forwarding).unwrap();
+        assert_eq!(
+            headers.get("forwarded").unwrap(),
+            "for=203.0.113.7, for=10.0.0.2;host=example.com;proto=https"
+        );
+
+        // IPv6 peers and hosts with a port are quoted
+        let mut headers = HeaderMap::new();
+        let addr: SocketAddr = "[2001:db8::1]:54321".parse().unwrap();
+        let host = Some(HeaderValue::from_static("localhost:440"));
+        add_forwarding_headers(&mut headers, addr, host, 440, &forwarding).unwrap();
+        assert_eq!(
+            headers.get("forwarded").unwrap(),
+            r#"for="[2001:db8::1]";host="localhost:440";proto=https"#
+        );
+    }
+
+    #[test]
+    fn test_forwarding_client_ip() {
+        let forwarding = trusting("10.0.0.0/8");
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "x-forwarded-for",
+            HeaderValue::from_static("198.51.100.1, 203.0.113.7, 10.0.0.5"),
+        );
+
+        let peer: IpAddr = "10.0.0.2".parse().unwrap();
+        assert_eq!(
+            forwarding.client_ip(&headers, peer),
+            "203.0.113.7".parse::<IpAddr>().unwrap()
+        );
+        let peer: IpAddr = "192.168.1.1".parse().unwrap();
+        assert_eq!(forwarding.client_ip(&headers, peer), peer);
+    }
+
     #[test]
     fn test_remove_hop_by_hop_headers() {
         let mut headers = HeaderMap::new();
@@ -542,7 +1655,7 @@ mod tests {
     fn test_build_upstream_uri_simple() {
         let original: Uri = "/api/users".parse().unwrap();
         let target = "http://backend:8080";
-        let result = build_upstream_uri(&original, target).unwrap();
+        let result = build_upstream_uri(&original, target, PathJoin::Merge).unwrap();
         assert_eq!(result.to_string(), "http://backend:8080/api/users");
     }
 
@@ -550,7 +1663,7 @@ mod tests {
     fn test_build_upstream_uri_with_query() {
         let original: Uri = "/search?q=hello&page=2".parse().unwrap();
         let target = "http://backend:8080";
-        let result = build_upstream_uri(&original, target).unwrap();
+        let result = build_upstream_uri(&original, target, PathJoin::Merge).unwrap();
         assert_eq!(
             result.to_string(),
             "http://backend:8080/search?q=hello&page=2"
@@ -561,7 +1674,7 @@ mod tests {
     fn test_build_upstream_uri_https() {
         let original: Uri = "/api".parse().unwrap();
         let target = "https://api.example.com";
-        let result = build_upstream_uri(&original, target).unwrap();
+        let result = build_upstream_uri(&original, target, PathJoin::Merge).unwrap();
         assert_eq!(result.to_string(), "https://api.example.com/api");
     }
 
@@ -569,10 +1682,117 @@ mod tests {
     fn test_build_upstream_uri_root_path() {
         let original: Uri = "/".parse().unwrap();
         let target = "http://backend:3000";
-        let result = build_upstream_uri(&original, target).unwrap();
+        let result = build_upstream_uri(&original, target, PathJoin::Merge).unwrap();
         assert_eq!(result.to_string(), "http://backend:3000/");
     }
 
+    #[test]
+    fn test_build_upstream_uri_base_path() {
+        let target = "http://backend:3000/service-a";
+        let uri = |original: &str, join| {
+            build_upstream_uri(&original.parse().unwrap(), target, join)
+                .unwrap()
+                .to_string()
+        };
+        assert_eq!(
+            uri("/users?page=2", PathJoin::Merge),
+            "http://backend:3000/service-a/users?page=2"
+        );
+        assert_eq!(uri("/", PathJoin::Merge), "http://backend:3000/service-a/");
+        assert_eq!(
+            uri("/?q=1", PathJoin::NoTrailingSlash),
+            "http://backend:3000/service-a?q=1"
+        );
+        assert_eq!(
+            uri("/users", PathJoin::NoTrailingSlash),
+            "http://backend:3000/service-a/users"
+        );
+    }
+
+    #[test]
+    fn test_join_base_path() {
+        assert_eq!(join_base_path("/", "/users", PathJoin::Merge), "/users");
+        assert_eq!(join_base_path("/a/", "/users", PathJoin::Merge), "/a/users");
+        assert_eq!(
+            join_base_path("/a/", "/users", PathJoin::Concat),
+            "/a//users"
+        );
+        assert_eq!(join_base_path("/a", "/", PathJoin::Concat), "/a/");
+        assert_eq!(join_base_path("/a/", "/", PathJoin::NoTrailingSlash), "/a");
+    }
+
+    #[test]
+    fn test_upstream_host_modes() {
+        let target: Uri = "https://httpbin.org".parse().unwrap();
+        let original = Some(HeaderValue::from_static("localhost:442"));
+
+        let host = upstream_host(&HostHeader::Preserve, original.clone(), &target).unwrap();
+        assert_eq!(host.unwrap(), "localhost:442");
+        let host = upstream_host(&HostHeader::Upstream, original.clone(), &target).unwrap();
+        assert_eq!(host.unwrap(), "httpbin.org");
+        let custom = HostHeader::Custom("api.example.com".into());
+        let host = upstream_host(&custom, original, &target).unwrap();
+        assert_eq!(host.unwrap(), "api.example.com");
+    }
+
+    #[test]
+    fn test_build_upstream_request_host_header() {
+        let addr: SocketAddr = "192.168.1.100:54321".parse().unwrap();
+        let request = || {
+            Request::builder()
+                .uri("/api")
+                .header("host", "localhost:442")
+                .body(Body::empty())
+                .unwrap()
+        };
+        let uri = || "http://backend:8080/api".parse::<Uri>().unwrap();
+
+        let req = build_upstream_request(request(), uri(), addr, 442, None, &Forwarding::default())
+            .unwrap();
+        assert_eq!(req.headers().get("host").unwrap(), "localhost:442");
+
+        let upstream = Some(&HostHeader::Upstream);
+        let req = build_upstream_request(
+            request(),
+            uri(),
+            addr,
+            442,
+            upstream,
+            &Forwarding::default(),
+        )
+        .unwrap();
+        assert_eq!(req.headers().get("host").unwrap(), "backend:8080");
+        assert_eq!(
+            req.headers().get("x-forwarded-host").unwrap(),
+            "localhost:442"
+        );
+    }
+
+    #[test]
+    fn test_with_tls_server_name() {
+        let mut headers = HeaderMap::new();
+        headers.insert("host", HeaderValue::from_static("bucket.example.com:443"));
+
+        let uri: Uri = "https://10.0.0.5/key".parse().unwrap();
+        let tagged = with_tls_server_name(uri, &headers).unwrap();
+        assert_eq!(
+            tagged.to_string(),
+            "https://bucket.example.com@10.0.0.5/key"
+        );
+        assert_eq!(tagged.host(), Some("10.0.0.5"));
+
+        // Plain HTTP and matching hosts are left alone
+        let uri: Uri = "http://10.0.0.5/key".parse().unwrap();
+        assert_eq!(with_tls_server_name(uri.clone(), &headers).unwrap(), uri);
+        let uri: Uri = "https://bucket.example.com/key".parse().unwrap();
+        assert_eq!(with_tls_server_name(uri.clone(), &headers).unwrap(), uri);
+
+        // IP addresses are not used as SNI
+        headers.insert("host", HeaderValue::from_static("127.0.0.1:8443"));
+        let uri: Uri = "https://backend/key".parse().unwrap();
+        assert_eq!(with_tls_server_name(uri.clone(), &headers).unwrap(), uri);
+    }
+
     #[test]
     fn test_bad_gateway_response() {
         let response = bad_gateway_response("Connection refused");
@@ -0,0 +1,404 @@
+//! HAProxy PROXY protocol (v1 and v2) on listeners and upstream connections.
+//!
+//! See <https://www.haproxy.org/download/2.9/doc/proxy-protocol.txt>.
+
+use axum::body::Body;
+use axum::http::{header::HOST, HeaderValue, Request, Response};
+use axum::middleware::AddExtension;
+use axum::Extension;
+use axum_server::accept::Accept;
+use futures_util::future::BoxFuture;
+use hyper::body::Incoming;
+use hyper_rustls::ResolveServerName;
+use hyper_util::rt::TokioIo;
+use rustls::ClientConfig;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tower::Layer;
+
+use crate::config::ProxyProtocolVersion;
+use crate::tls::UpstreamServerNameResolver;
+
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Longest possible v1 header, including the trailing CRLF
+const V1_MAX_LEN: usize = 107;
+
+/// Time allowed for the PROXY header to arrive on a new connection
+const HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Addresses of a client connection, added to every request's extensions.
+///
+/// Behind a load balancer speaking PROXY protocol these are the addresses it
+/// reported rather than those of the TCP connection.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ConnectionAddrs {
+    /// The client
+    pub client: SocketAddr,
+    /// The address the client connected to
+    pub local: SocketAddr,
+}
+
+/// Acceptor that records each connection's addresses, first reading a PROXY
+/// header when `accept` is enabled
+#[derive(Debug, Clone, Copy)]
+pub struct ProxyProtocolAcceptor {
+    accept: bool,
+}
+
+impl ProxyProtocolAcceptor {
+    pub fn new(accept: bool) -> Self {
+        Self { accept }
+    }
+}
+
+impl<S: Send + 'static> Accept<TcpStream, S> for ProxyProtocolAcceptor {
+    type Stream = TcpStream;
+    type Service = AddExtension<S, ConnectionAddrs>;
+    type Future = BoxFuture<'static, io::Result<(Self::Stream, Self::Service)>>;
+
+    fn accept(&self, mut stream: TcpStream, service: S) -> Self::Future {
+        let accept = self.accept;
+        Box::pin(async move {
+            let mut addrs = ConnectionAddrs {
+                client: stream.peer_addr()?,
+                local: stream.local_addr()?,
+            };
+
+            if accept {
+                let header = tokio::time::timeout(HEADER_TIMEOUT, read_header(&mut stream))
+                    .await
+                    .map_err(|_| {
+                        io::Error::new(
+                            io::ErrorKind::TimedOut,
+                            "timed out waiting for PROXY header",
+                        )
+                    })
+                    .and_then(|result| result)
+                    .inspect_err(|e| {
+                        tracing::warn!("Rejecting connection from {}: {}", addrs.client, e)
+                    })?;
+                if let Some(header) = header {
+                    addrs = header;
+                }
+            }
+
+            Ok((stream, Extension(addrs).layer(service)))
+        })
+    }
+}
+
+/// Read a v1 or v2 PROXY header.
+///
+/// Returns `None` for headers that carry no addresses (v1 `UNKNOWN`, v2
+/// `LOCAL` health checks, unix sockets).
+pub async fn read_header<R: AsyncRead + Unpin>(
+    stream: &mut R,
+) -> io::Result<Option<ConnectionAddrs>> {
+    // The shortest header (v1 "PROXY UNKNOWN\r\n") is longer than this
+    let mut buf = vec![0u8; V2_SIGNATURE.len()];
+    stream.read_exact(&mut buf).await?;
+
+    if buf == V2_SIGNATURE {
+        let mut fixed = [0u8; 4];
+        stream.read_exact(&mut fixed).await?;
+        let len = u16::from_be_bytes([fixed[2], fixed[3]]) as usize;
+        let mut payload = vec![0u8; len];
+        stream.read_exact(&mut payload).await?;
+        parse_v2(fixed[0], fixed[1], &payload)
+    } else if buf.starts_with(b"PROXY ") {
+        // Read byte by byte so nothing past the header is consumed
+        while !buf.ends_with(b"\r\n") {
+            if buf.len() >= V1_MAX_LEN {
+                return Err(invalid("PROXY v1 header too long"));
+            }
+            buf.push(stream.read_u8().await?);
+        }
+        parse_v1(&buf)
+    } else {
+        Err(invalid("missing PROXY protocol header"))
+    }
+}
+
+fn parse_v1(line: &[u8]) -> io::Result<Option<ConnectionAddrs>> {
+    let line = std::str::from_utf8(line).map_err(|_| invalid("invalid PROXY v1 header"))?;
+    let mut fields = line.trim_end_matches("\r\n").split(' ').skip(1);
+
+    match fields.next() {
+        Some("UNKNOWN") => Ok(None),
+        Some("TCP4") | Some("TCP6") => {
+            let fields: Vec<&str> = fields.collect();
+            let [source, destination, source_port, destination_port] = fields[..] else {
+                return Err(invalid("invalid PROXY v1 header"));
+            };
+            let addr = |ip: &str, port: &str| -> io::Result<SocketAddr> {
+                let ip: IpAddr = ip
+                    .parse()
+                    .map_err(|_| invalid("invalid PROXY v1 address"))?;
+                let port: u16 = port.parse().map_err(|_| invalid("invalid PROXY v1 port"))?;
+                Ok(SocketAddr::new(ip, port))
+            };
+            Ok(Some(ConnectionAddrs {
+                client: addr(source, source_port)?,
+                local: addr(destination, destination_port)?,
+            }))
+        }
+        _ => Err(invalid("unsupported PROXY v1 protocol")),
+    }
+}
+
+fn parse_v2(
+    version_command: u8,
+    family: u8,
+    payload: &[u8],
+) -> io::Result<Option<ConnectionAddrs>> {
+    if version_command >> 4 != 2 {
+        return Err(invalid("unsupported PROXY protocol version"));
+    }
+    match version_command & 0x0f {
+        // LOCAL: the balancer's own connection (e.g. health checks)
+        0 => return Ok(None),
+        1 => {}
+        _ => return Err(invalid("unsupported PROXY v2 command")),
+    }
+
+    let port = |at: usize| u16::from_be_bytes([payload[at], payload[at + 1]]);
+    let addrs = match family >> 4 {
+        1 if payload.len() >= 12 => {
+            let ip = |at: usize| {
+                IpAddr::V4(Ipv4Addr::from(
+                    <[u8; 4]>::try_from(&payload[at..at + 4]).unwrap(),
+                ))
+            };
+            Some(ConnectionAddrs {
+                client: SocketAddr::new(ip(0), port(8)),
+                local: SocketAddr::new(ip(4), port(10)),
+            })
+        }
+        2 if payload.len() >= 36 => {
+            let ip = |at: usize| {
+                IpAddr::V6(Ipv6Addr::from(
+                    <[u8; 16]>::try_from(&payload[at..at + 16]).unwrap(),
+                ))
+            };
+            Some(ConnectionAddrs {
+                client: SocketAddr::new(ip(0), port(32)),
+                local: SocketAddr::new(ip(16), port(34)),
+            })
+        }
+        1 | 2 => return Err(invalid("truncated PROXY v2 addresses")),
+        // UNSPEC and unix sockets: keep the connection's own addresses
+        _ => None,
+    };
+
+    Ok(addrs)
+}
+
+/// Encode a PROXY header describing `addrs`.
+///
+/// Mixed IPv4/IPv6 pairs are sent as IPv4-mapped IPv6 addresses.
+pub fn encode(version: ProxyProtocolVersion, addrs: ConnectionAddrs) -> Vec<u8> {
+    let (client, local) = (addrs.client, addrs.local);
+    let ips = match (client.ip(), local.ip()) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => (IpAddr::V4(src), IpAddr::V4(dst)),
+        (src, dst) => (IpAddr::V6(to_v6(src)), IpAddr::V6(to_v6(dst))),
+    };
+
+    match version {
+        ProxyProtocolVersion::V1 => {
+            let protocol = if ips.0.is_ipv4() { "TCP4" } else { "TCP6" };
+            format!(
+                "PROXY {} {} {} {} {}\r\n",
+                protocol,
+                ips.0,
+                ips.1,
+                client.port(),
+                local.port()
+            )
+            .into_bytes()
+        }
+        ProxyProtocolVersion::V2 => {
+            let mut out = V2_SIGNATURE.to_vec();
+            // Version 2, PROXY command
+            out.push(0x21);
+            match ips {
+                (IpAddr::V4(src), IpAddr::V4(dst)) => {
+                    // TCP over IPv4
+                    out.push(0x11);
+                    out.extend_from_slice(&12u16.to_be_bytes());
+                    out.extend_from_slice(&src.octets());
+                    out.extend_from_slice(&dst.octets());
+                }
+                (src, dst) => {
+                    // TCP over IPv6
+                    out.push(0x21);
+                    out.extend_from_slice(&36u16.to_be_bytes());
+                    out.extend_from_slice(&to_v6(src).octets());
+                    out.extend_from_slice(&to_v6(dst).octets());
+                }
+            }
+            out.extend_from_slice(&client.port().to_be_bytes());
+            out.extend_from_slice(&local.port().to_be_bytes());
+            out
+        }
+    }
+}
+
+fn to_v6(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+/// Send `req` over a new upstream connection that starts with a PROXY header.
+///
+/// These connections are not pooled since each one describes a single client.
+pub async fn send_request(
+    version: ProxyProtocolVersion,
+    addrs: ConnectionAddrs,
+    mut req: Request<Body>,
+    tls_config: Arc<ClientConfig>,
+) -> anyhow::Result<Response<Incoming>> {
+    let uri = req.uri().clone();
+    let host = uri
+        .host()
+        .ok_or_else(|| anyhow::anyhow!("upstream URI has no host"))?;
+    let https = uri.scheme_str() == Some("https");
+    let port = uri.port_u16().unwrap_or(if https { 443 } else { 80 });
+
+    let address = host.trim_start_matches('[').trim_end_matches(']');
+    let mut tcp = TcpStream::connect((address, port)).await?;
+    tcp.write_all(&encode(version, addrs)).await?;
+
+    // A bare HTTP/1 connection needs origin-form and an explicit Host
+    if !req.headers().contains_key(HOST) {
+        let host = match uri.port() {
+            Some(port) => format!("{}:{}", host, port),
+            None => host.to_string(),
+        };
+        req.headers_mut()
+            .insert(HOST, HeaderValue::from_str(&host)?);
+    }
+    *req.uri_mut() = uri.path_and_query().map_or("/", |pq| pq.as_str()).parse()?;
+
+    if https {
+        let server_name = UpstreamServerNameResolver
+            .resolve(&uri)
+            .map_err(|e| anyhow::anyhow!("{}", e))?;
+        let tls = tokio_rustls::TlsConnector::from(tls_config)
+            .connect(server_name, tcp)
+            .await?;
+        send_over(tls, req).await
+    } else {
+        send_over(tcp, req).await
+    }
+}
+
+async fn send_over<S>(stream: S, req: Request<Body>) -> anyhow::Result<Response<Incoming>>
+where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    let (mut sender, conn) = hyper::client::conn::http1::handshake(TokioIo::new(stream)).await?;
+    tokio::spawn(async move {
+        if let Err(e) = conn.await {
+            tracing::debug!("Upstream connection closed with error: {}", e);
+        }
+    });
+
+    Ok(sender.send_request(req).await?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addrs(client: &str, local: &str) -> ConnectionAddrs {
+        ConnectionAddrs {
+            client: client.parse().unwrap(),
+            local: local.parse().unwrap(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_read_v1_header() {
+        let mut input: &[u8] = b"PROXY TCP4 203.0.113.7 10.0.0.1 56324 443\r\nGET / HTTP/1.1\r\n";
+        let header = read_header(&mut input).await.unwrap();
+
+        assert_eq!(header, Some(addrs("203.0.113.7:56324", "10.0.0.1:443")));
+        // Nothing past the header is consumed
+        assert_eq!(input, b"GET / HTTP/1.1\r\n");
+
+        let mut input: &[u8] = b"PROXY UNKNOWN\r\n";
+        assert_eq!(read_header(&mut input).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_read_v2_header() {
+        let expected = addrs("[2001:db8::7]:56324", "[2001:db8::1]:443");
+        let mut encoded = encode(ProxyProtocolVersion::V2, expected);
+        encoded.extend_from_slice(b"rest");
+        let mut input: &[u8] = &encoded;
+
+        assert_eq!(read_header(&mut input).await.unwrap(), Some(expected));
+        assert_eq!(input, b"rest");
+    }
+
+    #[tokio::test]
+    async fn test_read_v2_local_command() {
+        let mut input = V2_SIGNATURE.to_vec();
+        input.extend_from_slice(&[0x20, 0x00, 0x00, 0x00]);
+
+        assert_eq!(read_header(&mut input.as_slice()).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_read_header_rejects_plain_request() {
+        let mut input: &[u8] = b"GET / HTTP/1.1\r\nHost: x\r\n\r\n";
+        assert!(read_header(&mut input).await.is_err());
+
+        let mut input: &[u8] = b"PROXY TCP4 not-an-ip 10.0.0.1 1 2\r\n";
+        assert!(read_header(&mut input).await.is_err());
+    }
+
+    #[test]
+    fn test_encode_v1() {
+        let header = encode(
+            ProxyProtocolVersion::V1,
+            addrs("203.0.113.7:56324", "10.0.0.1:443"),
+        );
+        assert_eq!(header, b"PROXY TCP4 203.0.113.7 10.0.0.1 56324 443\r\n");
+
+        // Mixed families are sent as IPv6
+        let header = encode(
+            ProxyProtocolVersion::V1,
+            addrs("203.0.113.7:56324", "[::1]:443"),
+        );
+        assert_eq!(
+            header,
+            b"PROXY TCP6 ::ffff:203.0.113.7 ::1 56324 443\r\n".to_vec()
+        );
+    }
+
+    #[test]
+    fn test_encode_v2_ipv4() {
+        let header = encode(
+            ProxyProtocolVersion::V2,
+            addrs("203.0.113.7:56324", "10.0.0.1:443"),
+        );
+
+        assert_eq!(&header[..12], &V2_SIGNATURE);
+        assert_eq!(&header[12..16], &[0x21, 0x11, 0x00, 0x0c]);
+        assert_eq!(&header[16..20], &[203, 0, 113, 7]);
+        assert_eq!(&header[20..24], &[10, 0, 0, 1]);
+        assert_eq!(&header[24..], &[0xdc, 0x04, 0x01, 0xbb]);
+    }
+}
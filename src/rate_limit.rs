@@ -0,0 +1,339 @@
+//! Per-client request rate limits.
+
+use axum::{
+    body::Body,
+    http::{header::AUTHORIZATION, HeaderMap, HeaderValue, Request, Response, StatusCode},
+};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::config::{RateLimitAlgorithm, RateLimitConfig, RateLimitKey};
+
+/// Idle clients are forgotten once this many are tracked
+const PRUNE_THRESHOLD: usize = 10_000;
+
+/// Counter state for one client
+#[derive(Debug, Clone, Copy)]
+enum Counter {
+    TokenBucket {
+        tokens: f64,
+        updated: Instant,
+    },
+    SlidingWindow {
+        window_start: Instant,
+        current: u32,
+        previous: u32,
+    },
+}
+
+/// Outcome of counting a request
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimitStatus {
+    pub allowed: bool,
+    pub limit: u32,
+    pub remaining: u32,
+    /// Until the client is back to its full allowance
+    pub reset: Duration,
+    /// Until the next request would be allowed (zero when allowed)
+    pub retry_after: Duration,
+    window: Duration,
+}
+
+impl RateLimitStatus {
+    /// Add the `RateLimit-*` headers describing this limit
+    pub fn apply_headers(&self, headers: &mut HeaderMap) {
+        headers.insert("ratelimit-limit", HeaderValue::from(self.limit));
+        headers.insert("ratelimit-remaining", HeaderValue::from(self.remaining));
+        headers.insert("ratelimit-reset", HeaderValue::from(ceil_secs(self.reset)));
+        if let Ok(policy) =
+            HeaderValue::from_str(&format!("{};w={}", self.limit, self.window.as_secs()))
+        {
+            headers.insert("ratelimit-policy", policy);
+        }
+    }
+
+    /// 429 response for a request over the limit
+    pub fn too_many_requests(&self) -> Response<Body> {
+        let mut response = Response::builder()
+            .status(StatusCode::TOO_MANY_REQUESTS)
+            .header("content-type", "text/plain; charset=utf-8")
+            .header("retry-after", ceil_secs(self.retry_after).max(1))
+            .body(Body::from("429 Too Many Requests"))
+            .unwrap();
+        self.apply_headers(response.headers_mut());
+        response
+    }
+}
+
+fn ceil_secs(duration: Duration) -> u64 {
+    duration.as_secs() + u64::from(duration.subsec_nanos() > 0)
+}
+
+/// A configured rate limit and the counters of the clients it has seen
+#[derive(Debug)]
+pub struct RateLimiter {
+    algorithm: RateLimitAlgorithm,
+    limit: u32,
+    window: Duration,
+    burst: u32,
+    key: RateLimitKey,
+    counters: Mutex<HashMap<String, Counter>>,
+}
+
+impl RateLimiter {
+    pub fn new(config: &RateLimitConfig) -> Self {
+        Self {
+            algorithm: config.algorithm,
+            limit: config.limit.max(1),
+            window: Duration::from_secs(config.window_secs.max(1)),
+            burst: config.burst.unwrap_or(config.limit).max(1),
+            key: config.key.clone(),
+            counters: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Count `req` against its client's allowance
+    pub fn check<B>(&self, req: &Request<B>, client_ip: IpAddr) -> RateLimitStatus {
+        self.check_key(&self.key(req, client_ip), Instant::now())
+    }
+
+    /// The client a request is counted for. Requests without the configured
+    /// header fall back to their IP so they can't avoid the limit.
+    fn key<B>(&self, req: &Request<B>, client_ip: IpAddr) -> String {
+        let header = |name: &str| {
+            req.headers()
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(str::trim)
+                .filter(|v| !v.is_empty())
+        };
+        let value = match &self.key {
+            RateLimitKey::Ip => None,
+            RateLimitKey::Header(name) => header(name).map(|v| format!("header:{}", v)),
+            RateLimitKey::ApiKey => header("x-api-key")
+                .or_else(|| {
+                    header(AUTHORIZATION.as_str())
+                        .and_then(|v| v.strip_prefix("Bearer ").map(str::trim))
+                })
+                .map(|v| format!("key:{}", v)),
+        };
+        value.unwrap_or_else(|| format!("ip:{}", client_ip))
+    }
+
+    fn check_key(&self, key: &str, now: Instant) -> RateLimitStatus {
+        let mut counters = self.counters.lock().unwrap();
+        if counters.len() >= PRUNE_THRESHOLD {
+            counters.retain(|_, counter| !self.is_idle(counter, now));
+        }
+
+        let counter = counters
+            .entry(key.to_string())
+            .or_insert_with(|| self.new_counter(now));
+        match counter {
+            Counter::TokenBucket { tokens, updated } => {
+                let capacity = f64::from(self.burst);
+                let rate = f64::from(self.limit) / self.window.as_secs_f64();
+                *tokens =
+                    (*tokens + now.duration_since(*updated).as_secs_f64() * rate).min(capacity);
+                *updated = now;
+
+                let allowed = *tokens >= 1.0;
+                if allowed {
+                    *tokens -= 1.0;
+                }
+                RateLimitStatus {
+                    allowed,
+                    limit: self.burst,
+                    remaining: tokens.floor() as u32,
+                    reset: Duration::from_secs_f64((capacity - *tokens) / rate),
+                    retry_after: if allowed {
+                        Duration::ZERO
+                    } else {
+                        Duration::from_secs_f64((1.0 - *tokens) / rate)
+                    },
+                    window: self.window,
+                }
+            }
+            Counter::SlidingWindow {
+                window_start,
+                current,
+                previous,
+            } => {
+                let elapsed = now.duration_since(*window_start);
+                if elapsed >= self.window {
+                    let windows = (elapsed.as_secs_f64() / self.window.as_secs_f64()) as u32;
+                    *previous = if windows == 1 { *current } else { 0 };
+                    *current = 0;
+                    *window_start += self.window * windows;
+                }
+
+                // The previous window counts for the part of it still in view
+                let into_window = now.duration_since(*window_start);
+                let overlap = 1.0 - into_window.as_secs_f64() / self.window.as_secs_f64();
+                let estimate = f64::from(*previous) * overlap + f64::from(*current);
+                let limit = f64::from(self.limit);
+
+                let allowed = estimate + 1.0 <= limit;
+                if allowed {
+                    *current += 1;
+                }
+                let used = (estimate + f64::from(u8::from(allowed))).ceil();
+                let until_next_window = self.window - into_window;
+                let retry_after = if allowed {
+                    Duration::ZERO
+                } else if f64::from(*current) + 1.0 > limit || *previous == 0 {
+                    until_next_window
+                } else {
+                    // When enough of the previous window has slid out of view
+                    let needed = 1.0 - (limit - 1.0 - f64::from(*current)) / f64::from(*previous);
+                    self.window
+                        .mul_f64(needed.clamp(0.0, 1.0))
+                        .saturating_sub(into_window)
+                };
+                RateLimitStatus {
+                    allowed,
+                    limit: self.limit,
+                    remaining: (limit - used).max(0.0) as u32,
+                    reset: until_next_window,
+                    retry_after,
+                    window: self.window,
+                }
+            }
+        }
+    }
+
+    fn new_counter(&self, now: Instant) -> Counter {
+        match self.algorithm {
+            RateLimitAlgorithm::TokenBucket => Counter::TokenBucket {
+                tokens: f64::from(self.burst),
+                updated: now,
+            },
+            RateLimitAlgorithm::SlidingWindow => Counter::SlidingWindow {
+                window_start: now,
+                current: 0,
+                previous: 0,
+            },
+        }
+    }
+
+    /// Whether a client's counter is back where a new client would start
+    fn is_idle(&self, counter: &Counter, now: Instant) -> bool {
+        match counter {
+            Counter::TokenBucket { tokens, updated } => {
+                let rate = f64::from(self.limit) / self.window.as_secs_f64();
+                tokens + now.duration_since(*updated).as_secs_f64() * rate >= f64::from(self.burst)
+            }
+            Counter::SlidingWindow { window_start, .. } => {
+                now.duration_since(*window_start) >= self.window * 2
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(algorithm: RateLimitAlgorithm, limit: u32, burst: Option<u32>) -> RateLimiter {
+        RateLimiter::new(&RateLimitConfig {
+            algorithm,
+            limit,
+            window_secs: 10,
+            burst,
+            key: RateLimitKey::Ip,
+        })
+    }
+
+    #[test]
+    fn test_token_bucket() {
+        let limiter = limiter(RateLimitAlgorithm::TokenBucket, 10, Some(3));
+        let start = Instant::now();
+
+        for remaining in [2, 1, 0] {
+            let status = limiter.check_key("a", start);
+            assert!(status.allowed);
+            assert_eq!(status.remaining, remaining);
+        }
+        let status = limiter.check_key("a", start);
+        assert!(!status.allowed);
+        assert_eq!(status.limit, 3);
+        assert_eq!(status.retry_after, Duration::from_secs(1));
+
+        // Other clients have their own bucket
+        assert!(limiter.check_key("b", start).allowed);
+
+        // One token per second comes back
+        assert!(
+            limiter
+                .check_key("a", start + Duration::from_secs(1))
+                .allowed
+        );
+        assert!(
+            !limiter
+                .check_key("a", start + Duration::from_secs(1))
+                .allowed
+        );
+    }
+
+    #[test]
+    fn test_sliding_window() {
+        let limiter = limiter(RateLimitAlgorithm::SlidingWindow, 4, None);
+        let start = Instant::now();
+
+        for _ in 0..4 {
+            assert!(limiter.check_key("a", start).allowed);
+        }
+        let status = limiter.check_key("a", start + Duration::from_secs(2));
+        assert!(!status.allowed);
+        assert_eq!(status.remaining, 0);
+        assert_eq!(status.retry_after, Duration::from_secs(8));
+
+        // Halfway through the next window half of the previous one still counts
+        let halfway = start + Duration::from_secs(15);
+        assert!(limiter.check_key("a", halfway).allowed);
+        assert!(limiter.check_key("a", halfway).allowed);
+        assert!(!limiter.check_key("a", halfway).allowed);
+
+        // Long after, the client starts afresh
+        let later = start + Duration::from_secs(60);
+        assert_eq!(limiter.check_key("a", later).remaining, 3);
+    }
+
+    #[test]
+    fn test_key() {
+        let mut limiter = limiter(RateLimitAlgorithm::TokenBucket, 1, None);
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let req = Request::builder()
+            .header("authorization", "Bearer secret")
+            .header("x-tenant", "acme")
+            .body(())
+            .unwrap();
+
+        assert_eq!(limiter.key(&req, ip), "ip:10.0.0.1");
+        limiter.key = RateLimitKey::ApiKey;
+        assert_eq!(limiter.key(&req, ip), "key:secret");
+        limiter.key = RateLimitKey::Header("x-tenant".into());
+        assert_eq!(limiter.key(&req, ip), "header:acme");
+        limiter.key = RateLimitKey::Header("x-missing".into());
+        assert_eq!(limiter.key(&req, ip), "ip:10.0.0.1");
+    }
+
+    #[test]
+    fn test_too_many_requests() {
+        let limiter = limiter(RateLimitAlgorithm::TokenBucket, 1, None);
+        let start = Instant::now();
+        limiter.check_key("a", start);
+        let response = limiter.check_key("a", start).too_many_requests();
+
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers().get("retry-after").unwrap(), "10");
+        assert_eq!(response.headers().get("ratelimit-limit").unwrap(), "1");
+        assert_eq!(response.headers().get("ratelimit-remaining").unwrap(), "0");
+        assert_eq!(
+            response.headers().get("ratelimit-policy").unwrap(),
+            "1;w=10"
+        );
+    }
+}
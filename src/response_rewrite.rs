@@ -0,0 +1,380 @@
+//! Rewriting of upstream URLs in response headers to the client-facing
+//! origin: `Location`, `Content-Location`, `Refresh` and `Set-Cookie`, like
+//! nginx's `proxy_redirect` and `proxy_cookie_domain`.
+
+use axum::http::{
+    header::{CONTENT_LOCATION, LOCATION, REFRESH, SET_COOKIE},
+    HeaderMap, HeaderValue, Uri,
+};
+use std::collections::BTreeMap;
+
+use crate::config::ResponseRewriteConfig;
+
+/// A listener's response rewriting settings
+#[derive(Debug)]
+pub struct ResponseRewriter {
+    locations: bool,
+    cookies: bool,
+    secure_cookies: bool,
+    /// Extra URL prefixes to rewrite, checked before the automatic ones
+    location_map: Vec<(String, String)>,
+    /// Cookie domains to rewrite, without a leading dot
+    cookie_domains: BTreeMap<String, String>,
+}
+
+/// How one upstream's URLs map to the client-facing origin
+#[derive(Debug)]
+pub struct UpstreamUrls {
+    /// `scheme://authority` forms the upstream may use, lowercased
+    origins: Vec<String>,
+    /// Host names a cookie `Domain` may name the upstream by
+    hosts: Vec<String>,
+    /// The target's base path, without a trailing slash
+    base_path: String,
+    /// `https://` and the host the client asked for
+    public_origin: String,
+}
+
+impl UpstreamUrls {
+    /// `target` is the target URL, `upstream_host` the `Host` sent to it and
+    /// `public_host` the `Host` the client sent
+    pub fn new(target: &Uri, upstream_host: Option<&str>, public_host: &str) -> Self {
+        let mut origins = Vec::new();
+        let mut hosts = Vec::new();
+        if let Some(authority) = target.authority() {
+            origins.push(format!(
+                "{}://{}",
+                target.scheme_str().unwrap_or("http"),
+                authority
+            ));
+            hosts.push(authority.host().to_string());
+        }
+        // An upstream that was sent another Host (or the public one) builds
+        // its URLs from that, over either scheme
+        if let Some(host) = upstream_host {
+            for scheme in ["http", "https"] {
+                origins.push(format!("{}://{}", scheme, host));
+            }
+            hosts.push(
+                host.rsplit_once(':')
+                    .map_or(host, |(name, _)| name)
+                    .to_string(),
+            );
+        }
+        for origin in &mut origins {
+            origin.make_ascii_lowercase();
+        }
+        for host in &mut hosts {
+            host.make_ascii_lowercase();
+        }
+
+        Self {
+            origins,
+            hosts,
+            base_path: target.path().trim_end_matches('/').to_string(),
+            public_origin: format!("https://{}", public_host),
+        }
+    }
+
+    /// The client-facing form of an upstream path, without the base path
+    fn strip_base_path<'a>(&self, path: &'a str) -> Option<&'a str> {
+        if self.base_path.is_empty() {
+            return None;
+        }
+        let rest = path.strip_prefix(self.base_path.as_str())?;
+        match rest.chars().next() {
+            None => Some("/"),
+            Some('/') => Some(rest),
+            Some('?') | Some('#') => Some(rest),
+            Some(_) => None,
+        }
+    }
+
+    /// The client-facing form of a URL, if it points at the upstream
+    fn rewrite_url(&self, url: &str) -> Option<String> {
+        if url.starts_with('/') && !url.starts_with("//") {
+            let rest = self.strip_base_path(url)?;
+            return Some(if rest.starts_with('/') {
+                rest.to_string()
+            } else {
+                format!("/{}", rest)
+            });
+        }
+
+        let lower = url.to_ascii_lowercase();
+        let origin = self.origins.iter().find(|origin| {
+            lower.starts_with(origin.as_str())
+                && matches!(
+                    lower[origin.len()..].chars().next(),
+                    None | Some('/') | Some('?') | Some('#')
+                )
+        })?;
+        let rest = &url[origin.len()..];
+        let path = match self.strip_base_path(rest) {
+            Some(path) => path,
+            None => rest,
+        };
+        let path = if path.starts_with('/') {
+            path.to_string()
+        } else {
+            format!("/{}", path)
+        };
+        Some(format!("{}{}", self.public_origin, path))
+    }
+}
+
+impl ResponseRewriter {
+    pub fn new(config: &ResponseRewriteConfig) -> Self {
+        Self {
+            locations: config.locations,
+            cookies: config.cookies,
+            secure_cookies: config.secure_cookies,
+            location_map: config
+                .location_map
+                .iter()
+                .map(|(from, to)| (from.clone(), to.clone()))
+                .collect(),
+            cookie_domains: config
+                .cookie_domains
+                .iter()
+                .map(|(from, to)| {
+                    (
+                        from.trim_start_matches('.').to_ascii_lowercase(),
+                        to.clone(),
+                    )
+                })
+                .collect(),
+        }
+    }
+
+    /// Rewrite the response headers that point at the upstream
+    pub fn apply(&self, headers: &mut HeaderMap, urls: &UpstreamUrls) {
+        if self.locations {
+            for name in [LOCATION, CONTENT_LOCATION] {
+                let rewritten = headers
+                    .get(&name)
+                    .and_then(|value| value.to_str().ok())
+                    .and_then(|url| self.rewrite_location(url, urls));
+                if let Some(value) = rewritten.and_then(|url| HeaderValue::from_str(&url).ok()) {
+                    headers.insert(name, value);
+                }
+            }
+            let refresh = headers
+                .get(REFRESH)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|refresh| self.rewrite_refresh(refresh, urls));
+            if let Some(value) = refresh.and_then(|refresh| HeaderValue::from_str(&refresh).ok()) {
+                headers.insert(REFRESH, value);
+            }
+        }
+
+        if self.cookies || self.secure_cookies {
+            let cookies: Vec<HeaderValue> = headers
+                .get_all(SET_COOKIE)
+                .iter()
+                .map(|value| {
+                    value
+                        .to_str()
+                        .ok()
+                        .map(|cookie| self.rewrite_cookie(cookie, urls))
+                        .and_then(|cookie| HeaderValue::from_str(&cookie).ok())
+                        .unwrap_or_else(|| value.clone())
+                })
+                .collect();
+            headers.remove(SET_COOKIE);
+            for cookie in cookies {
+                headers.append(SET_COOKIE, cookie);
+            }
+        }
+    }
+
+    fn rewrite_location(&self, url: &str, urls: &UpstreamUrls) -> Option<String> {
+        let mapped = self
+            .location_map
+            .iter()
+            .find_map(|(from, to)| Some(format!("{}{}", to, url.strip_prefix(from.as_str())?)));
+        mapped.or_else(|| urls.rewrite_url(url))
+    }
+
+    /// `Refresh: 5; url=http://api:3000/next`
+    fn rewrite_refresh(&self, refresh: &str, urls: &UpstreamUrls) -> Option<String> {
+        let start = refresh.to_ascii_lowercase().find("url=")? + "url=".len();
+        let url = refresh[start..].trim();
+        let quoted = url.starts_with(['"', '\'']) && url.len() > 1;
+        let bare = if quoted { &url[1..url.len() - 1] } else { url };
+        let rewritten = self.rewrite_location(bare, urls)?;
+        Some(if quoted {
+            format!(
+                "{}{}{}{}",
+                &refresh[..start],
+                &url[..1],
+                rewritten,
+                &url[..1]
+            )
+        } else {
+            format!("{}{}", &refresh[..start], rewritten)
+        })
+    }
+
+    fn rewrite_cookie(&self, cookie: &str, urls: &UpstreamUrls) -> String {
+        let mut parts = cookie.split(';').map(str::trim);
+        let mut rewritten = vec![parts.next().unwrap_or_default().to_string()];
+        let mut secure = false;
+
+        for attribute in parts.filter(|attribute| !attribute.is_empty()) {
+            let (name, value) = attribute.split_once('=').unwrap_or((attribute, ""));
+            let name_lower = name.trim().to_ascii_lowercase();
+            secure |= name_lower == "secure";
+            if !self.cookies {
+                rewritten.push(attribute.to_string());
+                continue;
+            }
+            match name_lower.as_str() {
+                "domain" => {
+                    let domain = value.trim().trim_start_matches('.').to_ascii_lowercase();
+                    if let Some(mapped) = self.cookie_domains.get(&domain) {
+                        rewritten.push(format!("{}={}", name, mapped));
+                    } else if !urls.hosts.contains(&domain) {
+                        rewritten.push(attribute.to_string());
+                    }
+                    // Cookies for the upstream's own host become host-only
+                    // cookies for the public host
+                }
+                "path" => match urls.strip_base_path(value.trim()) {
+                    Some(path) => rewritten.push(format!("{}={}", name, path)),
+                    None => rewritten.push(attribute.to_string()),
+                },
+                _ => rewritten.push(attribute.to_string()),
+            }
+        }
+        if self.secure_cookies && !secure {
+            rewritten.push("Secure".to_string());
+        }
+        rewritten.join("; ")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rewriter(yaml: &str) -> ResponseRewriter {
+        ResponseRewriter::new(&serde_yaml::from_str(yaml).unwrap())
+    }
+
+    fn urls(target: &str, upstream_host: Option<&str>) -> UpstreamUrls {
+        UpstreamUrls::new(
+            &target.parse().unwrap(),
+            upstream_host,
+            "app.localhost:8443",
+        )
+    }
+
+    fn location(rewriter: &ResponseRewriter, urls: &UpstreamUrls, location: &str) -> String {
+        let mut headers = HeaderMap::new();
+        headers.insert(LOCATION, HeaderValue::from_str(location).unwrap());
+        rewriter.apply(&mut headers, urls);
+        headers[LOCATION].to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn test_locations() {
+        let rewriter = rewriter("{}");
+        let urls = urls("http://api:3000/service-a", Some("api:3000"));
+
+        assert_eq!(
+            location(&rewriter, &urls, "http://api:3000/service-a/login?next=/"),
+            "https://app.localhost:8443/login?next=/"
+        );
+        assert_eq!(
+            location(&rewriter, &urls, "HTTP://API:3000/service-a"),
+            "https://app.localhost:8443/"
+        );
+        assert_eq!(
+            location(&rewriter, &urls, "http://api:3000/other"),
+            "https://app.localhost:8443/other"
+        );
+        assert_eq!(location(&rewriter, &urls, "/service-a/home"), "/home");
+        // Other hosts, and paths outside the base path, are left alone
+        assert_eq!(
+            location(&rewriter, &urls, "https://idp.example.com/authorize"),
+            "https://idp.example.com/authorize"
+        );
+        assert_eq!(
+            location(&rewriter, &urls, "http://api:30001/x"),
+            "http://api:30001/x"
+        );
+        assert_eq!(location(&rewriter, &urls, "/service-ab"), "/service-ab");
+    }
+
+    #[test]
+    fn test_preserved_host_gets_https() {
+        let rewriter = rewriter("{}");
+        let urls = urls("http://api:3000", Some("app.localhost:8443"));
+        assert_eq!(
+            location(&rewriter, &urls, "http://app.localhost:8443/next"),
+            "https://app.localhost:8443/next"
+        );
+    }
+
+    #[test]
+    fn test_location_map_and_refresh() {
+        let rewriter = rewriter("location_map:\n  http://auth:9000/: https://auth.localhost/\n");
+        let urls = urls("http://api:3000", None);
+        assert_eq!(
+            location(&rewriter, &urls, "http://auth:9000/login"),
+            "https://auth.localhost/login"
+        );
+
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            REFRESH,
+            HeaderValue::from_static("5; URL='http://api:3000/done'"),
+        );
+        rewriter.apply(&mut headers, &urls);
+        assert_eq!(headers[REFRESH], "5; URL='https://app.localhost:8443/done'");
+    }
+
+    #[test]
+    fn test_cookies() {
+        let rewriter = rewriter("cookie_domains:\n  .corp.internal: localhost.test\n");
+        let urls = urls("http://api:3000/service-a", None);
+        let mut headers = HeaderMap::new();
+        for cookie in [
+            "sid=1; Domain=api; Path=/service-a/app; HttpOnly",
+            "pref=2; domain=.corp.internal; path=/service-a",
+            "other=3; Domain=example.com; Path=/x; Secure",
+        ] {
+            headers.append(SET_COOKIE, HeaderValue::from_static(cookie));
+        }
+        rewriter.apply(&mut headers, &urls);
+
+        let cookies: Vec<_> = headers
+            .get_all(SET_COOKIE)
+            .iter()
+            .map(|v| v.to_str().unwrap())
+            .collect();
+        assert_eq!(
+            cookies,
+            [
+                "sid=1; Path=/app; HttpOnly; Secure",
+                "pref=2; domain=localhost.test; path=/; Secure",
+                "other=3; Domain=example.com; Path=/x; Secure",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_disabled() {
+        let rewriter = rewriter("locations: false\ncookies: false\nsecure_cookies: false\n");
+        let urls = urls("http://api:3000", None);
+        assert_eq!(
+            location(&rewriter, &urls, "http://api:3000/x"),
+            "http://api:3000/x"
+        );
+        let mut headers = HeaderMap::new();
+        headers.insert(SET_COOKIE, HeaderValue::from_static("a=1; Domain=api"));
+        rewriter.apply(&mut headers, &urls);
+        assert_eq!(headers[SET_COOKIE], "a=1; Domain=api");
+    }
+}
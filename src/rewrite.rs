@@ -0,0 +1,321 @@
+//! Per-route URL rewrites: regex path rewrites, query parameter edits and
+//! redirects, applied before the upstream URI is built.
+
+use anyhow::Context;
+use axum::{
+    body::Body,
+    http::{header::LOCATION, uri::PathAndQuery, Response, StatusCode, Uri},
+};
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use regex::{Captures, Regex};
+
+use crate::config::RewriteRule;
+
+/// Everything but RFC 3986 unreserved characters
+const QUERY_VALUE: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+/// What a matching rule does with the path
+#[derive(Debug)]
+enum Action {
+    /// Only edit the query
+    None,
+    Replace(String),
+    Redirect(String, StatusCode),
+}
+
+#[derive(Debug)]
+struct Rule {
+    /// Matched against the path; every path matches when `None`
+    pattern: Option<Regex>,
+    action: Action,
+    add_query: Vec<(String, String)>,
+    remove_query: Vec<String>,
+    last: bool,
+}
+
+/// The outcome of a route's rewrite rules
+#[derive(Debug)]
+pub enum Rewritten {
+    /// Forward with this URI
+    Uri(Uri),
+    /// Answer with this redirect instead of forwarding
+    Redirect(Response<Body>),
+}
+
+/// A route's rewrite rules, evaluated in order
+#[derive(Debug)]
+pub struct Rewriter {
+    rules: Vec<Rule>,
+}
+
+impl Rewriter {
+    pub fn new(rules: &[RewriteRule]) -> anyhow::Result<Self> {
+        let rules = rules
+            .iter()
+            .map(|rule| {
+                let pattern = rule
+                    .pattern
+                    .as_deref()
+                    .map(|pattern| {
+                        Regex::new(pattern)
+                            .with_context(|| format!("invalid rewrite regex {:?}", pattern))
+                    })
+                    .transpose()?;
+                let action = match (&rule.replace, &rule.redirect) {
+                    (Some(replace), _) => Action::Replace(replace.clone()),
+                    (None, Some(redirect)) => Action::Redirect(
+                        redirect.clone(),
+                        StatusCode::from_u16(rule.status)
+                            .with_context(|| format!("invalid redirect status {}", rule.status))?,
+                    ),
+                    (None, None) => Action::None,
+                };
+                Ok(Rule {
+                    pattern,
+                    action,
+                    add_query: rule
+                        .add_query
+                        .iter()
+                        .map(|(name, value)| (name.clone(), value.clone()))
+                        .collect(),
+                    remove_query: rule.remove_query.clone(),
+                    last: rule.last,
+                })
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Self { rules })
+    }
+
+    /// Run the rules over `uri`. The query string is kept through rewrites
+    /// and redirects; a `?` in a replacement adds parameters to it.
+    pub fn apply(&self, uri: &Uri) -> anyhow::Result<Rewritten> {
+        let mut path = uri.path().to_string();
+        let mut query: Vec<String> = uri
+            .query()
+            .into_iter()
+            .flat_map(|query| query.split('&'))
+            .filter(|param| !param.is_empty())
+            .map(str::to_string)
+            .collect();
+        let mut changed = false;
+
+        for rule in &self.rules {
+            let subject = path.clone();
+            let captures = match &rule.pattern {
+                Some(regex) => match regex.captures(&subject) {
+                    Some(captures) => Some(captures),
+                    None => continue,
+                },
+                None => None,
+            };
+            changed = true;
+
+            for name in &rule.remove_query {
+                query.retain(|param| param_name(param) != *name);
+            }
+            for (name, value) in &rule.add_query {
+                query.retain(|param| param_name(param) != *name);
+                query.push(format!(
+                    "{}={}",
+                    utf8_percent_encode(name, QUERY_VALUE),
+                    utf8_percent_encode(&expand(captures.as_ref(), value), QUERY_VALUE)
+                ));
+            }
+
+            match &rule.action {
+                Action::None => {}
+                Action::Replace(replace) => {
+                    let replaced = expand(captures.as_ref(), replace);
+                    let (new_path, added) = split_query(&replaced);
+                    path = if new_path.starts_with('/') {
+                        new_path.to_string()
+                    } else {
+                        format!("/{}", new_path)
+                    };
+                    query.extend(added);
+                }
+                Action::Redirect(redirect, status) => {
+                    let redirected = expand(captures.as_ref(), redirect);
+                    let (location, added) = split_query(&redirected);
+                    query.splice(0..0, added);
+                    let location = join_query(location, &query);
+                    tracing::debug!("Redirecting {} to {} ({})", uri, location, status);
+                    let response = Response::builder()
+                        .status(*status)
+                        .header(LOCATION, &location)
+                        .body(Body::empty())
+                        .with_context(|| format!("invalid redirect location {:?}", location))?;
+                    return Ok(Rewritten::Redirect(response));
+                }
+            }
+            if rule.last {
+                break;
+            }
+        }
+
+        if !changed {
+            return Ok(Rewritten::Uri(uri.clone()));
+        }
+        let path_and_query = join_query(&path, &query);
+        let mut parts = uri.clone().into_parts();
+        parts.path_and_query = Some(
+            PathAndQuery::try_from(path_and_query.as_str())
+                .with_context(|| format!("invalid rewritten path {:?}", path_and_query))?,
+        );
+        let rewritten = Uri::from_parts(parts)?;
+        tracing::debug!("Rewrote {} to {}", uri, rewritten);
+        Ok(Rewritten::Uri(rewritten))
+    }
+}
+
+/// `template` with `$1` and `${name}` replaced from the rule's match
+fn expand(captures: Option<&Captures>, template: &str) -> String {
+    match captures {
+        Some(captures) => {
+            let mut expanded = String::new();
+            captures.expand(template, &mut expanded);
+            expanded
+        }
+        None => template.to_string(),
+    }
+}
+
+/// Split `path?a=1&b=2` into the path and its parameters
+fn split_query(value: &str) -> (&str, Vec<String>) {
+    match value.split_once('?') {
+        Some((path, query)) => (
+            path,
+            query
+                .split('&')
+                .filter(|param| !param.is_empty())
+                .map(str::to_string)
+                .collect(),
+        ),
+        None => (value, Vec::new()),
+    }
+}
+
+fn join_query(path: &str, query: &[String]) -> String {
+    if query.is_empty() {
+        path.to_string()
+    } else {
+        format!("{}?{}", path, query.join("&"))
+    }
+}
+
+/// The decoded name of a `name=value` query parameter
+fn param_name(param: &str) -> String {
+    let name = param.split('=').next().unwrap_or_default();
+    percent_decode_str(&name.replace('+', " "))
+        .decode_utf8_lossy()
+        .into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rewriter(yaml: &str) -> Rewriter {
+        Rewriter::new(&serde_yaml::from_str::<Vec<RewriteRule>>(yaml).unwrap()).unwrap()
+    }
+
+    fn rewrite(rewriter: &Rewriter, uri: &str) -> String {
+        match rewriter.apply(&uri.parse().unwrap()).unwrap() {
+            Rewritten::Uri(uri) => uri.to_string(),
+            Rewritten::Redirect(response) => format!(
+                "{} {}",
+                response.status().as_u16(),
+                response.headers()["location"].to_str().unwrap()
+            ),
+        }
+    }
+
+    #[test]
+    fn test_replace_with_captures() {
+        let rules = rewriter(
+            r#"
+- match: ^/v1/(?P<rest>.*)$
+  replace: /api/v1/${rest}
+- match: ^/api/v1/users/(\d+)$
+  replace: /api/v1/users?id=$1
+"#,
+        );
+        assert_eq!(rewrite(&rules, "/v1/items?page=2"), "/api/v1/items?page=2");
+        // Rules see the previous rule's output
+        assert_eq!(
+            rewrite(&rules, "/v1/users/42?x=1"),
+            "/api/v1/users?x=1&id=42"
+        );
+        assert_eq!(rewrite(&rules, "/other"), "/other");
+    }
+
+    #[test]
+    fn test_last_stops_evaluation() {
+        let rules = rewriter(
+            r#"
+- match: ^/a$
+  replace: /b
+  last: true
+- match: ^/b$
+  replace: /c
+"#,
+        );
+        assert_eq!(rewrite(&rules, "/a"), "/b");
+        assert_eq!(rewrite(&rules, "/b"), "/c");
+    }
+
+    #[test]
+    fn test_query_edits() {
+        let rules = rewriter(
+            r#"
+- remove_query: [debug, "utm source"]
+  add_query:
+    api_key: k&1
+- match: ^/search/(\w+)$
+  replace: /search
+  add_query:
+    q: $1
+"#,
+        );
+        assert_eq!(
+            rewrite(&rules, "/x?debug=1&a=b&utm+source=mail&api_key=old"),
+            "/x?a=b&api_key=k%261"
+        );
+        assert_eq!(
+            rewrite(&rules, "/search/rust"),
+            "/search?api_key=k%261&q=rust"
+        );
+    }
+
+    #[test]
+    fn test_redirects() {
+        let rules = rewriter(
+            r#"
+- match: ^/old/(.*)$
+  redirect: /new/$1
+  status: 301
+- match: ^/docs$
+  redirect: https://docs.example.com/?from=proxy
+"#,
+        );
+        assert_eq!(rewrite(&rules, "/old/page?x=1"), "301 /new/page?x=1");
+        assert_eq!(
+            rewrite(&rules, "/docs?v=2"),
+            "302 https://docs.example.com/?from=proxy&v=2"
+        );
+    }
+
+    #[test]
+    fn test_invalid_rules() {
+        let rules: Vec<RewriteRule> =
+            serde_yaml::from_str("- match: \"(\"\n  replace: /x\n").unwrap();
+        assert!(Rewriter::new(&rules).is_err());
+
+        let rules = rewriter("- match: ^/(.*)$\n  replace: /a b/$1\n");
+        assert!(rules.apply(&"/x".parse().unwrap()).is_err());
+    }
+}
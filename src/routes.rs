@@ -0,0 +1,234 @@
+//! Path-based routes within a listener.
+
+use std::sync::Arc;
+
+use crate::auth::Authenticator;
+use crate::body_filter::BodyFilter;
+use crate::cache::ResponseCache;
+use crate::compression::Compressor;
+use crate::config::{HostHeader, Route};
+use crate::cors::CorsPolicy;
+use crate::forward_auth::ForwardAuth;
+use crate::headers::HeaderRewriter;
+use crate::ip_access::IpAccess;
+use crate::limits::Limits;
+use crate::maintenance::Maintenance;
+use crate::oidc::OidcAuth;
+use crate::rate_limit::RateLimiter;
+use crate::rewrite::Rewriter;
+use crate::static_files::StaticDir;
+
+/// What a matched route does with the request
+#[derive(Debug)]
+pub enum RouteAction {
+    /// Forward to this upstream URL, keeping the full request path
+    Proxy(String),
+    /// Serve files, with the route prefix stripped from the path
+    Static(StaticDir),
+}
+
+/// A route ready to match requests against
+#[derive(Debug)]
+pub struct CompiledRoute {
+    /// Prefix without a trailing slash; empty for `/`
+    prefix: String,
+    pub action: RouteAction,
+    pub rate_limit: Option<RateLimiter>,
+    /// Credentials required instead of the listener's
+    pub auth: Option<Authenticator>,
+    /// OpenID Connect login required instead of the listener's credentials
+    pub oidc: Option<OidcAuth>,
+    /// External auth service asked instead of the listener's credentials
+    pub forward_auth: Option<ForwardAuth>,
+    /// Client IPs allowed on or denied from the route
+    pub ip_access: Option<IpAccess>,
+    /// CORS policy used instead of the listener's
+    pub cors: Option<CorsPolicy>,
+    /// URL rewrite rules, if the route has any
+    pub rewrite: Option<Rewriter>,
+    /// Substitutions in proxied response bodies
+    pub body_filter: Option<BodyFilter>,
+    /// Request header rules applied after the listener's
+    pub request_headers: HeaderRewriter,
+    /// Response header rules applied after the listener's
+    pub response_headers: HeaderRewriter,
+    /// Size limits used instead of the listener's
+    pub limits: Option<Limits>,
+    /// The route's maintenance switch, on top of the listener's
+    pub maintenance: Maintenance,
+    /// `Host` header option used instead of the listener's
+    pub host_header: Option<HostHeader>,
+    /// Response compression used instead of the listener's
+    pub compression: Option<Compressor>,
+    /// Response cache used instead of the listener's
+    pub cache: Option<Arc<ResponseCache>>,
+}
+
+impl CompiledRoute {
+    /// The route's path as configured, without a trailing slash
+    pub fn path(&self) -> &str {
+        if self.prefix.is_empty() {
+            "/"
+        } else {
+            &self.prefix
+        }
+    }
+
+    /// The rest of `path` after this route's prefix, if the route matches
+    pub fn strip<'a>(&self, path: &'a str) -> Option<&'a str> {
+        let rest = path.strip_prefix(self.prefix.as_str())?;
+        // Only match on segment boundaries
+        (rest.is_empty() || rest.starts_with('/')).then_some(rest)
+    }
+}
+
+/// A listener's routes, longest prefix first
+#[derive(Debug, Clone, Default)]
+pub struct RouteTable {
+    routes: Arc<Vec<CompiledRoute>>,
+}
+
+impl RouteTable {
+    pub fn new(routes: &[Route]) -> anyhow::Result<Self> {
+        let mut compiled = routes
+            .iter()
+            .map(|route| {
+                let action = match (&route.target, &route.static_dir) {
+                    (Some(target), _) => RouteAction::Proxy(target.clone()),
+                    (None, dir) => RouteAction::Static(StaticDir::new(
+                        dir.as_deref().unwrap_or("."),
+                        &route.index,
+                        route.spa,
+                    )),
+                };
+                Ok(CompiledRoute {
+                    prefix: route.path.trim_end_matches('/').to_string(),
+                    action,
+                    rate_limit: route.rate_limit.as_ref().map(RateLimiter::new),
+                    auth: route.auth.as_ref().map(Authenticator::new).transpose()?,
+                    oidc: route.oidc.as_ref().map(OidcAuth::new).transpose()?,
+                    forward_auth: route
+                        .forward_auth
+                        .as_ref()
+                        .map(ForwardAuth::new)
+                        .transpose()?,
+                    ip_access: route.ip_access.as_ref().map(IpAccess::new),
+                    cors: route.cors.as_ref().map(CorsPolicy::new).transpose()?,
+                    rewrite: (!route.rewrite.is_empty())
+                        .then(|| Rewriter::new(&route.rewrite))
+                        .transpose()?,
+                    body_filter: route
+                        .body_filter
+                        .as_ref()
+                        .map(BodyFilter::new)
+                        .transpose()?,
+                    request_headers: HeaderRewriter::new(&route.request_headers)?,
+                    response_headers: HeaderRewriter::new(&route.response_headers)?,
+                    limits: route.limits.as_ref().map(Limits::new),
+                    maintenance: route
+                        .maintenance
+                        .as_ref()
+                        .map(Maintenance::new)
+                        .transpose()?
+                        .unwrap_or_default(),
+                    host_header: route.host_header.clone(),
+                    compression: route.compression.as_ref().map(Compressor::new),
+                    cache: route
+                        .cache
+                        .as_ref()
+                        .map(|cache| Arc::new(ResponseCache::new(cache))),
+                })
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        // Stable, so equal prefixes keep their configured order
+        compiled.sort_by_key(|route| std::cmp::Reverse(route.prefix.len()));
+
+        Ok(Self {
+            routes: Arc::new(compiled),
+        })
+    }
+
+    /// Every route, longest prefix first
+    pub fn iter(&self) -> impl Iterator<Item = &CompiledRoute> {
+        self.routes.iter()
+    }
+
+    /// The route configured for exactly `path`
+    pub fn get(&self, path: &str) -> Option<&CompiledRoute> {
+        let prefix = path.trim_end_matches('/');
+        self.routes.iter().find(|route| route.prefix == prefix)
+    }
+
+    /// The route for `path`
+    pub fn find(&self, path: &str) -> Option<&CompiledRoute> {
+        self.routes.iter().find(|route| route.strip(path).is_some())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn route(path: &str, target: Option<&str>, static_dir: Option<&str>) -> Route {
+        Route {
+            path: path.to_string(),
+            target: target.map(str::to_string),
+            static_dir: static_dir.map(str::to_string),
+            index: "index.html".to_string(),
+            spa: false,
+            rate_limit: None,
+            auth: None,
+            oidc: None,
+            forward_auth: None,
+            ip_access: None,
+            cors: None,
+            rewrite: Vec::new(),
+            body_filter: None,
+            request_headers: Default::default(),
+            response_headers: Default::default(),
+            limits: None,
+            maintenance: None,
+            host_header: None,
+            compression: None,
+            cache: None,
+        }
+    }
+
+    fn target_of(table: &RouteTable, path: &str) -> Option<String> {
+        let route = table.find(path)?;
+        let rest = route.strip(path).unwrap();
+        Some(match &route.action {
+            RouteAction::Proxy(target) => format!("{} {}", target, rest),
+            RouteAction::Static(dir) => format!("{} {}", dir.root().display(), rest),
+        })
+    }
+
+    #[test]
+    fn test_longest_prefix_wins() {
+        let table = RouteTable::new(&[
+            route("/", None, Some("./dist")),
+            route("/api", Some("http://api:3000"), None),
+            route("/api/v2/", Some("http://api-v2:3000"), None),
+        ])
+        .unwrap();
+
+        assert_eq!(
+            target_of(&table, "/api/users").unwrap(),
+            "http://api:3000 /users"
+        );
+        assert_eq!(
+            target_of(&table, "/api/v2/users").unwrap(),
+            "http://api-v2:3000 /users"
+        );
+        assert_eq!(target_of(&table, "/api").unwrap(), "http://api:3000 ");
+        assert_eq!(target_of(&table, "/apiary").unwrap(), "./dist /apiary");
+        assert_eq!(target_of(&table, "/").unwrap(), "./dist /");
+    }
+
+    #[test]
+    fn test_no_match() {
+        let table = RouteTable::new(&[route("/api", Some("http://api:3000"), None)]).unwrap();
+        assert!(table.find("/other").is_none());
+        assert!(RouteTable::default().find("/").is_none());
+    }
+}
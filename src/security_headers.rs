@@ -0,0 +1,125 @@
+//! Security headers added to every response of a listener.
+
+use anyhow::Context;
+use axum::http::{HeaderMap, HeaderName, HeaderValue};
+
+use crate::config::SecurityHeadersConfig;
+
+/// The security headers a listener adds to its responses
+#[derive(Debug, Clone)]
+pub struct SecurityHeaders {
+    headers: Vec<(HeaderName, HeaderValue)>,
+    /// Keep values the upstream already sent
+    prefer_upstream: bool,
+}
+
+impl SecurityHeaders {
+    pub fn new(config: &SecurityHeadersConfig) -> anyhow::Result<Self> {
+        let configured = [
+            (
+                "strict-transport-security",
+                &config.strict_transport_security,
+            ),
+            ("content-security-policy", &config.content_security_policy),
+            (
+                "content-security-policy-report-only",
+                &config.content_security_policy_report_only,
+            ),
+            ("x-content-type-options", &config.x_content_type_options),
+            ("referrer-policy", &config.referrer_policy),
+            ("permissions-policy", &config.permissions_policy),
+            ("x-frame-options", &config.x_frame_options),
+        ];
+        let headers = configured
+            .into_iter()
+            .filter_map(|(name, value)| Some((name, value.as_deref()?)))
+            .map(|(name, value)| {
+                let value = HeaderValue::from_str(value)
+                    .with_context(|| format!("invalid {} value {:?}", name, value))?;
+                Ok((HeaderName::from_static(name), value))
+            })
+            .collect::<anyhow::Result<_>>()?;
+
+        Ok(Self {
+            headers,
+            prefer_upstream: config.prefer_upstream,
+        })
+    }
+
+    /// Add the headers to a response
+    pub fn apply(&self, headers: &mut HeaderMap) {
+        for (name, value) in &self.headers {
+            if self.prefer_upstream && headers.contains_key(name) {
+                continue;
+            }
+            headers.insert(name.clone(), value.clone());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn security_headers(yaml: &str) -> SecurityHeaders {
+        SecurityHeaders::new(&serde_yaml::from_str(yaml).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_defaults() {
+        let mut headers = HeaderMap::new();
+        security_headers("{}").apply(&mut headers);
+        assert_eq!(headers["x-content-type-options"], "nosniff");
+        assert_eq!(
+            headers["referrer-policy"],
+            "strict-origin-when-cross-origin"
+        );
+        assert_eq!(headers["x-frame-options"], "SAMEORIGIN");
+        assert!(headers.get("strict-transport-security").is_none());
+        assert!(headers.get("content-security-policy").is_none());
+    }
+
+    #[test]
+    fn test_override_and_disable() {
+        let policy = security_headers(
+            "strict_transport_security: max-age=31536000; includeSubDomains\n\
+             content_security_policy_report_only: default-src 'self'\n\
+             x_frame_options: null\n",
+        );
+        let mut headers = HeaderMap::new();
+        headers.insert("x-frame-options", HeaderValue::from_static("DENY"));
+        headers.insert("referrer-policy", HeaderValue::from_static("no-referrer"));
+        policy.apply(&mut headers);
+
+        assert_eq!(
+            headers["strict-transport-security"],
+            "max-age=31536000; includeSubDomains"
+        );
+        assert_eq!(
+            headers["content-security-policy-report-only"],
+            "default-src 'self'"
+        );
+        // Disabled headers are left alone, configured ones replace the upstream's
+        assert_eq!(headers["x-frame-options"], "DENY");
+        assert_eq!(
+            headers["referrer-policy"],
+            "strict-origin-when-cross-origin"
+        );
+    }
+
+    #[test]
+    fn test_prefer_upstream() {
+        let policy = security_headers("prefer_upstream: true\n");
+        let mut headers = HeaderMap::new();
+        headers.insert("referrer-policy", HeaderValue::from_static("no-referrer"));
+        policy.apply(&mut headers);
+        assert_eq!(headers["referrer-policy"], "no-referrer");
+        assert_eq!(headers["x-content-type-options"], "nosniff");
+    }
+
+    #[test]
+    fn test_invalid_value() {
+        let config = serde_yaml::from_str("permissions_policy: \"camera=()\\n\"\n").unwrap();
+        assert!(SecurityHeaders::new(&config).is_err());
+    }
+}
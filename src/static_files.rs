@@ -0,0 +1,430 @@
+//! Files served from disk for `static` routes.
+
+use axum::body::Body;
+use axum::http::{
+    header::{
+        ACCEPT_RANGES, ALLOW, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, ETAG, IF_MODIFIED_SINCE,
+        IF_NONE_MATCH, IF_RANGE, LAST_MODIFIED, LOCATION, RANGE,
+    },
+    request::Parts,
+    HeaderMap, Method, Response, StatusCode,
+};
+use std::fs::Metadata;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio_util::io::ReaderStream;
+
+/// A directory served by a `static` route
+#[derive(Debug, Clone)]
+pub struct StaticDir {
+    root: PathBuf,
+    index: String,
+    spa: bool,
+}
+
+impl StaticDir {
+    pub fn new(root: impl Into<PathBuf>, index: &str, spa: bool) -> Self {
+        Self {
+            root: root.into(),
+            index: index.to_string(),
+            spa,
+        }
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Serve `path`, the request path with the route prefix removed
+    pub async fn serve(&self, req: &Parts, path: &str) -> Response<Body> {
+        if req.method != Method::GET && req.method != Method::HEAD {
+            return Response::builder()
+                .status(StatusCode::METHOD_NOT_ALLOWED)
+                .header(ALLOW, "GET, HEAD")
+                .body(Body::empty())
+                .unwrap();
+        }
+
+        let Some(relative) = sanitize_path(path) else {
+            return not_found();
+        };
+        let mut file_path = self.root.join(relative);
+        let mut metadata = tokio::fs::metadata(&file_path).await.ok();
+
+        if metadata.as_ref().is_some_and(Metadata::is_dir) {
+            // Redirect to the slash form so relative links in the index resolve
+            if !req.uri.path().ends_with('/') {
+                let location = match req.uri.query() {
+                    Some(query) => format!("{}/?{}", req.uri.path(), query),
+                    None => format!("{}/", req.uri.path()),
+                };
+                return Response::builder()
+                    .status(StatusCode::MOVED_PERMANENTLY)
+                    .header(LOCATION, location)
+                    .body(Body::empty())
+                    .unwrap();
+            }
+            file_path = file_path.join(&self.index);
+            metadata = tokio::fs::metadata(&file_path).await.ok();
+        }
+
+        if !metadata.as_ref().is_some_and(Metadata::is_file) {
+            if !self.spa {
+                return not_found();
+            }
+            file_path = self.root.join(&self.index);
+            metadata = tokio::fs::metadata(&file_path).await.ok();
+        }
+        let Some(metadata) = metadata.filter(Metadata::is_file) else {
+            return not_found();
+        };
+
+        match serve_file(req, &file_path, &metadata).await {
+            Ok(response) => response,
+            Err(e) => {
+                tracing::error!("Failed to serve {}: {}", file_path.display(), e);
+                Response::builder()
+                    .status(StatusCode::INTERNAL_SERVER_ERROR)
+                    .body(Body::empty())
+                    .unwrap()
+            }
+        }
+    }
+}
+
+/// Percent-decode `path` into a relative path, refusing anything that could
+/// escape the root
+fn sanitize_path(path: &str) -> Option<PathBuf> {
+    let decoded = percent_encoding::percent_decode_str(path)
+        .decode_utf8()
+        .ok()?;
+
+    let mut relative = PathBuf::new();
+    for segment in decoded.split('/').filter(|s| !s.is_empty()) {
+        if segment == "." || segment == ".." || segment.contains(['\\', '\0']) {
+            return None;
+        }
+        relative.push(segment);
+    }
+
+    Some(relative)
+}
+
+async fn serve_file(
+    req: &Parts,
+    path: &Path,
+    metadata: &Metadata,
+) -> std::io::Result<Response<Body>> {
+    let len = metadata.len();
+    let modified = metadata.modified().ok();
+    let etag = entity_tag(len, modified);
+    let last_modified = modified.map(httpdate::fmt_http_date);
+
+    let mut builder = Response::builder()
+        .header(ETAG, &etag)
+        .header(ACCEPT_RANGES, "bytes");
+    if let Some(last_modified) = &last_modified {
+        builder = builder.header(LAST_MODIFIED, last_modified);
+    }
+
+    if is_not_modified(&req.headers, &etag, modified) {
+        return Ok(builder
+            .status(StatusCode::NOT_MODIFIED)
+            .body(Body::empty())
+            .unwrap());
+    }
+
+    builder = builder.header(CONTENT_TYPE, content_type(path));
+
+    let range = req
+        .headers
+        .get(RANGE)
+        .and_then(|v| v.to_str().ok())
+        .filter(|_| if_range_matches(&req.headers, &etag, last_modified.as_deref()))
+        .and_then(|range| parse_range(range, len));
+    let (start, count) = match range {
+        Some(Ok((start, end))) => {
+            builder = builder
+                .status(StatusCode::PARTIAL_CONTENT)
+                .header(CONTENT_RANGE, format!("bytes {}-{}/{}", start, end, len));
+            (start, end - start + 1)
+        }
+        Some(Err(())) => {
+            return Ok(builder
+                .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                .header(CONTENT_RANGE, format!("bytes */{}", len))
+                .body(Body::empty())
+                .unwrap());
+        }
+        None => (0, len),
+    };
+    builder = builder.header(CONTENT_LENGTH, count);
+
+    if req.method == Method::HEAD {
+        return Ok(builder.body(Body::empty()).unwrap());
+    }
+
+    let mut file = tokio::fs::File::open(path).await?;
+    if start > 0 {
+        file.seek(SeekFrom::Start(start)).await?;
+    }
+    let body = Body::from_stream(ReaderStream::new(file.take(count)));
+
+    Ok(builder.body(body).unwrap())
+}
+
+/// Strong validator from the file's size and modification time
+fn entity_tag(len: u64, modified: Option<SystemTime>) -> String {
+    let mtime = modified
+        .and_then(|m| m.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |d| d.as_nanos());
+    format!("\"{:x}-{:x}\"", len, mtime)
+}
+
+fn content_type(path: &Path) -> String {
+    let mime = mime_guess::from_path(path).first_or_octet_stream();
+    let textual = mime.type_() == mime_guess::mime::TEXT
+        || matches!(
+            mime.essence_str(),
+            "application/javascript" | "application/json"
+        );
+    if textual {
+        format!("{}; charset=utf-8", mime.essence_str())
+    } else {
+        mime.essence_str().to_string()
+    }
+}
+
+/// `If-None-Match` (weak comparison) or, without it, `If-Modified-Since`
+fn is_not_modified(headers: &HeaderMap, etag: &str, modified: Option<SystemTime>) -> bool {
+    if let Some(if_none_match) = headers.get(IF_NONE_MATCH).and_then(|v| v.to_str().ok()) {
+        let strip_weak = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+        return if_none_match
+            .split(',')
+            .any(|tag| tag.trim() == "*" || strip_weak(tag) == strip_weak(etag));
+    }
+
+    let since = headers
+        .get(IF_MODIFIED_SINCE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| httpdate::parse_http_date(v).ok());
+    match (since, modified) {
+        // HTTP dates have one-second precision
+        (Some(since), Some(modified)) => modified
+            .duration_since(since)
+            .map_or(true, |newer_by| newer_by.as_secs() == 0),
+        _ => false,
+    }
+}
+
+/// `If-Range` must match exactly for a partial response; otherwise the whole
+/// file is sent
+fn if_range_matches(headers: &HeaderMap, etag: &str, last_modified: Option<&str>) -> bool {
+    match headers.get(IF_RANGE).and_then(|v| v.to_str().ok()) {
+        None => true,
+        Some(value) if value.starts_with('"') => value == etag,
+        Some(value) => Some(value) == last_modified,
+    }
+}
+
+/// Parse a single `bytes=` range into an inclusive `(start, end)`.
+///
+/// `None` means the header is ignored (malformed or multiple ranges);
+/// `Some(Err(()))` means the range cannot be satisfied.
+fn parse_range(value: &str, len: u64) -> Option<Result<(u64, u64), ()>> {
+    let spec = value.trim().strip_prefix("bytes=")?;
+    if spec.contains(',') {
+        return None;
+    }
+    let (start, end) = spec.trim().split_once('-')?;
+
+    if start.is_empty() {
+        // Suffix range: the last `n` bytes
+        let suffix: u64 = end.parse().ok()?;
+        if suffix == 0 || len == 0 {
+            return Some(Err(()));
+        }
+        return Some(Ok((len.saturating_sub(suffix), len - 1)));
+    }
+
+    let start: u64 = start.parse().ok()?;
+    let end = match end {
+        "" => u64::MAX,
+        end => end.parse().ok()?,
+    };
+    if end < start {
+        return None;
+    }
+    if start >= len {
+        return Some(Err(()));
+    }
+
+    Some(Ok((start, end.min(len - 1))))
+}
+
+/// 404 Not Found response
+pub(crate) fn not_found() -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::NOT_FOUND)
+        .header(CONTENT_TYPE, "text/plain; charset=utf-8")
+        .body(Body::from("404 Not Found"))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::Request;
+    use http_body_util::BodyExt;
+    use tempfile::TempDir;
+
+    fn site() -> TempDir {
+        let dir = TempDir::new().unwrap();
+        std::fs::write(dir.path().join("index.html"), "<h1>home</h1>").unwrap();
+        std::fs::create_dir(dir.path().join("docs")).unwrap();
+        std::fs::write(dir.path().join("docs/index.html"), "<h1>docs</h1>").unwrap();
+        std::fs::write(dir.path().join("app.js"), "console.log('hello');").unwrap();
+        dir
+    }
+
+    fn get(uri: &str) -> Parts {
+        request(Request::builder().uri(uri))
+    }
+
+    fn request(builder: axum::http::request::Builder) -> Parts {
+        builder.body(()).unwrap().into_parts().0
+    }
+
+    async fn body_string(response: Response<Body>) -> String {
+        let bytes = response.into_body().collect().await.unwrap().to_bytes();
+        String::from_utf8(bytes.to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_serve_file_and_index() {
+        let dir = site();
+        let static_dir = StaticDir::new(dir.path(), "index.html", false);
+
+        let response = static_dir.serve(&get("/app.js"), "/app.js").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers().get("content-type").unwrap(),
+            "text/javascript; charset=utf-8"
+        );
+        assert!(response.headers().get("etag").is_some());
+        assert!(response.headers().get("last-modified").is_some());
+        assert_eq!(body_string(response).await, "console.log('hello');");
+
+        let response = static_dir.serve(&get("/docs/"), "/docs/").await;
+        assert_eq!(body_string(response).await, "<h1>docs</h1>");
+
+        let response = static_dir.serve(&get("/docs?x=1"), "/docs").await;
+        assert_eq!(response.status(), StatusCode::MOVED_PERMANENTLY);
+        assert_eq!(response.headers().get("location").unwrap(), "/docs/?x=1");
+    }
+
+    #[tokio::test]
+    async fn test_serve_missing_and_spa_fallback() {
+        let dir = site();
+
+        let plain = StaticDir::new(dir.path(), "index.html", false);
+        let response = plain.serve(&get("/users/42"), "/users/42").await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let spa = StaticDir::new(dir.path(), "index.html", true);
+        let response = spa.serve(&get("/users/42"), "/users/42").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(body_string(response).await, "<h1>home</h1>");
+    }
+
+    #[tokio::test]
+    async fn test_serve_rejects_traversal_and_methods() {
+        let dir = site();
+        let static_dir = StaticDir::new(dir.path().join("docs"), "index.html", true);
+
+        let response = static_dir
+            .serve(&get("/%2e%2e/app.js"), "/%2e%2e/app.js")
+            .await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let post = request(Request::builder().method("POST").uri("/"));
+        let response = static_dir.serve(&post, "/").await;
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+    }
+
+    #[tokio::test]
+    async fn test_conditional_requests() {
+        let dir = site();
+        let static_dir = StaticDir::new(dir.path(), "index.html", false);
+
+        let response = static_dir.serve(&get("/app.js"), "/app.js").await;
+        let etag = response.headers().get("etag").unwrap().clone();
+        let last_modified = response.headers().get("last-modified").unwrap().clone();
+
+        let req = request(
+            Request::builder()
+                .uri("/app.js")
+                .header("if-none-match", format!("W/{}", etag.to_str().unwrap())),
+        );
+        let response = static_dir.serve(&req, "/app.js").await;
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+
+        let req = request(
+            Request::builder()
+                .uri("/app.js")
+                .header("if-modified-since", last_modified),
+        );
+        let response = static_dir.serve(&req, "/app.js").await;
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+    }
+
+    #[tokio::test]
+    async fn test_range_requests() {
+        let dir = site();
+        let static_dir = StaticDir::new(dir.path(), "index.html", false);
+
+        let req = request(
+            Request::builder()
+                .uri("/app.js")
+                .header("range", "bytes=0-6"),
+        );
+        let response = static_dir.serve(&req, "/app.js").await;
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(
+            response.headers().get("content-range").unwrap(),
+            "bytes 0-6/21"
+        );
+        assert_eq!(body_string(response).await, "console");
+
+        let req = request(
+            Request::builder()
+                .uri("/app.js")
+                .header("range", "bytes=100-"),
+        );
+        let response = static_dir.serve(&req, "/app.js").await;
+        assert_eq!(response.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+
+        // A stale If-Range sends the whole file
+        let req = request(
+            Request::builder()
+                .uri("/app.js")
+                .header("range", "bytes=0-6")
+                .header("if-range", "\"stale\""),
+        );
+        let response = static_dir.serve(&req, "/app.js").await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(parse_range("bytes=0-99", 1000), Some(Ok((0, 99))));
+        assert_eq!(parse_range("bytes=900-", 1000), Some(Ok((900, 999))));
+        assert_eq!(parse_range("bytes=-100", 1000), Some(Ok((900, 999))));
+        assert_eq!(parse_range("bytes=0-5000", 1000), Some(Ok((0, 999))));
+        assert_eq!(parse_range("bytes=1000-", 1000), Some(Err(())));
+        assert_eq!(parse_range("bytes=0-1, 5-6", 1000), None);
+        assert_eq!(parse_range("items=0-1", 1000), None);
+    }
+}
@@ -1,3 +1,5 @@
+use axum::http::Uri;
+use hyper_rustls::ResolveServerName;
 use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
 use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
 use rustls::{ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme};
@@ -71,6 +73,32 @@ pub fn get_insecure_client_config() -> ClientConfig {
     config
 }
 
+/// Picks the TLS server name (SNI) for an upstream connection.
+///
+/// The proxy asks for a server name other than the target host by putting it
+/// in the URI's userinfo (`https://sni.example.com@10.0.0.5/`). Userinfo is
+/// part of the connection pool key but is never sent in the request line.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct UpstreamServerNameResolver;
+
+impl ResolveServerName for UpstreamServerNameResolver {
+    fn resolve(
+        &self,
+        uri: &Uri,
+    ) -> Result<ServerName<'static>, Box<dyn std::error::Error + Sync + Send>> {
+        let authority = uri.authority().map(|a| a.as_str()).unwrap_or_default();
+        let name = match authority.rsplit_once('@') {
+            Some((server_name, _)) => server_name,
+            None => uri.host().unwrap_or_default(),
+        };
+
+        // Remove square brackets around IPv6 address
+        let name = name.trim_start_matches('[').trim_end_matches(']');
+
+        Ok(ServerName::try_from(name.to_string())?)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -108,4 +136,24 @@ mod tests {
 
         assert!(result.is_ok());
     }
+
+    #[test]
+    fn test_resolver_uses_target_host() {
+        let uri: Uri = "https://api.example.com:8443/path".parse().unwrap();
+        let name = UpstreamServerNameResolver.resolve(&uri).unwrap();
+        assert_eq!(name, ServerName::try_from("api.example.com").unwrap());
+
+        let uri: Uri = "https://[::1]:8443/".parse().unwrap();
+        let name = UpstreamServerNameResolver.resolve(&uri).unwrap();
+        assert_eq!(name, ServerName::try_from("::1").unwrap());
+    }
+
+    #[test]
+    fn test_resolver_uses_userinfo_override() {
+        let uri: Uri = "https://bucket.s3.example.com@10.0.0.5:443/key"
+            .parse()
+            .unwrap();
+        let name = UpstreamServerNameResolver.resolve(&uri).unwrap();
+        assert_eq!(name, ServerName::try_from("bucket.s3.example.com").unwrap());
+    }
 }
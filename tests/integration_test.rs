@@ -12,7 +12,7 @@ use std::net::SocketAddr;
 use std::sync::Arc;
 
 use wiremock::{
-    matchers::{header, method, path},
+    matchers::{header, method, path, query_param},
     Mock, MockServer, ResponseTemplate,
 };
 
@@ -420,3 +420,1790 @@ async fn test_proxy_forwards_websocket() {
     // Cleanup
     let _ = socket.close(None).await;
 }
+
+#[tokio::test]
+async fn test_admin_replays_captured_request() {
+    use tower::ServiceExt;
+
+    let mock_server = MockServer::start().await;
+
+    Mock::given(method("POST"))
+        .and(path("/api/orders"))
+        .and(header("x-replayed", "yes"))
+        .respond_with(ResponseTemplate::new(202))
+        .mount(&mock_server)
+        .await;
+    Mock::given(method("POST"))
+        .and(path("/api/orders"))
+        .respond_with(ResponseTemplate::new(201))
+        .mount(&mock_server)
+        .await;
+
+    let (http_client, tls_config) = create_test_client();
+    let capture = Arc::new(https_proxy::capture::CaptureStore::new(
+        &https_proxy::config::CaptureConfig {
+            max_entries: 10,
+            max_body_bytes: 1024,
+        },
+    ));
+    let ctx = https_proxy::ProxyContext {
+        port: 440,
+        target: mock_server.uri(),
+        path_join: Default::default(),
+        routes: Default::default(),
+        http_client,
+        tls_config,
+        capture: Some(capture.clone()),
+        har: None,
+        request_headers: Default::default(),
+        response_headers: Default::default(),
+        host_header: None,
+        forwarding: Default::default(),
+        proxy_protocol: Default::default(),
+        compression: None,
+        cache: None,
+        rate_limit: None,
+        concurrency: None,
+        circuit_breaker: None,
+        auth: None,
+        oidc: None,
+        forward_auth: None,
+        ip_access: None,
+        cors: None,
+        security_headers: None,
+        response_rewrite: None,
+        limits: None,
+        upstream_timeout: None,
+        error_pages: None,
+        maintenance: Default::default(),
+    };
+
+    // Original request goes through the proxy and is captured
+    let addr: SocketAddr = "192.168.1.100:54321".parse().unwrap();
+    let req = Request::builder()
+        .method("POST")
+        .uri("/api/orders")
+        .header("content-type", "application/json")
+        .body(Body::from(r#"{"qty": 1}"#))
+        .unwrap();
+    let response = https_proxy::handle_request(&ctx, addr, req).await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+
+    let captured = capture.list(1);
+    assert_eq!(captured.len(), 1);
+    let id = captured[0].id;
+
+    // Replay it through the admin API with an extra header
+    let admin = https_proxy::admin::router(https_proxy::admin::AdminState {
+        capture: Some(capture.clone()),
+        listeners: [(440, Arc::new(ctx))].into(),
+    });
+    let replay = Request::builder()
+        .method("POST")
+        .uri(format!("/api/requests/{}/replay", id))
+        .body(Body::from(r#"{"headers": {"x-replayed": "yes"}}"#))
+        .unwrap();
+    let response = admin.oneshot(replay).await.unwrap();
+
+    assert_eq!(response.status(), StatusCode::ACCEPTED);
+    assert_eq!(
+        response.headers().get("x-replay-of").unwrap(),
+        &id.to_string()
+    );
+
+    let requests = mock_server.received_requests().await.unwrap();
+    assert_eq!(requests.len(), 2);
+    assert_eq!(requests[1].body, br#"{"qty": 1}"#);
+}
+
+#[tokio::test]
+async fn test_proxy_applies_header_rules() {
+    let mock_server = MockServer::start().await;
+
+    Mock::given(method("GET"))
+        .and(path("/"))
+        .and(header("x-client", "10.0.0.50"))
+        .and(header("x-new", "legacy"))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .insert_header("server", "internal/1.0")
+                .insert_header("x-powered-by", "php"),
+        )
+        .mount(&mock_server)
+        .await;
+
+    let listener: https_proxy::config::Listener = serde_yaml::from_str(&format!(
+        r#"
+port: 440
+target: {}
+request_headers:
+  set:
+    X-Client: "${{client_ip}}"
+  rename:
+    X-Old: X-New
+response_headers:
+  remove: [X-Powered-By]
+  rename:
+    Server: X-Upstream-Server
+"#,
+        mock_server.uri()
+    ))
+    .unwrap();
+    let (http_client, tls_config) = create_test_client();
+    let ctx = https_proxy::ProxyContext::new(&listener, http_client, tls_config, None).unwrap();
+
+    let addr: SocketAddr = "10.0.0.50:12345".parse().unwrap();
+    let req = Request::builder()
+        .uri("/")
+        .header("x-old", "legacy")
+        .body(Body::empty())
+        .unwrap();
+    let response = https_proxy::handle_request(&ctx, addr, req).await;
+
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(response.headers().get("x-powered-by").is_none());
+    assert!(response.headers().get("server").is_none());
+    assert_eq!(
+        response.headers().get("x-upstream-server").unwrap(),
+        "internal/1.0"
+    );
+}
+
+#[tokio::test]
+async fn test_proxy_applies_route_header_rules() {
+    let mock_server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/admin/users"))
+        .and(header("x-area", "admin"))
+        .respond_with(ResponseTemplate::new(200).insert_header("x-debug", "on"))
+        .mount(&mock_server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/public"))
+        .respond_with(ResponseTemplate::new(200).insert_header("x-debug", "on"))
+        .mount(&mock_server)
+        .await;
+
+    let listener: https_proxy::config::Listener = serde_yaml::from_str(&format!(
+        r#"
+port: 440
+target: {0}
+request_headers:
+  set:
+    X-Area: public
+response_headers:
+  set:
+    X-Served-By: proxy
+routes:
+  - path: /admin
+    target: {0}
+    request_headers:
+      set:
+        X-Area: admin
+    response_headers:
+      remove: [X-Debug, X-Served-By]
+"#,
+        mock_server.uri()
+    ))
+    .unwrap();
+    let (http_client, tls_config) = create_test_client();
+    let ctx = https_proxy::ProxyContext::new(&listener, http_client, tls_config, None).unwrap();
+    let addr: SocketAddr = "127.0.0.1:12345".parse().unwrap();
+
+    // The route's rules run after the listener's, so they win
+    let req = Request::builder()
+        .uri("/admin/users")
+        .body(Body::empty())
+        .unwrap();
+    let response = https_proxy::handle_request(&ctx, addr, req).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(response.headers().get("x-debug").is_none());
+    assert!(response.headers().get("x-served-by").is_none());
+
+    // Other paths only get the listener's
+    let req = Request::builder()
+        .uri("/public")
+        .body(Body::empty())
+        .unwrap();
+    let response = https_proxy::handle_request(&ctx, addr, req).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()["x-debug"], "on");
+    assert_eq!(response.headers()["x-served-by"], "proxy");
+}
+
+#[tokio::test]
+async fn test_proxy_header_templates_use_forwarded_client_ip() {
+    let mock_server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(header("x-client", "203.0.113.7"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&mock_server)
+        .await;
+
+    let listener: https_proxy::config::Listener = serde_yaml::from_str(&format!(
+        "port: 440\ntarget: {}\ntrusted_proxies: [172.16.0.0/12]\nrequest_headers:\n  set:\n    X-Client: \"${{client_ip}}\"\n",
+        mock_server.uri()
+    ))
+    .unwrap();
+    let (http_client, tls_config) = create_test_client();
+    let ctx = https_proxy::ProxyContext::new(&listener, http_client, tls_config, None).unwrap();
+
+    // Behind a trusted load balancer, ${client_ip} is the original client
+    let addr: SocketAddr = "172.16.0.5:12345".parse().unwrap();
+    let req = Request::builder()
+        .uri("/")
+        .header("x-forwarded-for", "203.0.113.7")
+        .body(Body::empty())
+        .unwrap();
+    let response = https_proxy::handle_request(&ctx, addr, req).await;
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn test_proxy_rewrites_host_header() {
+    let mock_server = MockServer::start().await;
+    let upstream_host = mock_server.uri().trim_start_matches("http://").to_string();
+
+    Mock::given(method("GET"))
+        .and(path("/upstream"))
+        .and(header("host", upstream_host.as_str()))
+        .and(header("x-forwarded-host", "proxy.local"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&mock_server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/custom"))
+        .and(header("host", "bucket.s3.example.com"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&mock_server)
+        .await;
+
+    let (http_client, tls_config) = create_test_client();
+    let addr: SocketAddr = "10.0.0.50:12345".parse().unwrap();
+
+    for (host_header, request_path) in [
+        ("upstream", "/upstream"),
+        ("bucket.s3.example.com", "/custom"),
+    ] {
+        let listener: https_proxy::config::Listener = serde_yaml::from_str(&format!(
+            "port: 440\ntarget: {}\nhost_header: {}\n",
+            mock_server.uri(),
+            host_header
+        ))
+        .unwrap();
+        let ctx = https_proxy::ProxyContext::new(
+            &listener,
+            http_client.clone(),
+            tls_config.clone(),
+            None,
+        )
+        .unwrap();
+
+        let req = Request::builder()
+            .uri(request_path)
+            .header("host", "proxy.local")
+            .body(Body::empty())
+            .unwrap();
+        let response = https_proxy::handle_request(&ctx, addr, req).await;
+        assert_eq!(response.status(), StatusCode::OK, "{}", host_header);
+    }
+}
+
+#[tokio::test]
+async fn test_proxy_route_host_header() {
+    let mock_server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/bucket/file"))
+        .and(header("host", "bucket.s3.example.com"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&mock_server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/other"))
+        .and(header("host", "proxy.local"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&mock_server)
+        .await;
+
+    // Only the route overrides the Host; the listener keeps the client's
+    let listener: https_proxy::config::Listener = serde_yaml::from_str(&format!(
+        "port: 440\ntarget: {0}\nhost_header: preserve\nroutes:\n  - path: /bucket\n    target: {0}\n    host_header: bucket.s3.example.com\n",
+        mock_server.uri()
+    ))
+    .unwrap();
+    let (http_client, tls_config) = create_test_client();
+    let ctx = https_proxy::ProxyContext::new(&listener, http_client, tls_config, None).unwrap();
+    let addr: SocketAddr = "10.0.0.50:12345".parse().unwrap();
+
+    for request_path in ["/bucket/file", "/other"] {
+        let req = Request::builder()
+            .uri(request_path)
+            .header("host", "proxy.local")
+            .body(Body::empty())
+            .unwrap();
+        let response = https_proxy::handle_request(&ctx, addr, req).await;
+        assert_eq!(response.status(), StatusCode::OK, "{}", request_path);
+    }
+}
+
+#[tokio::test]
+async fn test_proxy_forwarding_headers_use_listener_port() {
+    let mock_server = MockServer::start().await;
+
+    Mock::given(method("GET"))
+        .and(path("/"))
+        .and(header("x-forwarded-port", "441"))
+        .and(header("x-forwarded-for", "10.0.0.50"))
+        .and(header("forwarded", "for=10.0.0.50;proto=https"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&mock_server)
+        .await;
+
+    let listener: https_proxy::config::Listener = serde_yaml::from_str(&format!(
+        "port: 441\ntarget: {}\ntrusted_proxies: [172.16.0.0/12]\nforwarded_header: true\n",
+        mock_server.uri()
+    ))
+    .unwrap();
+    let (http_client, tls_config) = create_test_client();
+    let ctx = https_proxy::ProxyContext::new(&listener, http_client, tls_config, None).unwrap();
+
+    // Spoofed forwarding headers from an untrusted client are replaced
+    let addr: SocketAddr = "10.0.0.50:12345".parse().unwrap();
+    let req = Request::builder()
+        .uri("/")
+        .header("x-forwarded-for", "1.2.3.4")
+        .header("x-forwarded-port", "80")
+        .header("forwarded", "for=1.2.3.4")
+        .body(Body::empty())
+        .unwrap();
+    let response = https_proxy::handle_request(&ctx, addr, req).await;
+
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn test_proxy_sends_proxy_protocol_header() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    // Upstream that expects a PROXY header before the HTTP request
+    let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let upstream_addr = upstream.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (mut stream, _) = upstream.accept().await.unwrap();
+        let addrs = https_proxy::proxy_protocol::read_header(&mut stream)
+            .await
+            .unwrap()
+            .unwrap();
+
+        let mut request = Vec::new();
+        while !request.ends_with(b"\r\n\r\n") {
+            request.push(stream.read_u8().await.unwrap());
+        }
+        let response = format!(
+            "HTTP/1.1 200 OK\r\nx-client: {}\r\ncontent-length: 0\r\n\r\n",
+            addrs.client
+        );
+        stream.write_all(response.as_bytes()).await.unwrap();
+    });
+
+    let listener: https_proxy::config::Listener = serde_yaml::from_str(&format!(
+        "port: 440\ntarget: http://{}\nproxy_protocol:\n  send: v2\n",
+        upstream_addr
+    ))
+    .unwrap();
+    let (http_client, tls_config) = create_test_client();
+    let ctx = https_proxy::ProxyContext::new(&listener, http_client, tls_config, None).unwrap();
+
+    let addr: SocketAddr = "10.0.0.50:12345".parse().unwrap();
+    let req = Request::builder().uri("/").body(Body::empty()).unwrap();
+    let response = https_proxy::handle_request(&ctx, addr, req).await;
+
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        response.headers().get("x-client").unwrap(),
+        "10.0.0.50:12345"
+    );
+}
+
+#[tokio::test]
+async fn test_proxy_compresses_responses() {
+    use http_body_util::BodyExt;
+    use tokio::io::AsyncReadExt;
+
+    let mock_server = MockServer::start().await;
+    let body = format!("[{}]", vec![r#"{"id": 1, "name": "item"}"#; 200].join(","));
+
+    Mock::given(method("GET"))
+        .and(path("/items"))
+        .respond_with(ResponseTemplate::new(200).set_body_raw(body.clone(), "application/json"))
+        .mount(&mock_server)
+        .await;
+
+    let listener: https_proxy::config::Listener = serde_yaml::from_str(&format!(
+        "port: 440\ntarget: {}\ncompression: {{}}\n",
+        mock_server.uri()
+    ))
+    .unwrap();
+    let (http_client, tls_config) = create_test_client();
+    let ctx = https_proxy::ProxyContext::new(&listener, http_client, tls_config, None).unwrap();
+
+    let addr: SocketAddr = "10.0.0.50:12345".parse().unwrap();
+    let req = Request::builder()
+        .uri("/items")
+        .header("accept-encoding", "gzip, br")
+        .body(Body::empty())
+        .unwrap();
+    let response = https_proxy::handle_request(&ctx, addr, req).await;
+
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers().get("content-encoding").unwrap(), "br");
+
+    let compressed = response.into_body().collect().await.unwrap().to_bytes();
+    let mut decoded = String::new();
+    async_compression::tokio::bufread::BrotliDecoder::new(&compressed[..])
+        .read_to_string(&mut decoded)
+        .await
+        .unwrap();
+    assert_eq!(decoded, body);
+}
+
+#[tokio::test]
+async fn test_proxy_route_compression() {
+    let mock_server = MockServer::start().await;
+    let body = format!("[{}]", vec![r#"{"id": 1, "name": "item"}"#; 200].join(","));
+    Mock::given(method("GET"))
+        .respond_with(ResponseTemplate::new(200).set_body_raw(body, "application/json"))
+        .mount(&mock_server)
+        .await;
+
+    // The route's settings replace the listener's rather than adding to them
+    let listener: https_proxy::config::Listener = serde_yaml::from_str(&format!(
+        "port: 440\ntarget: {0}\ncompression: {{}}\nroutes:\n  - path: /legacy\n    target: {0}\n    compression:\n      algorithms: [gzip]\n",
+        mock_server.uri()
+    ))
+    .unwrap();
+    let (http_client, tls_config) = create_test_client();
+    let ctx = https_proxy::ProxyContext::new(&listener, http_client, tls_config, None).unwrap();
+    let addr: SocketAddr = "10.0.0.50:12345".parse().unwrap();
+
+    for (request_path, encoding) in [("/legacy/items", "gzip"), ("/items", "br")] {
+        let req = Request::builder()
+            .uri(request_path)
+            .header("accept-encoding", "gzip, br")
+            .body(Body::empty())
+            .unwrap();
+        let response = https_proxy::handle_request(&ctx, addr, req).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["content-encoding"], encoding);
+    }
+}
+
+#[tokio::test]
+async fn test_listener_serves_static_and_proxied_routes() {
+    use http_body_util::BodyExt;
+
+    let mock_server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/api/users"))
+        .respond_with(ResponseTemplate::new(200).set_body_string("from api"))
+        .mount(&mock_server)
+        .await;
+
+    let dist = tempfile::TempDir::new().unwrap();
+    std::fs::write(dist.path().join("index.html"), "<h1>app</h1>").unwrap();
+
+    let listener: https_proxy::config::Listener = serde_yaml::from_str(&format!(
+        "port: 441\nroutes:\n  - path: /api\n    target: {}\n  - path: /\n    static: {}\n    spa: true\n",
+        mock_server.uri(),
+        dist.path().display()
+    ))
+    .unwrap();
+    let (http_client, tls_config) = create_test_client();
+    let ctx = https_proxy::ProxyContext::new(&listener, http_client, tls_config, None).unwrap();
+    let addr: SocketAddr = "10.0.0.50:12345".parse().unwrap();
+
+    let get = |uri: &str| Request::builder().uri(uri).body(Body::empty()).unwrap();
+
+    let response = https_proxy::handle_request(&ctx, addr, get("/api/users")).await;
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    assert_eq!(&body[..], b"from api");
+
+    // Client-side routes fall back to the SPA's index
+    let response = https_proxy::handle_request(&ctx, addr, get("/settings/profile")).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        response.headers().get("content-type").unwrap(),
+        "text/html; charset=utf-8"
+    );
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    assert_eq!(&body[..], b"<h1>app</h1>");
+}
+
+#[tokio::test]
+async fn test_proxy_caches_responses() {
+    use http_body_util::BodyExt;
+    use tower::ServiceExt;
+
+    let mock_server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/slow"))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .insert_header("cache-control", "public, max-age=60")
+                .set_body_string("expensive"),
+        )
+        .expect(2)
+        .mount(&mock_server)
+        .await;
+
+    let listener: https_proxy::config::Listener = serde_yaml::from_str(&format!(
+        "port: 442\ntarget: {}\ncache: {{}}\n",
+        mock_server.uri()
+    ))
+    .unwrap();
+    let (http_client, tls_config) = create_test_client();
+    let ctx =
+        Arc::new(https_proxy::ProxyContext::new(&listener, http_client, tls_config, None).unwrap());
+    let addr: SocketAddr = "10.0.0.50:12345".parse().unwrap();
+
+    let get = || Request::builder().uri("/slow").body(Body::empty()).unwrap();
+
+    let response = https_proxy::handle_request(&ctx, addr, get()).await;
+    assert_eq!(response.headers().get("x-cache").unwrap(), "MISS");
+    // The response is stored once its body has been sent
+    response.into_body().collect().await.unwrap();
+
+    let response = https_proxy::handle_request(&ctx, addr, get()).await;
+    assert_eq!(response.headers().get("x-cache").unwrap(), "HIT");
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    assert_eq!(&body[..], b"expensive");
+
+    // Purging through the admin API sends the next request upstream again
+    let admin = https_proxy::admin::router(https_proxy::admin::AdminState {
+        capture: None,
+        listeners: [(442, ctx.clone())].into(),
+    });
+    let purge = Request::builder()
+        .method("DELETE")
+        .uri("/api/cache?port=442&path=/slow")
+        .body(Body::empty())
+        .unwrap();
+    let response = admin.oneshot(purge).await.unwrap();
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    assert_eq!(&body[..], br#"{"purged":1}"#);
+
+    let response = https_proxy::handle_request(&ctx, addr, get()).await;
+    assert_eq!(response.headers().get("x-cache").unwrap(), "MISS");
+}
+
+#[tokio::test]
+async fn test_proxy_caches_per_route() {
+    use http_body_util::BodyExt;
+    use tower::ServiceExt;
+
+    let mock_server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/assets/app.js"))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .insert_header("cache-control", "public, max-age=60")
+                .set_body_string("bundle"),
+        )
+        .expect(2)
+        .mount(&mock_server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/api/items"))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .insert_header("cache-control", "public, max-age=60")
+                .set_body_string("items"),
+        )
+        .expect(2)
+        .mount(&mock_server)
+        .await;
+
+    // Only /assets has a cache; the listener has none
+    let listener: https_proxy::config::Listener = serde_yaml::from_str(&format!(
+        "port: 442\ntarget: {0}\nroutes:\n  - path: /assets\n    target: {0}\n    cache: {{}}\n  - path: /api\n    target: {0}\n",
+        mock_server.uri()
+    ))
+    .unwrap();
+    let (http_client, tls_config) = create_test_client();
+    let ctx =
+        Arc::new(https_proxy::ProxyContext::new(&listener, http_client, tls_config, None).unwrap());
+    let addr: SocketAddr = "10.0.0.50:12345".parse().unwrap();
+    let get = |uri: &str| Request::builder().uri(uri).body(Body::empty()).unwrap();
+
+    let response = https_proxy::handle_request(&ctx, addr, get("/assets/app.js")).await;
+    assert_eq!(response.headers()["x-cache"], "MISS");
+    response.into_body().collect().await.unwrap();
+    let response = https_proxy::handle_request(&ctx, addr, get("/assets/app.js")).await;
+    assert_eq!(response.headers()["x-cache"], "HIT");
+
+    for _ in 0..2 {
+        let response = https_proxy::handle_request(&ctx, addr, get("/api/items")).await;
+        assert!(response.headers().get("x-cache").is_none());
+        response.into_body().collect().await.unwrap();
+    }
+
+    // The admin purge reaches route caches too
+    let admin = https_proxy::admin::router(https_proxy::admin::AdminState {
+        capture: None,
+        listeners: [(442, ctx.clone())].into(),
+    });
+    let purge = Request::builder()
+        .method("DELETE")
+        .uri("/api/cache?port=442")
+        .body(Body::empty())
+        .unwrap();
+    let response = admin.oneshot(purge).await.unwrap();
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    assert_eq!(&body[..], br#"{"purged":1}"#);
+
+    let response = https_proxy::handle_request(&ctx, addr, get("/assets/app.js")).await;
+    assert_eq!(response.headers()["x-cache"], "MISS");
+}
+
+#[tokio::test]
+async fn test_proxy_cache_leaves_out_templated_headers() {
+    use http_body_util::BodyExt;
+
+    let mock_server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .insert_header("cache-control", "public, max-age=60")
+                .set_body_string("shared"),
+        )
+        .expect(1)
+        .mount(&mock_server)
+        .await;
+
+    let listener: https_proxy::config::Listener = serde_yaml::from_str(&format!(
+        "port: 442\ntarget: {}\ncache: {{}}\nresponse_headers:\n  set:\n    X-Request-Id: \"${{request_id}}\"\n",
+        mock_server.uri()
+    ))
+    .unwrap();
+    let (http_client, tls_config) = create_test_client();
+    let ctx = https_proxy::ProxyContext::new(&listener, http_client, tls_config, None).unwrap();
+    let addr: SocketAddr = "10.0.0.50:12345".parse().unwrap();
+    let get = |id: &str| {
+        Request::builder()
+            .uri("/page")
+            .header("x-request-id", id)
+            .body(Body::empty())
+            .unwrap()
+    };
+
+    let response = https_proxy::handle_request(&ctx, addr, get("first")).await;
+    assert_eq!(response.headers()["x-cache"], "MISS");
+    assert_eq!(response.headers()["x-request-id"], "first");
+    response.into_body().collect().await.unwrap();
+
+    // Each client gets its own value, not the one of the stored response
+    let response = https_proxy::handle_request(&ctx, addr, get("second")).await;
+    assert_eq!(response.headers()["x-cache"], "HIT");
+    assert_eq!(response.headers()["x-request-id"], "second");
+}
+
+#[tokio::test]
+async fn test_proxy_rate_limits_clients() {
+    let mock_server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&mock_server)
+        .await;
+
+    let listener: https_proxy::config::Listener = serde_yaml::from_str(&format!(
+        "port: 440\ntarget: {}\nrate_limit:\n  limit: 2\n  window_secs: 60\n",
+        mock_server.uri()
+    ))
+    .unwrap();
+    let (http_client, tls_config) = create_test_client();
+    let ctx = https_proxy::ProxyContext::new(&listener, http_client, tls_config, None).unwrap();
+
+    let get = || Request::builder().uri("/").body(Body::empty()).unwrap();
+    let client: SocketAddr = "10.0.0.50:12345".parse().unwrap();
+    let other: SocketAddr = "10.0.0.51:12345".parse().unwrap();
+
+    let response = https_proxy::handle_request(&ctx, client, get()).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers().get("ratelimit-remaining").unwrap(), "1");
+    https_proxy::handle_request(&ctx, client, get()).await;
+
+    let response = https_proxy::handle_request(&ctx, client, get()).await;
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(response.headers().get("retry-after").unwrap(), "30");
+
+    // Limits are per client
+    let response = https_proxy::handle_request(&ctx, other, get()).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(mock_server.received_requests().await.unwrap().len(), 3);
+}
+
+#[tokio::test]
+async fn test_proxy_limits_concurrent_upstream_requests() {
+    let mock_server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .respond_with(ResponseTemplate::new(200).set_delay(std::time::Duration::from_millis(200)))
+        .mount(&mock_server)
+        .await;
+
+    let listener: https_proxy::config::Listener = serde_yaml::from_str(&format!(
+        "port: 440\ntarget: {}\nconcurrency:\n  max_requests: 1\n  max_queue: 0\n",
+        mock_server.uri()
+    ))
+    .unwrap();
+    let (http_client, tls_config) = create_test_client();
+    let ctx = https_proxy::ProxyContext::new(&listener, http_client, tls_config, None).unwrap();
+    let addr: SocketAddr = "10.0.0.50:12345".parse().unwrap();
+
+    let get = || Request::builder().uri("/").body(Body::empty()).unwrap();
+    let (first, second) = tokio::join!(
+        https_proxy::handle_request(&ctx, addr, get()),
+        https_proxy::handle_request(&ctx, addr, get()),
+    );
+
+    let mut statuses = [first.status(), second.status()];
+    statuses.sort();
+    assert_eq!(statuses, [StatusCode::OK, StatusCode::SERVICE_UNAVAILABLE]);
+    assert_eq!(mock_server.received_requests().await.unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn test_proxy_opens_circuit_for_failing_upstream() {
+    use http_body_util::BodyExt;
+    use tower::ServiceExt;
+
+    let mock_server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .respond_with(ResponseTemplate::new(500))
+        .mount(&mock_server)
+        .await;
+
+    let listener: https_proxy::config::Listener = serde_yaml::from_str(&format!(
+        "port: 440\ntarget: {}\ncircuit_breaker:\n  min_requests: 2\n",
+        mock_server.uri()
+    ))
+    .unwrap();
+    let (http_client, tls_config) = create_test_client();
+    let ctx =
+        Arc::new(https_proxy::ProxyContext::new(&listener, http_client, tls_config, None).unwrap());
+    let addr: SocketAddr = "10.0.0.50:12345".parse().unwrap();
+    let get = || Request::builder().uri("/").body(Body::empty()).unwrap();
+
+    for _ in 0..2 {
+        let response = https_proxy::handle_request(&ctx, addr, get()).await;
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    // The upstream is no longer contacted while the circuit is open
+    let response = https_proxy::handle_request(&ctx, addr, get()).await;
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(response.headers().get("retry-after").unwrap(), "30");
+    assert_eq!(mock_server.received_requests().await.unwrap().len(), 2);
+
+    let admin = https_proxy::admin::router(https_proxy::admin::AdminState {
+        capture: None,
+        listeners: [(440, ctx.clone())].into(),
+    });
+    let response = admin
+        .oneshot(
+            Request::builder()
+                .uri("/api/circuits")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    let circuits: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(circuits[0]["port"], 440);
+    assert_eq!(circuits[0]["circuits"][0]["state"], "open");
+}
+
+#[tokio::test]
+async fn test_proxy_circuit_ignores_time_queued() {
+    use http_body_util::BodyExt;
+
+    let mock_server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .respond_with(ResponseTemplate::new(200).set_delay(std::time::Duration::from_millis(100)))
+        .mount(&mock_server)
+        .await;
+
+    let listener: https_proxy::config::Listener = serde_yaml::from_str(&format!(
+        "port: 440\ntarget: {}\nconcurrency:\n  max_requests: 1\ncircuit_breaker:\n  min_requests: 3\n  failure_rate: 0.3\n  slow_call_ms: 250\n",
+        mock_server.uri()
+    ))
+    .unwrap();
+    let (http_client, tls_config) = create_test_client();
+    let ctx = https_proxy::ProxyContext::new(&listener, http_client, tls_config, None).unwrap();
+    let addr: SocketAddr = "10.0.0.50:12345".parse().unwrap();
+    let get = || async {
+        let req = Request::builder().uri("/").body(Body::empty()).unwrap();
+        let response = https_proxy::handle_request(&ctx, addr, req).await;
+        let status = response.status();
+        response.into_body().collect().await.unwrap();
+        status
+    };
+
+    // The third request waits 200ms for a slot, but the upstream is fast
+    let statuses = tokio::join!(get(), get(), get());
+    assert_eq!(statuses, (StatusCode::OK, StatusCode::OK, StatusCode::OK));
+    assert_eq!(get().await, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn test_proxy_does_not_share_authenticated_responses() {
+    use http_body_util::BodyExt;
+
+    let mock_server = MockServer::start().await;
+    for user in ["alice", "bob"] {
+        Mock::given(method("GET"))
+            .and(header("x-forwarded-user", user))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .insert_header("cache-control", "public, max-age=60")
+                    .set_body_string(format!("profile of {}", user)),
+            )
+            .mount(&mock_server)
+            .await;
+    }
+
+    let listener: https_proxy::config::Listener = serde_yaml::from_str(&format!(
+        "port: 440\ntarget: {}\ncache: {{}}\nauth:\n  bearer_tokens:\n    alice: alice-token\n    bob: bob-token\n",
+        mock_server.uri()
+    ))
+    .unwrap();
+    let (http_client, tls_config) = create_test_client();
+    let ctx = https_proxy::ProxyContext::new(&listener, http_client, tls_config, None).unwrap();
+    let addr: SocketAddr = "10.0.0.50:12345".parse().unwrap();
+
+    for (token, expected) in [
+        ("alice-token", "profile of alice"),
+        ("bob-token", "profile of bob"),
+        ("alice-token", "profile of alice"),
+    ] {
+        let req = Request::builder()
+            .uri("/me")
+            .header("authorization", format!("Bearer {}", token))
+            .body(Body::empty())
+            .unwrap();
+        let response = https_proxy::handle_request(&ctx, addr, req).await;
+        assert_eq!(response.headers()["x-cache"], "MISS");
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(&body[..], expected.as_bytes());
+    }
+    assert_eq!(mock_server.received_requests().await.unwrap().len(), 3);
+}
+
+#[tokio::test]
+async fn test_proxy_requires_credentials() {
+    let mock_server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(header("x-forwarded-user", "ci"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&mock_server)
+        .await;
+
+    let listener: https_proxy::config::Listener = serde_yaml::from_str(&format!(
+        "port: 440\ntarget: {0}\nauth:\n  bearer_tokens:\n    ci: listener-token\nroutes:\n  - path: /hooks\n    target: {0}\n    auth:\n      bearer_tokens:\n        ci: hook-token\n",
+        mock_server.uri()
+    ))
+    .unwrap();
+    let (http_client, tls_config) = create_test_client();
+    let ctx = https_proxy::ProxyContext::new(&listener, http_client, tls_config, None).unwrap();
+    let addr: SocketAddr = "10.0.0.50:12345".parse().unwrap();
+
+    let get = |uri: &str, token: Option<&str>| {
+        let mut builder = Request::builder().uri(uri);
+        if let Some(token) = token {
+            builder = builder.header("authorization", format!("Bearer {}", token));
+        }
+        builder.body(Body::empty()).unwrap()
+    };
+
+    let response = https_proxy::handle_request(&ctx, addr, get("/", None)).await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(
+        response.headers().get("www-authenticate").unwrap(),
+        "Bearer realm=\"https-proxy\""
+    );
+
+    let response = https_proxy::handle_request(&ctx, addr, get("/", Some("listener-token"))).await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // The route's credentials replace the listener's
+    let response =
+        https_proxy::handle_request(&ctx, addr, get("/hooks/push", Some("listener-token"))).await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    let response =
+        https_proxy::handle_request(&ctx, addr, get("/hooks/push", Some("hook-token"))).await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // Credentials never reach the upstream
+    let received = mock_server.received_requests().await.unwrap();
+    assert_eq!(received.len(), 2);
+    assert!(received
+        .iter()
+        .all(|req| !req.headers.contains_key("authorization")));
+}
+
+#[tokio::test]
+async fn test_proxy_oidc_login() {
+    use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+    use base64::Engine;
+    use ring::rand::SystemRandom;
+    use ring::signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING};
+
+    let upstream = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(header("x-auth-request-email", "alice@example.com"))
+        .respond_with(ResponseTemplate::new(200).set_body_string("dashboard"))
+        .mount(&upstream)
+        .await;
+
+    // The provider publishes a P-256 key and signs ID tokens with it
+    let idp = MockServer::start().await;
+    let rng = SystemRandom::new();
+    let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng).unwrap();
+    let key =
+        EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, pkcs8.as_ref(), &rng).unwrap();
+    let point = key.public_key().as_ref();
+    Mock::given(path("/.well-known/openid-configuration"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+            "issuer": idp.uri(),
+            "authorization_endpoint": format!("{}/auth", idp.uri()),
+            "token_endpoint": format!("{}/token", idp.uri()),
+            "jwks_uri": format!("{}/jwks", idp.uri()),
+        })))
+        .mount(&idp)
+        .await;
+    Mock::given(path("/jwks"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+            "keys": [{
+                "kty": "EC",
+                "kid": "k1",
+                "crv": "P-256",
+                "x": URL_SAFE_NO_PAD.encode(&point[1..33]),
+                "y": URL_SAFE_NO_PAD.encode(&point[33..]),
+            }]
+        })))
+        .mount(&idp)
+        .await;
+
+    let listener: https_proxy::config::Listener = serde_yaml::from_str(&format!(
+        "port: 441\ntarget: {}\noidc:\n  issuer: {}\n  client_id: proxy\n  client_secret: secret\n  cookie_secret: 0123456789abcdef\n",
+        upstream.uri(),
+        idp.uri()
+    ))
+    .unwrap();
+    let (http_client, tls_config) = create_test_client();
+    let ctx = https_proxy::ProxyContext::new(&listener, http_client, tls_config, None).unwrap();
+    let addr: SocketAddr = "10.0.0.50:12345".parse().unwrap();
+
+    // API clients get a 401, browsers are sent to the provider
+    let api = Request::builder()
+        .uri("/reports?month=5")
+        .header("host", "localhost:441")
+        .body(Body::empty())
+        .unwrap();
+    let response = https_proxy::handle_request(&ctx, addr, api).await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    let page = |uri: &str, cookie: Option<&str>| {
+        let mut builder = Request::builder()
+            .uri(uri)
+            .header("host", "localhost:441")
+            .header("accept", "text/html");
+        if let Some(cookie) = cookie {
+            builder = builder.header("cookie", cookie);
+        }
+        builder.body(Body::empty()).unwrap()
+    };
+    let response = https_proxy::handle_request(&ctx, addr, page("/reports?month=5", None)).await;
+    assert_eq!(response.status(), StatusCode::FOUND);
+    let location = response.headers()["location"].to_str().unwrap().to_string();
+    assert!(location.starts_with(&format!("{}/auth?", idp.uri())));
+    assert!(location.contains("redirect_uri=https%3A%2F%2Flocalhost%3A441%2Foauth2%2Fcallback"));
+    let param = |name: &str| {
+        location
+            .split(['?', '&'])
+            .find_map(|pair| pair.strip_prefix(&format!("{}=", name)))
+            .unwrap()
+            .to_string()
+    };
+    let (state, nonce) = (param("state"), param("nonce"));
+    let state_cookie = response.headers()["set-cookie"]
+        .to_str()
+        .unwrap()
+        .split(';')
+        .next()
+        .unwrap()
+        .to_string();
+
+    // The provider redirects back with a code, exchanged for a signed ID token
+    let header = URL_SAFE_NO_PAD.encode(r#"{"alg":"ES256","kid":"k1"}"#);
+    let claims = URL_SAFE_NO_PAD.encode(
+        serde_json::json!({
+            "iss": idp.uri(),
+            "aud": "proxy",
+            "sub": "u-1",
+            "email": "alice@example.com",
+            "exp": 4_000_000_000u64,
+            "nonce": nonce,
+        })
+        .to_string(),
+    );
+    let signed = format!("{}.{}", header, claims);
+    let signature = key.sign(&rng, signed.as_bytes()).unwrap();
+    let id_token = format!("{}.{}", signed, URL_SAFE_NO_PAD.encode(signature.as_ref()));
+    Mock::given(method("POST"))
+        .and(path("/token"))
+        .and(wiremock::matchers::body_string_contains("code=abc"))
+        .respond_with(
+            ResponseTemplate::new(200).set_body_json(serde_json::json!({ "id_token": id_token })),
+        )
+        .mount(&idp)
+        .await;
+
+    let callback = format!("/oauth2/callback?code=abc&state={}", state);
+    let response =
+        https_proxy::handle_request(&ctx, addr, page(&callback, Some(&state_cookie))).await;
+    assert_eq!(response.status(), StatusCode::FOUND);
+    assert_eq!(response.headers()["location"], "/reports?month=5");
+    let session_cookie = response.headers()["set-cookie"]
+        .to_str()
+        .unwrap()
+        .split(';')
+        .next()
+        .unwrap()
+        .to_string();
+
+    // A forged state is refused
+    let forged = page(
+        "/oauth2/callback?code=abc&state=forged",
+        Some(&state_cookie),
+    );
+    let response = https_proxy::handle_request(&ctx, addr, forged).await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+    let cookies = format!("theme=dark; {}", session_cookie);
+    let response =
+        https_proxy::handle_request(&ctx, addr, page("/reports?month=5", Some(&cookies))).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let received = upstream.received_requests().await.unwrap();
+    assert_eq!(received.len(), 1);
+    assert_eq!(received[0].headers["x-auth-request-user"], "u-1");
+    assert_eq!(received[0].headers["cookie"], "theme=dark");
+}
+
+#[tokio::test]
+async fn test_proxy_forward_auth() {
+    use http_body_util::BodyExt;
+
+    let auth_server = MockServer::start().await;
+    Mock::given(path("/verify"))
+        .and(header("authorization", "Bearer good"))
+        .and(header("x-forwarded-uri", "/orders?id=7"))
+        .and(header("x-forwarded-method", "DELETE"))
+        .respond_with(ResponseTemplate::new(200).insert_header("remote-user", "alice"))
+        .mount(&auth_server)
+        .await;
+    Mock::given(path("/verify"))
+        .respond_with(
+            ResponseTemplate::new(302)
+                .insert_header("location", "https://login.local/")
+                .set_body_string("log in first"),
+        )
+        .mount(&auth_server)
+        .await;
+
+    let upstream = MockServer::start().await;
+    Mock::given(method("DELETE"))
+        .and(header("remote-user", "alice"))
+        .respond_with(ResponseTemplate::new(204))
+        .mount(&upstream)
+        .await;
+
+    let listener: https_proxy::config::Listener = serde_yaml::from_str(&format!(
+        "port: 440\ntarget: {}\nforward_auth:\n  address: {}/verify\n  auth_response_headers: [Remote-User]\n",
+        upstream.uri(),
+        auth_server.uri()
+    ))
+    .unwrap();
+    let (http_client, tls_config) = create_test_client();
+    let ctx = https_proxy::ProxyContext::new(&listener, http_client, tls_config, None).unwrap();
+    let addr: SocketAddr = "10.0.0.50:12345".parse().unwrap();
+
+    let delete = |token: &str| {
+        Request::delete("/orders?id=7")
+            .header("authorization", format!("Bearer {}", token))
+            .header("remote-user", "mallory")
+            .body(Body::empty())
+            .unwrap()
+    };
+
+    // The auth service's answer goes back to the client as-is
+    let response = https_proxy::handle_request(&ctx, addr, delete("bad")).await;
+    assert_eq!(response.status(), StatusCode::FOUND);
+    assert_eq!(response.headers()["location"], "https://login.local/");
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    assert_eq!(body, "log in first");
+    assert!(upstream.received_requests().await.unwrap().is_empty());
+
+    let response = https_proxy::handle_request(&ctx, addr, delete("good")).await;
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    let received = upstream.received_requests().await.unwrap();
+    assert_eq!(received.len(), 1);
+    assert_eq!(received[0].headers["remote-user"], "alice");
+}
+
+#[tokio::test]
+async fn test_proxy_ip_access_lists() {
+    use http_body_util::BodyExt;
+
+    let mock_server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&mock_server)
+        .await;
+
+    let listener: https_proxy::config::Listener = serde_yaml::from_str(&format!(
+        "port: 440\ntarget: {0}\ntrusted_proxies: [10.0.0.1]\nip_access:\n  deny: [192.0.2.0/24]\nroutes:\n  - path: /admin\n    target: {0}\n    ip_access:\n      allow: [10.0.0.0/8]\n      deny_body: internal only\n",
+        mock_server.uri()
+    ))
+    .unwrap();
+    let (http_client, tls_config) = create_test_client();
+    let ctx = https_proxy::ProxyContext::new(&listener, http_client, tls_config, None).unwrap();
+
+    let get = |uri: &str, forwarded_for: Option<&str>| {
+        let mut builder = Request::builder().uri(uri);
+        if let Some(forwarded_for) = forwarded_for {
+            builder = builder.header("x-forwarded-for", forwarded_for);
+        }
+        builder.body(Body::empty()).unwrap()
+    };
+    let internal: SocketAddr = "10.0.0.50:12345".parse().unwrap();
+    let external: SocketAddr = "198.51.100.7:12345".parse().unwrap();
+    let proxy: SocketAddr = "10.0.0.1:12345".parse().unwrap();
+
+    let response = https_proxy::handle_request(&ctx, internal, get("/admin/users", None)).await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let response = https_proxy::handle_request(&ctx, external, get("/admin/users", None)).await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    assert_eq!(body, "internal only");
+    let response = https_proxy::handle_request(&ctx, external, get("/", None)).await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // Behind a trusted proxy the forwarded client is checked
+    let response = https_proxy::handle_request(&ctx, proxy, get("/", Some("192.0.2.10"))).await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    let response =
+        https_proxy::handle_request(&ctx, proxy, get("/admin", Some("198.51.100.7"))).await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+    // ...but the header is ignored from anyone else
+    let response =
+        https_proxy::handle_request(&ctx, external, get("/admin", Some("10.0.0.99"))).await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+    assert_eq!(mock_server.received_requests().await.unwrap().len(), 2);
+}
+
+#[tokio::test]
+async fn test_proxy_answers_cors_preflight() {
+    let mock_server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .respond_with(ResponseTemplate::new(200).insert_header("access-control-allow-origin", "*"))
+        .mount(&mock_server)
+        .await;
+
+    let listener: https_proxy::config::Listener = serde_yaml::from_str(&format!(
+        "port: 440\ntarget: {}\nauth:\n  bearer_tokens:\n    web: token\ncors:\n  allow_origins: [\"http://localhost:*\"]\n  allow_credentials: true\n",
+        mock_server.uri()
+    ))
+    .unwrap();
+    let (http_client, tls_config) = create_test_client();
+    let ctx = https_proxy::ProxyContext::new(&listener, http_client, tls_config, None).unwrap();
+    let addr: SocketAddr = "10.0.0.50:12345".parse().unwrap();
+
+    // Preflights carry no credentials and never reach the upstream
+    let preflight = Request::builder()
+        .method("OPTIONS")
+        .uri("/items")
+        .header("origin", "http://localhost:5173")
+        .header("access-control-request-method", "GET")
+        .header("access-control-request-headers", "authorization")
+        .body(Body::empty())
+        .unwrap();
+    let response = https_proxy::handle_request(&ctx, addr, preflight).await;
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    assert_eq!(
+        response.headers()["access-control-allow-headers"],
+        "authorization"
+    );
+
+    let get = |token: Option<&str>| {
+        let mut builder = Request::builder()
+            .uri("/items")
+            .header("origin", "http://localhost:5173");
+        if let Some(token) = token {
+            builder = builder.header("authorization", format!("Bearer {}", token));
+        }
+        builder.body(Body::empty()).unwrap()
+    };
+
+    // Errors from the proxy are readable by the page too
+    let response = https_proxy::handle_request(&ctx, addr, get(None)).await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(
+        response.headers()["access-control-allow-origin"],
+        "http://localhost:5173"
+    );
+
+    // The upstream's own CORS headers are replaced by the policy
+    let response = https_proxy::handle_request(&ctx, addr, get(Some("token"))).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        response.headers()["access-control-allow-origin"],
+        "http://localhost:5173"
+    );
+    assert_eq!(
+        response.headers()["access-control-allow-credentials"],
+        "true"
+    );
+    assert_eq!(mock_server.received_requests().await.unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn test_proxy_adds_security_headers() {
+    let mock_server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .insert_header("x-frame-options", "DENY")
+                .insert_header("referrer-policy", "no-referrer"),
+        )
+        .mount(&mock_server)
+        .await;
+
+    let yaml = |prefer_upstream: bool| {
+        format!(
+            "port: 441\ntarget: {}\nsecurity_headers:\n  strict_transport_security: max-age=31536000\n  content_security_policy_report_only: \"default-src 'self'\"\n  prefer_upstream: {}\n",
+            mock_server.uri(),
+            prefer_upstream
+        )
+    };
+    let addr: SocketAddr = "10.0.0.50:12345".parse().unwrap();
+    let get = || Request::builder().uri("/").body(Body::empty()).unwrap();
+
+    let listener: https_proxy::config::Listener = serde_yaml::from_str(&yaml(false)).unwrap();
+    let (http_client, tls_config) = create_test_client();
+    let ctx = https_proxy::ProxyContext::new(&listener, http_client, tls_config, None).unwrap();
+    let response = https_proxy::handle_request(&ctx, addr, get()).await;
+    let headers = response.headers();
+    assert_eq!(headers["strict-transport-security"], "max-age=31536000");
+    assert_eq!(
+        headers["content-security-policy-report-only"],
+        "default-src 'self'"
+    );
+    assert_eq!(headers["x-content-type-options"], "nosniff");
+    assert_eq!(headers["x-frame-options"], "SAMEORIGIN");
+    assert_eq!(
+        headers["referrer-policy"],
+        "strict-origin-when-cross-origin"
+    );
+
+    // With prefer_upstream the upstream's values are kept
+    let listener: https_proxy::config::Listener = serde_yaml::from_str(&yaml(true)).unwrap();
+    let (http_client, tls_config) = create_test_client();
+    let ctx = https_proxy::ProxyContext::new(&listener, http_client, tls_config, None).unwrap();
+    let response = https_proxy::handle_request(&ctx, addr, get()).await;
+    let headers = response.headers();
+    assert_eq!(headers["x-frame-options"], "DENY");
+    assert_eq!(headers["referrer-policy"], "no-referrer");
+    assert_eq!(headers["strict-transport-security"], "max-age=31536000");
+}
+
+#[tokio::test]
+async fn test_proxy_rewrites_urls() {
+    let mock_server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/api/v1/users"))
+        .and(query_param("page", "2"))
+        .and(query_param("source", "proxy"))
+        .respond_with(ResponseTemplate::new(200).set_body_string("users"))
+        .mount(&mock_server)
+        .await;
+
+    let listener: https_proxy::config::Listener = serde_yaml::from_str(&format!(
+        r#"
+port: 440
+routes:
+  - path: /
+    target: {}
+    rewrite:
+      - match: ^/old/(.*)$
+        redirect: /v1/$1
+        status: 308
+      - match: ^/v1/(.*)$
+        replace: /api/v1/$1
+        remove_query: [debug]
+        add_query:
+          source: proxy
+"#,
+        mock_server.uri()
+    ))
+    .unwrap();
+    let (http_client, tls_config) = create_test_client();
+    let ctx = https_proxy::ProxyContext::new(&listener, http_client, tls_config, None).unwrap();
+    let addr: SocketAddr = "10.0.0.50:12345".parse().unwrap();
+    let get = |uri: &str| Request::builder().uri(uri).body(Body::empty()).unwrap();
+
+    let response = https_proxy::handle_request(&ctx, addr, get("/v1/users?page=2&debug=1")).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let requests = mock_server.received_requests().await.unwrap();
+    assert_eq!(requests[0].url.query(), Some("page=2&source=proxy"));
+
+    // Redirects are answered without reaching the upstream
+    let response = https_proxy::handle_request(&ctx, addr, get("/old/users?page=2")).await;
+    assert_eq!(response.status(), StatusCode::PERMANENT_REDIRECT);
+    assert_eq!(response.headers()["location"], "/v1/users?page=2");
+    assert_eq!(mock_server.received_requests().await.unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn test_proxy_joins_target_base_path() {
+    let mock_server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/service-a/users"))
+        .respond_with(ResponseTemplate::new(200).set_body_string("users"))
+        .mount(&mock_server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/service-b"))
+        .respond_with(ResponseTemplate::new(200).set_body_string("root"))
+        .mount(&mock_server)
+        .await;
+
+    let listener: https_proxy::config::Listener = serde_yaml::from_str(&format!(
+        "port: 440\ntarget: {0}/service-b/\npath_join: no_trailing_slash\nroutes:\n  - path: /users\n    target: {0}/service-a\n",
+        mock_server.uri()
+    ))
+    .unwrap();
+    let (http_client, tls_config) = create_test_client();
+    let ctx = https_proxy::ProxyContext::new(&listener, http_client, tls_config, None).unwrap();
+    let addr: SocketAddr = "10.0.0.50:12345".parse().unwrap();
+    let get = |uri: &str| Request::builder().uri(uri).body(Body::empty()).unwrap();
+
+    let response = https_proxy::handle_request(&ctx, addr, get("/users")).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = https_proxy::handle_request(&ctx, addr, get("/")).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    assert_eq!(&body[..], b"root");
+}
+
+#[tokio::test]
+async fn test_proxy_websocket_target_base_path() {
+    let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let backend_addr = backend.local_addr().unwrap();
+    let app = Router::new().route(
+        "/service-a/ws",
+        any(|ws: WebSocketUpgrade| async {
+            ws.on_upgrade(|mut socket: WebSocket| async move {
+                while let Some(Ok(Message::Text(text))) = socket.recv().await {
+                    if socket.send(Message::Text(text)).await.is_err() {
+                        break;
+                    }
+                }
+            })
+        }),
+    );
+    tokio::spawn(async move { axum::serve(backend, app).await.unwrap() });
+
+    let target = format!("http://{}/service-a", backend_addr);
+    let (http_client, tls_config) = create_test_client();
+    let proxy = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let proxy_addr = proxy.local_addr().unwrap();
+    let app = Router::new().fallback(any(move |connect_info: ConnectInfo<SocketAddr>, req| {
+        let target = target.clone();
+        let http_client = http_client.clone();
+        let tls_config = tls_config.clone();
+        async move {
+            https_proxy::proxy_handler(connect_info, req, target, http_client, tls_config).await
+        }
+    }));
+    tokio::spawn(async move {
+        axum::serve(
+            proxy,
+            app.into_make_service_with_connect_info::<SocketAddr>(),
+        )
+        .await
+        .unwrap()
+    });
+
+    let (mut socket, _) = tokio_tungstenite::connect_async(format!("ws://{}/ws", proxy_addr))
+        .await
+        .expect("Failed to connect to proxy");
+    socket
+        .send(TungsteniteMessage::Text("ping".to_string()))
+        .await
+        .unwrap();
+    let received = socket.next().await.unwrap().unwrap();
+    assert_eq!(received.to_text().unwrap(), "ping");
+    let _ = socket.close(None).await;
+}
+
+#[tokio::test]
+async fn test_proxy_rewrites_upstream_redirects_and_cookies() {
+    let mock_server = MockServer::start().await;
+    let upstream = mock_server.uri();
+    Mock::given(method("GET"))
+        .and(path("/service-a/private"))
+        .respond_with(
+            ResponseTemplate::new(302)
+                .insert_header("location", format!("{}/service-a/login", upstream).as_str())
+                .append_header(
+                    "set-cookie",
+                    "sid=abc; Domain=127.0.0.1; Path=/service-a; HttpOnly",
+                ),
+        )
+        .mount(&mock_server)
+        .await;
+
+    let listener: https_proxy::config::Listener = serde_yaml::from_str(&format!(
+        "port: 440\ntarget: {}/service-a\nresponse_rewrite: {{}}\n",
+        upstream
+    ))
+    .unwrap();
+    let (http_client, tls_config) = create_test_client();
+    let ctx = https_proxy::ProxyContext::new(&listener, http_client, tls_config, None).unwrap();
+    let addr: SocketAddr = "10.0.0.50:12345".parse().unwrap();
+    let req = Request::builder()
+        .uri("/private")
+        .header("host", "app.localhost:8443")
+        .body(Body::empty())
+        .unwrap();
+
+    let response = https_proxy::handle_request(&ctx, addr, req).await;
+    assert_eq!(response.status(), StatusCode::FOUND);
+    assert_eq!(
+        response.headers()["location"],
+        "https://app.localhost:8443/login"
+    );
+    assert_eq!(
+        response.headers()["set-cookie"],
+        "sid=abc; Path=/; HttpOnly; Secure"
+    );
+}
+
+#[tokio::test]
+async fn test_proxy_filters_response_bodies() {
+    let mock_server = MockServer::start().await;
+    let upstream = mock_server.uri();
+    Mock::given(method("GET"))
+        .and(path("/"))
+        .respond_with(ResponseTemplate::new(200).set_body_raw(
+            format!(
+                "<a href=\"{0}/about\">About</a><img src=\"{0}/logo.png\">",
+                upstream
+            ),
+            "text/html",
+        ))
+        .mount(&mock_server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/data.json"))
+        .respond_with(ResponseTemplate::new(200).set_body_raw(
+            format!("{{\"self\":\"{}/data.json\"}}", upstream),
+            "application/json",
+        ))
+        .mount(&mock_server)
+        .await;
+
+    let listener: https_proxy::config::Listener = serde_yaml::from_str(&format!(
+        "port: 440\nroutes:\n  - path: /\n    target: {0}\n    body_filter:\n      substitutions:\n        - find: {0}\n          replace: https://localhost:8443\n",
+        upstream
+    ))
+    .unwrap();
+    let (http_client, tls_config) = create_test_client();
+    let ctx = https_proxy::ProxyContext::new(&listener, http_client, tls_config, None).unwrap();
+    let addr: SocketAddr = "10.0.0.50:12345".parse().unwrap();
+    let get = |uri: &str| Request::builder().uri(uri).body(Body::empty()).unwrap();
+
+    let response = https_proxy::handle_request(&ctx, addr, get("/")).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(response.headers().get("content-length").is_none());
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    assert_eq!(
+        &body[..],
+        b"<a href=\"https://localhost:8443/about\">About</a><img src=\"https://localhost:8443/logo.png\">"
+    );
+
+    // Only the configured content types are filtered
+    let response = https_proxy::handle_request(&ctx, addr, get("/data.json")).await;
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    assert_eq!(body, format!("{{\"self\":\"{}/data.json\"}}", upstream));
+}
+
+#[tokio::test]
+async fn test_proxy_enforces_size_limits() {
+    let mock_server = MockServer::start().await;
+    Mock::given(method("POST"))
+        .respond_with(ResponseTemplate::new(201))
+        .mount(&mock_server)
+        .await;
+    Mock::given(method("GET"))
+        .respond_with(ResponseTemplate::new(200).set_body_string("x".repeat(100)))
+        .mount(&mock_server)
+        .await;
+
+    let listener: https_proxy::config::Listener = serde_yaml::from_str(&format!(
+        "port: 440\ntarget: {0}\nlimits:\n  max_request_body_bytes: 16\n  max_response_body_bytes: 50\n  max_header_count: 5\nroutes:\n  - path: /upload\n    target: {0}\n    limits:\n      max_request_body_bytes: 1024\n",
+        mock_server.uri()
+    ))
+    .unwrap();
+    let (http_client, tls_config) = create_test_client();
+    let ctx = https_proxy::ProxyContext::new(&listener, http_client, tls_config, None).unwrap();
+    let addr: SocketAddr = "10.0.0.50:12345".parse().unwrap();
+    let chunked = |uri: &str, size: usize| {
+        let chunks: Vec<Result<bytes::Bytes, std::io::Error>> = (0..size / 8)
+            .map(|_| Ok(bytes::Bytes::from_static(b"12345678")))
+            .collect();
+        Request::post(uri)
+            .body(Body::from_stream(futures_util::stream::iter(chunks)))
+            .unwrap()
+    };
+
+    // Chunked uploads have no Content-Length and are cut off as they stream
+    let response = https_proxy::handle_request(&ctx, addr, chunked("/items", 64)).await;
+    assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    let response = https_proxy::handle_request(&ctx, addr, chunked("/items", 16)).await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    // The route's limits replace the listener's
+    let response = https_proxy::handle_request(&ctx, addr, chunked("/upload", 64)).await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+
+    let mut req = Request::get("/").body(Body::empty()).unwrap();
+    for i in 0..6 {
+        req.headers_mut().insert(
+            axum::http::HeaderName::from_bytes(format!("x-h{}", i).as_bytes()).unwrap(),
+            axum::http::HeaderValue::from_static("1"),
+        );
+    }
+    let response = https_proxy::handle_request(&ctx, addr, req).await;
+    assert_eq!(
+        response.status(),
+        StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE
+    );
+
+    // The upstream's 100 byte body is over the 50 byte response limit
+    let req = Request::get("/").body(Body::empty()).unwrap();
+    let response = https_proxy::handle_request(&ctx, addr, req).await;
+    assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+}
+
+#[tokio::test]
+async fn test_proxy_renders_error_pages() {
+    use http_body_util::BodyExt;
+
+    let mock_server = MockServer::start().await;
+    Mock::given(path("/slow"))
+        .respond_with(ResponseTemplate::new(200).set_delay(std::time::Duration::from_secs(2)))
+        .mount(&mock_server)
+        .await;
+
+    let pages = tempfile::TempDir::new().unwrap();
+    let page = pages.path().join("5xx.html");
+    std::fs::write(&page, "<h1>${status} ${reason}</h1><p>${detail}</p>").unwrap();
+
+    let listener: https_proxy::config::Listener = serde_yaml::from_str(&format!(
+        "port: 440\ntarget: {}\nupstream_timeout_ms: 200\nerror_pages:\n  pages:\n    5xx: {}\nroutes:\n  - path: /down\n    target: http://127.0.0.1:1\n",
+        mock_server.uri(),
+        page.display()
+    ))
+    .unwrap();
+    let (http_client, tls_config) = create_test_client();
+    let ctx = https_proxy::ProxyContext::new(&listener, http_client, tls_config, None).unwrap();
+    let addr: SocketAddr = "10.0.0.60:12345".parse().unwrap();
+
+    // Browsers get the HTML page
+    let req = Request::get("/slow")
+        .header("accept", "text/html,*/*;q=0.8")
+        .body(Body::empty())
+        .unwrap();
+    let response = https_proxy::handle_request(&ctx, addr, req).await;
+    assert_eq!(response.status(), StatusCode::GATEWAY_TIMEOUT);
+    assert_eq!(
+        response.headers()["content-type"],
+        "text/html; charset=utf-8"
+    );
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    let body = String::from_utf8(body.to_vec()).unwrap();
+    assert!(body.starts_with("<h1>504 Gateway Timeout</h1><p>Upstream timed out"));
+
+    // API clients get a problem document
+    let req = Request::get("/down")
+        .header("accept", "application/json")
+        .body(Body::empty())
+        .unwrap();
+    let response = https_proxy::handle_request(&ctx, addr, req).await;
+    assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+    assert_eq!(
+        response.headers()["content-type"],
+        "application/problem+json"
+    );
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    let problem: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(problem["status"], 502);
+    assert_eq!(problem["title"], "Bad Gateway");
+    assert!(problem["detail"]
+        .as_str()
+        .unwrap()
+        .starts_with("Upstream connection failed"));
+}
+
+#[tokio::test]
+async fn test_proxy_maintenance_mode() {
+    use http_body_util::BodyExt;
+    use tower::ServiceExt;
+
+    let mock_server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&mock_server)
+        .await;
+
+    let listener: https_proxy::config::Listener = serde_yaml::from_str(&format!(
+        "port: 440\ntarget: {0}\nroutes:\n  - path: /api\n    target: {0}\n    maintenance:\n      enabled: true\n      retry_after_secs: 60\n      allow: [10.9.0.0/16]\n      allow_headers:\n        X-Maintenance-Bypass: s3cret\n",
+        mock_server.uri()
+    ))
+    .unwrap();
+    let (http_client, tls_config) = create_test_client();
+    let ctx =
+        Arc::new(https_proxy::ProxyContext::new(&listener, http_client, tls_config, None).unwrap());
+    let addr: SocketAddr = "10.0.0.50:12345".parse().unwrap();
+    let get = |uri: &str| Request::builder().uri(uri).body(Body::empty()).unwrap();
+
+    let response = https_proxy::handle_request(&ctx, addr, get("/api/users")).await;
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(response.headers()["retry-after"], "60");
+    let response = https_proxy::handle_request(&ctx, addr, get("/")).await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // The allowlist still reaches the upstream
+    let allowed: SocketAddr = "10.9.1.1:12345".parse().unwrap();
+    let response = https_proxy::handle_request(&ctx, allowed, get("/api/users")).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let req = Request::get("/api/users")
+        .header("x-maintenance-bypass", "s3cret")
+        .body(Body::empty())
+        .unwrap();
+    let response = https_proxy::handle_request(&ctx, addr, req).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    // The bypass secret stays at the proxy
+    let requests = mock_server.received_requests().await.unwrap();
+    assert!(requests
+        .last()
+        .unwrap()
+        .headers
+        .get("x-maintenance-bypass")
+        .is_none());
+
+    // The admin API switches the listener and routes at runtime
+    let admin = https_proxy::admin::router(https_proxy::admin::AdminState {
+        capture: None,
+        listeners: [(440, ctx.clone())].into(),
+    });
+    let switch = |query: &str, enabled: bool| {
+        Request::put(format!("/api/maintenance?{}", query))
+            .header("content-type", "application/json")
+            .body(Body::from(format!("{{\"enabled\": {}}}", enabled)))
+            .unwrap()
+    };
+    let response = admin
+        .clone()
+        .oneshot(switch("port=440&path=/api", false))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = admin
+        .clone()
+        .oneshot(switch("port=440", true))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = admin
+        .clone()
+        .oneshot(switch("port=440&path=/nope", true))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    let response = https_proxy::handle_request(&ctx, addr, get("/")).await;
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(response.headers()["retry-after"], "300");
+
+    let response = admin.oneshot(get("/api/maintenance")).await.unwrap();
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    let switches: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(
+        switches,
+        serde_json::json!([
+            {"port": 440, "path": null, "enabled": true},
+            {"port": 440, "path": "/api", "enabled": false},
+        ])
+    );
+}
+
+#[tokio::test]
+async fn test_proxy_size_limits_keep_bodiless_requests() {
+    let mock_server = MockServer::start().await;
+    Mock::given(method("DELETE"))
+        .respond_with(ResponseTemplate::new(204))
+        .mount(&mock_server)
+        .await;
+    Mock::given(method("POST"))
+        .respond_with(ResponseTemplate::new(201))
+        .mount(&mock_server)
+        .await;
+
+    let listener: https_proxy::config::Listener = serde_yaml::from_str(&format!(
+        "port: 440\ntarget: {}\nlimits:\n  max_request_body_bytes: 16\n",
+        mock_server.uri()
+    ))
+    .unwrap();
+    let (http_client, tls_config) = create_test_client();
+    let ctx = https_proxy::ProxyContext::new(&listener, http_client, tls_config, None).unwrap();
+    let addr: SocketAddr = "10.0.0.50:12345".parse().unwrap();
+
+    let req = Request::delete("/items/1").body(Body::empty()).unwrap();
+    let response = https_proxy::handle_request(&ctx, addr, req).await;
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    let req = Request::post("/items")
+        .header("content-length", "4")
+        .body(Body::from("1234"))
+        .unwrap();
+    let response = https_proxy::handle_request(&ctx, addr, req).await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+
+    // Neither turned into a chunked upload
+    let received = mock_server.received_requests().await.unwrap();
+    assert!(received[0].headers.get("transfer-encoding").is_none());
+    assert!(received[0].body.is_empty());
+    assert!(received[1].headers.get("transfer-encoding").is_none());
+    assert_eq!(received[1].headers["content-length"], "4");
+    assert_eq!(received[1].body, b"1234");
+}